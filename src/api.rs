@@ -0,0 +1,517 @@
+use engine;
+use engine_operator;
+use query;
+use serde_json::{self, Value};
+use statements;
+use std::time::{Duration, Instant};
+use util;
+
+/// Version of the response envelope, bumped on incompatible changes.
+pub const VERSION: u32 = 1;
+
+/// Content type asking for rows as newline delimited JSON.
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+// Rows of a streamed response sent in one chunk.
+const ROWS_PER_CHUNK: usize = 256;
+
+/// A JSON request body. Plain text bodies are taken as the query itself.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Request {
+    #[serde(flatten)]
+    pub command: Command,
+    #[serde(default)]
+    pub params: statements::Params,
+    #[serde(default)]
+    pub dialect: Option<query::Dialect>,
+}
+
+/// What a request asks for, keyed by its JSON field, e.g. `{"prepare": "? id > users : id = ?1"}`.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    Query(String),
+    Prepare(String),
+    Execute(u64),
+    Close(u64),
+}
+
+impl Request {
+    pub fn from_body(body: &str) -> Result<Request, ErrorDetails> {
+        // TQL never starts with a brace, so it's safe to tell the two apart by it.
+        if !body.trim_start().starts_with('{') {
+            return Ok(Request {
+                command: Command::Query(body.to_owned()),
+                params: Default::default(),
+                dialect: None,
+            });
+        }
+
+        serde_json::from_str(body).map_err(|e| {
+            ErrorDetails::new(
+                ErrorKind::InvalidRequest,
+                format!("Request cannot be parsed: {}", e),
+            )
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Error,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    InvalidRequest,
+    Parse,
+    Execution,
+    UnknownTable,
+    ConstraintViolation,
+    Conflict,
+    Unauthenticated,
+    PermissionDenied,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::InvalidRequest => "invalid_request",
+            ErrorKind::Parse => "parse",
+            ErrorKind::Execution => "execution",
+            ErrorKind::UnknownTable => "unknown_table",
+            ErrorKind::ConstraintViolation => "constraint_violation",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::Unauthenticated => "unauthenticated",
+            ErrorKind::PermissionDenied => "permission_denied",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ErrorDetails {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl ErrorDetails {
+    pub fn new<S: Into<String>>(kind: ErrorKind, message: S) -> ErrorDetails {
+        ErrorDetails {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<engine::Error> for ErrorDetails {
+    fn from(error: engine::Error) -> ErrorDetails {
+        match error {
+            engine::Error::UnknownTable => {
+                ErrorDetails::new(ErrorKind::UnknownTable, "Table does not exist")
+            }
+            engine::Error::ConstraintViolation => ErrorDetails::new(
+                ErrorKind::ConstraintViolation,
+                "Constraint violated, see the server log for details",
+            ),
+            engine::Error::Conflict => ErrorDetails::new(
+                ErrorKind::Conflict,
+                "Conflicts with a concurrent transaction",
+            ),
+            engine::Error::Failed => ErrorDetails::new(
+                ErrorKind::Execution,
+                "Query failed, see the server log for details",
+            ),
+        }
+    }
+}
+
+/// The response envelope. Rows hold plain JSON values, their types are in `columns`.
+#[derive(Debug, Serialize)]
+pub struct Response {
+    pub version: u32,
+    pub status: Status,
+    pub columns: Vec<engine::Column>,
+    pub rows: Vec<Vec<Value>>,
+    pub affected: usize,
+    pub generated_ids: Vec<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statement: Option<u64>,
+    pub error: Option<ErrorDetails>,
+    pub time_us: u64,
+}
+
+impl Response {
+    pub fn new(
+        result: Result<engine_operator::QueryResult, ErrorDetails>,
+        elapsed: Duration,
+    ) -> Response {
+        let mut response = Response {
+            version: VERSION,
+            status: Status::Ok,
+            columns: vec![],
+            rows: vec![],
+            affected: 0,
+            generated_ids: vec![],
+            message: None,
+            statement: None,
+            error: None,
+            time_us: elapsed.as_micros() as u64,
+        };
+
+        match result {
+            Ok(result) => {
+                response.columns = result.columns;
+                response.rows = result
+                    .rows
+                    .iter()
+                    .map(|row| row.iter().map(json_value).collect())
+                    .collect();
+                response.affected = result.affected;
+                response.generated_ids = result.generated_ids;
+                response.message = result.message;
+                response.statement = result.statement;
+            }
+            Err(error) => {
+                response.status = Status::Error;
+                response.error = Some(error);
+            }
+        }
+
+        response
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// How a response body is written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // The response envelope.
+    Json,
+    // A line with the columns, a line per row, then a line with the rest of the envelope.
+    // Statements without rows get the envelope on a single line.
+    Ndjson,
+}
+
+// Start of a streamed envelope, followed by its rows.
+#[derive(Serialize)]
+struct Head<'a> {
+    version: u32,
+    status: Status,
+    columns: &'a [engine::Column],
+}
+
+// End of a streamed envelope, written after its rows.
+#[derive(Serialize)]
+struct Tail<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+    affected: usize,
+    generated_ids: &'a [u32],
+    error: Option<ErrorDetails>,
+    time_us: u64,
+}
+
+/// Writes a response in chunks, reading the rows of a select as the body is sent. A row that
+/// cannot be read ends the stream with an error, which cuts the body short.
+pub struct ResponseStream {
+    format: Format,
+    head: Option<String>,
+    rows: Option<engine::SelectRows>,
+    is_first_row: bool,
+    result: engine_operator::QueryResult,
+    start: Instant,
+}
+
+impl ResponseStream {
+    pub fn new(
+        format: Format,
+        result: Result<engine_operator::QueryStream, ErrorDetails>,
+        start: Instant,
+    ) -> ResponseStream {
+        let mut stream = ResponseStream {
+            format,
+            head: None,
+            rows: None,
+            is_first_row: true,
+            result: Default::default(),
+            start,
+        };
+
+        match result {
+            Ok(engine_operator::QueryStream {
+                result,
+                rows: Some(rows),
+            }) => {
+                let head = serde_json::to_string(&Head {
+                    version: VERSION,
+                    status: Status::Ok,
+                    columns: &result.columns,
+                })
+                .unwrap();
+
+                stream.head = Some(match format {
+                    // Reopened to add the rows.
+                    Format::Json => format!("{},\"rows\":[", &head[..head.len() - 1]),
+                    Format::Ndjson => head + "\n",
+                });
+                stream.rows = Some(rows);
+                stream.result = result;
+            }
+            result => {
+                let result = result.map(|stream| stream.result);
+                let mut body = Response::new(result, start.elapsed()).to_json();
+                if format == Format::Ndjson {
+                    body.push('\n');
+                }
+                stream.head = Some(body);
+            }
+        }
+
+        stream
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    fn tail(&self) -> String {
+        let is_ndjson = self.format == Format::Ndjson;
+        let tail = serde_json::to_string(&Tail {
+            version: if is_ndjson { Some(VERSION) } else { None },
+            status: if is_ndjson { Some(Status::Ok) } else { None },
+            affected: self.result.affected,
+            generated_ids: &self.result.generated_ids,
+            error: None,
+            time_us: self.start.elapsed().as_micros() as u64,
+        })
+        .unwrap();
+
+        match self.format {
+            // Closes the rows, the rest of the envelope follows.
+            Format::Json => format!("],{}", &tail[1..]),
+            Format::Ndjson => tail + "\n",
+        }
+    }
+}
+
+impl Iterator for ResponseStream {
+    type Item = Result<String, ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(head) = self.head.take() {
+            return Some(Ok(head));
+        }
+
+        let mut chunk = String::new();
+        let mut count = 0;
+        for row in self.rows.as_mut()?.take(ROWS_PER_CHUNK) {
+            let row = match row {
+                Ok(row) => row,
+                Err(()) => {
+                    self.rows = None;
+                    return Some(Err(()));
+                }
+            };
+            let row: Vec<Value> = row.iter().map(json_value).collect();
+
+            match self.format {
+                Format::Json => {
+                    if !self.is_first_row {
+                        chunk.push(',');
+                    }
+                    chunk.push_str(&serde_json::to_string(&row).unwrap());
+                }
+                Format::Ndjson => {
+                    chunk.push_str(&serde_json::to_string(&row).unwrap());
+                    chunk.push('\n');
+                }
+            }
+            self.is_first_row = false;
+            count += 1;
+        }
+
+        if count < ROWS_PER_CHUNK {
+            self.rows = None;
+            chunk.push_str(&self.tail());
+        }
+
+        Some(Ok(chunk))
+    }
+}
+
+fn json_value(val: &util::Val) -> Value {
+    match val {
+        util::Val::U32(n) => Value::from(*n),
+        util::Val::Varchar(s) => Value::from(s.clone()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use query_parser;
+
+    #[test]
+    fn test_request_from_body() {
+        assert_eq!(
+            Ok(Request {
+                command: Command::Query("? id > users".to_owned()),
+                params: Default::default(),
+                dialect: None,
+            }),
+            Request::from_body("? id > users")
+        );
+        assert_eq!(
+            Ok(Request {
+                command: Command::Query("? id > users : id = ?1".to_owned()),
+                params: statements::Params::Positional(vec![Value::from(1)]),
+                dialect: None,
+            }),
+            Request::from_body(r#" {"query": "? id > users : id = ?1", "params": [1]}"#)
+        );
+        assert_eq!(
+            Ok(Request {
+                command: Command::Execute(3),
+                params: serde_json::from_str(r#"{"id": 1}"#).unwrap(),
+                dialect: Some(query::Dialect::Sql),
+            }),
+            Request::from_body(r#"{"execute": 3, "params": {"id": 1}, "dialect": "sql"}"#)
+        );
+
+        let err = Request::from_body(r#"{"qeury": "? id > users"}"#).unwrap_err();
+        assert_eq!(ErrorKind::InvalidRequest, err.kind);
+    }
+
+    #[test]
+    fn test_prepare_response_envelope() {
+        let result = engine_operator::QueryResult {
+            statement: Some(3),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            r#"{"version":1,"status":"ok","columns":[],"rows":[],"affected":0,"generated_ids":[],"statement":3,"error":null,"time_us":0}"#,
+            Response::new(Ok(result), Duration::from_micros(0)).to_json()
+        );
+    }
+
+    #[test]
+    fn test_response_envelope() {
+        let result = engine_operator::QueryResult {
+            columns: vec![
+                engine::Column {
+                    name: "id".to_owned(),
+                    data_type: query::Type::Int,
+                },
+                engine::Column {
+                    name: "name".to_owned(),
+                    data_type: query::Type::Varchar(8),
+                },
+            ],
+            rows: vec![vec![
+                util::Val::U32(1),
+                util::Val::Varchar("Ann".to_owned()),
+            ]],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            r#"{"version":1,"status":"ok","columns":[{"name":"id","type":"Int"},{"name":"name","type":{"Varchar":8}}],"rows":[[1,"Ann"]],"affected":0,"generated_ids":[],"error":null,"time_us":12}"#,
+            Response::new(Ok(result), Duration::from_micros(12)).to_json()
+        );
+    }
+
+    // Rows of a select on a table of `count` ints.
+    fn select_stream(table_name: &str, count: u32) -> engine_operator::QueryStream {
+        let eo: engine_operator::EngineOperator = Default::default();
+        let run = |raw: &str| {
+            let query = query_parser::QueryParser.parse(raw).unwrap();
+            eo.execute_streaming(None, None, query).unwrap()
+        };
+
+        run(&format!("+ {} id int", table_name));
+        for i in 0..count {
+            run(&format!("> {} id {}", table_name, i));
+        }
+        run(&format!("? id > {}", table_name))
+    }
+
+    fn body(stream: ResponseStream) -> String {
+        // The time is left out, it differs between runs.
+        let body = stream.collect::<Result<String, ()>>().unwrap();
+        let time_start = body.rfind("\"time_us\":").unwrap();
+        body[..time_start].to_owned()
+    }
+
+    #[test]
+    fn test_streamed_envelope_matches_response() {
+        let count = ROWS_PER_CHUNK as u32 + 1;
+        let stream = select_stream("api_stream_json", count);
+        let chunks: Vec<String> = ResponseStream::new(Format::Json, Ok(stream), Instant::now())
+            .collect::<Result<_, ()>>()
+            .unwrap();
+        // The head, then the rows in two chunks, the last one closing the envelope.
+        assert_eq!(3, chunks.len());
+
+        let expected = Response::new(
+            Ok(select_stream("api_stream_expected", count)
+                .into_result()
+                .unwrap()),
+            Duration::from_micros(0),
+        )
+        .to_json();
+        let body = chunks.concat();
+        assert!(serde_json::from_str::<Value>(&body).is_ok());
+        assert_eq!(
+            &expected[..expected.rfind("\"time_us\":").unwrap()],
+            &body[..body.rfind("\"time_us\":").unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_streamed_ndjson() {
+        let stream = select_stream("api_stream_ndjson", 2);
+
+        assert_eq!(
+            "{\"version\":1,\"status\":\"ok\",\"columns\":[{\"name\":\"id\",\"type\":\"Int\"}]}\n\
+             [0]\n\
+             [1]\n\
+             {\"version\":1,\"status\":\"ok\",\"affected\":0,\"generated_ids\":[],\"error\":null,",
+            body(ResponseStream::new(
+                Format::Ndjson,
+                Ok(stream),
+                Instant::now()
+            ))
+        );
+
+        let error = ErrorDetails::new(ErrorKind::Parse, "Query cannot be parsed");
+        assert_eq!(
+            r#"{"version":1,"status":"error","columns":[],"rows":[],"affected":0,"generated_ids":[],"error":{"kind":"parse","message":"Query cannot be parsed"},"#,
+            body(ResponseStream::new(
+                Format::Ndjson,
+                Err(error),
+                Instant::now()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_error_response_envelope() {
+        let error = ErrorDetails::new(ErrorKind::Parse, "Query cannot be parsed");
+
+        assert_eq!(
+            r#"{"version":1,"status":"error","columns":[],"rows":[],"affected":0,"generated_ids":[],"error":{"kind":"parse","message":"Query cannot be parsed"},"time_us":0}"#,
+            Response::new(Err(error), Duration::from_micros(0)).to_json()
+        );
+    }
+}
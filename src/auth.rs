@@ -0,0 +1,348 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use query;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Table name standing for every table in grants.
+pub const ALL_TABLES: &str = "*";
+
+// Rounds of PBKDF2 for new passwords, making each guess at one as slow. Tests don't need that.
+#[cfg(not(test))]
+const PASSWORD_ITERATIONS: u32 = 100_000;
+#[cfg(test)]
+const PASSWORD_ITERATIONS: u32 = 16;
+
+const SHA256_BLOCK_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct User {
+    // Base64 of a random salt, and of the PBKDF2-HMAC-SHA256 of the password with it.
+    salt: String,
+    password_hash: String,
+    iterations: u32,
+    // Admins may do anything, including managing users.
+    #[serde(default)]
+    is_admin: bool,
+    #[serde(default)]
+    grants: HashMap<String, HashSet<query::Privilege>>,
+    // Hashes of the tokens issued to the user.
+    #[serde(default)]
+    tokens: HashSet<String>,
+}
+
+/// Users and their grants, kept as the system catalog of the data directory.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Catalog {
+    users: BTreeMap<String, User>,
+}
+
+// What a query needs to be allowed.
+enum Required<'a> {
+    Nothing,
+    Admin,
+    Table(&'a str, query::Privilege),
+}
+
+impl Catalog {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn create_user(&mut self, name: &str, password: &str, is_admin: bool) -> Result<(), ()> {
+        if self.users.contains_key(name) {
+            error!("User already exists: {}", name);
+            return Err(());
+        }
+
+        let salt = random_bytes(16);
+        self.users.insert(
+            name.to_owned(),
+            User {
+                salt: STANDARD.encode(&salt),
+                password_hash: STANDARD.encode(pbkdf2(
+                    password.as_bytes(),
+                    &salt,
+                    PASSWORD_ITERATIONS,
+                )),
+                iterations: PASSWORD_ITERATIONS,
+                is_admin,
+                grants: HashMap::new(),
+                tokens: HashSet::new(),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn drop_user(&mut self, name: &str) -> Result<(), ()> {
+        self.users
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| error!("Unknown user: {}", name))
+    }
+
+    pub fn grant(
+        &mut self,
+        name: &str,
+        table: &str,
+        privileges: &[query::Privilege],
+    ) -> Result<(), ()> {
+        let user = self.user_mut(name)?;
+        user.grants
+            .entry(table.to_owned())
+            .or_default()
+            .extend(privileges);
+        Ok(())
+    }
+
+    /// Takes back privileges granted on the table. Revoking from `*` leaves the grants on single
+    /// tables in place.
+    pub fn revoke(
+        &mut self,
+        name: &str,
+        table: &str,
+        privileges: &[query::Privilege],
+    ) -> Result<(), ()> {
+        let user = self.user_mut(name)?;
+        if let Some(granted) = user.grants.get_mut(table) {
+            for privilege in privileges {
+                granted.remove(privilege);
+            }
+            if granted.is_empty() {
+                user.grants.remove(table);
+            }
+        }
+        Ok(())
+    }
+
+    /// Issues a token the user can authenticate with instead of the password. Only its hash is
+    /// kept.
+    pub fn create_token(&mut self, name: &str) -> Result<String, ()> {
+        let user = self.user_mut(name)?;
+        let token = URL_SAFE_NO_PAD.encode(random_bytes(32));
+        user.tokens
+            .insert(STANDARD.encode(hash(&[], token.as_bytes())));
+        Ok(token)
+    }
+
+    /// Checks the password of the user, or one of the tokens issued to them.
+    pub fn authenticate(&self, name: &str, secret: &str) -> bool {
+        let user = match self.users.get(name) {
+            Some(user) => user,
+            None => return false,
+        };
+
+        // Tokens are checked first, they're cheap to hash.
+        if user
+            .tokens
+            .contains(&STANDARD.encode(hash(&[], secret.as_bytes())))
+        {
+            return true;
+        }
+
+        let salt = STANDARD.decode(&user.salt).unwrap_or_default();
+        let password_hash = STANDARD.encode(pbkdf2(secret.as_bytes(), &salt, user.iterations));
+        is_equal(&password_hash, &user.password_hash)
+    }
+
+    /// User the token was issued to.
+    pub fn token_user(&self, token: &str) -> Option<String> {
+        let token_hash = STANDARD.encode(hash(&[], token.as_bytes()));
+        self.users
+            .iter()
+            .find(|(_, user)| user.tokens.contains(&token_hash))
+            .map(|(name, _)| name.clone())
+    }
+
+    /// Whether the user may run the query.
+    pub fn is_allowed(&self, name: &str, query: &query::Query) -> bool {
+        let user = match self.users.get(name) {
+            Some(user) => user,
+            None => return false,
+        };
+        if user.is_admin {
+            return true;
+        }
+
+        match required(query) {
+            Required::Nothing => true,
+            Required::Admin => false,
+            Required::Table(table, privilege) => [table, ALL_TABLES].iter().any(|table| {
+                user.grants
+                    .get(*table)
+                    .is_some_and(|granted| granted.contains(&privilege))
+            }),
+        }
+    }
+
+    fn user_mut(&mut self, name: &str) -> Result<&mut User, ()> {
+        self.users
+            .get_mut(name)
+            .ok_or_else(|| error!("Unknown user: {}", name))
+    }
+}
+
+/// What the query needs to be allowed, for logs, as its values may be passwords.
+pub fn requirement(query: &query::Query) -> String {
+    match required(query) {
+        Required::Nothing => "nothing".to_owned(),
+        Required::Admin => "admin".to_owned(),
+        Required::Table(table, privilege) => format!("{:?} on {}", privilege, table),
+    }
+}
+
+fn required(query: &query::Query) -> Required<'_> {
+    match query {
+        query::Query::Create(q) => Required::Table(&q.table, query::Privilege::Ddl),
+        query::Query::CreateIndex(q) => Required::Table(&q.table, query::Privilege::Ddl),
+        query::Query::Select(q) => Required::Table(&q.table, query::Privilege::Select),
+        query::Query::Cursor(query::CursorQuery::Declare { select, .. }) => {
+            Required::Table(&select.table, query::Privilege::Select)
+        }
+        query::Query::Insert(q) => Required::Table(&q.table_name, query::Privilege::Insert),
+        query::Query::Delete(q) => Required::Table(&q.table, query::Privilege::Delete),
+        query::Query::Auth(query::AuthQuery::CreateToken) => Required::Nothing,
+        query::Query::Auth(_) => Required::Admin,
+        query::Query::Describe(_)
+        | query::Query::Transaction(_)
+        | query::Query::SetDialect(_)
+        | query::Query::Cursor(_) => Required::Nothing,
+    }
+}
+
+fn hash(salt: &[u8], secret: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(secret);
+    hasher.finalize().to_vec()
+}
+
+// PBKDF2 with HMAC-SHA256, as long as one hash.
+fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    // The HMAC key is the same every round, so the hashers are keyed once.
+    let mut key = [0u8; SHA256_BLOCK_SIZE];
+    if password.len() > SHA256_BLOCK_SIZE {
+        key[..32].copy_from_slice(&Sha256::digest(password));
+    } else {
+        key[..password.len()].copy_from_slice(password);
+    }
+    let mut inner = Sha256::new();
+    inner.update(key.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    let mut outer = Sha256::new();
+    outer.update(key.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    let hmac = |message: &[u8]| {
+        let inner = inner.clone().chain_update(message).finalize();
+        outer.clone().chain_update(inner).finalize()
+    };
+
+    let mut block = hmac(&[salt, &1u32.to_be_bytes()].concat());
+    let mut derived = block;
+    for _ in 1..iterations {
+        block = hmac(&block);
+        for (d, b) in derived.iter_mut().zip(block.iter()) {
+            *d ^= b;
+        }
+    }
+    derived.to_vec()
+}
+
+/// A session id nobody can guess, for the servers that name the sessions of their clients.
+pub fn new_session_id(prefix: &str) -> String {
+    format!("{}-{}", prefix, URL_SAFE_NO_PAD.encode(random_bytes(16)))
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+// Compares in the same time wherever the first difference is.
+fn is_equal(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use query_parser::QueryParser;
+
+    fn query(raw: &str) -> query::Query {
+        QueryParser.parse(raw).unwrap()
+    }
+
+    #[test]
+    fn test_passwords_and_tokens_authenticate() {
+        let mut catalog = Catalog::default();
+        catalog.create_user("ann", "secret", false).unwrap();
+        assert!(catalog.create_user("ann", "other", false).is_err());
+
+        assert!(catalog.authenticate("ann", "secret"));
+        assert!(!catalog.authenticate("ann", "Secret"));
+        assert!(!catalog.authenticate("bob", "secret"));
+
+        let token = catalog.create_token("ann").unwrap();
+        assert!(catalog.authenticate("ann", &token));
+        assert_eq!(Some("ann".to_owned()), catalog.token_user(&token));
+        assert_eq!(None, catalog.token_user("forged"));
+
+        // Only hashes are stored.
+        let json = serde_json::to_string(&catalog).unwrap();
+        assert!(!json.contains("secret") && !json.contains(&token));
+    }
+
+    #[test]
+    fn test_pbkdf2_matches_the_reference() {
+        // RFC 7914, section 11: "passwd" with "salt" in one round, first 32 bytes.
+        assert_eq!(
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc",
+            pbkdf2(b"passwd", b"salt", 1)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        );
+    }
+
+    #[test]
+    fn test_grants_allow_statements() {
+        let mut catalog = Catalog::default();
+        catalog.create_user("root", "pw", true).unwrap();
+        catalog.create_user("ann", "pw", false).unwrap();
+
+        let select = query("? id > users");
+        let insert = query("> users id 1");
+        let create = query("+ books id int");
+        assert!(!catalog.is_allowed("ann", &select));
+        assert!(catalog.is_allowed("root", &select));
+        assert!(catalog.is_allowed("ann", &query(":begin")));
+
+        catalog
+            .grant("ann", "users", &[query::Privilege::Select])
+            .unwrap();
+        assert!(catalog.is_allowed("ann", &select));
+        assert!(!catalog.is_allowed("ann", &insert));
+
+        catalog
+            .grant("ann", ALL_TABLES, &[query::Privilege::Ddl])
+            .unwrap();
+        assert!(catalog.is_allowed("ann", &create));
+        assert!(!catalog.is_allowed("ann", &query(":create_user bob pw")));
+
+        catalog
+            .revoke("ann", "users", &[query::Privilege::Select])
+            .unwrap();
+        assert!(!catalog.is_allowed("ann", &select));
+        assert!(catalog
+            .grant("bob", "users", &query::Privilege::all())
+            .is_err());
+
+        catalog.drop_user("ann").unwrap();
+        assert!(!catalog.is_allowed("ann", &create));
+    }
+}
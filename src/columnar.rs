@@ -0,0 +1,413 @@
+use engine;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops;
+use std::path::PathBuf;
+
+// Runs of equal values, each a count and the value.
+const RUN_LENGTH: u8 = 0;
+// The distinct values, then the position of each value among them.
+const DICTIONARY: u8 = 1;
+// The values one after the other, when neither encoding makes them smaller.
+const PLAIN: u8 = 2;
+
+// Encoding, number of values and payload length.
+const HEADER_SIZE: usize = 17;
+
+/// Keeps each column of a table in a file of its own, so a scan reads only the columns it
+/// needs. Rows appended together make a segment in every column file, run-length or dictionary
+/// encoded, whichever is smaller.
+#[derive(Debug)]
+pub struct ColumnStore {
+    dir: PathBuf,
+    schema: engine::Schema,
+    row_size: usize,
+}
+
+impl ColumnStore {
+    pub fn open<P: Into<PathBuf>>(dir: P, schema: engine::Schema) -> Result<ColumnStore, ()> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .map_err(|e| error!("Column directory cannot be created {:?}: {}", dir, e))?;
+
+        let row_size = schema.values().map(|column| column.range().len()).sum();
+        Ok(ColumnStore {
+            dir,
+            schema,
+            row_size,
+        })
+    }
+
+    pub fn append(&self, rows: &[&engine::Row]) -> Result<(), ()> {
+        for (name, column) in &self.schema {
+            let f = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path(name))
+                .map_err(|e| error!("Column {} cannot be written: {}", name, e))?;
+            write_segment(f, column.range(), rows)
+                .map_err(|e| error!("Column {} cannot be written: {}", name, e))?;
+        }
+        Ok(())
+    }
+
+    /// Replaces all the rows. Columns are written aside and renamed once they all are, so a
+    /// failed write leaves the old rows.
+    pub fn write(&self, rows: &[&engine::Row]) -> Result<(), ()> {
+        for (name, column) in &self.schema {
+            let f = File::create(self.tmp_path(name))
+                .map_err(|e| error!("Column {} cannot be written: {}", name, e))?;
+            write_segment(f, column.range(), rows)
+                .map_err(|e| error!("Column {} cannot be written: {}", name, e))?;
+        }
+
+        for name in self.schema.keys() {
+            fs::rename(self.tmp_path(name), self.path(name))
+                .map_err(|e| error!("Column {} cannot be written: {}", name, e))?;
+        }
+        Ok(())
+    }
+
+    /// Rows with only the given columns read, the others are left zeroed. Rows are counted by
+    /// any one column when none is given.
+    pub fn scan(&self, columns: &[&str]) -> Result<ColumnScan, ()> {
+        let mut names: Vec<&str> = columns.to_vec();
+        if names.is_empty() {
+            names.extend(self.schema.keys().take(1).map(String::as_str));
+        }
+        names.sort_unstable();
+        names.dedup();
+
+        let mut readers: Vec<ColumnReader> = vec![];
+        for name in names {
+            let column = self.schema.get(name).ok_or_else(|| {
+                error!("Column not found: {}", name);
+            })?;
+            let f = match File::open(self.path(name)) {
+                Ok(f) => f,
+                // Column files are created by the first rows.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Ok(ColumnScan::default());
+                }
+                Err(e) => {
+                    error!("Column {} cannot be read: {}", name, e);
+                    return Err(());
+                }
+            };
+            readers.push(ColumnReader {
+                name: name.to_owned(),
+                f: BufReader::new(f),
+                range: column.range(),
+                values: vec![],
+                count: 0,
+                position: 0,
+            });
+        }
+
+        Ok(ColumnScan {
+            readers,
+            row_size: self.row_size,
+            is_done: false,
+        })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.schema.keys().map(String::as_str)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.col", name))
+    }
+
+    fn tmp_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.col.tmp", name))
+    }
+}
+
+/// Rows put together from the column files as they are read, a segment at a time.
+#[derive(Default)]
+pub struct ColumnScan {
+    readers: Vec<ColumnReader>,
+    row_size: usize,
+    is_done: bool,
+}
+
+impl Iterator for ColumnScan {
+    type Item = Result<engine::Row, ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_done || self.readers.is_empty() {
+            return None;
+        }
+
+        let mut row = vec![0; self.row_size];
+        for reader in &mut self.readers {
+            match reader.read_into(&mut row) {
+                Ok(true) => {}
+                // Columns cut short by a crash while appending end the scan at the shortest.
+                Ok(false) => {
+                    self.is_done = true;
+                    return None;
+                }
+                Err(()) => {
+                    self.is_done = true;
+                    return Some(Err(()));
+                }
+            }
+        }
+        Some(Ok(row))
+    }
+}
+
+struct ColumnReader {
+    name: String,
+    f: BufReader<File>,
+    range: ops::Range<usize>,
+    // Values of the current segment, decoded.
+    values: Vec<u8>,
+    count: usize,
+    position: usize,
+}
+
+impl ColumnReader {
+    // Copies the next value into the row, false once the column has no more.
+    fn read_into(&mut self, row: &mut [u8]) -> Result<bool, ()> {
+        while self.position == self.count {
+            match read_segment(&mut self.f, self.range.len()) {
+                Ok(Some((count, values))) => {
+                    self.count = count;
+                    self.values = values;
+                    self.position = 0;
+                }
+                Ok(None) => return Ok(false),
+                Err(e) => {
+                    error!("Column {} cannot be read: {}", self.name, e);
+                    return Err(());
+                }
+            }
+        }
+
+        let size = self.range.len();
+        let offs = self.position * size;
+        row[self.range.clone()].copy_from_slice(&self.values[offs..offs + size]);
+        self.position += 1;
+        Ok(true)
+    }
+}
+
+fn write_segment(f: File, range: ops::Range<usize>, rows: &[&engine::Row]) -> io::Result<()> {
+    let values: Vec<&[u8]> = rows.iter().map(|row| &row[range.clone()]).collect();
+    let (encoding, payload) = encode(&values);
+
+    let mut f = BufWriter::new(f);
+    let mut header: Vec<u8> = vec![encoding];
+    write_uint(&mut header, values.len(), 8);
+    write_uint(&mut header, payload.len(), 8);
+    f.write_all(&header)?;
+    f.write_all(&payload)?;
+    f.flush()
+}
+
+// The number of values and the values one after the other. None at the end of the file, or of
+// a segment cut short by a crash while appending.
+fn read_segment<R: Read>(f: &mut R, size: usize) -> io::Result<Option<(usize, Vec<u8>)>> {
+    let mut header = [0; HEADER_SIZE];
+    match f.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut payload = vec![0; read_uint(&header[9..17])];
+    match f.read_exact(&mut payload) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let count = read_uint(&header[1..9]);
+    decode(header[0], &payload, count, size)
+        .map(|values| Some((count, values)))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "corrupt segment"))
+}
+
+// Encodes the values every way, and keeps the shortest.
+fn encode(values: &[&[u8]]) -> (u8, Vec<u8>) {
+    let mut run_length: Vec<u8> = vec![];
+    let mut position = 0;
+    while position < values.len() {
+        let value = values[position];
+        let run = values[position..]
+            .iter()
+            .take_while(|other| **other == value)
+            .count();
+        write_uint(&mut run_length, run, 4);
+        run_length.extend_from_slice(value);
+        position += run;
+    }
+
+    let mut distinct: Vec<&[u8]> = vec![];
+    let mut positions: HashMap<&[u8], usize> = HashMap::new();
+    let indices: Vec<usize> = values
+        .iter()
+        .map(|value| {
+            *positions.entry(value).or_insert_with(|| {
+                distinct.push(value);
+                distinct.len() - 1
+            })
+        })
+        .collect();
+    let width = match distinct.len() {
+        0..=0x100 => 1,
+        0x101..=0x10000 => 2,
+        _ => 4,
+    };
+    let mut dictionary: Vec<u8> = vec![];
+    write_uint(&mut dictionary, distinct.len(), 4);
+    for value in &distinct {
+        dictionary.extend_from_slice(value);
+    }
+    dictionary.push(width as u8);
+    for index in indices {
+        write_uint(&mut dictionary, index, width);
+    }
+
+    vec![
+        (RUN_LENGTH, run_length),
+        (DICTIONARY, dictionary),
+        (PLAIN, values.concat()),
+    ]
+    .into_iter()
+    .min_by_key(|(_, payload)| payload.len())
+    .unwrap()
+}
+
+fn decode(encoding: u8, mut payload: &[u8], count: usize, size: usize) -> Result<Vec<u8>, ()> {
+    let mut values: Vec<u8> = Vec::with_capacity(count * size);
+    match encoding {
+        RUN_LENGTH => {
+            while !payload.is_empty() {
+                let run = read_uint(take(&mut payload, 4)?);
+                let value = take(&mut payload, size)?;
+                for _ in 0..run {
+                    values.extend_from_slice(value);
+                }
+            }
+        }
+        DICTIONARY => {
+            let len = read_uint(take(&mut payload, 4)?);
+            let distinct = take(&mut payload, len * size)?;
+            let width = take(&mut payload, 1)?[0] as usize;
+            for _ in 0..count {
+                let index = read_uint(take(&mut payload, width)?);
+                if index >= len {
+                    return Err(());
+                }
+                values.extend_from_slice(&distinct[index * size..(index + 1) * size]);
+            }
+        }
+        PLAIN => values.extend_from_slice(payload),
+        _ => return Err(()),
+    }
+
+    if values.len() != count * size {
+        return Err(());
+    }
+    Ok(values)
+}
+
+fn take<'a>(payload: &mut &'a [u8], len: usize) -> Result<&'a [u8], ()> {
+    if payload.len() < len {
+        return Err(());
+    }
+    let (head, tail) = payload.split_at(len);
+    *payload = tail;
+    Ok(head)
+}
+
+// Little endian, like the ints of rows.
+fn write_uint(buf: &mut Vec<u8>, value: usize, width: usize) {
+    for idx in 0..width {
+        buf.push(((value as u64) >> (idx * 8)) as u8);
+    }
+}
+
+fn read_uint(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |acc, byte| (acc << 8) | *byte as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use query;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn test_values_are_encoded_by_the_shortest_encoding() {
+        let (one, two, three): (&[u8], &[u8], &[u8]) =
+            (&[1, 0, 0, 0], &[2, 0, 0, 0], &[3, 0, 0, 0]);
+        let runs = vec![one, one, one, one, two];
+        let repeated = vec![one, two, one, two, one, two];
+        let distinct = vec![one, two, three];
+
+        for (values, expected_encoding) in &[
+            (runs, RUN_LENGTH),
+            (repeated, DICTIONARY),
+            (distinct, PLAIN),
+        ] {
+            let (encoding, payload) = encode(values);
+            assert_eq!(*expected_encoding, encoding);
+            assert_eq!(
+                Ok(values.concat()),
+                decode(encoding, &payload, values.len(), 4)
+            );
+        }
+
+        assert!(decode(DICTIONARY, &[1, 0, 0, 0, 7, 7, 1, 1], 1, 2).is_err());
+    }
+
+    #[test]
+    fn test_scans_read_only_the_given_columns() {
+        let dir = TempDir::new("columns");
+        let table: engine::Table = engine::Table::new(
+            vec![
+                query::FieldDef::new("id".to_owned(), query::Type::Int),
+                query::FieldDef::new("kind".to_owned(), query::Type::Int),
+            ],
+            vec![],
+        );
+        let store = ColumnStore::open(dir.path(), table.schema.clone()).unwrap();
+        let row = |id: u8, kind: u8| -> engine::Row {
+            let mut row = vec![0; 8];
+            row[table.schema["id"].range().start] = id;
+            row[table.schema["kind"].range().start] = kind;
+            row
+        };
+        let scan = |columns: &[&str]| -> Vec<engine::Row> {
+            store.scan(columns).unwrap().map(Result::unwrap).collect()
+        };
+        assert!(scan(&["id"]).is_empty());
+
+        let (first, second, third) = (row(1, 7), row(2, 7), row(3, 8));
+        store.append(&[&first, &second]).unwrap();
+        store.append(&[&third]).unwrap();
+        assert_eq!(
+            vec![first.clone(), second.clone(), third.clone()],
+            scan(&["id", "kind"])
+        );
+        assert_eq!(3, scan(&[]).len());
+
+        // The other column isn't read, so it can go missing.
+        fs::remove_file(dir.path().join("id.col")).unwrap();
+        assert_eq!(vec![row(0, 7), row(0, 7), row(0, 8)], scan(&["kind"]));
+        assert!(store.scan(&["id"]).unwrap().next().is_none());
+        assert!(store.scan(&["name"]).is_err());
+
+        store.write(&[&first, &third]).unwrap();
+        assert_eq!(vec![first, third], scan(&["id", "kind", "id"]));
+    }
+}
@@ -0,0 +1,164 @@
+use engine_operator;
+use listener;
+use native_tls;
+use std::fs::File;
+use std::io::Read;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use tls;
+use toml;
+
+/// Server settings, read from a TOML file. Missing keys keep their defaults.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    // Port of the binary protocol.
+    pub binary_port: u16,
+    // Port of the Postgres protocol.
+    pub pg_port: u16,
+    pub data_dir: String,
+    // Keep the tables in memory only, nothing is read from or written to the data directory.
+    pub memory: bool,
+    // Cursors open at once on the server.
+    pub max_cursors: usize,
+    // Seconds a cursor stays open without being fetched from.
+    pub cursor_idle_timeout: u64,
+    // Connections each of the binary and Postgres ports take at once.
+    pub max_connections: usize,
+    // Seconds a binary or Postgres connection may wait on a read or a write before it's dropped.
+    pub connection_timeout: u64,
+    // Seconds an HTTP session stays open without a request, its transaction is then rolled back.
+    pub session_idle_timeout: u64,
+    // Whether statements need an authenticated user with the privileges to run them.
+    pub auth: bool,
+    // Password of the `admin` user, created if there are no users yet.
+    pub admin_password: Option<String>,
+    // PEM certificate and PKCS #8 key, HTTP is served over TLS when both are set.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            host: "127.0.0.1".to_owned(),
+            port: 8421,
+            binary_port: 8422,
+            pg_port: 5433,
+            data_dir: "./db/".to_owned(),
+            memory: false,
+            max_cursors: 64,
+            cursor_idle_timeout: 300,
+            max_connections: 64,
+            connection_timeout: 300,
+            session_idle_timeout: 300,
+            auth: false,
+            admin_password: None,
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> Result<Config, ()> {
+        let mut raw = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut raw))
+            .map_err(|e| error!("Config cannot be read: {}", e))?;
+
+        Config::from_toml(&raw)
+    }
+
+    pub fn from_toml(raw: &str) -> Result<Config, ()> {
+        toml::from_str(raw).map_err(|e| error!("Config cannot be parsed: {}", e))
+    }
+
+    pub fn cursor_limits(&self) -> engine_operator::CursorLimits {
+        engine_operator::CursorLimits {
+            max: self.max_cursors,
+            idle_timeout: Duration::from_secs(self.cursor_idle_timeout),
+        }
+    }
+
+    pub fn connection_limits(&self) -> listener::ConnectionLimits {
+        listener::ConnectionLimits {
+            max: self.max_connections,
+            timeout: Duration::from_secs(self.connection_timeout),
+        }
+    }
+
+    /// TLS acceptor for HTTP, if it's configured.
+    pub fn tls_acceptor(&self) -> Result<Option<native_tls::TlsAcceptor>, ()> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => tls::acceptor(cert, key).map(Some),
+            (None, None) => Ok(None),
+            _ => {
+                error!("TLS needs both tls_cert and tls_key");
+                Err(())
+            }
+        }
+    }
+
+    pub fn addr(&self) -> Result<SocketAddr, ()> {
+        self.socket_addr(self.port)
+    }
+
+    pub fn binary_addr(&self) -> Result<SocketAddr, ()> {
+        self.socket_addr(self.binary_port)
+    }
+
+    pub fn pg_addr(&self) -> Result<SocketAddr, ()> {
+        self.socket_addr(self.pg_port)
+    }
+
+    fn socket_addr(&self, port: u16) -> Result<SocketAddr, ()> {
+        let mut addrs = (&self.host[..], port)
+            .to_socket_addrs()
+            .map_err(|e| error!("Invalid address {}:{}: {}", self.host, port, e))?;
+
+        addrs.next().ok_or(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_toml_keeps_defaults_for_missing_keys() {
+        let config = Config::from_toml("port = 9000\ndata_dir = \"/tmp/toydb\"").unwrap();
+
+        assert_eq!("127.0.0.1", config.host);
+        assert_eq!(9000, config.port);
+        assert_eq!("/tmp/toydb", config.data_dir);
+    }
+
+    #[test]
+    fn test_from_toml_rejects_unknown_keys_and_bad_types() {
+        assert!(Config::from_toml("prot = 9000").is_err());
+        assert!(Config::from_toml("port = \"high\"").is_err());
+    }
+
+    #[test]
+    fn test_tls_needs_cert_and_key() {
+        assert!(Config::default().tls_acceptor().unwrap().is_none());
+
+        let config = Config::from_toml("tls_cert = \"cert.pem\"").unwrap();
+        assert!(config.tls_acceptor().is_err());
+    }
+
+    #[test]
+    fn test_addr() {
+        let config = Config {
+            host: "0.0.0.0".to_owned(),
+            port: 9000,
+            ..Default::default()
+        };
+
+        assert_eq!(Ok("0.0.0.0:9000".parse().unwrap()), config.addr());
+        assert_eq!(Ok("0.0.0.0:8422".parse().unwrap()), config.binary_addr());
+    }
+}
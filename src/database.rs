@@ -0,0 +1,368 @@
+use engine_operator;
+use query;
+use query_parser;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use storage;
+use table_sync;
+use util::Val;
+
+/// Why a statement failed. The details are logged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    Open,
+    Parse,
+    Execution,
+}
+
+/// Outcome of a statement.
+#[derive(Debug, Default, PartialEq)]
+pub struct QueryResult {
+    pub columns: Vec<(String, query::Type)>,
+    pub rows: Vec<Vec<Val>>,
+    pub affected: usize,
+    pub generated_ids: Vec<u32>,
+    // Free text output, like the database description.
+    pub message: Option<String>,
+}
+
+impl From<engine_operator::QueryResult> for QueryResult {
+    fn from(result: engine_operator::QueryResult) -> QueryResult {
+        QueryResult {
+            columns: result
+                .columns
+                .into_iter()
+                .map(|column| (column.name, column.data_type))
+                .collect(),
+            rows: result.rows,
+            affected: result.affected,
+            generated_ids: result.generated_ids,
+            message: result.message,
+        }
+    }
+}
+
+/// A database used in-process, without the server. Statements run on their own, each
+/// committed when it's done.
+pub struct Database {
+    engine_operator: engine_operator::EngineOperator,
+}
+
+impl Database {
+    /// Opens the database in the directory, which is created if it's not there.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Database, Error> {
+        let engine_operator =
+            engine_operator::EngineOperator::new(table_sync::TableSyncer::new(path));
+        engine_operator.init().map_err(|_| Error::Open)?;
+        Ok(Database { engine_operator })
+    }
+
+    /// Opens a database kept in memory only, gone when it's dropped. Each is on its own.
+    pub fn open_in_memory() -> Result<Database, Error> {
+        let engine_operator = engine_operator::EngineOperator::new(storage::MemoryBackend);
+        engine_operator.init().map_err(|_| Error::Open)?;
+        Ok(Database { engine_operator })
+    }
+
+    /// Runs a statement of TQL, or of SQL if it doesn't start with a TQL symbol.
+    pub fn execute(&self, raw: &str) -> Result<QueryResult, Error> {
+        let raw = raw.trim();
+        let dialect = if raw.starts_with(|c| "?+>-:".contains(c)) {
+            query::Dialect::Tql
+        } else {
+            query::Dialect::Sql
+        };
+        let query = query_parser::QueryParser
+            .parse_as(raw, dialect)
+            .map_err(|_| Error::Parse)?;
+        self.run(query)
+    }
+
+    /// Removes what deleted rows leave behind, returning how many row versions are gone.
+    pub fn vacuum(&self) -> usize {
+        self.engine_operator.vacuum()
+    }
+
+    pub fn create_table(&self, table: &str) -> CreateTable<'_> {
+        CreateTable {
+            database: self,
+            table: table.to_owned(),
+            fields: vec![],
+            indices: vec![],
+            primary_key: None,
+            storage: Default::default(),
+        }
+    }
+
+    pub fn select(&self, table: &str, columns: &[&str]) -> Select<'_> {
+        Select {
+            database: self,
+            table: table.to_owned(),
+            columns: columns.iter().map(|c| (*c).to_owned()).collect(),
+            conditions: vec![],
+        }
+    }
+
+    pub fn insert(&self, table: &str) -> Insert<'_> {
+        Insert {
+            database: self,
+            table: table.to_owned(),
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn delete(&self, table: &str) -> Delete<'_> {
+        Delete {
+            database: self,
+            table: table.to_owned(),
+            conditions: vec![],
+        }
+    }
+
+    fn run(&self, query: query::Query) -> Result<QueryResult, Error> {
+        self.engine_operator
+            .execute(None, None, query)
+            .map(QueryResult::from)
+            .map_err(|_| Error::Execution)
+    }
+}
+
+pub struct CreateTable<'a> {
+    database: &'a Database,
+    table: String,
+    fields: Vec<query::FieldDef>,
+    indices: Vec<String>,
+    primary_key: Option<String>,
+    storage: query::Storage,
+}
+
+impl<'a> CreateTable<'a> {
+    pub fn column(mut self, name: &str, data_type: query::Type) -> CreateTable<'a> {
+        self.fields
+            .push(query::FieldDef::new(name.to_owned(), data_type));
+        self
+    }
+
+    pub fn index(mut self, column: &str) -> CreateTable<'a> {
+        self.indices.push(column.to_owned());
+        self
+    }
+
+    /// Makes the column unique. LSM tables are ordered by it.
+    pub fn primary_key(mut self, column: &str) -> CreateTable<'a> {
+        self.primary_key = Some(column.to_owned());
+        self
+    }
+
+    pub fn storage(mut self, storage: query::Storage) -> CreateTable<'a> {
+        self.storage = storage;
+        self
+    }
+
+    pub fn run(self) -> Result<QueryResult, Error> {
+        let mut create_query =
+            query::CreateQuery::new(self.table, self.fields, self.indices, Default::default());
+        create_query.primary_key = self.primary_key;
+        create_query.storage = self.storage;
+        self.database.run(query::Query::Create(create_query))
+    }
+}
+
+pub struct Select<'a> {
+    database: &'a Database,
+    table: String,
+    columns: Vec<String>,
+    conditions: Vec<query::FieldCondition>,
+}
+
+impl<'a> Select<'a> {
+    /// Keeps the rows where the column relates to the value so. All filters have to pass.
+    pub fn filter<V: Into<Val>>(
+        mut self,
+        column: &str,
+        relation: query::Relation,
+        value: V,
+    ) -> Select<'a> {
+        self.conditions.push(condition(column, relation, value));
+        self
+    }
+
+    pub fn run(self) -> Result<QueryResult, Error> {
+        self.database
+            .run(query::Query::Select(query::SelectQuery::new(
+                self.table,
+                self.columns,
+                self.conditions,
+            )))
+    }
+}
+
+pub struct Insert<'a> {
+    database: &'a Database,
+    table: String,
+    values: HashMap<String, String>,
+}
+
+impl<'a> Insert<'a> {
+    pub fn value<V: Into<Val>>(mut self, column: &str, value: V) -> Insert<'a> {
+        self.values.insert(column.to_owned(), raw(value.into()));
+        self
+    }
+
+    pub fn run(self) -> Result<QueryResult, Error> {
+        self.database
+            .run(query::Query::Insert(query::InsertQuery::new(
+                self.table,
+                self.values,
+            )))
+    }
+}
+
+pub struct Delete<'a> {
+    database: &'a Database,
+    table: String,
+    conditions: Vec<query::FieldCondition>,
+}
+
+impl<'a> Delete<'a> {
+    /// Deletes the rows where the column relates to the value so. All filters have to pass.
+    pub fn filter<V: Into<Val>>(
+        mut self,
+        column: &str,
+        relation: query::Relation,
+        value: V,
+    ) -> Delete<'a> {
+        self.conditions.push(condition(column, relation, value));
+        self
+    }
+
+    pub fn run(self) -> Result<QueryResult, Error> {
+        self.database
+            .run(query::Query::Delete(query::DeleteQuery::new(
+                self.table,
+                self.conditions,
+            )))
+    }
+}
+
+fn condition<V: Into<Val>>(
+    column: &str,
+    relation: query::Relation,
+    value: V,
+) -> query::FieldCondition {
+    query::FieldCondition::new(
+        column.to_owned(),
+        format!("{:?}", relation),
+        raw(value.into()),
+    )
+}
+
+// Values are handed to the engine the way they're written in a statement.
+fn raw(value: Val) -> String {
+    match value {
+        Val::U32(n) => n.to_string(),
+        Val::Varchar(s) => s,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use query::{Relation, Storage, Type};
+    use temp_dir::TempDir;
+
+    #[test]
+    fn test_statements_and_builders() {
+        let dir = TempDir::new("database");
+        let db = Database::open(dir.path()).unwrap();
+
+        db.create_table("users")
+            .column("id", Type::Int)
+            .column("name", Type::Varchar(8))
+            .index("id")
+            .run()
+            .unwrap();
+        db.insert("users")
+            .value("id", 1)
+            .value("name", "Ann")
+            .run()
+            .unwrap();
+        db.execute("INSERT INTO users (id, name) VALUES (2, 'Bob')")
+            .unwrap();
+        db.execute("> users id 3 name Cy").unwrap();
+        assert_eq!(Err(Error::Parse), db.execute("SELECT FROM users"));
+        assert_eq!(Err(Error::Execution), db.execute("? id > books"));
+
+        let result = db
+            .select("users", &["name", "id"])
+            .filter("id", Relation::Gt, 1)
+            .run()
+            .unwrap();
+        assert_eq!(
+            vec![
+                ("name".to_owned(), Type::Varchar(8)),
+                ("id".to_owned(), Type::Int)
+            ],
+            result.columns
+        );
+        assert_eq!(
+            vec![
+                vec![Val::Varchar("Bob".to_owned()), Val::U32(2)],
+                vec![Val::Varchar("Cy".to_owned()), Val::U32(3)],
+            ],
+            result.rows
+        );
+
+        let deleted = db
+            .delete("users")
+            .filter("name", Relation::Eq, "Bob")
+            .run()
+            .unwrap();
+        assert_eq!(1, deleted.affected);
+        assert_eq!(1, db.vacuum());
+        assert_eq!(0, db.vacuum());
+        let rows = db.execute("SELECT id FROM users").unwrap().rows;
+        assert_eq!(vec![vec![Val::U32(1)], vec![Val::U32(3)]], rows);
+    }
+
+    #[test]
+    fn test_lsm_table() {
+        let dir = TempDir::new("database-lsm");
+        let db = Database::open(dir.path()).unwrap();
+        assert_eq!(
+            Err(Error::Execution),
+            db.create_table("events")
+                .column("id", Type::Int)
+                .storage(Storage::Lsm)
+                .run()
+        );
+        db.create_table("events")
+            .column("id", Type::Int)
+            .primary_key("id")
+            .storage(Storage::Lsm)
+            .run()
+            .unwrap();
+        db.insert("events").value("id", 2).run().unwrap();
+        db.insert("events").value("id", 1).run().unwrap();
+        assert_eq!(
+            Err(Error::Execution),
+            db.insert("events").value("id", 1).run()
+        );
+        drop(db);
+
+        let db = Database::open(dir.path()).unwrap();
+        let rows = db.execute("SELECT id FROM events").unwrap().rows;
+        assert_eq!(vec![vec![Val::U32(1)], vec![Val::U32(2)]], rows);
+    }
+
+    #[test]
+    fn test_in_memory_databases_are_apart() {
+        let first = Database::open_in_memory().unwrap();
+        let second = Database::open_in_memory().unwrap();
+        first.execute("+ users id int : id").unwrap();
+        first.execute("> users id 1").unwrap();
+
+        assert_eq!(1, first.execute("? id > users").unwrap().rows.len());
+        assert_eq!(Err(Error::Execution), second.execute("? id > users"));
+    }
+}
@@ -1,31 +1,651 @@
-#[derive(Debug, Default)]
-pub struct DBClient;
+/// A connection to the server, over HTTP or the binary protocol. Statements either stream
+/// their results to a `ResultHandler`, or return them whole, typed, with `query`.
+#[derive(Debug)]
+pub struct DBClient {
+  transport: Transport,
+  // Kept to connect to other servers with.
+  credentials: Option<Credentials>,
+}
+
+#[derive(Debug)]
+enum Transport {
+  Http(Box<Http>),
+  // One connection for the whole run, which is the session itself.
+  Binary(RefCell<wire::Connection>),
+}
+
+#[derive(Debug)]
+struct Http {
+  uri: hyper::Uri,
+  // Sent with every request so the server can scope transactions to this client.
+  session: String,
+  // Value of the Authorization header, if credentials were given.
+  authorization: Option<String>,
+  // Set when talking HTTPS.
+  tls: Option<native_tls::TlsConnector>,
+  // Pooled, so the connection is kept alive between statements.
+  client: Client<HttpsConnector<HttpConnector>>,
+  // Runs the requests of the blocking calls, and the connections kept alive in between.
+  runtime: Mutex<Runtime>,
+}
+
+/// Why a statement failed: the kind of error the server answered with, or `connection` when
+/// the server couldn't be reached or its answer couldn't be read.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Error {
+  pub kind: String,
+  pub message: String,
+}
+
+impl Error {
+  pub(crate) fn connection<E: fmt::Display>(e: E) -> Error {
+    Error {
+      kind: "connection".to_owned(),
+      message: e.to_string(),
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} ({})", self.message, self.kind)
+  }
+}
+
+/// The whole result of a statement.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct QueryResult {
+  pub columns: Vec<(String, query::Type)>,
+  pub rows: Vec<Vec<util::Val>>,
+  pub affected: u64,
+  pub generated_ids: Vec<u32>,
+  pub message: Option<String>,
+}
 
-use futures::{Future, Stream};
+// The response envelope of the HTTP API.
+#[derive(Deserialize)]
+struct Envelope {
+  #[serde(default)]
+  columns: Vec<EnvelopeColumn>,
+  #[serde(default)]
+  rows: Vec<Vec<Value>>,
+  #[serde(default)]
+  affected: u64,
+  #[serde(default)]
+  generated_ids: Vec<u32>,
+  #[serde(default)]
+  message: Option<String>,
+  #[serde(default)]
+  error: Option<Error>,
+}
+
+#[derive(Deserialize)]
+struct EnvelopeColumn {
+  name: String,
+  #[serde(rename = "type")]
+  data_type: query::Type,
+}
+
+/// What the client authenticates with, when the server requires it.
+#[derive(Debug, Clone)]
+pub enum Credentials {
+  Password { user: String, password: String },
+  Token(String),
+}
+
+impl Credentials {
+  fn authorization(&self) -> String {
+    match self {
+      Credentials::Password { user, password } => {
+        format!("Basic {}", STANDARD.encode(format!("{}:{}", user, password)))
+      }
+      Credentials::Token(token) => format!("Bearer {}", token),
+    }
+  }
+
+  // The server finds the user of a token itself.
+  fn user_and_secret(&self) -> (&str, &str) {
+    match self {
+      Credentials::Password { user, password } => (user, password),
+      Credentials::Token(token) => ("", token),
+    }
+  }
+}
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::{future, Future, Stream};
 use hyper::header::HeaderValue;
-use hyper::rt::{lazy, run};
+use hyper::client::HttpConnector;
 use hyper::{self, Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use native_tls;
+use serde_json::{self, Value};
+use query;
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::process;
 use std::str;
+use std::sync::{mpsc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tls;
+use tokio::runtime::Runtime;
+use util;
+use wire;
+
+pub const HTTP_PORT: u16 = 8421;
+pub const BINARY_PORT: u16 = 8422;
+const SESSION_HEADER: &str = "X-Toydb-Session";
+const JSON_CONTENT_TYPE: &str = "application/json";
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+impl Default for DBClient {
+  fn default() -> DBClient {
+    DBClient::new("localhost", HTTP_PORT, None).unwrap()
+  }
+}
 
 impl DBClient {
-  pub fn send(&self, raw: &String) {
-    let uri: hyper::Uri = "http://localhost:8421/".parse().unwrap();
-    let mut req = Request::new(Body::from(raw.clone()));
+  pub fn new(host: &str, port: u16, credentials: Option<&Credentials>) -> Result<DBClient, Error> {
+    let uri: hyper::Uri = format!("http://{}:{}/", host, port)
+      .parse()
+      .map_err(|e| Error::connection(format!("Invalid server address {}:{}: {}", host, port, e)))?;
+
+    let nanos = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_nanos())
+      .unwrap_or(0);
+
+    // Plain HTTP goes through the TLS connector too, untouched.
+    let client = http_client(tls::connector(None).map_err(|_| Error::connection("TLS cannot be set up"))?);
+    let runtime =
+      Runtime::new().map_err(|e| Error::connection(format!("Runtime cannot be started: {}", e)))?;
+
+    Ok(DBClient {
+      transport: Transport::Http(Box::new(Http {
+        uri,
+        session: format!("{}-{}", process::id(), nanos),
+        authorization: credentials.map(Credentials::authorization),
+        tls: None,
+        client,
+        runtime: Mutex::new(runtime),
+      })),
+      credentials: credentials.cloned(),
+    })
+  }
+
+  /// Connects with the binary protocol instead of HTTP.
+  pub fn connect_binary(
+    host: &str,
+    port: u16,
+    credentials: Option<&Credentials>,
+  ) -> Result<DBClient, Error> {
+    let secret = credentials.map(Credentials::user_and_secret);
+    let connection = wire::Connection::connect_as(host, port, secret)
+      .map_err(|e| Error::connection(format!("Cannot connect to {}:{}: {}", host, port, e)))?;
+
+    Ok(DBClient {
+      transport: Transport::Binary(RefCell::new(connection)),
+      credentials: credentials.cloned(),
+    })
+  }
+
+  /// Talks HTTPS instead of HTTP, trusting the PEM certificate given besides the system roots.
+  pub fn with_tls(mut self, ca_cert_path: Option<&str>) -> Result<DBClient, Error> {
+    match &mut self.transport {
+      Transport::Http(http) => {
+        let tls = tls::connector(ca_cert_path).map_err(|_| Error::connection("TLS cannot be set up"))?;
+        http.use_tls(tls)?
+      }
+      Transport::Binary(_) => return Err(Error::connection("TLS is only supported over HTTP")),
+    }
+    Ok(self)
+  }
+
+  pub fn is_binary(&self) -> bool {
+    matches!(self.transport, Transport::Binary(_))
+  }
+
+  /// Connects to another server with the same protocol, credentials and TLS settings, and a
+  /// new session.
+  pub fn connect_to(&self, host: &str, port: u16) -> Result<DBClient, Error> {
+    match &self.transport {
+      Transport::Http(http) => {
+        let mut client = DBClient::new(host, port, self.credentials.as_ref())?;
+        if let (Transport::Http(new_http), Some(tls)) = (&mut client.transport, &http.tls) {
+          new_http.use_tls(tls.clone())?;
+        }
+        Ok(client)
+      }
+      Transport::Binary(_) => DBClient::connect_binary(host, port, self.credentials.as_ref()),
+    }
+  }
+
+  /// Sends the statement, handing the result over as it arrives.
+  pub fn send(&self, raw: &str, handler: &mut dyn ResultHandler) {
+    match &self.transport {
+      Transport::Http(http) => http.send(raw, handler),
+      Transport::Binary(connection) => {
+        if let Err(e) = send_binary(&mut connection.borrow_mut(), raw, handler) {
+          handler.error(&e.to_string());
+        }
+      }
+    }
+  }
+
+  /// Runs the statement and returns its whole result, blocking until it's there.
+  pub fn query(&self, raw: &str) -> Result<QueryResult, Error> {
+    match &self.transport {
+      Transport::Http(http) => {
+        let result = http.query(raw.to_owned());
+        http.runtime.lock().unwrap().block_on(result)
+      }
+      Transport::Binary(connection) => query_binary(&mut connection.borrow_mut(), raw),
+    }
+  }
+
+  /// Same as `query`, as a future to run on any executor. Over the binary protocol the
+  /// statement runs right away, blocking, and the future is already resolved.
+  pub fn query_async(
+    &self,
+    raw: &str,
+  ) -> Box<dyn Future<Item = QueryResult, Error = Error> + Send> {
+    match &self.transport {
+      Transport::Http(http) => http.query(raw.to_owned()),
+      Transport::Binary(connection) => {
+        Box::new(future::result(query_binary(&mut connection.borrow_mut(), raw)))
+      }
+    }
+  }
+
+  /// Text of `:db`, describing the tables on the server.
+  pub fn describe(&self) -> Result<String, Error> {
+    // Sent as TQL whatever the dialect of the session is.
+    let body = json!({"query": ":db", "dialect": "tql"}).to_string();
+    self.query(&body).map(|result| result.message.unwrap_or_default())
+  }
+}
+
+/// Receives the parts of a result, in the order they arrive: the columns, if the statement
+/// has any, its rows, then either `complete` or `error`.
+pub trait ResultHandler {
+  fn columns(&mut self, columns: Vec<String>);
+  fn row(&mut self, row: Vec<Value>);
+  fn complete(&mut self, affected: u64, message: Option<String>);
+  fn error(&mut self, message: &str);
+}
+
+impl Http {
+  fn use_tls(&mut self, tls: native_tls::TlsConnector) -> Result<(), Error> {
+    let authority = self
+      .uri
+      .authority_part()
+      .map(|a| a.to_string())
+      .unwrap_or_default();
+    self.uri = format!("https://{}/", authority)
+      .parse()
+      .map_err(|e| Error::connection(format!("Invalid server address {}: {}", authority, e)))?;
+    self.client = http_client(tls.clone());
+    self.tls = Some(tls);
+    Ok(())
+  }
+
+  fn request(&self, body: &str, accept: &str) -> Request<Body> {
+    let mut req = Request::new(Body::from(body.to_owned()));
     *req.method_mut() = Method::POST;
-    *req.uri_mut() = uri.clone();
+    *req.uri_mut() = self.uri.clone();
     req
       .headers_mut()
       .insert("Content-Type", HeaderValue::from_str("text/plain").unwrap());
+    req
+      .headers_mut()
+      .insert(SESSION_HEADER, HeaderValue::from_str(&self.session).unwrap());
+    if let Some(authorization) = &self.authorization {
+      req
+        .headers_mut()
+        .insert("Authorization", HeaderValue::from_str(authorization).unwrap());
+    }
+    req
+      .headers_mut()
+      .insert("Accept", HeaderValue::from_str(accept).unwrap());
+    req
+  }
 
-    run(lazy(move || {
-      Client::new()
+  fn send(&self, raw: &str, handler: &mut dyn ResultHandler) {
+    // Rows come a line each. The request runs on the runtime, passing the chunks over as
+    // they arrive, so rows are handled before the whole result is there.
+    let req = self.request(raw, NDJSON_CONTENT_TYPE);
+    let (tx, rx) = mpsc::channel();
+    let error_tx = tx.clone();
+    self.runtime.lock().unwrap().spawn(
+      self
+        .client
         .request(req)
-        .and_then(|res| res.into_body().concat2())
-        .map(|chunk| {
-          println!("{}", str::from_utf8(chunk.as_ref()).unwrap());
-          ()
+        .and_then(move |res| {
+          res.into_body().for_each(move |chunk| {
+            let _ = tx.send(Ok(chunk));
+            Ok(())
+          })
         })
-        .map_err(|_| ())
-    }));
+        .map_err(move |e| {
+          let _ = error_tx.send(Err(e.to_string()));
+        }),
+    );
+
+    let mut reader = LineReader::default();
+    for chunk in rx {
+      match chunk {
+        Ok(chunk) => reader.push(chunk.as_ref(), handler),
+        Err(e) => {
+          handler.error(&e);
+          return;
+        }
+      }
+    }
+    reader.finish(handler);
+  }
+
+  fn query(&self, body: String) -> Box<dyn Future<Item = QueryResult, Error = Error> + Send> {
+    let req = self.request(&body, JSON_CONTENT_TYPE);
+    Box::new(
+      self
+        .client
+        .request(req)
+        .and_then(|res| res.into_body().concat2())
+        .map_err(Error::connection)
+        .and_then(|body| decode_envelope(&body)),
+    )
+  }
+}
+
+fn http_client(tls: native_tls::TlsConnector) -> Client<HttpsConnector<HttpConnector>> {
+  let mut http = HttpConnector::new(1);
+  http.enforce_http(false);
+  Client::builder().build(HttpsConnector::from((http, tls)))
+}
+
+fn decode_envelope(body: &[u8]) -> Result<QueryResult, Error> {
+  let envelope: Envelope = serde_json::from_slice(body)
+    .map_err(|e| Error::connection(format!("Response cannot be parsed: {}", e)))?;
+  if let Some(error) = envelope.error {
+    return Err(error);
+  }
+
+  let columns: Vec<(String, query::Type)> = envelope
+    .columns
+    .into_iter()
+    .map(|column| (column.name, column.data_type))
+    .collect();
+  let rows = envelope
+    .rows
+    .iter()
+    .map(|row| typed_row(row, &columns))
+    .collect::<Result<_, _>>()?;
+
+  Ok(QueryResult {
+    columns,
+    rows,
+    affected: envelope.affected,
+    generated_ids: envelope.generated_ids,
+    message: envelope.message,
+  })
+}
+
+fn typed_row(row: &[Value], columns: &[(String, query::Type)]) -> Result<Vec<util::Val>, Error> {
+  if row.len() != columns.len() {
+    let message = format!("Row of {} values for {} columns", row.len(), columns.len());
+    return Err(Error::connection(message));
+  }
+
+  row
+    .iter()
+    .zip(columns)
+    .map(|(value, (_, data_type))| {
+      let val = match (data_type, value) {
+        (query::Type::Int, Value::Number(n)) => {
+          n.as_u64().and_then(|n| u32::try_from(n).ok()).map(util::Val::U32)
+        }
+        (query::Type::Varchar(_), Value::String(s)) => Some(util::Val::Varchar(s.clone())),
+        _ => None,
+      };
+      val.ok_or_else(|| Error::connection(format!("{} is not of type {:?}", value, data_type)))
+    })
+    .collect()
+}
+
+// Reads the lines of a streamed response: the columns, a line per row, then the rest of
+// the envelope.
+#[derive(Default)]
+struct LineReader {
+  pending: Vec<u8>,
+}
+
+impl LineReader {
+  fn push(&mut self, bytes: &[u8], handler: &mut dyn ResultHandler) {
+    self.pending.extend_from_slice(bytes);
+    while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+      let line: Vec<u8> = self.pending.drain(..=end).collect();
+      read_line(&String::from_utf8_lossy(&line), handler);
+    }
+  }
+
+  fn finish(mut self, handler: &mut dyn ResultHandler) {
+    let rest = mem::take(&mut self.pending);
+    read_line(&String::from_utf8_lossy(&rest), handler);
+  }
+}
+
+fn read_line(line: &str, handler: &mut dyn ResultHandler) {
+  let line = line.trim();
+  if line.is_empty() {
+    return;
+  }
+
+  let value: Value = match serde_json::from_str(line) {
+    Ok(value) => value,
+    Err(_) => {
+      handler.error(&format!("Invalid response: {}", line));
+      return;
+    }
+  };
+
+  if let Value::Array(row) = value {
+    handler.row(row);
+  } else if let Some(message) = value["error"]["message"].as_str() {
+    handler.error(message);
+  } else if let Some(rows) = value["rows"].as_array() {
+    // A whole envelope, sent for statements without a row stream.
+    let columns = column_names(&value);
+    if !columns.is_empty() {
+      handler.columns(columns);
+      for row in rows {
+        handler.row(row.as_array().cloned().unwrap_or_default());
+      }
+    }
+    complete(&value, handler);
+  } else if value.get("columns").is_some() {
+    handler.columns(column_names(&value));
+  } else {
+    complete(&value, handler);
+  }
+}
+
+fn column_names(value: &Value) -> Vec<String> {
+  value["columns"]
+    .as_array()
+    .map(|columns| {
+      columns
+        .iter()
+        .map(|c| c["name"].as_str().unwrap_or_default().to_owned())
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn complete(value: &Value, handler: &mut dyn ResultHandler) {
+  handler.complete(
+    value["affected"].as_u64().unwrap_or(0),
+    value["message"].as_str().map(str::to_owned),
+  );
+}
+
+// Hands the rows over batch by batch, as they arrive.
+fn send_binary(
+  connection: &mut wire::Connection,
+  raw: &str,
+  handler: &mut dyn ResultHandler,
+) -> io::Result<()> {
+  let id = connection.send(raw)?;
+  connection.flush()?;
+
+  loop {
+    match connection.read()? {
+      wire::Frame::Columns {
+        id: frame_id,
+        columns,
+      } if frame_id == id => {
+        handler.columns(columns.into_iter().map(|(name, _)| name).collect());
+      }
+      wire::Frame::Rows { id: frame_id, rows } if frame_id == id => {
+        for row in rows {
+          handler.row(row.iter().map(json_value).collect());
+        }
+      }
+      wire::Frame::Complete {
+        id: frame_id,
+        affected,
+        message,
+        ..
+      } if frame_id == id => {
+        handler.complete(affected, message);
+        return Ok(());
+      }
+      wire::Frame::Error {
+        id: frame_id,
+        message,
+        ..
+      } if frame_id == id => {
+        handler.error(&message);
+        return Ok(());
+      }
+      _ => {}
+    }
+  }
+}
+
+fn query_binary(connection: &mut wire::Connection, raw: &str) -> Result<QueryResult, Error> {
+  let id = connection.send(raw).map_err(Error::connection)?;
+  connection.flush().map_err(Error::connection)?;
+
+  let mut result = QueryResult::default();
+  loop {
+    match connection.read().map_err(Error::connection)? {
+      wire::Frame::Columns {
+        id: frame_id,
+        columns,
+      } if frame_id == id => result.columns = columns,
+      wire::Frame::Rows { id: frame_id, rows } if frame_id == id => result.rows.extend(rows),
+      wire::Frame::Complete {
+        id: frame_id,
+        affected,
+        generated_ids,
+        message,
+        ..
+      } if frame_id == id => {
+        result.affected = affected;
+        result.generated_ids = generated_ids;
+        result.message = message;
+        return Ok(result);
+      }
+      wire::Frame::Error {
+        id: frame_id,
+        kind,
+        message,
+      } if frame_id == id => return Err(Error { kind, message }),
+      _ => {}
+    }
+  }
+}
+
+fn json_value(val: &util::Val) -> Value {
+  match val {
+    util::Val::U32(n) => Value::from(*n),
+    util::Val::Varchar(s) => Value::from(s.clone()),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hyper::service::service_fn;
+  use hyper::{Response, Server};
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+  use std::thread;
+  use std::time::Duration;
+
+  const ENVELOPE: &str = r#"{"version":1,"status":"ok","columns":[{"name":"id","type":"Int"},{"name":"name","type":{"Varchar":8}}],"rows":[[1,"Ann"]],"affected":0,"generated_ids":[],"error":null,"time_us":12}"#;
+
+  #[test]
+  fn test_rows_are_typed() {
+    let result = decode_envelope(ENVELOPE.as_bytes()).unwrap();
+    assert_eq!(
+      vec![
+        ("id".to_owned(), query::Type::Int),
+        ("name".to_owned(), query::Type::Varchar(8)),
+      ],
+      result.columns
+    );
+    assert_eq!(
+      vec![vec![util::Val::U32(1), util::Val::Varchar("Ann".to_owned())]],
+      result.rows
+    );
+
+    let mistyped = ENVELOPE.replace(r#"[[1,"Ann"]]"#, r#"[["1","Ann"]]"#);
+    assert_eq!("connection", decode_envelope(mistyped.as_bytes()).unwrap_err().kind);
+  }
+
+  #[test]
+  fn test_errors_keep_their_kind() {
+    let body = r#"{"version":1,"status":"error","columns":[],"rows":[],"affected":0,"generated_ids":[],"error":{"kind":"parse","message":"Query cannot be parsed"},"time_us":0}"#;
+    assert_eq!(
+      Error {
+        kind: "parse".to_owned(),
+        message: "Query cannot be parsed".to_owned(),
+      },
+      decode_envelope(body.as_bytes()).unwrap_err()
+    );
+  }
+
+  #[test]
+  fn test_connection_is_kept_alive() {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+      counter.fetch_add(1, Ordering::SeqCst);
+      // The body is read, or the connection couldn't be kept alive.
+      service_fn(|req: Request<Body>| {
+        req
+          .into_body()
+          .concat2()
+          .map(|_| Response::new(Body::from(ENVELOPE)))
+      })
+    });
+    let port = server.local_addr().port();
+    let mut runtime = Runtime::new().unwrap();
+    runtime.spawn(server.map_err(|e| panic!("{}", e)));
+
+    let client = DBClient::new("127.0.0.1", port, None).unwrap();
+    assert_eq!(1, client.query("? id name > users").unwrap().rows.len());
+    // The connection goes back to the pool once the response is read, on the runtime.
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(1, client.query("? id name > users").unwrap().rows.len());
+    assert_eq!(1, connections.load(Ordering::SeqCst));
+
+    let result = runtime.block_on(client.query_async("? id name > users")).unwrap();
+    assert_eq!(1, result.rows.len());
   }
 }
@@ -1,43 +1,245 @@
+use api;
+use auth;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use config;
 use engine_operator;
-use futures::{future, Future, Stream};
+use futures::{future, stream, Future, Stream};
+use hyper::body::Payload;
+use hyper::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE};
 use hyper::rt;
-use hyper::service::service_fn;
+use hyper::service::{service_fn, NewService, Service};
 use hyper::{self, Body, Method, Request, Response, Server, StatusCode};
+use native_tls;
+use pg_server;
+use query;
 use query_parser;
+use statements;
+use std::collections::HashMap;
+use std::error::Error;
 use std::fs::File;
 use std::io::{self, prelude::*};
+use std::net::TcpListener;
 use std::str;
 use std::sync::{Arc, Mutex};
-use util;
+use std::thread;
+use std::time::{Duration, Instant};
+use storage;
+use table_sync;
+use tokio;
+use tokio_tls;
+use wire_server;
+
+// Requests sharing this header value share a transaction scope.
+pub const SESSION_HEADER: &str = "X-Toydb-Session";
+
+const VACUUM_INTERVAL: Duration = Duration::from_secs(10);
+
+// TLS handshakes going on at once.
+const TLS_HANDSHAKES: usize = 64;
 
 #[derive(Debug, Default)]
 pub struct DBServer {
-    engine_operator: Arc<Mutex<engine_operator::EngineOperator>>,
+    config: config::Config,
+    engine_operator: Arc<engine_operator::EngineOperator>,
     query_parser: Arc<query_parser::QueryParser>,
+    statements: Arc<statements::StatementCache>,
+    http_sessions: Arc<HttpSessions>,
+}
+
+// HTTP clients never say they are done with a session, so the ones left without a request for
+// the idle timeout are ended, like a closed connection of the other protocols.
+#[derive(Debug, Default)]
+struct HttpSessions {
+    last_used: Mutex<HashMap<(Option<String>, String), Instant>>,
+    idle_timeout: Duration,
+}
+
+impl HttpSessions {
+    fn new(idle_timeout: Duration) -> HttpSessions {
+        HttpSessions {
+            idle_timeout,
+            ..Default::default()
+        }
+    }
+
+    fn touch(&self, user: Option<&str>, session: &str) {
+        self.last_used.lock().unwrap().insert(
+            (user.map(str::to_owned), session.to_owned()),
+            Instant::now(),
+        );
+    }
+
+    // Ends the idle sessions, rolling back their transactions and dropping their statements.
+    fn expire(
+        &self,
+        engine_operator: &engine_operator::EngineOperator,
+        statements: &statements::StatementCache,
+    ) -> usize {
+        let idle = {
+            let mut last_used = self.last_used.lock().unwrap();
+            let idle: Vec<_> = last_used
+                .iter()
+                .filter(|(_, used)| used.elapsed() >= self.idle_timeout)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in &idle {
+                last_used.remove(key);
+            }
+            idle
+        };
+
+        for (user, session) in &idle {
+            engine_operator.end_session(user.as_deref(), session);
+            statements.end_session(user.as_deref(), session);
+        }
+        idle.len()
+    }
 }
 
 impl DBServer {
+    pub fn new(config: config::Config) -> DBServer {
+        let engine_operator = if config.memory {
+            engine_operator::EngineOperator::new(storage::MemoryBackend)
+        } else {
+            engine_operator::EngineOperator::new(table_sync::TableSyncer::new(&config.data_dir[..]))
+        };
+        let engine_operator = engine_operator
+            .with_cursor_limits(config.cursor_limits())
+            .with_auth(config.auth);
+        let http_sessions = HttpSessions::new(Duration::from_secs(config.session_idle_timeout));
+
+        DBServer {
+            config,
+            engine_operator: Arc::new(engine_operator),
+            query_parser: Default::default(),
+            statements: Default::default(),
+            http_sessions: Arc::new(http_sessions),
+        }
+    }
+
     pub fn init(&mut self) {
-        self.engine_operator.lock().unwrap().init();
+        if self.engine_operator.init().is_err() {
+            error!("Engine operator cannot be initialized");
+        }
+
+        if let Some(ref password) = self.config.admin_password {
+            if self
+                .engine_operator
+                .ensure_admin("admin", password)
+                .is_err()
+            {
+                error!("Admin user cannot be created");
+            }
+        }
+        if self.config.auth && !self.engine_operator.has_users() {
+            error!("Authentication is required but there are no users, set admin_password");
+        }
     }
 
     pub fn run(&self) {
-        let addr = ([127, 0, 0, 1], 8421).into();
+        let addr = match self.config.addr() {
+            Ok(addr) => addr,
+            Err(_) => return,
+        };
+        let tls = match self.config.tls_acceptor() {
+            Ok(tls) => tls,
+            Err(_) => return,
+        };
+        info!(
+            "Listening on {}{}",
+            addr,
+            if tls.is_some() { " with TLS" } else { "" }
+        );
+
+        if self.start_binary().is_err() || self.start_pg().is_err() {
+            return;
+        }
+        self.start_vacuum();
+
         let eo = self.engine_operator.clone();
         let qp = self.query_parser.clone();
+        let sc = self.statements.clone();
+        let hs = self.http_sessions.clone();
         rt::run(rt::lazy(move || {
-            let eo = eo.clone();
-            let qp = qp.clone();
             let new_service = move || {
                 let eo = eo.clone();
                 let qp = qp.clone();
-                service_fn(move |req| prepare_response(req, eo.clone(), qp.clone()))
+                let sc = sc.clone();
+                let hs = hs.clone();
+                service_fn(move |req| {
+                    prepare_response(req, eo.clone(), qp.clone(), sc.clone(), hs.clone())
+                })
+            };
+            let server: Box<dyn Future<Item = (), Error = ()> + Send> = match tls {
+                Some(acceptor) => Box::new(
+                    future::result(tokio::net::TcpListener::bind(&addr))
+                        .map_err(move |e| error!("Cannot bind {}: {}", addr, e))
+                        .and_then(|listener| serve_tls(listener, acceptor, new_service)),
+                ),
+                None => Box::new(
+                    future::result(Server::try_bind(&addr))
+                        .map_err(move |e| error!("Cannot bind {}: {}", addr, e))
+                        .and_then(|server| server.serve(new_service).map_err(|_| ())),
+                ),
             };
-            let server = Server::bind(&addr).serve(new_service).map_err(|_| ());
             server
         }));
     }
 
+    // Serves the binary protocol next to HTTP.
+    fn start_binary(&self) -> Result<(), ()> {
+        let addr = self.config.binary_addr()?;
+        let listener =
+            TcpListener::bind(addr).map_err(|e| error!("Cannot bind {}: {}", addr, e))?;
+        info!("Binary protocol listening on {}", addr);
+
+        let server = wire_server::WireServer::new(
+            self.engine_operator.clone(),
+            self.query_parser.clone(),
+            self.statements.clone(),
+        );
+        let limits = self.config.connection_limits();
+        thread::spawn(move || server.serve(listener, limits));
+        Ok(())
+    }
+
+    fn start_pg(&self) -> Result<(), ()> {
+        let addr = self.config.pg_addr()?;
+        let listener =
+            TcpListener::bind(addr).map_err(|e| error!("Cannot bind {}: {}", addr, e))?;
+        info!("Postgres protocol listening on {}", addr);
+
+        let server =
+            pg_server::PgServer::new(self.engine_operator.clone(), self.query_parser.clone());
+        let limits = self.config.connection_limits();
+        thread::spawn(move || server.serve(listener, limits));
+        Ok(())
+    }
+
+    // Cleans up idle sessions, old row versions and idle cursors in the background.
+    fn start_vacuum(&self) {
+        let eo = self.engine_operator.clone();
+        let hs = self.http_sessions.clone();
+        let sc = self.statements.clone();
+        thread::spawn(move || loop {
+            thread::sleep(VACUUM_INTERVAL);
+            // Ended first, so the row versions their transactions held on to can go as well.
+            let ended = hs.expire(&eo, &sc);
+            if ended > 0 {
+                info!("Ended {} idle HTTP sessions", ended);
+            }
+            let removed = eo.vacuum();
+            if removed > 0 {
+                info!("Vacuum removed {} row versions", removed);
+            }
+            let expired = eo.expire_cursors();
+            if expired > 0 {
+                info!("Closed {} idle cursors", expired);
+            }
+        });
+    }
+
     pub fn read_file(&self, file_name: &String) -> Result<(), io::Error> {
         let mut f = File::open(file_name)?;
         let mut buffer = String::new();
@@ -47,14 +249,12 @@ impl DBServer {
         buffer
             .split('\n')
             .map(|l| l.trim())
-            .filter(|l| l.len() > 0)
+            .filter(|l| !l.is_empty())
+            // The dump is trusted, it runs without authorization.
             .for_each(|l| {
-                let _ = execute_raw_command(
-                    &l.to_owned(),
-                    self.engine_operator.clone(),
-                    self.query_parser.clone(),
-                );
-                ()
+                if let Ok(query) = parse_query(l, query::Dialect::Tql, &self.query_parser) {
+                    let _ = self.engine_operator.execute(None, None, query);
+                }
             });
 
         Ok(())
@@ -62,33 +262,332 @@ impl DBServer {
 }
 
 fn execute_raw_command(
-    raw: &String,
-    engine_operator: Arc<Mutex<engine_operator::EngineOperator>>,
+    raw: &str,
+    dialect: query::Dialect,
+    session: Option<&str>,
+    user: Option<&str>,
+    engine_operator: Arc<engine_operator::EngineOperator>,
     query_parser: Arc<query_parser::QueryParser>,
-) -> Result<String, ()> {
-    match query_parser.parse(&raw) {
-        Ok(query) => {
-            let mut engine_operator = engine_operator.lock().unwrap();
-            engine_operator.execute(query)
+) -> Result<engine_operator::QueryStream, api::ErrorDetails> {
+    let query = parse_query(raw, dialect, &query_parser)?;
+    execute_query(query, session, user, engine_operator)
+}
+
+pub fn parse_query(
+    raw: &str,
+    dialect: query::Dialect,
+    query_parser: &query_parser::QueryParser,
+) -> Result<query::Query, api::ErrorDetails> {
+    query_parser
+        .parse_as(raw, dialect)
+        .map_err(|_| api::ErrorDetails::new(api::ErrorKind::Parse, "Query cannot be parsed"))
+}
+
+/// Runs the query as the user, if it's allowed to.
+pub fn execute_query(
+    query: query::Query,
+    session: Option<&str>,
+    user: Option<&str>,
+    engine_operator: Arc<engine_operator::EngineOperator>,
+) -> Result<engine_operator::QueryStream, api::ErrorDetails> {
+    if !engine_operator.authorize(user, &query) {
+        warn!(
+            "Permission denied for {:?}, {} is needed",
+            user,
+            auth::requirement(&query)
+        );
+        return Err(api::ErrorDetails::new(
+            api::ErrorKind::PermissionDenied,
+            "Permission denied",
+        ));
+    }
+
+    engine_operator
+        .execute_streaming(user, session, query)
+        .map_err(api::ErrorDetails::from)
+}
+
+fn prepare_query(
+    raw: &str,
+    dialect: query::Dialect,
+    query_parser: &query_parser::QueryParser,
+) -> Result<query::PreparedQuery, api::ErrorDetails> {
+    query_parser
+        .prepare_as(raw, dialect)
+        .map_err(|_| api::ErrorDetails::new(api::ErrorKind::Parse, "Query cannot be parsed"))
+}
+
+fn bind_params(
+    prepared: &query::PreparedQuery,
+    params: &statements::Params,
+    engine_operator: &engine_operator::EngineOperator,
+) -> Result<query::Query, api::ErrorDetails> {
+    statements::bind(prepared, params, |table_name, column_name| {
+        engine_operator.column_type(table_name, column_name)
+    })
+    .map_err(|e| api::ErrorDetails::new(api::ErrorKind::InvalidRequest, e))
+}
+
+pub fn execute_request(
+    body: &str,
+    session: Option<&str>,
+    user: Option<&str>,
+    engine_operator: Arc<engine_operator::EngineOperator>,
+    query_parser: Arc<query_parser::QueryParser>,
+    statements: Arc<statements::StatementCache>,
+) -> Result<engine_operator::QueryStream, api::ErrorDetails> {
+    let request = api::Request::from_body(body)?;
+    // The dialect of the request wins over the one of the session.
+    let dialect = request
+        .dialect
+        .or_else(|| engine_operator.dialect(user, session))
+        .unwrap_or(query::Dialect::Tql);
+
+    match request.command {
+        api::Command::Query(ref raw) if request.params.is_empty() => {
+            execute_raw_command(raw, dialect, session, user, engine_operator, query_parser)
         }
-        Err(_) => Err(()),
+        // One-off statement, parsed and bound without going through the cache.
+        api::Command::Query(ref raw) => {
+            let prepared = prepare_query(raw, dialect, &query_parser)?;
+            let query = bind_params(&prepared, &request.params, &engine_operator)?;
+            execute_query(query, session, user, engine_operator)
+        }
+        api::Command::Prepare(ref raw) => {
+            let prepared = prepare_query(raw, dialect, &query_parser)?;
+            let handle = statements
+                .insert(user, statement_session(session)?, prepared)
+                .map_err(|_| {
+                    api::ErrorDetails::new(
+                        api::ErrorKind::InvalidRequest,
+                        "Too many prepared statements, close some first",
+                    )
+                })?;
+
+            Ok(engine_operator::QueryResult {
+                statement: Some(handle),
+                ..Default::default()
+            }
+            .into())
+        }
+        api::Command::Execute(handle) => {
+            let prepared = statements
+                .get(user, statement_session(session)?, handle)
+                .ok_or_else(|| unknown_statement(handle))?;
+            let query = bind_params(&prepared, &request.params, &engine_operator)?;
+            execute_query(query, session, user, engine_operator)
+        }
+        api::Command::Close(handle) => {
+            if !statements.remove(user, statement_session(session)?, handle) {
+                return Err(unknown_statement(handle));
+            }
+
+            Ok(engine_operator::QueryResult::default().into())
+        }
+    }
+}
+
+// Statements are kept for the session they're prepared in.
+fn statement_session(session: Option<&str>) -> Result<&str, api::ErrorDetails> {
+    session.ok_or_else(|| {
+        api::ErrorDetails::new(
+            api::ErrorKind::InvalidRequest,
+            "Prepared statements need a session",
+        )
+    })
+}
+
+fn unknown_statement(handle: u64) -> api::ErrorDetails {
+    api::ErrorDetails::new(
+        api::ErrorKind::InvalidRequest,
+        format!("Unknown statement {}", handle),
+    )
+}
+
+/// Serves HTTP over TLS. Handshakes run concurrently, a failed one only drops its connection.
+fn serve_tls<S, B>(
+    listener: tokio::net::TcpListener,
+    acceptor: native_tls::TlsAcceptor,
+    new_service: S,
+) -> impl Future<Item = (), Error = ()>
+where
+    S: NewService<ReqBody = Body, ResBody = B> + Send + 'static,
+    S::Error: Into<Box<dyn Error + Send + Sync>>,
+    S::Service: Send,
+    S::Future: Send + 'static,
+    <S::Service as Service>::Future: Send + 'static,
+    B: Payload,
+{
+    let acceptor = tokio_tls::TlsAcceptor::from(acceptor);
+    let incoming = listener
+        .incoming()
+        .then(|stream| match stream {
+            Ok(stream) => Ok(Some(stream)),
+            Err(e) => {
+                warn!("Connection cannot be accepted: {}", e);
+                Ok::<_, io::Error>(None)
+            }
+        })
+        .filter_map(|stream| stream)
+        .map(move |stream| {
+            acceptor.accept(stream).then(|stream| {
+                Ok::<_, io::Error>(
+                    stream
+                        .map_err(|e| warn!("TLS handshake failed: {}", e))
+                        .ok(),
+                )
+            })
+        })
+        .buffer_unordered(TLS_HANDSHAKES)
+        .filter_map(|stream| stream);
+
+    Server::builder(incoming)
+        .serve(new_service)
+        .map_err(|e| error!("Server failed: {}", e))
+}
+
+/// User of the request, from HTTP Basic credentials or a bearer token. There's none when
+/// authentication isn't required.
+fn authenticate(
+    headers: &HeaderMap,
+    engine_operator: &engine_operator::EngineOperator,
+) -> Result<Option<String>, api::ErrorDetails> {
+    if !engine_operator.is_auth_required() {
+        return Ok(None);
+    }
+
+    let header = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let user = if let Some(credentials) = header.strip_prefix("Basic ") {
+        STANDARD
+            .decode(credentials.trim())
+            .ok()
+            .and_then(|raw| String::from_utf8(raw).ok())
+            .and_then(|credentials| {
+                let mut parts = credentials.splitn(2, ':');
+                let user = parts.next()?.to_owned();
+                let password = parts.next()?;
+                if engine_operator.authenticate(&user, password) {
+                    Some(user)
+                } else {
+                    None
+                }
+            })
+    } else if let Some(token) = header.strip_prefix("Bearer ") {
+        engine_operator.token_user(token.trim())
+    } else {
+        None
+    };
+
+    match user {
+        Some(user) => Ok(Some(user)),
+        None => Err(api::ErrorDetails::new(
+            api::ErrorKind::Unauthenticated,
+            "Authentication required",
+        )),
+    }
+}
+
+fn status_code(result: &Result<engine_operator::QueryStream, api::ErrorDetails>) -> StatusCode {
+    match result {
+        Ok(_) => StatusCode::OK,
+        Err(error) => match error.kind {
+            api::ErrorKind::Execution | api::ErrorKind::ConstraintViolation => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            api::ErrorKind::UnknownTable => StatusCode::NOT_FOUND,
+            api::ErrorKind::Conflict => StatusCode::CONFLICT,
+            api::ErrorKind::Unauthenticated => StatusCode::UNAUTHORIZED,
+            api::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            api::ErrorKind::InvalidRequest | api::ErrorKind::Parse => StatusCode::BAD_REQUEST,
+        },
     }
 }
 
+// The body is sent in chunks, the rows of a select being read while they are written.
+fn streamed_response(status: StatusCode, stream: api::ResponseStream) -> Response<Body> {
+    let content_type = match stream.format() {
+        api::Format::Json => "application/json",
+        api::Format::Ndjson => api::NDJSON_CONTENT_TYPE,
+    };
+    let chunks = stream::iter_result(
+        stream.map(|chunk| chunk.map_err(|_| io::Error::other("Row cannot be read"))),
+    );
+
+    Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .body(Body::wrap_stream(chunks))
+        .unwrap()
+}
+
 fn prepare_response(
     req: Request<Body>,
-    engine_operator: Arc<Mutex<engine_operator::EngineOperator>>,
+    engine_operator: Arc<engine_operator::EngineOperator>,
     query_parser: Arc<query_parser::QueryParser>,
-) -> Box<Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+    statements: Arc<statements::StatementCache>,
+    http_sessions: Arc<HttpSessions>,
+) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
     match req.method() {
         &Method::POST => {
-            let fut = req.into_body().concat2().and_then(move |chunk| {
-                let value = str::from_utf8(chunk.as_ref()).unwrap().to_owned();
+            let session: Option<String> = req
+                .headers()
+                .get(SESSION_HEADER)
+                .and_then(|v| v.to_str().ok())
+                // Kept apart from the sessions the wire and pg servers name.
+                .map(|v| format!("http-{}", v));
+            let is_ndjson = req
+                .headers()
+                .get(ACCEPT)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.contains(api::NDJSON_CONTENT_TYPE));
+            let format = if is_ndjson {
+                api::Format::Ndjson
+            } else {
+                api::Format::Json
+            };
 
-                match execute_raw_command(&value, engine_operator, query_parser) {
-                    Ok(s) => future::ok(Response::new(Body::from(s))),
-                    Err(_) => future::ok(Response::new(Body::empty())),
+            let user = match authenticate(req.headers(), &engine_operator) {
+                Ok(user) => user,
+                Err(error) => {
+                    let result = Err(error);
+                    let mut response = streamed_response(
+                        status_code(&result),
+                        api::ResponseStream::new(format, result, Instant::now()),
+                    );
+                    response.headers_mut().insert(
+                        WWW_AUTHENTICATE,
+                        HeaderValue::from_static("Basic realm=\"toydb\""),
+                    );
+                    return Box::new(future::ok(response));
                 }
+            };
+            if let Some(ref session) = session {
+                http_sessions.touch(user.as_deref(), session);
+            }
+
+            let fut = req.into_body().concat2().and_then(move |chunk| {
+                let start = Instant::now();
+                let result = match str::from_utf8(chunk.as_ref()) {
+                    Ok(body) => execute_request(
+                        body,
+                        session.as_ref().map(|s| &s[..]),
+                        user.as_ref().map(|u| &u[..]),
+                        engine_operator,
+                        query_parser,
+                        statements,
+                    ),
+                    Err(_) => Err(api::ErrorDetails::new(
+                        api::ErrorKind::InvalidRequest,
+                        "Request body is not valid UTF-8",
+                    )),
+                };
+
+                let status = status_code(&result);
+                let stream = api::ResponseStream::new(format, result, start);
+                future::ok(streamed_response(status, stream))
             });
             Box::new(fut)
         }
@@ -103,3 +602,142 @@ fn prepare_response(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::client::HttpConnector;
+    use hyper::Client;
+    use hyper_tls::HttpsConnector;
+    use temp_dir::TempDir;
+    use tls;
+
+    #[test]
+    fn test_http_is_served_over_tls() {
+        let dir = TempDir::new("https");
+        let (cert_path, key_path) = tls::write_self_signed(dir.path());
+        let config = config::Config {
+            data_dir: dir.path().to_string_lossy().into_owned(),
+            tls_cert: Some(cert_path.clone()),
+            tls_key: Some(key_path),
+            ..Default::default()
+        };
+        let acceptor = config.tls_acceptor().unwrap().unwrap();
+        let server = DBServer::new(config);
+        let eo = server.engine_operator.clone();
+        let qp = server.query_parser.clone();
+        let sc = server.statements.clone();
+        let hs = server.http_sessions.clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            rt::run(rt::lazy(move || {
+                let new_service = move || {
+                    let eo = eo.clone();
+                    let qp = qp.clone();
+                    let sc = sc.clone();
+                    let hs = hs.clone();
+                    service_fn(move |req| {
+                        prepare_response(req, eo.clone(), qp.clone(), sc.clone(), hs.clone())
+                    })
+                };
+                let listener =
+                    tokio::net::TcpListener::from_std(listener, &tokio::reactor::Handle::default())
+                        .unwrap();
+                serve_tls(listener, acceptor, new_service)
+            }))
+        });
+
+        let mut http = HttpConnector::new(1);
+        http.enforce_http(false);
+        let connector = tls::connector(Some(&cert_path)).unwrap();
+        let client = Client::builder().build::<_, Body>(HttpsConnector::from((http, connector)));
+        let req = Request::post(format!("https://localhost:{}/", port))
+            .body(Body::from(":db"))
+            .unwrap();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let body = runtime
+            .block_on(client.request(req).and_then(|res| {
+                assert_eq!(StatusCode::OK, res.status());
+                res.into_body().concat2()
+            }))
+            .unwrap();
+        assert!(str::from_utf8(&body).unwrap().contains("\"status\":\"ok\""));
+
+        // Plain HTTP is not answered.
+        let plain = Client::new().get(format!("http://localhost:{}/", port).parse().unwrap());
+        assert!(runtime.block_on(plain).is_err());
+    }
+
+    #[test]
+    fn test_malformed_tql_is_a_parse_error() {
+        let qp = query_parser::QueryParser;
+        for raw in &[
+            "? a b c",
+            "? id >",
+            "? id id id",
+            "> users id",
+            "- users id",
+        ] {
+            let error = parse_query(raw, query::Dialect::Tql, &qp).unwrap_err();
+            assert_eq!(api::ErrorKind::Parse, error.kind);
+        }
+    }
+
+    #[test]
+    fn test_failed_queries_tell_why() {
+        let eo = Arc::new(engine_operator::EngineOperator::new(storage::MemoryBackend));
+        let run = |session, raw| {
+            parse_query(raw, query::Dialect::Tql, &query_parser::QueryParser)
+                .and_then(|query| execute_query(query, session, None, eo.clone()))
+                .map(|_| ())
+                .map_err(|error| error.kind)
+        };
+
+        assert_eq!(
+            Err(api::ErrorKind::UnknownTable),
+            run(None, "? id > missing")
+        );
+        assert_eq!(Ok(()), run(None, "+ users id int primary_key"));
+        assert_eq!(Ok(()), run(None, "> users id 1"));
+        assert_eq!(
+            Err(api::ErrorKind::ConstraintViolation),
+            run(None, "> users id 1")
+        );
+
+        assert_eq!(Ok(()), run(Some("a"), ":begin"));
+        assert_eq!(Ok(()), run(Some("b"), ":begin"));
+        assert_eq!(Ok(()), run(Some("a"), "- users : id = 1"));
+        assert_eq!(
+            Err(api::ErrorKind::Conflict),
+            run(Some("b"), "- users : id = 1")
+        );
+    }
+
+    #[test]
+    fn test_idle_http_sessions_are_ended() {
+        let eo: engine_operator::EngineOperator = Default::default();
+        let sessions = HttpSessions::new(Duration::from_millis(50));
+        let statements: statements::StatementCache = Default::default();
+        let prepared = query_parser::QueryParser.prepare("? id > t").unwrap();
+        let handle = statements
+            .insert(Some("alice"), "http-idle", prepared)
+            .unwrap();
+        let begin = |session| {
+            let query = query_parser::QueryParser.parse(":begin").unwrap();
+            eo.execute(Some("alice"), Some(session), query)
+        };
+        assert!(begin("http-idle").is_ok());
+        sessions.touch(Some("alice"), "http-idle");
+        assert!(begin("http-busy").is_ok());
+
+        thread::sleep(Duration::from_millis(60));
+        sessions.touch(Some("alice"), "http-busy");
+        assert_eq!(1, sessions.expire(&eo, &statements));
+        assert!(statements.get(Some("alice"), "http-idle", handle).is_none());
+        assert!(!eo.in_transaction(Some("alice"), "http-idle"));
+        assert!(eo.in_transaction(Some("alice"), "http-busy"));
+        assert_eq!(0, sessions.expire(&eo, &statements));
+    }
+}
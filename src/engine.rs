@@ -1,13 +1,33 @@
 use index;
+use mvcc;
 use query;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{self, Deref};
 use std::str;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use util;
 
 pub type Schema = HashMap<String, ColumnInfo>;
-type Row = Vec<u8>;
+pub type Row = Vec<u8>;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Why a statement failed, as far as the client is told. The details are logged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    UnknownTable,
+    ConstraintViolation,
+    // A concurrent transaction changed the same rows.
+    Conflict,
+    Failed,
+}
+
+// Failures that aren't told apart.
+impl From<()> for Error {
+    fn from(_: ()) -> Error {
+        Error::Failed
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ColumnInfo {
     name: String,
     offs: usize,
@@ -24,13 +44,33 @@ impl ColumnInfo {
             field_def,
         }
     }
+
+    /// The bytes of the column in the row, ordered the way the values are.
+    pub fn sort_key(&self, row: &[u8]) -> Vec<u8> {
+        let mut key = row[self.offs..self.offs + self.size].to_vec();
+        // Ints are little endian in rows.
+        if self.field_def.config == query::Type::Int {
+            key.reverse();
+        }
+        key
+    }
+
+    /// Where the column is in a row.
+    pub fn range(&self) -> ops::Range<usize> {
+        self.offs..self.offs + self.size
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Table<T: index::Index = index::BasicIndex> {
     pub schema: Schema,
-    pub data: Vec<Row>,
+    pub data: mvcc::Rows,
     indices: HashMap<String, T>,
+    // Next value handed out to the auto_increment field.
+    pub sequence: u32,
+    pub constraints: query::Constraints,
+    pub primary_key: Option<String>,
+    pub storage: query::Storage,
 }
 
 impl<T: index::Index + Default> Table<T> {
@@ -42,69 +82,304 @@ impl<T: index::Index + Default> Table<T> {
 
         Table {
             schema: restructure_field_def_list(schema),
-            data: Vec::new(),
+            data: Default::default(),
             indices,
+            sequence: 0,
+            constraints: Default::default(),
+            primary_key: None,
+            storage: Default::default(),
         }
     }
 
-    pub fn new_with_schema(schema: Schema) -> Table<T> {
+    pub fn new_with_schema(schema: Schema, index_fields: Vec<String>) -> Table<T> {
+        let mut indices: HashMap<String, T> = Default::default();
+        index_fields.iter().for_each(|field| {
+            indices.insert(field.to_string(), Default::default());
+        });
+
         Table {
             schema,
-            data: Vec::new(),
-            indices: HashMap::new(),
+            data: Default::default(),
+            indices,
+            sequence: 0,
+            constraints: Default::default(),
+            primary_key: None,
+            storage: Default::default(),
         }
     }
 
-    pub fn raw_insert(&mut self, raw_inserts: HashMap<String, String>) -> Result<(), ()> {
+    pub fn index_fields(&self) -> Vec<String> {
+        self.indices.keys().cloned().collect()
+    }
+
+    /// Indexes an existing column, including the rows it already has.
+    pub fn add_index(&mut self, field: &str) -> Result<(), ()> {
+        if !self.schema.contains_key(field) {
+            error!("Cannot index unknown column: {}", field);
+            return Err(());
+        }
+        if self.indices.contains_key(field) {
+            error!("Column is already indexed: {}", field);
+            return Err(());
+        }
+
+        self.indices.insert(field.to_owned(), Default::default());
+        self.rebuild_indices();
+        Ok(())
+    }
+
+    /// Inserts a row version created by the transaction and returns the generated
+    /// auto_increment id, if any.
+    pub fn raw_insert(
+        &mut self,
+        mut raw_inserts: HashMap<String, String>,
+        tx: mvcc::TxId,
+    ) -> Result<Option<u32>, Error> {
+        let generated_id = self.fill_omitted_fields(&mut raw_inserts)?;
+
         let schema_size = self.schema_byte_size();
         let mut row: Row = vec![0; schema_size];
 
         for (column_name, column_info) in &self.schema {
-            let raw_val = match raw_inserts.get(&column_name[..]) {
-                Some(raw) => {
-                    let _ = write_bytes(
-                        &mut row,
-                        column_info.size,
-                        column_info.offs,
-                        raw,
-                        &column_info.field_def.config,
-                    )
-                    .or_else(|e| {
-                        warn!("Data write error");
-                        Err(e)
-                    });
-                    raw
-                }
-                None => "",
-            };
+            if let Some(raw) = raw_inserts.get(&column_name[..]) {
+                write_bytes(
+                    &mut row,
+                    column_info.size,
+                    column_info.offs,
+                    raw,
+                    &column_info.field_def.config,
+                )
+                .inspect_err(|_| {
+                    warn!("Invalid value for {}: {}", column_name, raw);
+                })?;
+            }
+        }
 
-            if self.indices.contains_key(column_name) {
-                let real_val = raw_string_to_val(
-                    raw_val,
-                    &self.schema.get(column_name).unwrap().field_def.config,
-                );
-                let mut index = self.indices.get_mut(column_name).unwrap();
+        for check in &self.constraints.checks {
+            if !are_conditions_passing(&row, &self.schema, check) {
+                warn!("Check constraint failed: {:?}", check);
+                return Err(Error::ConstraintViolation);
+            }
+        }
 
-                index.insert(real_val.unwrap(), self.data.len());
+        // Converted before anything changes, so a bad value leaves the table as it was.
+        let mut index_vals: HashMap<&str, util::Val> = HashMap::new();
+        for (field, raw) in &raw_inserts {
+            if self.indices.contains_key(field) {
+                let data_type = &self.schema[field].field_def.config;
+                let val = raw_string_to_val(raw, data_type).inspect_err(|_| {
+                    warn!("Invalid value for {}: {}", field, raw);
+                })?;
+                index_vals.insert(field, val);
             }
         }
 
-        self.data.push(row);
+        self.data.push(mvcc::RowVersion::new(row, tx));
 
         let position: usize = self.data.len() - 1;
         for (index_field, index) in &mut self.indices {
-            if raw_inserts.contains_key(index_field) {
-                let data_type = &self.schema.get(index_field).unwrap().field_def.config;
-                let val =
-                    raw_string_to_val(raw_inserts.get(index_field).unwrap(), data_type).unwrap();
+            if let Some(val) = index_vals.remove(&index_field[..]) {
                 index.insert(val, position);
             }
         }
 
-        Ok(())
+        Ok(generated_id)
+    }
+
+    fn fill_omitted_fields(
+        &mut self,
+        raw_inserts: &mut HashMap<String, String>,
+    ) -> Result<Option<u32>, ()> {
+        let mut generated_id: Option<u32> = None;
+
+        for (column_name, column_info) in &self.schema {
+            let field_def = &column_info.field_def;
+
+            if field_def.auto_increment {
+                match raw_inserts.get(column_name) {
+                    Some(raw) => {
+                        // Explicit ids move the sequence past them to avoid collisions later.
+                        if let Ok(id) = raw.parse::<u32>() {
+                            self.sequence = u32::max(self.sequence, id.saturating_add(1));
+                        }
+                    }
+                    None => {
+                        // The last id is never handed out, it marks the sequence as exhausted.
+                        let next = self.sequence.checked_add(1).ok_or_else(|| {
+                            error!("Sequence of {} is exhausted", column_name);
+                        })?;
+                        raw_inserts.insert(column_name.clone(), self.sequence.to_string());
+                        generated_id = Some(self.sequence);
+                        self.sequence = next;
+                    }
+                }
+            } else if let Some(ref default) = field_def.default {
+                raw_inserts
+                    .entry(column_name.clone())
+                    .or_insert_with(|| default.clone());
+            }
+        }
+
+        Ok(generated_id)
+    }
+
+    // The raw value an insert would store for the field, without advancing the sequence.
+    fn raw_value_for(
+        &self,
+        raw_inserts: &HashMap<String, String>,
+        column_name: &str,
+    ) -> Option<String> {
+        let field_def = &self.schema.get(column_name)?.field_def;
+
+        match raw_inserts.get(column_name) {
+            Some(raw) => Some(raw.clone()),
+            None if field_def.auto_increment => Some(self.sequence.to_string()),
+            None => field_def.default.clone(),
+        }
+    }
+
+    // Whether the snapshot sees a row with the value that no one else is deleting.
+    fn index_contains(
+        &self,
+        column_name: &str,
+        raw: &str,
+        snapshot: &mvcc::Snapshot,
+    ) -> Result<bool, ()> {
+        let index = self.indices.get(column_name).ok_or(())?;
+        let data_type = &self.schema.get(column_name).ok_or(())?.field_def.config;
+        let val = raw_string_to_val(raw, data_type)?;
+
+        Ok(index.get_pos(val).is_some_and(|positions| {
+            positions.iter().any(|position| {
+                let version = self.data.get(*position);
+                snapshot.is_visible(version) && version.deleted.is_none()
+            })
+        }))
+    }
+
+    // Whether the value may already have a live version: any not deleted in the snapshot, even
+    // one it doesn't see, as a concurrent insert of the same value would conflict.
+    fn index_may_contain(
+        &self,
+        column_name: &str,
+        raw: &str,
+        snapshot: &mvcc::Snapshot,
+    ) -> Result<bool, ()> {
+        let index = self.indices.get(column_name).ok_or(())?;
+        let data_type = &self.schema.get(column_name).ok_or(())?.field_def.config;
+        let val = raw_string_to_val(raw, data_type)?;
+
+        Ok(index.get_pos(val).is_some_and(|positions| {
+            positions.iter().any(|position| {
+                let version = self.data.get(*position);
+                !version.deleted.is_some_and(|tx| snapshot.sees(tx))
+            })
+        }))
     }
 
-    fn schema_byte_size(&self) -> usize {
+    pub fn visible_rows<'a>(
+        &'a self,
+        snapshot: &'a mvcc::Snapshot,
+    ) -> impl Iterator<Item = &'a Row> + 'a {
+        self.data
+            .iter()
+            .filter(move |version| snapshot.is_visible(version))
+            .map(|version| &version.data)
+    }
+
+    /// Storage key of the row the conditions ask for, when one of them is primary key = value.
+    pub fn primary_key_of(&self, conditions: &[query::FieldCondition]) -> Option<Vec<u8>> {
+        let column_info = self.schema.get(self.primary_key.as_ref()?)?;
+        let condition = conditions.iter().find(|condition| {
+            condition.field_name == column_info.name
+                && matches!(
+                    query::Relation::from(&condition.relation),
+                    Some(query::Relation::Eq)
+                )
+        })?;
+
+        let mut row: Row = vec![0; self.schema_byte_size()];
+        write_bytes(
+            &mut row,
+            column_info.size,
+            column_info.offs,
+            &condition.value,
+            &column_info.field_def.config,
+        )
+        .ok()?;
+        Some(column_info.sort_key(&row))
+    }
+
+    /// Adds rows read from storage, as committed before any transaction.
+    pub fn load_rows<I: IntoIterator<Item = Row>>(&mut self, rows: I) {
+        for row in rows {
+            self.data.push(mvcc::RowVersion::new(row, mvcc::LOADED_TX));
+        }
+        self.rebuild_indices();
+    }
+
+    /// Drops every trace of an aborted transaction.
+    pub fn purge(&mut self, tx: mvcc::TxId) {
+        for position in 0..self.data.len() {
+            if self.data.get(position).deleted == Some(tx) {
+                self.data.get_mut(position).deleted = None;
+            }
+        }
+
+        if self.data.retain(|version| version.created != tx) > 0 {
+            self.rebuild_indices();
+        }
+    }
+
+    /// Removes versions deleted by transactions every open snapshot already sees.
+    pub fn vacuum<F: Fn(mvcc::TxId) -> bool>(&mut self, is_dead: F) -> usize {
+        let removed = self
+            .data
+            .retain(|version| !version.deleted.is_some_and(&is_dead));
+        if removed > 0 {
+            self.rebuild_indices();
+        }
+        removed
+    }
+
+    // Positions shift after removal, so indices are rebuilt from scratch.
+    fn rebuild_indices(&mut self) {
+        for (index_field, index) in &mut self.indices {
+            *index = Default::default();
+            let column_info = self.schema.get(index_field).unwrap();
+            for (position, version) in self.data.iter().enumerate() {
+                if let Ok(val) = extract_row_value(&version.data, column_info) {
+                    index.insert(val, position);
+                }
+            }
+        }
+    }
+
+    /// Copies what a select needs. The copy shares rows with the table, so it is cheap, and can
+    /// be scanned after releasing the table lock.
+    pub fn snapshot(&self, snapshot: mvcc::Snapshot) -> TableSnapshot {
+        TableSnapshot {
+            schema: self.schema.clone(),
+            rows: self.data.clone(),
+            snapshot,
+        }
+    }
+
+    /// Like snapshot, but over rows read from storage instead of the ones the table holds.
+    pub fn snapshot_of_rows(&self, rows: Vec<Row>, snapshot: mvcc::Snapshot) -> TableSnapshot {
+        let mut data: mvcc::Rows = Default::default();
+        for row in rows {
+            data.push(mvcc::RowVersion::new(row, mvcc::LOADED_TX));
+        }
+
+        TableSnapshot {
+            schema: self.schema.clone(),
+            rows: data,
+            snapshot,
+        }
+    }
+
+    pub fn schema_byte_size(&self) -> usize {
         self.schema
             .iter()
             .fold(0_usize, |acc, (_, elem)| acc + elem.size)
@@ -112,13 +387,13 @@ impl<T: index::Index + Default> Table<T> {
 }
 
 fn raw_string_to_val(raw: &str, data_type: &query::Type) -> Result<util::Val, ()> {
-    match data_type {
-        &query::Type::Int => match u32::from_str_radix(raw, 10) {
+    match *data_type {
+        query::Type::Int => match raw.parse::<u32>() {
             Ok(n) => Ok(util::Val::U32(n)),
             Err(_) => Err(()),
         },
-        &query::Type::Varchar(len) => Ok(util::Val::Varchar(
-            (&raw[0..usize::min(len as usize, raw.len())]).to_owned(),
+        query::Type::Varchar(len) => Ok(util::Val::Varchar(
+            raw[0..usize::min(len as usize, raw.len())].to_owned(),
         )),
     }
 }
@@ -148,7 +423,7 @@ fn write_bytes(
 ) -> Result<(), ()> {
     match data_type {
         query::Type::Int => {
-            let uint_val: u128 = match u128::from_str_radix(raw, 10) {
+            let uint_val: u128 = match raw.parse::<u128>() {
                 Ok(n) => n,
                 Err(e) => {
                     error!("Int cannot be parsed: {:?}", e);
@@ -160,15 +435,13 @@ fn write_bytes(
             }
         }
         query::Type::Varchar(_) => {
-            let mut idx = 0_usize;
-            for ch in raw.chars() {
+            for (idx, ch) in raw.chars().enumerate() {
                 if idx >= len {
                     warn!("String truncated");
                     return Err(());
                 }
 
                 buf[offs + idx] = ch as u8;
-                idx += 1;
             }
         }
     }
@@ -182,6 +455,27 @@ fn size_of_type(data_type: &query::Type) -> usize {
     }
 }
 
+fn validate_conditions(schema: &Schema, conditions: &[query::FieldCondition]) -> Result<(), ()> {
+    for condition in conditions {
+        let column_info = match schema.get(&condition.field_name[..]) {
+            Some(ci) => ci,
+            None => {
+                error!("Condition has unknown field: {}", condition.field_name);
+                return Err(());
+            }
+        };
+
+        let relation = query::Relation::from(&condition.relation);
+        let value = util::Val::from(condition.value.clone(), &column_info.field_def.config);
+        if relation.is_none() || value.is_none() {
+            error!("Condition cannot be parsed: {:?}", condition);
+            return Err(());
+        }
+    }
+
+    Ok(())
+}
+
 fn are_conditions_passing(
     row: &Row,
     schema: &Schema,
@@ -200,7 +494,7 @@ fn are_conditions_passing(
             return false;
         }
 
-        let orig = extract_row_value(row, &column_info);
+        let orig = extract_row_value(row, column_info);
         if orig.is_err() {
             error!("Value cannot be extracted");
             return false;
@@ -213,12 +507,12 @@ fn are_conditions_passing(
                 }
             }
             query::Relation::Lt => {
-                if !(orig.unwrap() < value.unwrap()) {
+                if orig.unwrap() >= value.unwrap() {
                     return false;
                 }
             }
             query::Relation::Gt => {
-                if !(orig.unwrap() > value.unwrap()) {
+                if orig.unwrap() <= value.unwrap() {
                     return false;
                 }
             }
@@ -239,108 +533,319 @@ fn extract_row_value(row: &Row, column_info: &ColumnInfo) -> Result<util::Val, (
 
             Ok(util::Val::U32(val))
         }
-        query::Type::Varchar(n) => Ok(util::Val::Varchar(
-            str::from_utf8(&row[column_info.offs..(column_info.offs + (n as usize))])
-                .unwrap()
-                .to_owned(),
-        )),
+        query::Type::Varchar(n) => {
+            let slice =
+                str::from_utf8(&row[column_info.offs..(column_info.offs + (n as usize))]).unwrap();
+
+            let slice = match slice.find('\0') {
+                Some(n) => slice[0..n].to_owned(),
+                None => slice.to_owned(),
+            };
+
+            Ok(util::Val::Varchar(slice))
+        }
+    }
+}
+
+pub type TableRef = Arc<RwLock<Table>>;
+
+/// A locked table, shared for reading or exclusive for writing.
+pub enum TableGuard<'a> {
+    Shared(RwLockReadGuard<'a, Table>),
+    Exclusive(RwLockWriteGuard<'a, Table>),
+}
+
+impl<'a> TableGuard<'a> {
+    fn as_mut(&mut self) -> Result<&mut Table, ()> {
+        match self {
+            TableGuard::Exclusive(guard) => Ok(&mut *guard),
+            TableGuard::Shared(_) => {
+                error!("Table is not locked for writing");
+                Err(())
+            }
+        }
     }
 }
 
+impl<'a> Deref for TableGuard<'a> {
+    type Target = Table;
+
+    fn deref(&self) -> &Table {
+        match self {
+            TableGuard::Shared(guard) => guard,
+            TableGuard::Exclusive(guard) => guard,
+        }
+    }
+}
+
+/// Locks the tables a statement touches, the ones in `exclusive` for writing. Locks are always
+/// taken in table name order, so statements over several tables (foreign key checks, joins)
+/// cannot deadlock each other.
+pub fn lock_tables<'a>(
+    tables: &'a BTreeMap<String, TableRef>,
+    exclusive: &[&str],
+) -> BTreeMap<&'a str, TableGuard<'a>> {
+    tables
+        .iter()
+        .map(|(name, table)| {
+            let guard = if exclusive.contains(&&name[..]) {
+                TableGuard::Exclusive(table.write().unwrap())
+            } else {
+                TableGuard::Shared(table.read().unwrap())
+            };
+            (&name[..], guard)
+        })
+        .collect()
+}
+
+/// Tables behind their own read/write locks. The catalog lock guards the set of tables: reads
+/// hold it only to look tables up, DDL holds it exclusively. It is never requested while a table
+/// lock is held, so lock order is always catalog first, then tables by name.
 #[derive(Debug, Default)]
 pub struct Engine {
-    pub tables: HashMap<String, Table>,
+    tables: RwLock<HashMap<String, TableRef>>,
 }
 
 impl Engine {
-    pub fn create_table(&mut self, q: query::CreateQuery) -> Result<(), ()> {
-        self.tables.insert(q.table, Table::new(q.fields, q.indices));
-        Ok(())
+    pub fn load(&self, tables: HashMap<String, Table>) {
+        let mut catalog = self.tables.write().unwrap();
+        *catalog = tables
+            .into_iter()
+            .map(|(name, table)| (name, Arc::new(RwLock::new(table))))
+            .collect();
     }
 
-    pub fn insert(&mut self, query: query::InsertQuery) -> Result<(), ()> {
-        match self.tables.get_mut(&query.table_name[..]) {
-            Some(table) => {
-                let _ = table.raw_insert(query.raw_inserts);
-            }
+    pub fn table(&self, table_name: &str) -> Result<TableRef, ()> {
+        match self.tables.read().unwrap().get(table_name) {
+            Some(table) => Ok(table.clone()),
             None => {
-                error!("Missing table: {}", query.table_name);
-                return Err(());
+                warn!("No table: {}", table_name);
+                Err(())
             }
         }
+    }
 
-        Ok(())
+    pub fn column_type(&self, table_name: &str, column_name: &str) -> Option<query::Type> {
+        let table = self.tables.read().unwrap().get(table_name)?.clone();
+        let table = table.read().unwrap();
+        let column_info = table.schema.get(column_name)?;
+        Some(column_info.field_def.config.clone())
     }
 
-    pub fn select(&self, query: query::SelectQuery) -> Result<Vec<Vec<util::Val>>, ()> {
-        let db = match self.tables.get(&query.table[..]) {
-            Some(db) => db,
-            None => {
-                warn!("No table: {}", &query.table);
-                return Err(());
-            }
-        };
+    // Copy of the catalog, so table locks can be taken after releasing it.
+    fn catalog(&self) -> HashMap<String, TableRef> {
+        self.tables.read().unwrap().clone()
+    }
 
-        let mut res: Vec<Vec<util::Val>> = vec![];
+    pub fn create_table(&self, q: query::CreateQuery) -> Result<(), ()> {
+        let mut catalog = self.tables.write().unwrap();
+        if catalog.contains_key(&q.table) {
+            error!("Table already exists: {}", q.table);
+            return Err(());
+        }
 
-        for row in &db.data {
-            if !are_conditions_passing(row, &db.schema, &query.conditions) {
-                continue;
+        let mut indices = q.indices;
+        if let Some(ref primary_key) = q.primary_key {
+            if !q.fields.iter().any(|field| &field.name == primary_key) {
+                error!("Primary key is not a column: {}", primary_key);
+                return Err(());
             }
+            // Uniqueness is checked through the index.
+            if !indices.contains(primary_key) {
+                indices.push(primary_key.clone());
+            }
+        } else if q.storage == query::Storage::Lsm {
+            error!("LSM tables need a primary key: {}", q.table);
+            return Err(());
+        }
 
-            let mut row_vals: Vec<util::Val> = vec![];
+        let mut table = Table::new(q.fields, indices);
 
-            for column_name in &query.columns {
-                let column_info = match db.schema.get(column_name) {
-                    Some(ci) => ci,
+        for check in &q.constraints.checks {
+            validate_conditions(&table.schema, check)?;
+        }
+
+        for foreign_key in &q.constraints.foreign_keys {
+            let guard;
+            let referenced = if foreign_key.table == q.table {
+                &table
+            } else {
+                match catalog.get(&foreign_key.table[..]) {
+                    Some(referenced) => {
+                        guard = referenced.read().unwrap();
+                        &*guard
+                    }
                     None => {
-                        // @TODO this check should happen before looping rows.
-                        error!("Column not found");
-                        continue;
+                        error!("Referenced table is missing: {}", foreign_key.table);
+                        return Err(());
                     }
-                };
-                row_vals.push(match column_info.field_def.config {
-                    query::Type::Int => {
-                        let mut val = 0_u32;
+                }
+            };
 
-                        for i in 0..column_info.size {
-                            val |= (row[column_info.offs + i] as u32) << (i * 8);
-                        }
+            // References are verified through the index of the referenced column.
+            if !referenced.indices.contains_key(&foreign_key.column) {
+                error!(
+                    "Referenced column must be indexed: {}({})",
+                    foreign_key.table, foreign_key.column
+                );
+                return Err(());
+            }
 
-                        util::Val::U32(val)
-                    }
-                    query::Type::Varchar(n) => {
-                        let slice = str::from_utf8(
-                            &row[column_info.offs..(column_info.offs + (n as usize))],
-                        )
-                        .unwrap();
-
-                        let slice = match slice.find('\0') {
-                            Some(n) => slice[0..n].to_owned(),
-                            None => slice.to_owned(),
-                        };
-
-                        util::Val::Varchar(slice)
-                    }
-                });
+            let field_info = table.schema.get(&foreign_key.field).ok_or(())?;
+            let column_info = referenced.schema.get(&foreign_key.column).ok_or(())?;
+            if field_info.field_def.config != column_info.field_def.config {
+                error!("Foreign key type mismatch: {:?}", foreign_key);
+                return Err(());
+            }
+        }
+
+        table.constraints = q.constraints;
+        table.primary_key = q.primary_key;
+        table.storage = q.storage;
+        catalog.insert(q.table, Arc::new(RwLock::new(table)));
+        Ok(())
+    }
+
+    pub fn create_index(&self, q: query::CreateIndexQuery) -> Result<(), ()> {
+        let table = self.table(&q.table)?;
+        let mut table = table.write().unwrap();
+        table.add_index(&q.field)
+    }
+
+    pub fn insert(
+        &self,
+        query: query::InsertQuery,
+        snapshot: &mvcc::Snapshot,
+    ) -> Result<Option<u32>, Error> {
+        let table_name = &query.table_name[..];
+        let target = self.table(table_name).map_err(|_| Error::UnknownTable)?;
+
+        let mut tables: BTreeMap<String, TableRef> = BTreeMap::new();
+        for foreign_key in &target.read().unwrap().constraints.foreign_keys {
+            tables.insert(foreign_key.table.clone(), self.table(&foreign_key.table)?);
+        }
+        tables.insert(table_name.to_owned(), target);
+
+        let mut locked = lock_tables(&tables, &[table_name]);
+        verify_foreign_keys(&locked, table_name, &query.raw_inserts, snapshot)
+            .map_err(|_| Error::ConstraintViolation)?;
+        verify_primary_key(&locked, table_name, &query.raw_inserts, snapshot)
+            .map_err(|_| Error::ConstraintViolation)?;
+
+        let table = locked.get_mut(table_name).ok_or(())?.as_mut()?;
+        table.raw_insert(query.raw_inserts, snapshot.tx)
+    }
+
+    pub fn delete(
+        &self,
+        query: query::DeleteQuery,
+        snapshot: &mvcc::Snapshot,
+    ) -> Result<usize, Error> {
+        let table_name = &query.table[..];
+        let target = self.table(table_name).map_err(|_| Error::UnknownTable)?;
+
+        // Constraints don't change after creation, so the referencing tables can be found
+        // before locking.
+        let mut tables: BTreeMap<String, TableRef> = self
+            .catalog()
+            .into_iter()
+            .filter(|(_, table)| {
+                let table = table.read().unwrap();
+                let foreign_keys = &table.constraints.foreign_keys;
+                foreign_keys.iter().any(|fk| fk.table == table_name)
+            })
+            .collect();
+        tables.insert(table_name.to_owned(), target);
+
+        let mut locked = lock_tables(&tables, &[table_name]);
+        let positions: Vec<usize> = {
+            let table = locked.get(table_name).ok_or(())?;
+            validate_conditions(&table.schema, &query.conditions)?;
+
+            let positions: Vec<usize> = table
+                .data
+                .iter()
+                .enumerate()
+                .filter(|(_, version)| snapshot.is_visible(version))
+                .filter(|(_, version)| {
+                    are_conditions_passing(&version.data, &table.schema, &query.conditions)
+                })
+                .map(|(position, _)| position)
+                .collect();
+
+            // A visible version already marked is being deleted by a concurrent transaction.
+            if positions
+                .iter()
+                .any(|position| table.data.get(*position).deleted.is_some())
+            {
+                warn!("Delete conflicts with a concurrent transaction");
+                return Err(Error::Conflict);
             }
 
-            res.push(row_vals);
+            positions
+        };
+
+        verify_not_referenced(&locked, table_name, &positions, snapshot)
+            .map_err(|_| Error::ConstraintViolation)?;
+
+        let table = locked.get_mut(table_name).ok_or(())?.as_mut()?;
+        for position in &positions {
+            table.data.get_mut(*position).deleted = Some(snapshot.tx);
         }
 
-        Ok(res)
+        Ok(positions.len())
+    }
+
+    /// Removes dead row versions from every table, returns the number removed. Tables are
+    /// locked one at a time.
+    pub fn vacuum<F: Fn(mvcc::TxId) -> bool>(&self, is_dead: F) -> usize {
+        self.catalog()
+            .values()
+            .map(|table| {
+                // Mostly there's nothing to remove, which is found out without blocking reads.
+                let is_reclaimable = table
+                    .read()
+                    .unwrap()
+                    .data
+                    .iter()
+                    .any(|version| version.deleted.is_some_and(&is_dead));
+                if !is_reclaimable {
+                    return 0;
+                }
+                table.write().unwrap().vacuum(&is_dead)
+            })
+            .sum()
     }
 
     pub fn describe_db(&self) -> String {
         let mut out = String::new();
 
-        for (name, db) in &self.tables {
+        for (name, db) in &self.catalog() {
+            let db = db.read().unwrap();
             out.push_str(format!("{}\n", name).as_str());
             for (column_name, column_info) in &db.schema {
                 out.push_str(format!("\t{:12} : {:?}\n", column_name, column_info).as_str());
             }
             for index_field in db.indices.keys() {
-                out.push_str(format!("\tIndex on: {:12}", index_field).as_str());
+                out.push_str(format!("\tIndex on: {:12}\n", index_field).as_str());
+            }
+            if let Some(ref primary_key) = db.primary_key {
+                out.push_str(format!("\tPrimary key: {}\n", primary_key).as_str());
+            }
+            if db.storage != query::Storage::Rows {
+                out.push_str(format!("\tStorage: {:?}\n", db.storage).as_str());
+            }
+            for check in &db.constraints.checks {
+                out.push_str(format!("\tCheck: {:?}\n", check).as_str());
+            }
+            for foreign_key in &db.constraints.foreign_keys {
+                out.push_str(
+                    format!(
+                        "\tForeign key: {} references {}({})\n",
+                        foreign_key.field, foreign_key.table, foreign_key.column
+                    )
+                    .as_str(),
+                );
             }
         }
 
@@ -348,9 +853,190 @@ impl Engine {
     }
 }
 
+fn verify_primary_key(
+    locked: &BTreeMap<&str, TableGuard>,
+    table_name: &str,
+    raw_inserts: &HashMap<String, String>,
+    snapshot: &mvcc::Snapshot,
+) -> Result<(), ()> {
+    let table = locked.get(table_name).ok_or(())?;
+    let primary_key = match table.primary_key {
+        Some(ref primary_key) => primary_key,
+        None => return Ok(()),
+    };
+
+    let raw = match table.raw_value_for(raw_inserts, primary_key) {
+        Some(raw) => raw,
+        None => {
+            warn!("Primary key is missing: {}", primary_key);
+            return Err(());
+        }
+    };
+    if table.index_may_contain(primary_key, &raw, snapshot)? {
+        warn!("Duplicate primary key {}: {}", primary_key, raw);
+        return Err(());
+    }
+    Ok(())
+}
+
+fn verify_foreign_keys(
+    locked: &BTreeMap<&str, TableGuard>,
+    table_name: &str,
+    raw_inserts: &HashMap<String, String>,
+    snapshot: &mvcc::Snapshot,
+) -> Result<(), ()> {
+    let table = locked.get(table_name).ok_or(())?;
+
+    for foreign_key in &table.constraints.foreign_keys {
+        let raw = match table.raw_value_for(raw_inserts, &foreign_key.field) {
+            Some(raw) => raw,
+            None => {
+                warn!("Foreign key field is missing: {}", foreign_key.field);
+                return Err(());
+            }
+        };
+
+        let referenced = locked.get(&foreign_key.table[..]).ok_or(())?;
+        if !referenced.index_contains(&foreign_key.column, &raw, snapshot)? {
+            warn!(
+                "Foreign key violation: {} {} is not in {}({})",
+                foreign_key.field, raw, foreign_key.table, foreign_key.column
+            );
+            return Err(());
+        }
+    }
+
+    Ok(())
+}
+
+// Restrict semantics: rows still referenced by a foreign key cannot be deleted. Referencing
+// rows of transactions in progress count too, as they might commit.
+fn verify_not_referenced(
+    locked: &BTreeMap<&str, TableGuard>,
+    table_name: &str,
+    positions: &[usize],
+    snapshot: &mvcc::Snapshot,
+) -> Result<(), ()> {
+    let table = locked.get(table_name).ok_or(())?;
+
+    for (referencing_name, referencing) in locked {
+        for foreign_key in &referencing.constraints.foreign_keys {
+            if foreign_key.table != table_name {
+                continue;
+            }
+
+            let column_info = table.schema.get(&foreign_key.column).ok_or(())?;
+            let field_info = referencing.schema.get(&foreign_key.field).ok_or(())?;
+
+            for position in positions {
+                let val = extract_row_value(&table.data.get(*position).data, column_info)?;
+                let is_referenced = referencing
+                    .data
+                    .iter()
+                    .filter(|version| !version.deleted.is_some_and(|tx| snapshot.sees(tx)))
+                    .any(|version| {
+                        extract_row_value(&version.data, field_info).as_ref() == Ok(&val)
+                    });
+
+                if is_referenced {
+                    warn!(
+                        "Cannot delete, {:?} is referenced by {}.{}",
+                        val, referencing_name, foreign_key.field
+                    );
+                    return Err(());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Column {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub data_type: query::Type,
+}
+
+pub struct TableSnapshot {
+    schema: Schema,
+    rows: mvcc::Rows,
+    snapshot: mvcc::Snapshot,
+}
+
+impl TableSnapshot {
+    /// Name and type of the selected columns, in select order.
+    pub fn columns(&self, column_names: &[String]) -> Result<Vec<Column>, ()> {
+        column_names
+            .iter()
+            .map(|column_name| match self.schema.get(column_name) {
+                Some(column_info) => Ok(Column {
+                    name: column_name.clone(),
+                    data_type: column_info.field_def.config.clone(),
+                }),
+                None => {
+                    error!("Column not found: {}", column_name);
+                    Err(())
+                }
+            })
+            .collect()
+    }
+
+    /// Rows of the select, read from the snapshot as they are consumed.
+    pub fn select(self, query: query::SelectQuery) -> Result<SelectRows, ()> {
+        validate_conditions(&self.schema, &query.conditions)?;
+        self.columns(&query.columns)?;
+
+        Ok(SelectRows {
+            table: self,
+            query,
+            position: 0,
+        })
+    }
+}
+
+/// Iterates the rows of a select. Holds its snapshot, so no lock is needed while reading.
+pub struct SelectRows {
+    table: TableSnapshot,
+    query: query::SelectQuery,
+    position: usize,
+}
+
+impl Iterator for SelectRows {
+    type Item = Result<Vec<util::Val>, ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.position < self.table.rows.len() {
+            let version = self.table.rows.get(self.position);
+            self.position += 1;
+
+            if !self.table.snapshot.is_visible(version) {
+                continue;
+            }
+
+            let row = &version.data;
+            if !are_conditions_passing(row, &self.table.schema, &self.query.conditions) {
+                continue;
+            }
+
+            return Some(
+                self.query
+                    .columns
+                    .iter()
+                    .map(|column_name| extract_row_value(row, &self.table.schema[column_name]))
+                    .collect(),
+            );
+        }
+
+        None
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::thread;
 
     #[test]
     fn test_raw_string_to_val_u32() {
@@ -398,4 +1084,301 @@ mod test {
             raw_string_to_val("hello", &query::Type::Varchar(7))
         );
     }
+
+    fn table_with_modifiers() -> Table {
+        let mut id = query::FieldDef::new("id".to_owned(), query::Type::Int);
+        id.auto_increment = true;
+        let mut age = query::FieldDef::new("age".to_owned(), query::Type::Int);
+        age.default = Some("18".to_owned());
+
+        Table::new(vec![id, age], vec![])
+    }
+
+    fn raw_row(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_raw_insert_fills_default_and_auto_increment() {
+        let mut table = table_with_modifiers();
+
+        assert_eq!(Ok(Some(0)), table.raw_insert(raw_row(&[]), 0));
+        assert_eq!(Ok(Some(1)), table.raw_insert(raw_row(&[("age", "30")]), 0));
+
+        let age_info = table.schema.get("age").unwrap();
+        assert_eq!(
+            Ok(util::Val::U32(18)),
+            extract_row_value(&table.data.get(0).data, age_info)
+        );
+        assert_eq!(
+            Ok(util::Val::U32(30)),
+            extract_row_value(&table.data.get(1).data, age_info)
+        );
+
+        let id_info = table.schema.get("id").unwrap();
+        assert_eq!(
+            Ok(util::Val::U32(1)),
+            extract_row_value(&table.data.get(1).data, id_info)
+        );
+    }
+
+    #[test]
+    fn test_raw_insert_explicit_id_moves_sequence() {
+        let mut table = table_with_modifiers();
+
+        assert_eq!(Ok(None), table.raw_insert(raw_row(&[("id", "10")]), 0));
+        assert_eq!(Ok(Some(11)), table.raw_insert(raw_row(&[]), 0));
+        assert_eq!(Ok(None), table.raw_insert(raw_row(&[("id", "3")]), 0));
+        assert_eq!(Ok(Some(12)), table.raw_insert(raw_row(&[]), 0));
+    }
+
+    #[test]
+    fn test_raw_insert_fails_once_sequence_is_exhausted() {
+        let mut table = table_with_modifiers();
+
+        assert_eq!(
+            Ok(None),
+            table.raw_insert(raw_row(&[("id", "4294967295")]), 0)
+        );
+        assert_eq!(Err(Error::Failed), table.raw_insert(raw_row(&[]), 0));
+        assert_eq!(u32::MAX, table.sequence);
+        assert_eq!(1, table.data.len());
+    }
+
+    // Tests run everything in the same transaction, which sees its own writes.
+    fn snapshot() -> mvcc::Snapshot {
+        mvcc::TxManager::default().begin()
+    }
+
+    fn condition(field_name: &str, relation: &str, value: &str) -> query::FieldCondition {
+        query::FieldCondition::new(field_name.to_owned(), relation.to_owned(), value.to_owned())
+    }
+
+    fn engine_with_users_and_booking() -> Engine {
+        let engine: Engine = Default::default();
+
+        let users = query::CreateQuery::new(
+            "users".to_owned(),
+            vec![
+                query::FieldDef::new("id".to_owned(), query::Type::Int),
+                query::FieldDef::new("name".to_owned(), query::Type::Varchar(8)),
+            ],
+            vec!["id".to_owned()],
+            query::Constraints {
+                checks: vec![vec![condition("name", "<", "x")]],
+                foreign_keys: vec![],
+            },
+        );
+        assert_eq!(Ok(()), engine.create_table(users));
+
+        let booking = query::CreateQuery::new(
+            "booking".to_owned(),
+            vec![
+                query::FieldDef::new("id".to_owned(), query::Type::Int),
+                query::FieldDef::new("user_id".to_owned(), query::Type::Int),
+            ],
+            vec![],
+            query::Constraints {
+                checks: vec![],
+                foreign_keys: vec![query::ForeignKey::new(
+                    "user_id".to_owned(),
+                    "users".to_owned(),
+                    "id".to_owned(),
+                )],
+            },
+        );
+        assert_eq!(Ok(()), engine.create_table(booking));
+
+        engine
+    }
+
+    fn insert(engine: &Engine, table: &str, pairs: &[(&str, &str)]) -> Result<Option<u32>, Error> {
+        engine.insert(
+            query::InsertQuery::new(table.to_owned(), raw_row(pairs)),
+            &snapshot(),
+        )
+    }
+
+    fn delete(
+        engine: &Engine,
+        table: &str,
+        conditions: Vec<query::FieldCondition>,
+    ) -> Result<usize, Error> {
+        engine.delete(
+            query::DeleteQuery::new(table.to_owned(), conditions),
+            &snapshot(),
+        )
+    }
+
+    #[test]
+    fn test_insert_rejects_failing_check() {
+        let engine = engine_with_users_and_booking();
+
+        assert!(insert(&engine, "users", &[("id", "1"), ("name", "Steve")]).is_ok());
+        assert!(insert(&engine, "users", &[("id", "2"), ("name", "zed")]).is_err());
+        assert_eq!(1, engine.table("users").unwrap().read().unwrap().data.len());
+    }
+
+    #[test]
+    fn test_insert_rejects_invalid_indexed_value() {
+        let engine = engine_with_users_and_booking();
+
+        assert!(insert(&engine, "users", &[("id", "abc"), ("name", "Steve")]).is_err());
+        assert!(insert(&engine, "users", &[("id", "4294967296"), ("name", "Steve")]).is_err());
+        assert!(insert(&engine, "users", &[("id", "1"), ("name", "Steve")]).is_ok());
+        assert_eq!(1, engine.table("users").unwrap().read().unwrap().data.len());
+        assert_eq!(
+            Ok(1),
+            delete(&engine, "users", vec![condition("id", "=", "1")])
+        );
+    }
+
+    #[test]
+    fn test_insert_verifies_foreign_key() {
+        let engine = engine_with_users_and_booking();
+
+        assert!(insert(&engine, "users", &[("id", "1"), ("name", "Steve")]).is_ok());
+        assert!(insert(&engine, "booking", &[("id", "1"), ("user_id", "1")]).is_ok());
+        assert!(insert(&engine, "booking", &[("id", "2"), ("user_id", "2")]).is_err());
+        assert!(insert(&engine, "booking", &[("id", "3")]).is_err());
+        assert_eq!(
+            1,
+            engine.table("booking").unwrap().read().unwrap().data.len()
+        );
+    }
+
+    #[test]
+    fn test_delete_restricts_referenced_rows() {
+        let engine = engine_with_users_and_booking();
+
+        assert!(insert(&engine, "users", &[("id", "1"), ("name", "Steve")]).is_ok());
+        assert!(insert(&engine, "users", &[("id", "2"), ("name", "John")]).is_ok());
+        assert!(insert(&engine, "booking", &[("id", "1"), ("user_id", "2")]).is_ok());
+
+        assert!(delete(&engine, "users", vec![condition("id", "=", "2")]).is_err());
+        assert_eq!(
+            Ok(1),
+            delete(&engine, "users", vec![condition("id", "=", "1")])
+        );
+
+        // Index is rebuilt after the delete, so references still resolve.
+        assert!(insert(&engine, "booking", &[("id", "2"), ("user_id", "2")]).is_ok());
+        assert!(insert(&engine, "booking", &[("id", "3"), ("user_id", "1")]).is_err());
+
+        assert_eq!(Ok(2), delete(&engine, "booking", vec![]));
+        assert_eq!(Ok(1), delete(&engine, "users", vec![]));
+    }
+
+    #[test]
+    fn test_create_table_rejects_invalid_constraints() {
+        let engine = engine_with_users_and_booking();
+
+        let unindexed_reference = query::CreateQuery::new(
+            "review".to_owned(),
+            vec![query::FieldDef::new(
+                "booking_id".to_owned(),
+                query::Type::Int,
+            )],
+            vec![],
+            query::Constraints {
+                checks: vec![],
+                foreign_keys: vec![query::ForeignKey::new(
+                    "booking_id".to_owned(),
+                    "booking".to_owned(),
+                    "id".to_owned(),
+                )],
+            },
+        );
+        assert!(engine.create_table(unindexed_reference).is_err());
+
+        let unknown_check_field = query::CreateQuery::new(
+            "review".to_owned(),
+            vec![query::FieldDef::new("stars".to_owned(), query::Type::Int)],
+            vec![],
+            query::Constraints {
+                checks: vec![vec![condition("rating", "<", "6")]],
+                foreign_keys: vec![],
+            },
+        );
+        assert!(engine.create_table(unknown_check_field).is_err());
+    }
+
+    #[test]
+    fn test_primary_key_is_unique() {
+        let engine: Engine = Default::default();
+        let fields = vec![
+            query::FieldDef::new("id".to_owned(), query::Type::Int),
+            query::FieldDef::new("kind".to_owned(), query::Type::Varchar(4)),
+        ];
+        let mut events = query::CreateQuery::new(
+            "events".to_owned(),
+            fields.clone(),
+            vec![],
+            Default::default(),
+        );
+        events.storage = query::Storage::Lsm;
+        assert!(engine.create_table(events.clone()).is_err());
+        events.primary_key = Some("id".to_owned());
+        assert_eq!(Ok(()), engine.create_table(events));
+
+        assert!(insert(&engine, "events", &[("id", "258"), ("kind", "a")]).is_ok());
+        assert!(insert(&engine, "events", &[("id", "258"), ("kind", "b")]).is_err());
+        assert!(insert(&engine, "events", &[("kind", "c")]).is_err());
+        assert_eq!(Ok(1), delete(&engine, "events", vec![]));
+        assert!(insert(&engine, "events", &[("id", "258"), ("kind", "d")]).is_ok());
+
+        let table = engine.table("events").unwrap();
+        let table = table.read().unwrap();
+        let id = table.schema.get("id").unwrap();
+        let kind = table.schema.get("kind").unwrap();
+        let row = &table.data.get(1).data;
+        assert_eq!(vec![0, 0, 1, 2], id.sort_key(row));
+        assert_eq!(b"d\0\0\0".to_vec(), kind.sort_key(row));
+    }
+
+    #[test]
+    fn test_lock_tables_in_opposite_order_does_not_deadlock() {
+        let engine = Arc::new(engine_with_users_and_booking());
+
+        let threads: Vec<_> = [["users", "booking"], ["booking", "users"]]
+            .iter()
+            .map(|order| {
+                let engine = engine.clone();
+                let order = *order;
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let tables: BTreeMap<String, TableRef> = order
+                            .iter()
+                            .map(|name| (name.to_string(), engine.table(name).unwrap()))
+                            .collect();
+                        let locked = lock_tables(&tables, &order[..1]);
+                        assert_eq!(
+                            vec!["booking", "users"],
+                            locked.keys().cloned().collect::<Vec<_>>()
+                        );
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_select_on_other_table_is_not_blocked_by_writer() {
+        let engine = engine_with_users_and_booking();
+        assert!(insert(&engine, "users", &[("id", "1"), ("name", "Steve")]).is_ok());
+
+        let booking = engine.table("booking").unwrap();
+        let _writer = booking.write().unwrap();
+
+        let users = engine.table("users").unwrap();
+        assert!(users.try_read().is_ok());
+        assert!(engine.catalog().contains_key("booking"));
+    }
 }
@@ -1,53 +1,1390 @@
+use auth;
 use engine;
+use mvcc;
 use query;
-use serde_json;
-use table_sync;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use storage;
+use util;
 
-#[derive(Default, Debug)]
+/// Outcome of a statement, independent of how it is sent to the client.
+#[derive(Debug, Default, PartialEq)]
+pub struct QueryResult {
+    pub columns: Vec<engine::Column>,
+    pub rows: Vec<Vec<util::Val>>,
+    pub affected: usize,
+    pub generated_ids: Vec<u32>,
+    // Free text output, like the database description.
+    pub message: Option<String>,
+    // Handle of a statement just prepared.
+    pub statement: Option<u64>,
+}
+
+/// A result whose rows are read while they are sent, instead of being collected first.
+pub struct QueryStream {
+    // Everything but the rows.
+    pub result: QueryResult,
+    pub rows: Option<engine::SelectRows>,
+}
+
+impl QueryStream {
+    pub fn into_result(self) -> Result<QueryResult, ()> {
+        let mut result = self.result;
+        if let Some(rows) = self.rows {
+            result.rows = rows.collect::<Result<_, _>>()?;
+        }
+        Ok(result)
+    }
+}
+
+impl From<QueryResult> for QueryStream {
+    fn from(result: QueryResult) -> QueryStream {
+        QueryStream { result, rows: None }
+    }
+}
+
+// Open transaction of a session. Its row versions are only visible to itself until commit.
+#[derive(Debug)]
+struct Transaction {
+    snapshot: mvcc::Snapshot,
+    written: HashSet<String>,
+}
+
+// Open cursor, reading the rows of its select a page at a time from the snapshot it was
+// declared with.
+struct Cursor {
+    columns: Vec<engine::Column>,
+    rows: engine::SelectRows,
+    last_used: Instant,
+}
+
+// Open cursors by session and name.
+type Cursors = HashMap<(SessionKey, String), Arc<Mutex<Cursor>>>;
+
+// Session state is kept under the user that authenticated for it as well, so another user
+// naming the same session finds none of its transaction, cursors or dialect.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SessionKey {
+    user: Option<String>,
+    id: String,
+}
+
+impl SessionKey {
+    fn new(user: Option<&str>, id: &str) -> SessionKey {
+        SessionKey {
+            user: user.map(str::to_owned),
+            id: id.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.user {
+            Some(ref user) => write!(f, "{} of {}", self.id, user),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+/// How many cursors may be open on the server, and how long they stay open unused.
+#[derive(Debug, Clone, Copy)]
+pub struct CursorLimits {
+    pub max: usize,
+    pub idle_timeout: Duration,
+}
+
+impl Default for CursorLimits {
+    fn default() -> CursorLimits {
+        CursorLimits {
+            max: 64,
+            idle_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Runs queries with snapshot isolation. Selects hold a shared lock on their table only while
+/// taking their snapshot, writes hold an exclusive lock on the tables they change, and a shared
+/// one on the tables their constraints look at. Transaction bookkeeping has its own locks, never
+/// held while waiting for a table.
+#[derive(Debug)]
 pub struct EngineOperator {
     engine: engine::Engine,
-    table_syncer: table_sync::TableSyncer,
+    storage: Box<dyn storage::StorageBackend>,
+    tx_manager: Mutex<mvcc::TxManager>,
+    transactions: Mutex<HashMap<SessionKey, Transaction>>,
+    // Dialect each session chose, instead of the default of its protocol.
+    dialects: Mutex<HashMap<SessionKey, query::Dialect>>,
+    // Each cursor has its own lock, so a long fetch doesn't block the cursors of other sessions.
+    cursors: Mutex<Cursors>,
+    cursor_limits: CursorLimits,
+    // Users and grants, only checked when authentication is required.
+    catalog: RwLock<auth::Catalog>,
+    is_auth_required: bool,
+}
+
+impl fmt::Debug for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cursor [{:?}]", self.columns)
+    }
+}
+
+// Without a backend given, nothing outlives the operator.
+impl Default for EngineOperator {
+    fn default() -> EngineOperator {
+        EngineOperator {
+            engine: Default::default(),
+            storage: Box::new(storage::MemoryBackend),
+            tx_manager: Default::default(),
+            transactions: Default::default(),
+            dialects: Default::default(),
+            cursors: Default::default(),
+            cursor_limits: Default::default(),
+            catalog: Default::default(),
+            is_auth_required: false,
+        }
+    }
 }
 
 impl EngineOperator {
-    pub fn init(&mut self) -> Result<(), ()> {
-        self.engine.tables = dbg!(self.table_syncer.read_tables()?);
+    pub fn new<S: storage::StorageBackend + 'static>(storage: S) -> EngineOperator {
+        EngineOperator {
+            storage: Box::new(storage),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_cursor_limits(mut self, cursor_limits: CursorLimits) -> EngineOperator {
+        self.cursor_limits = cursor_limits;
+        self
+    }
+
+    /// Statements then need an authenticated user allowed to run them.
+    pub fn with_auth(mut self, is_auth_required: bool) -> EngineOperator {
+        self.is_auth_required = is_auth_required;
+        self
+    }
+
+    pub fn init(&self) -> Result<(), ()> {
+        let mut tables = self.storage.read_tables()?;
+        for (name, table) in &mut tables {
+            let rows = self.storage.scan(name)?.collect::<Result<Vec<_>, _>>()?;
+            table.load_rows(rows);
+        }
+        self.engine.load(tables);
+        *self.catalog.write().unwrap() = self.storage.read_catalog()?;
         Ok(())
     }
 
-    pub fn execute(&mut self, query: query::Query) -> Result<String, ()> {
+    pub fn is_auth_required(&self) -> bool {
+        self.is_auth_required
+    }
+
+    /// Creates the first user as an admin, unless there are users already.
+    pub fn ensure_admin(&self, name: &str, password: &str) -> Result<(), ()> {
+        let mut catalog = self.catalog.write().unwrap();
+        if !catalog.is_empty() {
+            return Ok(());
+        }
+
+        info!("Creating admin user: {}", name);
+        catalog.create_user(name, password, true)?;
+        self.storage.write_catalog(&catalog)
+    }
+
+    pub fn has_users(&self) -> bool {
+        !self.catalog.read().unwrap().is_empty()
+    }
+
+    /// Checks a password or a token of the user.
+    pub fn authenticate(&self, user: &str, secret: &str) -> bool {
+        self.catalog.read().unwrap().authenticate(user, secret)
+    }
+
+    pub fn token_user(&self, token: &str) -> Option<String> {
+        self.catalog.read().unwrap().token_user(token)
+    }
+
+    /// Whether the user may run the query. Anyone may when authentication isn't required.
+    pub fn authorize(&self, user: Option<&str>, query: &query::Query) -> bool {
+        if !self.is_auth_required {
+            return true;
+        }
+
+        let catalog = self.catalog.read().unwrap();
+        user.is_some_and(|user| catalog.is_allowed(user, query))
+    }
+
+    /// Runs the query in the session of the user, if any.
+    pub fn execute(
+        &self,
+        user: Option<&str>,
+        session: Option<&str>,
+        query: query::Query,
+    ) -> Result<QueryResult, engine::Error> {
         info!("Execute query");
+        let key = session.map(|session| SessionKey::new(user, session));
+        let session = key.as_ref();
+
+        match query {
+            query::Query::Transaction(q) => match session {
+                Some(session) => Ok(self.execute_transaction(session, q)?),
+                None => {
+                    warn!("Transactions need a session");
+                    Err(engine::Error::Failed)
+                }
+            },
+            query::Query::Select(q) => Ok(self.select(session, q)?.into_result()?),
+            query::Query::Describe(_) => Ok(QueryResult {
+                message: Some(self.engine.describe_db()),
+                ..Default::default()
+            }),
+            query::Query::SetDialect(dialect) => match session {
+                Some(session) => {
+                    self.dialects
+                        .lock()
+                        .unwrap()
+                        .insert(session.clone(), dialect);
+                    Ok(Default::default())
+                }
+                None => {
+                    warn!("Dialects are set for a session");
+                    Err(engine::Error::Failed)
+                }
+            },
+            query::Query::Cursor(q) => self.execute_cursor(session, q),
+            query::Query::Auth(q) => Ok(self.execute_auth(None, q)?),
+            query => self.execute_write(session, query),
+        }
+    }
 
+    /// Same as `execute`, but the rows of a select are left to be read from the stream. The
+    /// user gets the tokens it asks for, and every privilege on the tables it creates.
+    pub fn execute_streaming(
+        &self,
+        user: Option<&str>,
+        session: Option<&str>,
+        query: query::Query,
+    ) -> Result<QueryStream, engine::Error> {
         match query {
+            query::Query::Select(q) => {
+                let key = session.map(|session| SessionKey::new(user, session));
+                self.select(key.as_ref(), q)
+            }
+            query::Query::Auth(q) => Ok(self.execute_auth(user, q)?.into()),
             query::Query::Create(q) => {
-                let _ = self.engine.create_table(q);
-                self.sync_tables()?;
-                Ok("".to_owned())
+                let table_name = q.table.clone();
+                let result = self.execute(user, session, query::Query::Create(q))?;
+                if let Some(user) = user {
+                    let mut catalog = self.catalog.write().unwrap();
+                    if catalog
+                        .grant(user, &table_name, &query::Privilege::all())
+                        .is_ok()
+                    {
+                        self.storage.write_catalog(&catalog)?;
+                    }
+                }
+                Ok(result.into())
             }
-            query::Query::Select(q) => {
-                info!("Exec query {:#?}", q);
-                let res = self.engine.select(q);
+            query => self.execute(user, session, query).map(QueryStream::from),
+        }
+    }
+
+    fn select(
+        &self,
+        session: Option<&SessionKey>,
+        q: query::SelectQuery,
+    ) -> Result<QueryStream, engine::Error> {
+        info!("Exec query {:#?}", q);
+        let table = self
+            .engine
+            .table(&q.table)
+            .map_err(|_| engine::Error::UnknownTable)?;
+        let table_snapshot = {
+            // Taken under the table lock, so vacuum cannot drop rows the snapshot sees
+            // before they are copied.
+            let table = table.read().unwrap();
+            let snapshot = self.read_snapshot(session);
+            let rows = match self.read_by_key(session, &table, &q)? {
+                Some(rows) => Some(rows),
+                None => self.scan_columns(session, &table, &q)?,
+            };
+            match rows {
+                Some(rows) => table.snapshot_of_rows(rows, snapshot),
+                None => table.snapshot(snapshot),
+            }
+        };
+
+        Ok(QueryStream {
+            result: QueryResult {
+                columns: table_snapshot.columns(&q.columns)?,
+                ..Default::default()
+            },
+            rows: Some(table_snapshot.select(q)?),
+        })
+    }
+
+    // Selects of a primary key of an LSM table read the one row by its key, skipping the
+    // SSTables whose bloom filter rules it out. Like columns, it only holds committed rows.
+    fn read_by_key(
+        &self,
+        session: Option<&SessionKey>,
+        table: &engine::Table,
+        q: &query::SelectQuery,
+    ) -> Result<Option<Vec<engine::Row>>, ()> {
+        if table.storage != query::Storage::Lsm || self.transaction_snapshot(session).is_some() {
+            return Ok(None);
+        }
 
-                if res.is_ok() {
-                    let res = serde_json::to_string(&res.unwrap()).unwrap();
-                    Ok(res)
-                } else {
-                    Err(())
+        match table.primary_key_of(&q.conditions) {
+            Some(key) => Ok(Some(
+                self.storage.read_row(&q.table, &key)?.into_iter().collect(),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    // Columnar tables are read from storage, only the selected and filtered columns. It holds
+    // the committed rows while the table is locked, but not the ones a transaction wrote.
+    fn scan_columns(
+        &self,
+        session: Option<&SessionKey>,
+        table: &engine::Table,
+        q: &query::SelectQuery,
+    ) -> Result<Option<Vec<engine::Row>>, ()> {
+        if table.storage != query::Storage::Columnar || self.transaction_snapshot(session).is_some()
+        {
+            return Ok(None);
+        }
+
+        let mut columns: Vec<&str> = q.columns.iter().map(String::as_str).collect();
+        columns.extend(q.conditions.iter().map(|c| c.field_name.as_str()));
+        match self.storage.scan_columns(&q.table, &columns)? {
+            Some(rows) => rows.collect::<Result<Vec<_>, _>>().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn dialect(&self, user: Option<&str>, session: Option<&str>) -> Option<query::Dialect> {
+        let dialects = self.dialects.lock().unwrap();
+        session
+            .and_then(|session| dialects.get(&SessionKey::new(user, session)))
+            .cloned()
+    }
+
+    pub fn in_transaction(&self, user: Option<&str>, session: &str) -> bool {
+        self.transactions
+            .lock()
+            .unwrap()
+            .contains_key(&SessionKey::new(user, session))
+    }
+
+    /// Rolls back the open transaction of a session that went away.
+    pub fn end_session(&self, user: Option<&str>, session: &str) {
+        let session = SessionKey::new(user, session);
+        self.dialects.lock().unwrap().remove(&session);
+        self.cursors
+            .lock()
+            .unwrap()
+            .retain(|(owner, _), _| *owner != session);
+        let transaction = self.transactions.lock().unwrap().remove(&session);
+        if let Some(transaction) = transaction {
+            info!(
+                "Rolling back the transaction of closed session: {}",
+                session
+            );
+            self.rollback(transaction);
+        }
+    }
+
+    /// Closes the cursors left unused for longer than the idle timeout.
+    pub fn expire_cursors(&self) -> usize {
+        let mut cursors = self.cursors.lock().unwrap();
+        self.expire_idle_cursors(&mut cursors)
+    }
+
+    pub fn column_type(&self, table_name: &str, column_name: &str) -> Option<query::Type> {
+        self.engine.column_type(table_name, column_name)
+    }
+
+    /// Removes row versions no open transaction can see anymore.
+    pub fn vacuum(&self) -> usize {
+        let tx_manager = self.tx_manager.lock().unwrap().clone();
+        let snapshots: Vec<mvcc::Snapshot> = self
+            .transactions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|transaction| transaction.snapshot.clone())
+            .collect();
+
+        // A deletion is final once committed and seen by every open transaction. Transactions
+        // starting later see it as well.
+        self.engine.vacuum(|tx| {
+            !tx_manager.is_active(tx) && snapshots.iter().all(|snapshot| snapshot.sees(tx))
+        })
+    }
+
+    fn read_snapshot(&self, session: Option<&SessionKey>) -> mvcc::Snapshot {
+        match self.transaction_snapshot(session) {
+            Some(snapshot) => snapshot,
+            None => self.tx_manager.lock().unwrap().snapshot(),
+        }
+    }
+
+    fn transaction_snapshot(&self, session: Option<&SessionKey>) -> Option<mvcc::Snapshot> {
+        let transactions = self.transactions.lock().unwrap();
+        session
+            .and_then(|session| transactions.get(session))
+            .map(|transaction| transaction.snapshot.clone())
+    }
+
+    fn execute_write(
+        &self,
+        session: Option<&SessionKey>,
+        query: query::Query,
+    ) -> Result<QueryResult, engine::Error> {
+        let table_name = match target_table(&query) {
+            Some(table_name) => table_name,
+            None => return Err(engine::Error::Failed),
+        };
+
+        if let Some(snapshot) = self.transaction_snapshot(session) {
+            if let query::Query::Create(_) | query::Query::CreateIndex(_) = query {
+                warn!("Tables and indices cannot be created inside a transaction");
+                return Err(engine::Error::Failed);
+            }
+
+            let res = run_write(&self.engine, query, &snapshot)?;
+            if let Some(transaction) = self.transactions.lock().unwrap().get_mut(session.unwrap()) {
+                transaction.written.insert(table_name);
+            }
+            return Ok(res);
+        }
+
+        // Outside of a transaction every statement commits on its own.
+        let snapshot = self.tx_manager.lock().unwrap().begin();
+        let res = run_write(&self.engine, query, &snapshot);
+        if res.is_err() {
+            self.abort(snapshot.tx, &table_name);
+            self.tx_manager.lock().unwrap().end(snapshot.tx);
+            return res;
+        }
+
+        self.commit(snapshot.tx, &[&table_name[..]])?;
+        res
+    }
+
+    fn execute_transaction(
+        &self,
+        session: &SessionKey,
+        query: query::TransactionQuery,
+    ) -> Result<QueryResult, ()> {
+        match query {
+            query::TransactionQuery::Begin => {
+                let mut transactions = self.transactions.lock().unwrap();
+                if transactions.contains_key(session) {
+                    warn!("Transaction already open for session: {}", session);
+                    return Err(());
+                }
+
+                let transaction = Transaction {
+                    snapshot: self.tx_manager.lock().unwrap().begin(),
+                    written: HashSet::new(),
+                };
+                transactions.insert(session.clone(), transaction);
+                Ok(Default::default())
+            }
+            query::TransactionQuery::Commit => match self.take_transaction(session) {
+                Some(transaction) => {
+                    let written: Vec<&str> =
+                        transaction.written.iter().map(|name| &name[..]).collect();
+                    self.commit(transaction.snapshot.tx, &written)?;
+                    Ok(Default::default())
+                }
+                None => Err(()),
+            },
+            query::TransactionQuery::Rollback => match self.take_transaction(session) {
+                Some(transaction) => {
+                    self.rollback(transaction);
+                    Ok(Default::default())
+                }
+                None => Err(()),
+            },
+        }
+    }
+
+    fn take_transaction(&self, session: &SessionKey) -> Option<Transaction> {
+        let transaction = self.transactions.lock().unwrap().remove(session);
+        if transaction.is_none() {
+            warn!("No open transaction for session: {}", session);
+        }
+        transaction
+    }
+
+    fn execute_auth(&self, user: Option<&str>, q: query::AuthQuery) -> Result<QueryResult, ()> {
+        let mut catalog = self.catalog.write().unwrap();
+        let mut result = QueryResult::default();
+
+        match q {
+            query::AuthQuery::CreateUser { name, password } => {
+                catalog.create_user(&name, &password, false)?
+            }
+            query::AuthQuery::DropUser(name) => catalog.drop_user(&name)?,
+            query::AuthQuery::Grant {
+                privileges,
+                table,
+                user,
+            } => catalog.grant(&user, &table, &privileges)?,
+            query::AuthQuery::Revoke {
+                privileges,
+                table,
+                user,
+            } => catalog.revoke(&user, &table, &privileges)?,
+            query::AuthQuery::CreateToken => match user {
+                Some(user) => result.message = Some(format!("{}\n", catalog.create_token(user)?)),
+                None => {
+                    warn!("Tokens are issued to authenticated users");
+                    return Err(());
+                }
+            },
+        }
+
+        self.storage.write_catalog(&catalog)?;
+        Ok(result)
+    }
+
+    fn execute_cursor(
+        &self,
+        session: Option<&SessionKey>,
+        q: query::CursorQuery,
+    ) -> Result<QueryResult, engine::Error> {
+        let owner = match session {
+            Some(session) => session.clone(),
+            None => {
+                warn!("Cursors need a session");
+                return Err(engine::Error::Failed);
+            }
+        };
+
+        match q {
+            query::CursorQuery::Declare { name, select } => {
+                let stream = self.select(session, select)?;
+                let cursor = Cursor {
+                    columns: stream.result.columns,
+                    rows: stream.rows.ok_or(())?,
+                    last_used: Instant::now(),
+                };
+
+                let mut cursors = self.cursors.lock().unwrap();
+                self.expire_idle_cursors(&mut cursors);
+                if cursors.contains_key(&(owner.clone(), name.clone())) {
+                    error!("Cursor already open: {}", name);
+                    return Err(engine::Error::Failed);
                 }
+                if cursors.len() >= self.cursor_limits.max {
+                    warn!("Too many open cursors, at most {}", self.cursor_limits.max);
+                    return Err(engine::Error::Failed);
+                }
+                cursors.insert((owner, name), Arc::new(Mutex::new(cursor)));
+                Ok(Default::default())
+            }
+            query::CursorQuery::Fetch { name, count } => {
+                let cursor = {
+                    let mut cursors = self.cursors.lock().unwrap();
+                    self.expire_idle_cursors(&mut cursors);
+                    match cursors.get(&(owner, name.clone())) {
+                        Some(cursor) => cursor.clone(),
+                        None => {
+                            error!("Unknown cursor: {}", name);
+                            return Err(engine::Error::Failed);
+                        }
+                    }
+                };
+
+                let mut cursor = cursor.lock().unwrap();
+                cursor.last_used = Instant::now();
+                let rows = cursor.rows.by_ref().take(count).collect::<Result<_, _>>()?;
+                Ok(QueryResult {
+                    columns: cursor.columns.clone(),
+                    rows,
+                    ..Default::default()
+                })
+            }
+            query::CursorQuery::Close(name) => {
+                if self
+                    .cursors
+                    .lock()
+                    .unwrap()
+                    .remove(&(owner, name.clone()))
+                    .is_none()
+                {
+                    error!("Unknown cursor: {}", name);
+                    return Err(engine::Error::Failed);
+                }
+                Ok(Default::default())
+            }
+        }
+    }
+
+    fn expire_idle_cursors(&self, cursors: &mut Cursors) -> usize {
+        let idle_timeout = self.cursor_limits.idle_timeout;
+        let len = cursors.len();
+        // A cursor that is locked is being fetched from, so it isn't idle.
+        cursors.retain(|_, cursor| match cursor.try_lock() {
+            Ok(cursor) => cursor.last_used.elapsed() < idle_timeout,
+            Err(_) => true,
+        });
+        len - cursors.len()
+    }
+
+    fn rollback(&self, transaction: Transaction) {
+        for table_name in &transaction.written {
+            self.abort(transaction.snapshot.tx, table_name);
+        }
+        self.tx_manager.lock().unwrap().end(transaction.snapshot.tx);
+    }
+
+    // Drops the versions of the transaction from the table. The transaction must be ended after.
+    fn abort(&self, tx: mvcc::TxId, table_name: &str) {
+        if let Ok(table) = self.engine.table(table_name) {
+            table.write().unwrap().purge(tx);
+        }
+    }
+
+    // Hands what the transaction wrote to the storage, then ends it, so its writes only
+    // become visible once they are durable. The tables stay locked in between, so the storage
+    // gets the commits on a table in the order they became visible. If the storage fails, the
+    // transaction is rolled back and the tables it already reached are reverted.
+    fn commit(&self, tx: mvcc::TxId, table_names: &[&str]) -> Result<(), ()> {
+        let tables: BTreeMap<String, engine::TableRef> = table_names
+            .iter()
+            .filter_map(|name| Some(((*name).to_owned(), self.engine.table(name).ok()?)))
+            .collect();
+        let guards = engine::lock_tables(&tables, table_names);
+        let committed = self.tx_manager.lock().unwrap().commit_snapshot(tx);
+
+        let mut synced: Vec<&str> = vec![];
+        for (table_name, table) in &guards {
+            if self.sync_table(table_name, table, &committed).is_err() {
+                break;
             }
-            query::Query::Insert(q) => {
-                let _ = self.engine.insert(q);
-                self.sync_tables()?;
-                Ok("".to_owned())
+            synced.push(table_name);
+        }
+        if synced.len() == guards.len() {
+            self.tx_manager.lock().unwrap().end(tx);
+            return Ok(());
+        }
+
+        error!("Commit failed, the transaction is rolled back");
+        let snapshot = self.tx_manager.lock().unwrap().snapshot();
+        for table_name in synced {
+            let _ = self.revert_table(table_name, &guards[table_name], tx, &snapshot);
+        }
+        drop(guards);
+        for table_name in table_names {
+            self.abort(tx, table_name);
+        }
+        self.tx_manager.lock().unwrap().end(tx);
+        Err(())
+    }
+
+    // Puts the rows the snapshot sees back in the storage, before the transaction is aborted.
+    // Tables kept in key order have the rows it wrote deleted and the ones it deleted written
+    // again, the others are rewritten.
+    fn revert_table(
+        &self,
+        table_name: &str,
+        table: &engine::Table,
+        tx: mvcc::TxId,
+        snapshot: &mvcc::Snapshot,
+    ) -> Result<(), ()> {
+        if table.storage != query::Storage::Lsm {
+            let rows: Vec<&engine::Row> = table.visible_rows(snapshot).collect();
+            return self.storage.write_rows(table_name, &rows);
+        }
+
+        let mut written: Vec<&engine::Row> = vec![];
+        let mut restored: Vec<&engine::Row> = vec![];
+        for version in table.data.iter() {
+            if version.created == tx {
+                written.push(&version.data);
+            } else if version.deleted == Some(tx) {
+                restored.push(&version.data);
             }
-            query::Query::Describe(_) => Ok(self.engine.describe_db()),
         }
+        self.storage.delete_rows(table_name, &written)?;
+        self.storage.append_rows(table_name, &restored)
     }
 
-    fn sync_tables(&self) -> Result<(), ()> {
-        for (table_name, table) in &self.engine.tables {
-            self.table_syncer.create(table_name.clone(), &table)?;
+    // Rows the transaction inserted are appended. Tables kept in key order have the rows it
+    // deleted removed by key, the others are rewritten.
+    fn sync_table(
+        &self,
+        table_name: &str,
+        table: &engine::Table,
+        committed: &mvcc::Snapshot,
+    ) -> Result<(), ()> {
+        let mut inserted: Vec<&engine::Row> = vec![];
+        let mut deleted: Vec<&engine::Row> = vec![];
+        for version in table.data.iter() {
+            if version.deleted == Some(committed.tx) {
+                deleted.push(&version.data);
+            } else if version.created == committed.tx {
+                inserted.push(&version.data);
+            }
+        }
+
+        self.storage.write_table(table_name, table)?;
+        if !deleted.is_empty() && table.storage != query::Storage::Lsm {
+            let rows: Vec<&engine::Row> = table.visible_rows(committed).collect();
+            return self.storage.write_rows(table_name, &rows);
+        }
+
+        // Deletes go first, the transaction may have inserted a key it deleted.
+        if !deleted.is_empty() {
+            self.storage.delete_rows(table_name, &deleted)?;
+        }
+        if !inserted.is_empty() {
+            self.storage.append_rows(table_name, &inserted)?;
         }
         Ok(())
     }
 }
+
+fn target_table(query: &query::Query) -> Option<String> {
+    match query {
+        query::Query::Create(q) => Some(q.table.clone()),
+        query::Query::Insert(q) => Some(q.table_name.clone()),
+        query::Query::Delete(q) => Some(q.table.clone()),
+        query::Query::CreateIndex(q) => Some(q.table.clone()),
+        _ => None,
+    }
+}
+
+fn run_write(
+    engine: &engine::Engine,
+    query: query::Query,
+    snapshot: &mvcc::Snapshot,
+) -> Result<QueryResult, engine::Error> {
+    match query {
+        query::Query::Create(q) => {
+            engine.create_table(q)?;
+            Ok(Default::default())
+        }
+        query::Query::CreateIndex(q) => {
+            engine.create_index(q)?;
+            Ok(Default::default())
+        }
+        query::Query::Insert(q) => {
+            let generated_id = engine.insert(q, snapshot)?;
+            Ok(QueryResult {
+                affected: 1,
+                generated_ids: generated_id.into_iter().collect(),
+                ..Default::default()
+            })
+        }
+        query::Query::Delete(q) => Ok(QueryResult {
+            affected: engine.delete(q, snapshot)?,
+            ..Default::default()
+        }),
+        _ => Err(engine::Error::Failed),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use query_parser;
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use table_sync;
+    use temp_dir::TempDir;
+    use util::Val;
+
+    fn run(eo: &EngineOperator, session: Option<&str>, raw: &str) -> Result<QueryResult, ()> {
+        let query = query_parser::QueryParser.parse(raw)?;
+        eo.execute(None, session, query).map_err(|_| ())
+    }
+
+    fn rows(eo: &EngineOperator, session: Option<&str>, raw: &str) -> Result<Vec<Vec<Val>>, ()> {
+        run(eo, session, raw).map(|res| res.rows)
+    }
+
+    fn count_rows(eo: &EngineOperator, session: Option<&str>, table_name: &str) -> usize {
+        rows(eo, session, &format!("? pair > {}", table_name))
+            .unwrap()
+            .len()
+    }
+
+    #[test]
+    fn test_committed_rows_are_read_back_from_storage() {
+        let dir = TempDir::new("operator");
+        let open = || {
+            let eo = EngineOperator::new(table_sync::TableSyncer::new(dir.path()));
+            eo.init().unwrap();
+            eo
+        };
+
+        let eo = open();
+        assert!(run(&eo, None, "+ stored id int : id").is_ok());
+        assert!(run(&eo, None, "> stored id 1").is_ok());
+        assert!(run(&eo, None, "> stored id 2").is_ok());
+        assert!(run(&eo, Some("a"), ":begin").is_ok());
+        assert!(run(&eo, Some("a"), "> stored id 3").is_ok());
+        assert!(run(&eo, Some("b"), ":begin").is_ok());
+        assert!(run(&eo, Some("b"), "> stored id 4").is_ok());
+        assert!(run(&eo, Some("a"), ":commit").is_ok());
+        assert!(run(&eo, Some("b"), ":rollback").is_ok());
+        assert!(run(&eo, None, "- stored : id = 1").is_ok());
+
+        let eo = open();
+        let ids = rows(&eo, None, "? id > stored");
+        assert_eq!(Ok(vec![vec![Val::U32(2)], vec![Val::U32(3)]]), ids);
+        assert!(run(&eo, None, "> stored id 5").is_ok());
+        assert_eq!(3, rows(&eo, None, "? id > stored").unwrap().len());
+    }
+
+    #[test]
+    fn test_commit_fails_when_the_storage_does() {
+        let dir = TempDir::new("operator-sync");
+        let eo = EngineOperator::new(table_sync::TableSyncer::new(dir.path()));
+        eo.init().unwrap();
+        assert!(run(&eo, None, "+ synced id int : id").is_ok());
+        assert!(run(&eo, None, "> synced id 1").is_ok());
+        assert!(run(&eo, Some("a"), ":begin").is_ok());
+        assert!(run(&eo, Some("a"), "> synced id 2").is_ok());
+
+        fs::remove_dir_all(dir.path()).unwrap();
+        assert!(run(&eo, Some("a"), ":commit").is_err());
+        assert!(run(&eo, None, "> synced id 3").is_err());
+        assert_eq!(
+            Ok(vec![vec![Val::U32(1)]]),
+            rows(&eo, None, "? id > synced")
+        );
+
+        // Nothing of the failed commits is left behind, not even index entries.
+        fs::create_dir_all(dir.path()).unwrap();
+        assert!(run(&eo, None, "> synced id 2").is_ok());
+        assert!(run(&eo, None, "> synced id 3").is_ok());
+    }
+
+    #[test]
+    fn test_lsm_rows_are_read_back_in_key_order() {
+        let dir = TempDir::new("operator-lsm");
+        let open = || {
+            let eo = EngineOperator::new(table_sync::TableSyncer::new(dir.path()));
+            eo.init().unwrap();
+            eo
+        };
+
+        let eo = open();
+        assert!(run(
+            &eo,
+            None,
+            "+ keyed id int primary_key name varchar 4 using lsm"
+        )
+        .is_ok());
+        for (id, name) in &[(3, "c"), (1, "a"), (2, "b")] {
+            assert!(run(&eo, None, &format!("> keyed id {} name {}", id, name)).is_ok());
+        }
+        assert!(run(&eo, None, "> keyed id 2 name x").is_err());
+        assert!(run(&eo, None, "- keyed : id = 1").is_ok());
+        assert!(run(&eo, Some("a"), ":begin").is_ok());
+        assert!(run(&eo, Some("a"), "- keyed : id = 2").is_ok());
+        assert!(run(&eo, Some("a"), "> keyed id 2 name bb").is_ok());
+        assert!(run(&eo, Some("a"), ":commit").is_ok());
+        drop(eo);
+
+        let eo = open();
+        assert_eq!(
+            Ok(vec![
+                vec![Val::U32(2), Val::Varchar("bb".to_owned())],
+                vec![Val::U32(3), Val::Varchar("c".to_owned())],
+            ]),
+            rows(&eo, None, "? id name > keyed")
+        );
+        assert!(run(&eo, None, "> keyed id 3 name y").is_err());
+
+        // Selects of a key are read by it.
+        assert_eq!(
+            Ok(vec![vec![Val::Varchar("c".to_owned())]]),
+            rows(&eo, None, "? name > keyed : id = 3")
+        );
+        assert_eq!(Ok(vec![]), rows(&eo, None, "? name > keyed : id = 1"));
+        assert!(run(&eo, Some("b"), ":begin").is_ok());
+        assert!(run(&eo, Some("b"), "> keyed id 1 name a").is_ok());
+        assert_eq!(
+            1,
+            rows(&eo, Some("b"), "? id > keyed : id = 1").unwrap().len()
+        );
+        assert_eq!(Ok(vec![]), rows(&eo, None, "? id > keyed : id = 1"));
+    }
+
+    #[test]
+    fn test_columnar_selects_read_only_their_columns() {
+        let dir = TempDir::new("operator-col");
+        let open = || {
+            let eo = EngineOperator::new(table_sync::TableSyncer::new(dir.path()));
+            eo.init().unwrap();
+            eo
+        };
+
+        let eo = open();
+        assert!(run(
+            &eo,
+            None,
+            "+ reports id int region varchar 4 amount int using columnar"
+        )
+        .is_ok());
+        for (id, region, amount) in &[(1, "eu", 10), (2, "us", 20), (3, "eu", 30), (4, "eu", 40)] {
+            let insert = format!("> reports id {} region {} amount {}", id, region, amount);
+            assert!(run(&eo, None, &insert).is_ok());
+        }
+        assert!(run(&eo, None, "- reports : id = 3").is_ok());
+        assert!(run(&eo, Some("a"), ":begin").is_ok());
+        assert!(run(&eo, Some("a"), "> reports id 5 region eu amount 50").is_ok());
+
+        let inside = rows(&eo, Some("a"), "? amount > reports : region = eu");
+        let outside = rows(&eo, Some("b"), "? amount > reports : region = eu");
+        assert_eq!(
+            Ok(vec![
+                vec![Val::U32(10)],
+                vec![Val::U32(40)],
+                vec![Val::U32(50)]
+            ]),
+            inside
+        );
+        assert_eq!(Ok(vec![vec![Val::U32(10)], vec![Val::U32(40)]]), outside);
+        assert!(run(&eo, Some("a"), ":rollback").is_ok());
+        drop(eo);
+
+        // Only the selected and filtered columns are read.
+        let eo = open();
+        fs::remove_file(dir.path().join("reports.tdb.columns").join("id.col")).unwrap();
+        let rows = rows(&eo, None, "? region amount > reports : amount > 15");
+        assert_eq!(
+            Ok(vec![
+                vec![Val::Varchar("us".to_owned()), Val::U32(20)],
+                vec![Val::Varchar("eu".to_owned()), Val::U32(40)],
+            ]),
+            rows
+        );
+    }
+
+    #[test]
+    fn test_transaction_writes_are_visible_after_commit_only() {
+        let eo: EngineOperator = Default::default();
+        assert!(run(&eo, None, "+ tx_commit id int : id").is_ok());
+
+        assert!(run(&eo, Some("a"), ":begin").is_ok());
+        assert!(run(&eo, Some("a"), "> tx_commit id 1").is_ok());
+
+        let inside = rows(&eo, Some("a"), "? id > tx_commit");
+        let outside = rows(&eo, Some("b"), "? id > tx_commit");
+        assert_eq!(Ok(vec![vec![Val::U32(1)]]), inside);
+        assert_eq!(Ok(vec![]), outside);
+
+        assert!(run(&eo, Some("a"), ":commit").is_ok());
+        let outside = rows(&eo, Some("b"), "? id > tx_commit");
+        assert_eq!(Ok(vec![vec![Val::U32(1)]]), outside);
+    }
+
+    #[test]
+    fn test_transaction_keeps_its_snapshot() {
+        let eo: EngineOperator = Default::default();
+        assert!(run(&eo, None, "+ tx_snapshot id int").is_ok());
+
+        assert!(run(&eo, Some("a"), ":begin").is_ok());
+        assert!(run(&eo, None, "> tx_snapshot id 1").is_ok());
+
+        assert_eq!(Ok(vec![]), rows(&eo, Some("a"), "? id > tx_snapshot"));
+        assert_eq!(
+            Ok(vec![vec![Val::U32(1)]]),
+            rows(&eo, None, "? id > tx_snapshot")
+        );
+    }
+
+    #[test]
+    fn test_transaction_rollback_discards_writes_and_indices() {
+        let eo: EngineOperator = Default::default();
+        assert!(run(&eo, None, "+ tx_rb_users id int : id").is_ok());
+        assert!(run(
+            &eo,
+            None,
+            "+ tx_rb_booking user_id int references tx_rb_users(id)"
+        )
+        .is_ok());
+
+        assert!(run(&eo, Some("a"), ":begin").is_ok());
+        assert!(run(&eo, Some("a"), "> tx_rb_users id 1").is_ok());
+        assert!(run(&eo, Some("a"), "> tx_rb_booking user_id 1").is_ok());
+        assert!(run(&eo, Some("a"), ":rollback").is_ok());
+
+        assert_eq!(Ok(vec![]), rows(&eo, Some("a"), "? id > tx_rb_users"));
+        // The index entry of the rolled back row is gone too.
+        assert!(run(&eo, None, "> tx_rb_booking user_id 1").is_err());
+    }
+
+    #[test]
+    fn test_concurrent_delete_of_same_row_conflicts() {
+        let eo: EngineOperator = Default::default();
+        assert!(run(&eo, None, "+ tx_conflict id int").is_ok());
+        assert!(run(&eo, None, "> tx_conflict id 1").is_ok());
+
+        assert!(run(&eo, Some("a"), ":begin").is_ok());
+        assert!(run(&eo, Some("b"), ":begin").is_ok());
+        assert_eq!(
+            Ok(1),
+            run(&eo, Some("a"), "- tx_conflict : id = 1").map(|res| res.affected)
+        );
+        assert!(run(&eo, Some("b"), "- tx_conflict : id = 1").is_err());
+        assert!(run(&eo, Some("a"), ":commit").is_ok());
+        assert!(run(&eo, Some("b"), ":rollback").is_ok());
+
+        assert_eq!(Ok(vec![]), rows(&eo, None, "? id > tx_conflict"));
+    }
+
+    #[test]
+    fn test_create_table_inside_transaction_fails() {
+        let eo: EngineOperator = Default::default();
+
+        assert!(run(&eo, Some("a"), ":begin").is_ok());
+        assert!(run(&eo, Some("a"), "+ tx_ddl id int").is_err());
+        assert!(rows(&eo, None, "? id > tx_ddl").is_err());
+    }
+
+    #[test]
+    fn test_index_created_on_existing_rows() {
+        let eo: EngineOperator = Default::default();
+        let sql = |session: Option<&str>, raw: &str| {
+            let query = query_parser::QueryParser.parse_as(raw, query::Dialect::Sql)?;
+            eo.execute(None, session, query)
+        };
+        assert!(sql(None, "CREATE TABLE sql_users (id INT)").is_ok());
+        assert!(sql(None, "INSERT INTO sql_users (id) VALUES (1)").is_ok());
+
+        // References need an index on the referenced column.
+        let booking = "CREATE TABLE sql_booking (user_id INT REFERENCES sql_users(id))";
+        assert!(sql(None, booking).is_err());
+        assert!(sql(Some("a"), "BEGIN").is_ok());
+        assert!(sql(Some("a"), "CREATE INDEX ON sql_users (id)").is_err());
+        assert!(sql(Some("a"), "ROLLBACK").is_ok());
+        assert!(sql(None, "CREATE INDEX ON sql_users (id)").is_ok());
+        assert!(sql(None, "CREATE INDEX ON sql_users (id)").is_err());
+        assert!(sql(None, booking).is_ok());
+
+        assert!(sql(None, "INSERT INTO sql_booking (user_id) VALUES (1)").is_ok());
+        assert!(sql(None, "INSERT INTO sql_booking (user_id) VALUES (2)").is_err());
+    }
+
+    #[test]
+    fn test_streamed_select_reads_its_snapshot() {
+        let eo: EngineOperator = Default::default();
+        assert!(run(&eo, None, "+ stream_snapshot id int").is_ok());
+        assert!(run(&eo, None, "> stream_snapshot id 1").is_ok());
+
+        let query = query_parser::QueryParser
+            .parse("? id > stream_snapshot")
+            .unwrap();
+        let mut stream = eo.execute_streaming(None, None, query).unwrap();
+        assert!(stream.result.rows.is_empty());
+
+        // Writes going on while the rows are read stay invisible to them.
+        assert!(run(&eo, None, "> stream_snapshot id 2").is_ok());
+        assert!(run(&eo, None, "- stream_snapshot : id = 1").is_ok());
+        let rows: Result<Vec<_>, ()> = stream.rows.take().unwrap().collect();
+        assert_eq!(Ok(vec![vec![Val::U32(1)]]), rows);
+    }
+
+    #[test]
+    fn test_cursor_fetches_pages_of_its_snapshot() {
+        let eo: EngineOperator = Default::default();
+        assert!(run(&eo, None, "+ cursor_pages id int").is_ok());
+        for i in 0..5 {
+            assert!(run(&eo, None, &format!("> cursor_pages id {}", i)).is_ok());
+        }
+
+        assert!(run(
+            &eo,
+            Some("a"),
+            ":declare export ? id > cursor_pages : id > 0"
+        )
+        .is_ok());
+        assert!(run(&eo, None, "> cursor_pages id 5").is_ok());
+
+        let page = |n| rows(&eo, Some("a"), &format!(":fetch export {}", n));
+        assert_eq!(Ok(vec![vec![Val::U32(1)], vec![Val::U32(2)]]), page(2));
+        assert_eq!(Ok(vec![vec![Val::U32(3)], vec![Val::U32(4)]]), page(3));
+        assert_eq!(Ok(vec![]), page(3));
+
+        // Cursors belong to the session that declared them.
+        assert!(run(&eo, Some("b"), ":fetch export 1").is_err());
+        assert!(run(&eo, None, ":fetch export 1").is_err());
+        assert!(run(&eo, None, ":declare export ? id > cursor_pages").is_err());
+        assert!(run(&eo, Some("a"), ":declare export ? id > cursor_pages").is_err());
+        assert!(run(&eo, Some("a"), ":close export").is_ok());
+        assert!(run(&eo, Some("a"), ":fetch export 1").is_err());
+        assert!(run(&eo, Some("a"), ":close export").is_err());
+    }
+
+    #[test]
+    fn test_cursors_are_limited_and_expire() {
+        let eo = EngineOperator::default().with_cursor_limits(CursorLimits {
+            max: 2,
+            idle_timeout: Duration::from_millis(50),
+        });
+        assert!(run(&eo, None, "+ cursor_limits id int").is_ok());
+
+        let declare = |session, name| {
+            run(
+                &eo,
+                Some(session),
+                &format!(":declare {} ? id > cursor_limits", name),
+            )
+        };
+        assert!(declare("a", "first").is_ok());
+        assert!(declare("b", "second").is_ok());
+        assert!(declare("a", "third").is_err());
+
+        eo.end_session(None, "b");
+        assert!(declare("a", "third").is_ok());
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(2, eo.expire_cursors());
+        assert!(run(&eo, Some("a"), ":fetch first 1").is_err());
+    }
+
+    #[test]
+    fn test_dialect_is_kept_per_session() {
+        let eo: EngineOperator = Default::default();
+
+        assert!(run(&eo, None, ":dialect sql").is_err());
+        assert!(run(&eo, Some("a"), ":dialect sql").is_ok());
+        assert_eq!(Some(query::Dialect::Sql), eo.dialect(None, Some("a")));
+        assert_eq!(None, eo.dialect(None, Some("b")));
+
+        eo.end_session(None, "a");
+        assert_eq!(None, eo.dialect(None, Some("a")));
+    }
+
+    #[test]
+    fn test_session_of_another_user_is_refused() {
+        let eo: EngineOperator = Default::default();
+        assert!(run(&eo, None, "+ session_owner id int").is_ok());
+        let exec = |user, raw: &str| {
+            let query = query_parser::QueryParser.parse(raw).unwrap();
+            eo.execute(Some(user), Some("s"), query)
+        };
+        assert!(exec("alice", ":dialect sql").is_ok());
+        assert!(exec("alice", ":begin").is_ok());
+        assert!(exec("alice", "> session_owner id 1").is_ok());
+        assert!(exec("alice", ":declare export ? id > session_owner").is_ok());
+
+        // Bob naming the session of alice finds nothing of hers.
+        assert_eq!(None, eo.dialect(Some("bob"), Some("s")));
+        assert!(!eo.in_transaction(Some("bob"), "s"));
+        assert!(exec("bob", ":fetch export 1").is_err());
+        assert!(exec("bob", ":close export").is_err());
+        assert!(exec("bob", ":commit").is_err());
+        assert!(exec("bob", ":rollback").is_err());
+        assert_eq!(
+            Ok(vec![]),
+            exec("bob", "? id > session_owner").map(|res| res.rows)
+        );
+        eo.end_session(Some("bob"), "s");
+
+        assert!(eo.in_transaction(Some("alice"), "s"));
+        assert_eq!(
+            Ok(1),
+            exec("alice", ":fetch export 1").map(|res| res.rows.len())
+        );
+        assert!(exec("alice", ":commit").is_ok());
+        assert_eq!(
+            Ok(1),
+            rows(&eo, None, "? id > session_owner").map(|rows| rows.len())
+        );
+    }
+
+    #[test]
+    fn test_transaction_commands_need_open_transaction() {
+        let eo: EngineOperator = Default::default();
+
+        assert!(run(&eo, None, ":begin").is_err());
+        assert!(run(&eo, Some("a"), ":commit").is_err());
+        assert!(run(&eo, Some("a"), ":rollback").is_err());
+        assert!(run(&eo, Some("a"), ":begin").is_ok());
+        assert!(run(&eo, Some("a"), ":begin").is_err());
+    }
+
+    #[test]
+    fn test_vacuum_keeps_versions_visible_to_open_transactions() {
+        let eo: EngineOperator = Default::default();
+        assert!(run(&eo, None, "+ tx_vacuum pair int").is_ok());
+        assert!(run(&eo, None, "> tx_vacuum pair 1").is_ok());
+
+        assert!(run(&eo, Some("a"), ":begin").is_ok());
+        assert!(run(&eo, None, "- tx_vacuum : pair = 1").is_ok());
+        assert_eq!(0, eo.vacuum());
+        assert_eq!(1, count_rows(&eo, Some("a"), "tx_vacuum"));
+
+        assert!(run(&eo, Some("a"), ":commit").is_ok());
+        assert_eq!(1, eo.vacuum());
+        assert_eq!(0, count_rows(&eo, None, "tx_vacuum"));
+    }
+
+    #[test]
+    fn test_hammer_readers_never_see_partial_transactions() {
+        let table_name = "tx_hammer";
+        let eo: Arc<EngineOperator> = Default::default();
+        assert!(run(&eo, None, &format!("+ {} pair int", table_name)).is_ok());
+
+        let done = Arc::new(AtomicBool::new(false));
+        let mut writers = vec![];
+        for w in 0..4 {
+            let eo = eo.clone();
+            writers.push(thread::spawn(move || {
+                let session = format!("writer-{}", w);
+                let session = Some(session.as_str());
+                for i in 0..50 {
+                    let pair = w * 1000 + i;
+                    assert!(run(&eo, session, ":begin").is_ok());
+                    for _ in 0..2 {
+                        let insert = format!("> {} pair {}", table_name, pair);
+                        assert!(run(&eo, session, &insert).is_ok());
+                    }
+                    assert!(run(&eo, session, ":commit").is_ok());
+
+                    // Every fifth pair is deleted again, in one transaction as well.
+                    if i % 5 == 0 {
+                        assert!(run(&eo, session, ":begin").is_ok());
+                        let delete = format!("- {} : pair = {}", table_name, pair);
+                        assert_eq!(Ok(2), run(&eo, session, &delete).map(|res| res.affected));
+                        assert!(run(&eo, session, ":commit").is_ok());
+                    }
+                }
+            }));
+        }
+
+        let mut readers = vec![];
+        for _ in 0..4 {
+            let eo = eo.clone();
+            let done = done.clone();
+            readers.push(thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    let count = count_rows(&eo, None, table_name);
+                    assert!(count.is_multiple_of(2), "partial pair seen: {}", count);
+                }
+            }));
+        }
+
+        let vacuum = {
+            let eo = eo.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut vacuumed = 0;
+                while !done.load(Ordering::SeqCst) {
+                    vacuumed += eo.vacuum();
+                    thread::yield_now();
+                }
+                vacuumed
+            })
+        };
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        let vacuumed = vacuum.join().unwrap() + eo.vacuum();
+
+        assert_eq!(4 * 40 * 2, count_rows(&eo, None, table_name));
+        assert_eq!(4 * 10 * 2, vacuumed);
+    }
+
+    #[test]
+    fn test_hammer_snapshot_is_stable_inside_transaction() {
+        let table_name = "tx_hammer_stable";
+        let eo: Arc<EngineOperator> = Default::default();
+        assert!(run(&eo, None, &format!("+ {} pair int", table_name)).is_ok());
+
+        let writer = {
+            let eo = eo.clone();
+            thread::spawn(move || {
+                for i in 0..200 {
+                    let insert = format!("> {} pair {}", table_name, i);
+                    assert!(run(&eo, None, &insert).is_ok());
+                }
+            })
+        };
+
+        let mut readers = vec![];
+        for r in 0..4 {
+            let eo = eo.clone();
+            readers.push(thread::spawn(move || {
+                let session = format!("reader-{}", r);
+                let session = Some(session.as_str());
+                for _ in 0..20 {
+                    assert!(run(&eo, session, ":begin").is_ok());
+                    let first = count_rows(&eo, session, table_name);
+                    for _ in 0..5 {
+                        eo.vacuum();
+                        assert_eq!(first, count_rows(&eo, session, table_name));
+                    }
+                    assert!(run(&eo, session, ":commit").is_ok());
+                    assert!(first <= count_rows(&eo, None, table_name));
+                }
+            }));
+        }
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(200, count_rows(&eo, None, table_name));
+    }
+
+    #[test]
+    fn test_hammer_writes_across_referencing_tables() {
+        let eo: Arc<EngineOperator> = Default::default();
+        assert!(run(&eo, None, "+ tx_hammer_users id int : id").is_ok());
+        assert!(run(
+            &eo,
+            None,
+            "+ tx_hammer_booking user_id int references tx_hammer_users(id)"
+        )
+        .is_ok());
+
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let eo = eo.clone();
+                thread::spawn(move || {
+                    for i in 0..50 {
+                        let id = t * 1000 + i;
+                        let user = format!("> tx_hammer_users id {}", id);
+                        assert!(run(&eo, None, &user).is_ok());
+                        // Even users get a booking and cannot be deleted anymore.
+                        if id % 2 == 0 {
+                            let booking = format!("> tx_hammer_booking user_id {}", id);
+                            assert!(run(&eo, None, &booking).is_ok());
+                        }
+                        let delete = format!("- tx_hammer_users : id = {}", id);
+                        assert_eq!(id % 2 == 0, run(&eo, None, &delete).is_err());
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let users = rows(&eo, None, "? id > tx_hammer_users").unwrap();
+        assert_eq!(4 * 25, users.len());
+    }
+}
@@ -0,0 +1,77 @@
+//! ToyDB used in-process, without the server: a `Database` in a directory.
+//!
+//! ```no_run
+//! use toydb::query::{Relation, Type};
+//! use toydb::Database;
+//!
+//! let db = Database::open("./db/").unwrap();
+//! db.create_table("users")
+//!     .column("id", Type::Int)
+//!     .column("name", Type::Varchar(8))
+//!     .run()
+//!     .unwrap();
+//! db.insert("users").value("id", 1).value("name", "Ann").run().unwrap();
+//! let result = db
+//!     .select("users", &["name"])
+//!     .filter("id", Relation::Eq, 1)
+//!     .run()
+//!     .unwrap();
+//! let rows = db.execute("SELECT id, name FROM users").unwrap().rows;
+//! ```
+//!
+//! `dbclient::DBClient` talks to a running server instead, blocking or with futures.
+//!
+//! ```no_run
+//! use toydb::dbclient::{Credentials, DBClient};
+//!
+//! let credentials = Credentials::Token("secret".to_owned());
+//! let client = DBClient::new("localhost", 8421, Some(&credentials)).unwrap();
+//! let rows = client.query("? id name > users").unwrap().rows;
+//! ```
+
+#[macro_use]
+extern crate log;
+extern crate base64;
+extern crate futures;
+extern crate hyper;
+extern crate hyper_tls;
+extern crate native_tls;
+#[cfg(test)]
+extern crate openssl;
+extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
+extern crate tokio;
+
+// Parts of these modules are only used by the server.
+#[allow(dead_code)]
+mod auth;
+mod columnar;
+mod database;
+pub mod dbclient;
+#[allow(dead_code)]
+mod engine;
+#[allow(dead_code)]
+mod engine_operator;
+mod index;
+mod lsm;
+mod mvcc;
+pub mod query;
+#[allow(dead_code)]
+mod query_parser;
+mod sql_parser;
+mod storage;
+mod table_sync;
+#[cfg(test)]
+mod temp_dir;
+#[allow(dead_code)]
+mod tls;
+pub mod util;
+#[allow(dead_code)]
+mod wire;
+
+pub use database::{CreateTable, Database, Delete, Error, Insert, QueryResult, Select};
@@ -0,0 +1,126 @@
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How many connections a listener serves at once, and how long a connection may wait on a read
+/// or a write before it's dropped.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    pub max: usize,
+    pub timeout: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> ConnectionLimits {
+        ConnectionLimits {
+            max: 64,
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+// Counts a connection as open until it's dropped.
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Handles each connection on its own thread. Connections over the limit are closed right away.
+pub fn serve<F>(listener: TcpListener, limits: ConnectionLimits, protocol: &'static str, handle: F)
+where
+    F: Fn(TcpStream) -> io::Result<()> + Clone + Send + 'static,
+{
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Connection cannot be accepted: {}", e);
+                continue;
+            }
+        };
+        let peer = stream.peer_addr().ok();
+
+        if open.fetch_add(1, Ordering::SeqCst) >= limits.max {
+            open.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                "{} connection of {:?} refused, {} are open already",
+                protocol, peer, limits.max
+            );
+            continue;
+        }
+        let connection = OpenConnection(open.clone());
+
+        let timeout = Some(limits.timeout);
+        if let Err(e) = stream
+            .set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+        {
+            warn!("{} connection of {:?} failed: {}", protocol, peer, e);
+            continue;
+        }
+
+        let handle = handle.clone();
+        thread::spawn(move || {
+            let _connection = connection;
+            if let Err(e) = handle(stream) {
+                warn!("{} connection of {:?} failed: {}", protocol, peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_connections_over_the_limit_are_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = ConnectionLimits {
+            max: 1,
+            timeout: Duration::from_secs(5),
+        };
+        // Echoes every byte until the client closes the connection.
+        thread::spawn(move || {
+            serve(listener, limits, "Test", |mut stream| {
+                let mut buf = [0; 1];
+                while stream.read(&mut buf)? > 0 {
+                    stream.write_all(&buf)?;
+                }
+                Ok(())
+            })
+        });
+        let echoes = |stream: &mut TcpStream| {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut buf = [0; 1];
+            stream.write_all(b"a").is_ok() && matches!(stream.read(&mut buf), Ok(1))
+        };
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        assert!(echoes(&mut first));
+        let mut second = TcpStream::connect(addr).unwrap();
+        assert!(!echoes(&mut second));
+
+        // Once the first one is gone, a new connection is served.
+        drop(first);
+        let is_served = (0..50).any(|_| {
+            if echoes(&mut TcpStream::connect(addr).unwrap()) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+            false
+        });
+        assert!(is_served);
+    }
+}
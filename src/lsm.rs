@@ -0,0 +1,917 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+
+// Bytes of entries after which an SSTable block is closed. Blocks are the unit read from disk.
+const BLOCK_SIZE: usize = 4096;
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u64 = 7;
+const SSTABLE_MAGIC: u64 = 0x7464_625f_7373_7401;
+const MANIFEST: &str = "MANIFEST";
+const WAL: &str = "wal";
+
+pub type Key = Vec<u8>;
+// None is a tombstone, hiding the older values of the key.
+type Value = Option<Vec<u8>>;
+type Entry = (Key, Value);
+type Entries = Box<dyn Iterator<Item = io::Result<Entry>> + Send>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    // Bytes of keys and values the memtable holds before it's written out to level 0.
+    pub memtable_size: usize,
+    // Level 0 tables that start a compaction into level 1.
+    pub level0_tables: usize,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            memtable_size: 4 << 20,
+            level0_tables: 4,
+        }
+    }
+}
+
+/// Sorted keys and values in a directory. Writes go to a log and a memtable, which is written
+/// out to a level 0 SSTable once full. A background thread merges level 0 into level 1, where
+/// deleted keys are finally dropped. Reads merge the memtable and the levels, newest first.
+pub struct LsmTree {
+    inner: Arc<Inner>,
+    compactor: Option<thread::JoinHandle<()>>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    options: Options,
+    state: RwLock<State>,
+    // Held through a compaction, so the background one and a requested one don't overlap.
+    compacting: Mutex<()>,
+    signal: Mutex<Signal>,
+    wakeup: Condvar,
+}
+
+struct State {
+    memtable: BTreeMap<Key, Value>,
+    memtable_size: usize,
+    // Written before the memtable, replayed into it on open.
+    wal: BufWriter<File>,
+    // Level 0 newest first, its tables can overlap. Level 1 has a single table.
+    levels: [Vec<Arc<SsTable>>; 2],
+    next_id: u64,
+}
+
+#[derive(Default)]
+struct Signal {
+    is_pending: bool,
+    is_stopped: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    next_id: u64,
+    levels: [Vec<u64>; 2],
+}
+
+impl fmt::Debug for LsmTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LsmTree [{:?}]", self.inner.dir)
+    }
+}
+
+impl LsmTree {
+    /// Opens the tree in the directory, which is created if it's not there.
+    pub fn open<P: Into<PathBuf>>(dir: P, options: Options) -> Result<LsmTree, ()> {
+        let dir = dir.into();
+        let inner = Inner::open(dir.clone(), options)
+            .map_err(|e| error!("LSM tree cannot be opened {:?}: {}", dir, e))?;
+        let inner = Arc::new(inner);
+
+        let compactor = {
+            let inner = inner.clone();
+            thread::spawn(move || inner.compact_when_signaled())
+        };
+        inner.signal_if_full(&inner.state.read().unwrap());
+
+        Ok(LsmTree {
+            inner,
+            compactor: Some(compactor),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn put(&self, key: Key, value: Vec<u8>) -> Result<(), ()> {
+        self.write(vec![(key, Some(value))])
+    }
+
+    #[cfg(test)]
+    pub fn delete(&self, key: Key) -> Result<(), ()> {
+        self.write(vec![(key, None)])
+    }
+
+    /// Applies the puts (`Some`) and deletes (`None`) in order, logged as one write.
+    pub fn write(&self, batch: Vec<(Key, Option<Vec<u8>>)>) -> Result<(), ()> {
+        self.inner
+            .write(batch)
+            .map_err(|e| error!("LSM tree cannot be written {:?}: {}", self.inner.dir, e))
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, ()> {
+        let tables = {
+            let state = self.inner.state.read().unwrap();
+            if let Some(value) = state.memtable.get(key) {
+                return Ok(value.clone());
+            }
+            state.tables()
+        };
+
+        for table in tables {
+            match table.get(key) {
+                Ok(Some(value)) => return Ok(value),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("SSTable cannot be read {:?}: {}", table.path, e);
+                    return Err(());
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Keys and values between the bounds, in key order.
+    pub fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Range {
+        let start = owned_bound(start);
+        let end = owned_bound(end);
+
+        let state = self.inner.state.read().unwrap();
+        let memtable: Vec<io::Result<Entry>> = state
+            .memtable
+            .range::<[u8], _>((borrowed_bound(&start), borrowed_bound(&end)))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        let mut sources: Vec<Entries> = vec![Box::new(memtable.into_iter())];
+        for table in state.tables() {
+            sources.push(Box::new(SsTableIter::new(table, start.clone())));
+        }
+
+        Range {
+            entries: MergeIter::new(sources, end),
+            dir: self.inner.dir.clone(),
+        }
+    }
+
+    pub fn scan(&self) -> Range {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Merges level 0 into level 1 now, instead of waiting for the background thread.
+    #[cfg(test)]
+    pub fn compact(&self) -> Result<(), ()> {
+        self.inner
+            .compact(1)
+            .map_err(|e| error!("LSM tree cannot be compacted {:?}: {}", self.inner.dir, e))
+    }
+
+    /// SSTables in each level.
+    #[cfg(test)]
+    pub fn table_counts(&self) -> [usize; 2] {
+        let state = self.inner.state.read().unwrap();
+        [state.levels[0].len(), state.levels[1].len()]
+    }
+}
+
+impl Drop for LsmTree {
+    fn drop(&mut self) {
+        self.inner.signal.lock().unwrap().is_stopped = true;
+        self.inner.wakeup.notify_one();
+        if let Some(compactor) = self.compactor.take() {
+            let _ = compactor.join();
+        }
+    }
+}
+
+/// Entries of a range read. A read error ends it.
+pub struct Range {
+    entries: MergeIter,
+    dir: PathBuf,
+}
+
+impl Iterator for Range {
+    type Item = Result<(Key, Vec<u8>), ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.entries.next()? {
+            Ok((key, Some(value))) => Some(Ok((key, value))),
+            Ok((_, None)) => unreachable!("Tombstones are skipped"),
+            Err(e) => {
+                error!("LSM tree cannot be read {:?}: {}", self.dir, e);
+                Some(Err(()))
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn open(dir: PathBuf, options: Options) -> io::Result<Inner> {
+        fs::create_dir_all(&dir)?;
+
+        let manifest: Manifest = match File::open(dir.join(MANIFEST)) {
+            Ok(f) => serde_json::from_reader(BufReader::new(f))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e),
+        };
+        let mut levels: [Vec<Arc<SsTable>>; 2] = Default::default();
+        for (level, ids) in levels.iter_mut().zip(&manifest.levels) {
+            for id in ids {
+                level.push(Arc::new(SsTable::open(&dir, *id)?));
+            }
+        }
+
+        // Tables a crash left out of the manifest.
+        let ids: HashSet<u64> = manifest.levels.iter().flatten().cloned().collect();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let is_orphan = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("tmp") => true,
+                Some("sst") => path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u64>().ok())
+                    .is_none_or(|id| !ids.contains(&id)),
+                _ => false,
+            };
+            if is_orphan {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let mut memtable: BTreeMap<Key, Value> = BTreeMap::new();
+        let mut memtable_size = 0;
+        if let Ok(f) = File::open(dir.join(WAL)) {
+            let mut f = BufReader::new(f);
+            loop {
+                match read_entry(&mut f) {
+                    Ok(Some((key, value))) => {
+                        memtable_size += entry_size(&key, &value);
+                        memtable.insert(key, value);
+                    }
+                    Ok(None) => break,
+                    // The last write was cut short by a crash, it never completed.
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        let wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(WAL))?;
+
+        Ok(Inner {
+            dir,
+            options,
+            state: RwLock::new(State {
+                memtable,
+                memtable_size,
+                wal: BufWriter::new(wal),
+                levels,
+                next_id: manifest.next_id,
+            }),
+            compacting: Mutex::new(()),
+            signal: Default::default(),
+            wakeup: Condvar::new(),
+        })
+    }
+
+    fn write(&self, batch: Vec<Entry>) -> io::Result<()> {
+        let mut state = self.state.write().unwrap();
+        for (key, value) in &batch {
+            write_entry(&mut state.wal, key, value)?;
+        }
+        state.wal.flush()?;
+
+        for (key, value) in batch {
+            state.memtable_size += entry_size(&key, &value);
+            state.memtable.insert(key, value);
+        }
+        if state.memtable_size >= self.options.memtable_size {
+            self.flush(&mut state)?;
+        }
+        Ok(())
+    }
+
+    // Writes the memtable out as the newest level 0 table and starts a new log.
+    fn flush(&self, state: &mut State) -> io::Result<()> {
+        let id = state.next_id;
+        state.next_id += 1;
+        let entries = state
+            .memtable
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), value.clone())));
+        let table = SsTable::write(&self.dir, id, entries)?;
+
+        state.levels[0].insert(0, Arc::new(table));
+        self.write_manifest(state)?;
+        state.wal = BufWriter::new(File::create(self.dir.join(WAL))?);
+        state.memtable.clear();
+        state.memtable_size = 0;
+
+        self.signal_if_full(state);
+        Ok(())
+    }
+
+    fn signal_if_full(&self, state: &State) {
+        if state.levels[0].len() >= self.options.level0_tables {
+            self.signal.lock().unwrap().is_pending = true;
+            self.wakeup.notify_one();
+        }
+    }
+
+    fn compact_when_signaled(&self) {
+        loop {
+            {
+                let mut signal = self.signal.lock().unwrap();
+                while !signal.is_pending && !signal.is_stopped {
+                    signal = self.wakeup.wait(signal).unwrap();
+                }
+                if signal.is_stopped {
+                    return;
+                }
+                signal.is_pending = false;
+            }
+
+            if let Err(e) = self.compact(self.options.level0_tables) {
+                error!("LSM tree cannot be compacted {:?}: {}", self.dir, e);
+            }
+        }
+    }
+
+    // Merges level 0, once it has enough tables, with level 1 into a new level 1 table. Writes
+    // go on meanwhile, the tables flushed in between are kept in level 0.
+    fn compact(&self, level0_tables: usize) -> io::Result<()> {
+        let _compacting = self.compacting.lock().unwrap();
+        let (inputs, id) = {
+            let mut state = self.state.write().unwrap();
+            if state.levels[0].is_empty() || state.levels[0].len() < level0_tables {
+                return Ok(());
+            }
+            let id = state.next_id;
+            state.next_id += 1;
+            (state.tables(), id)
+        };
+
+        let sources: Vec<Entries> = inputs
+            .iter()
+            .map(|table| Box::new(SsTableIter::new(table.clone(), Bound::Unbounded)) as Entries)
+            .collect();
+        let merged = MergeIter::new(sources, Bound::Unbounded);
+        let table = Arc::new(SsTable::write(&self.dir, id, merged)?);
+
+        {
+            let mut state = self.state.write().unwrap();
+            let ids: HashSet<u64> = inputs.iter().map(|table| table.id).collect();
+            state.levels[0].retain(|table| !ids.contains(&table.id));
+            state.levels[1] = vec![table];
+            self.write_manifest(&state)?;
+        }
+
+        // Reads still holding the old tables keep their open files.
+        for table in inputs {
+            fs::remove_file(&table.path)?;
+        }
+        Ok(())
+    }
+
+    fn write_manifest(&self, state: &State) -> io::Result<()> {
+        let manifest = Manifest {
+            next_id: state.next_id,
+            levels: [
+                state.levels[0].iter().map(|table| table.id).collect(),
+                state.levels[1].iter().map(|table| table.id).collect(),
+            ],
+        };
+        let json = serde_json::to_vec(&manifest)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // Replaced whole, so a crash leaves either the old or the new one.
+        let tmp_path = self.dir.join(format!("{}.tmp", MANIFEST));
+        let mut f = File::create(&tmp_path)?;
+        f.write_all(&json)?;
+        f.sync_data()?;
+        fs::rename(tmp_path, self.dir.join(MANIFEST))
+    }
+}
+
+impl State {
+    // Every table, newest first.
+    fn tables(&self) -> Vec<Arc<SsTable>> {
+        self.levels.iter().flatten().cloned().collect()
+    }
+}
+
+/// A sorted, immutable file of entries: blocks of entries, then the first key and place of each
+/// block, then a bloom filter of the keys, then where those two start.
+struct SsTable {
+    id: u64,
+    path: PathBuf,
+    file: Mutex<File>,
+    // First key, offset and length of each block.
+    blocks: Vec<(Key, u64, u64)>,
+    bloom: Bloom,
+}
+
+impl SsTable {
+    fn path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("{:08}.sst", id))
+    }
+
+    fn write<I: Iterator<Item = io::Result<Entry>>>(
+        dir: &Path,
+        id: u64,
+        entries: I,
+    ) -> io::Result<SsTable> {
+        let tmp_path = dir.join(format!("{:08}.sst.tmp", id));
+        let mut f = BufWriter::new(File::create(&tmp_path)?);
+
+        let mut blocks: Vec<(Key, u64, u64)> = vec![];
+        let mut hashes: Vec<u64> = vec![];
+        let mut offset = 0_u64;
+        let mut block_start = 0_u64;
+        for entry in entries {
+            let (key, value) = entry?;
+            if blocks.is_empty() || offset - block_start >= BLOCK_SIZE as u64 {
+                if let Some(last) = blocks.last_mut() {
+                    last.2 = offset - last.1;
+                }
+                blocks.push((key.clone(), offset, 0));
+                block_start = offset;
+            }
+            hashes.push(hash(&key));
+            offset += write_entry(&mut f, &key, &value)? as u64;
+        }
+        if let Some(last) = blocks.last_mut() {
+            last.2 = offset - last.1;
+        }
+
+        let index_offset = offset;
+        write_u64(&mut f, blocks.len() as u64)?;
+        for (key, block_offset, len) in &blocks {
+            write_bytes(&mut f, key)?;
+            write_u64(&mut f, *block_offset)?;
+            write_u64(&mut f, *len)?;
+        }
+        let bloom = Bloom::from_hashes(&hashes);
+        let bloom_offset = index_offset + index_size(&blocks);
+        write_bytes(&mut f, &bloom.bits)?;
+        write_u64(&mut f, index_offset)?;
+        write_u64(&mut f, bloom_offset)?;
+        write_u64(&mut f, SSTABLE_MAGIC)?;
+
+        let f = f.into_inner().map_err(|e| e.into_error())?;
+        f.sync_data()?;
+        let path = SsTable::path(dir, id);
+        fs::rename(tmp_path, &path)?;
+
+        Ok(SsTable {
+            id,
+            file: Mutex::new(File::open(&path)?),
+            path,
+            blocks,
+            bloom,
+        })
+    }
+
+    fn open(dir: &Path, id: u64) -> io::Result<SsTable> {
+        let path = SsTable::path(dir, id);
+        let mut f = File::open(&path)?;
+        f.seek(SeekFrom::End(-24))?;
+        let index_offset = read_u64(&mut f)?;
+        let bloom_offset = read_u64(&mut f)?;
+        if read_u64(&mut f)? != SSTABLE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Not an SSTable: {:?}", path),
+            ));
+        }
+
+        f.seek(SeekFrom::Start(index_offset))?;
+        let mut reader = BufReader::new(&mut f);
+        let count = read_u64(&mut reader)?;
+        let mut blocks = vec![];
+        for _ in 0..count {
+            let key = read_bytes(&mut reader)?;
+            let block_offset = read_u64(&mut reader)?;
+            let len = read_u64(&mut reader)?;
+            blocks.push((key, block_offset, len));
+        }
+        drop(reader);
+
+        f.seek(SeekFrom::Start(bloom_offset))?;
+        let bloom = Bloom {
+            bits: read_bytes(&mut f)?,
+        };
+
+        Ok(SsTable {
+            id,
+            path,
+            file: Mutex::new(f),
+            blocks,
+            bloom,
+        })
+    }
+
+    // Some(None) is a deleted key, None a key the table doesn't have.
+    fn get(&self, key: &[u8]) -> io::Result<Option<Value>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let block = match self.block_of(key) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+
+        Ok(self
+            .read_block(block)?
+            .into_iter()
+            .find(|(entry_key, _)| &entry_key[..] == key)
+            .map(|(_, value)| value))
+    }
+
+    // The block the key would be in.
+    fn block_of(&self, key: &[u8]) -> Option<usize> {
+        let after = self
+            .blocks
+            .partition_point(|(first_key, _, _)| &first_key[..] <= key);
+        after.checked_sub(1)
+    }
+
+    fn read_block(&self, block: usize) -> io::Result<Vec<Entry>> {
+        let (_, offset, len) = self.blocks[block];
+        let mut raw = vec![0; len as usize];
+        {
+            let mut f = self.file.lock().unwrap();
+            f.seek(SeekFrom::Start(offset))?;
+            f.read_exact(&mut raw)?;
+        }
+
+        let mut raw = &raw[..];
+        let mut entries = vec![];
+        while let Some(entry) = read_entry(&mut raw)? {
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+}
+
+// Reads the blocks of a table one at a time, from the one holding the start.
+struct SsTableIter {
+    table: Arc<SsTable>,
+    start: Bound<Key>,
+    next_block: usize,
+    entries: ::std::vec::IntoIter<Entry>,
+}
+
+impl SsTableIter {
+    fn new(table: Arc<SsTable>, start: Bound<Key>) -> SsTableIter {
+        let next_block = match start {
+            Bound::Included(ref key) | Bound::Excluded(ref key) => table.block_of(key).unwrap_or(0),
+            Bound::Unbounded => 0,
+        };
+        SsTableIter {
+            table,
+            start,
+            next_block,
+            entries: vec![].into_iter(),
+        }
+    }
+}
+
+impl Iterator for SsTableIter {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, value)) = self.entries.next() {
+                let is_after_start = match self.start {
+                    Bound::Included(ref start) => key >= *start,
+                    Bound::Excluded(ref start) => key > *start,
+                    Bound::Unbounded => true,
+                };
+                if is_after_start {
+                    self.start = Bound::Unbounded;
+                    return Some(Ok((key, value)));
+                }
+                continue;
+            }
+
+            if self.next_block >= self.table.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => {
+                    self.next_block = self.table.blocks.len();
+                    return Some(Err(e));
+                }
+            }
+            self.next_block += 1;
+        }
+    }
+}
+
+// Merges sorted sources, ordered newest first. Of the entries with the same key, only the one
+// of the newest source is kept, and left out if it's a tombstone.
+struct MergeIter {
+    sources: Vec<Peekable<Entries>>,
+    end: Bound<Key>,
+}
+
+impl MergeIter {
+    fn new(sources: Vec<Entries>, end: Bound<Key>) -> MergeIter {
+        MergeIter {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+            end,
+        }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut smallest: Option<(usize, Key)> = None;
+            for (i, source) in self.sources.iter_mut().enumerate() {
+                match source.peek() {
+                    Some(Ok((key, _)))
+                        if smallest.as_ref().is_none_or(|(_, smallest)| key < smallest) =>
+                    {
+                        smallest = Some((i, key.clone()));
+                    }
+                    Some(Err(_)) => return source.next(),
+                    _ => {}
+                }
+            }
+            let (newest, key) = smallest?;
+
+            let is_before_end = match self.end {
+                Bound::Included(ref end) => key <= *end,
+                Bound::Excluded(ref end) => key < *end,
+                Bound::Unbounded => true,
+            };
+            if !is_before_end {
+                return None;
+            }
+
+            let value = match self.sources[newest].next() {
+                Some(Ok((_, value))) => value,
+                other => return other,
+            };
+            for source in &mut self.sources[newest + 1..] {
+                if let Some(Ok((older, _))) = source.peek() {
+                    if *older == key {
+                        source.next();
+                    }
+                }
+            }
+
+            if value.is_some() {
+                return Some(Ok((key, value)));
+            }
+        }
+    }
+}
+
+struct Bloom {
+    bits: Vec<u8>,
+}
+
+impl Bloom {
+    fn from_hashes(hashes: &[u64]) -> Bloom {
+        let len = cmp::max(1, (hashes.len() * BLOOM_BITS_PER_KEY).div_ceil(8));
+        let mut bloom = Bloom { bits: vec![0; len] };
+        for hash in hashes {
+            for bit in bloom.bits_of(*hash) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.bits_of(hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // Double hashing, the halves of the hash make the rest.
+    fn bits_of(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 8) as u64;
+        let (low, high) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        (0..BLOOM_HASHES).map(move |i| (low.wrapping_add(i.wrapping_mul(high)) % len) as usize)
+    }
+}
+
+// Stable across builds, as filters are kept on disk. FNV-1a, with the bits of short keys
+// spread by the finalizer of MurmurHash3.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+fn entry_size(key: &[u8], value: &Value) -> usize {
+    key.len() + value.as_ref().map_or(0, Vec::len)
+}
+
+fn index_size(blocks: &[(Key, u64, u64)]) -> u64 {
+    8 + blocks
+        .iter()
+        .map(|(key, _, _)| 8 + key.len() as u64 + 16)
+        .sum::<u64>()
+}
+
+// An entry is its key, a tag, and the value unless the tag says it was deleted.
+fn write_entry<W: Write>(out: &mut W, key: &[u8], value: &Value) -> io::Result<usize> {
+    write_bytes(out, key)?;
+    match value {
+        Some(value) => {
+            out.write_all(&[1])?;
+            write_bytes(out, value)?;
+            Ok(8 + key.len() + 1 + 8 + value.len())
+        }
+        None => {
+            out.write_all(&[0])?;
+            Ok(8 + key.len() + 1)
+        }
+    }
+}
+
+// None at the end of the input.
+fn read_entry<R: Read>(input: &mut R) -> io::Result<Option<Entry>> {
+    let mut len = [0; 8];
+    match input.read(&mut len[..1])? {
+        0 => return Ok(None),
+        _ => input.read_exact(&mut len[1..])?,
+    }
+    let mut key = vec![0; u64::from_le_bytes(len) as usize];
+    input.read_exact(&mut key)?;
+
+    let mut tag = [0];
+    input.read_exact(&mut tag)?;
+    let value = match tag[0] {
+        0 => None,
+        _ => Some(read_bytes(input)?),
+    };
+    Ok(Some((key, value)))
+}
+
+fn write_u64<W: Write>(out: &mut W, n: u64) -> io::Result<()> {
+    out.write_all(&n.to_le_bytes())
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut raw = [0; 8];
+    input.read_exact(&mut raw)?;
+    Ok(u64::from_le_bytes(raw))
+}
+
+fn write_bytes<W: Write>(out: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_u64(out, bytes.len() as u64)?;
+    out.write_all(bytes)
+}
+
+fn read_bytes<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; read_u64(input)? as usize];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn owned_bound(bound: Bound<&[u8]>) -> Bound<Key> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn borrowed_bound(bound: &Bound<Key>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(&key[..]),
+        Bound::Excluded(key) => Bound::Excluded(&key[..]),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use temp_dir::TempDir;
+
+    fn key(n: u32) -> Key {
+        n.to_be_bytes().to_vec()
+    }
+
+    fn open(dir: &Path, level0_tables: usize) -> LsmTree {
+        let options = Options {
+            memtable_size: 256,
+            level0_tables,
+        };
+        LsmTree::open(dir, options).unwrap()
+    }
+
+    fn keys(range: Range) -> Vec<u32> {
+        range
+            .map(|entry| {
+                let (key, _) = entry.unwrap();
+                u32::from_be_bytes([key[0], key[1], key[2], key[3]])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reads_are_merged_across_levels() {
+        let dir = TempDir::new("lsm");
+
+        let tree = open(dir.path(), 100);
+        for n in 0..200 {
+            tree.put(key(n), vec![1]).unwrap();
+        }
+        for n in (0..200).filter(|n| n % 3 == 0) {
+            tree.delete(key(n)).unwrap();
+        }
+        tree.put(key(3), vec![2]).unwrap();
+        assert!(tree.table_counts()[0] > 1);
+
+        let check = |tree: &LsmTree| {
+            assert_eq!(Ok(Some(vec![2])), tree.get(&key(3)));
+            assert_eq!(Ok(Some(vec![1])), tree.get(&key(100)));
+            assert_eq!(Ok(None), tree.get(&key(99)));
+            assert_eq!(Ok(None), tree.get(&key(500)));
+            assert_eq!(
+                vec![2, 3, 4, 5, 7, 8],
+                keys(tree.range(Bound::Included(&key(2)[..]), Bound::Excluded(&key(9)[..])))
+            );
+            let below_1000 = tree.range(Bound::Unbounded, Bound::Excluded(&key(1000)[..]));
+            assert_eq!(134, below_1000.count());
+        };
+        check(&tree);
+
+        tree.compact().unwrap();
+        assert_eq!([0, 1], tree.table_counts());
+        check(&tree);
+
+        // The last writes are only in the log until the memtable fills up again.
+        tree.put(key(1000), vec![3]).unwrap();
+        drop(tree);
+        let tree = open(dir.path(), 100);
+        check(&tree);
+        assert_eq!(Ok(Some(vec![3])), tree.get(&key(1000)));
+    }
+
+    #[test]
+    fn test_level0_is_compacted_in_the_background() {
+        let dir = TempDir::new("lsm-bg");
+
+        let tree = open(dir.path(), 2);
+        for n in 0..500 {
+            tree.write(vec![(key(n % 50), Some(n.to_le_bytes().to_vec()))])
+                .unwrap();
+        }
+
+        let started = Instant::now();
+        while tree.table_counts()[0] >= 2 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(1, tree.table_counts()[1]);
+        assert_eq!((0..50).collect::<Vec<u32>>(), keys(tree.scan()));
+        assert_eq!(Ok(Some(499_u32.to_le_bytes().to_vec())), tree.get(&key(49)));
+    }
+
+    #[test]
+    fn test_bloom_filter_has_no_false_negatives() {
+        let keys: Vec<Key> = (0..1000).map(key).collect();
+        let hashes: Vec<u64> = keys.iter().map(|key| hash(key)).collect();
+        let bloom = Bloom::from_hashes(&hashes);
+
+        assert!(keys.iter().all(|key| bloom.may_contain(key)));
+        let false_positives = (1000..11000)
+            .filter(|n| bloom.may_contain(&key(*n)))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
@@ -8,33 +8,53 @@ extern crate serde;
 extern crate serde_derive;
 extern crate clap;
 extern crate serde_json;
-#[macro_use]
-extern crate lazy_static;
+extern crate toml;
+extern crate base64;
+extern crate rand;
+extern crate sha2;
+extern crate native_tls;
+extern crate tokio;
+extern crate tokio_tls;
+#[cfg(test)]
+extern crate hyper_tls;
+#[cfg(test)]
+extern crate openssl;
 
+mod api;
+mod auth;
+mod columnar;
+mod config;
 mod dbserver;
 mod engine;
 mod engine_operator;
 mod index;
+mod listener;
+mod lsm;
+mod mvcc;
+mod pg_server;
 mod query;
 mod query_parser;
+mod script;
+mod sql_parser;
+mod statements;
+mod storage;
 mod table_sync;
+#[cfg(test)]
+mod temp_dir;
+// The connector is for `dbclient`, which only the library has.
+#[allow(dead_code)]
+mod tls;
 mod util;
+// The client side of the protocol is for `dbclient`, which only the library has.
+#[allow(dead_code)]
+mod wire;
+mod wire_server;
 
 use clap::{App, Arg};
-use std::cell::Cell;
-use std::sync::Mutex;
-
-lazy_static! {
-    static ref IS_VERBOSE: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
-}
-
-fn is_verbose() -> bool {
-    IS_VERBOSE.lock().unwrap().get()
-}
+use std::env;
+use std::process;
 
 fn main() {
-    env_logger::init();
-
     let matches = App::new("ToyDB")
         .version("0.1")
         .author("Peter Arato <it.arato@gmail.com>")
@@ -46,14 +66,93 @@ fn main() {
                 .help("Database dump to start with")
                 .takes_value(true),
         )
-        .arg(Arg::with_name("v").short("v").help("Verbose mode"))
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("TOML config file with host, port, binary_port, pg_port and data_dir")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .value_name("HOST")
+                .help("Address to listen on [default: 127.0.0.1]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .value_name("PORT")
+                .help("Port to listen on [default: 8421]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("binary-port")
+                .long("binary-port")
+                .value_name("PORT")
+                .help("Port of the binary protocol [default: 8422]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pg-port")
+                .long("pg-port")
+                .value_name("PORT")
+                .help("Port of the Postgres protocol [default: 5433]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .help("Directory of the table files [default: ./db/]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memory")
+                .long("memory")
+                .help("Keep the tables in memory only, nothing is written")
+                .conflicts_with("data-dir"),
+        )
+        .arg(Arg::with_name("v").short("v").help("Verbose mode, logs at debug level"))
         .get_matches();
 
-    IS_VERBOSE.lock().unwrap().set(matches.is_present("v"));
+    // RUST_LOG still wins over the verbose mode.
+    let mut logger = env_logger::Builder::from_default_env();
+    if matches.is_present("v") && env::var_os("RUST_LOG").is_none() {
+        logger.filter(None, log::LevelFilter::Debug);
+    }
+    logger.init();
+
+    // Command line options override the config file.
+    let mut config = match matches.value_of("config") {
+        Some(path) => config::Config::from_file(path).unwrap_or_else(|_| process::exit(1)),
+        None => Default::default(),
+    };
+    if let Some(host) = matches.value_of("host") {
+        config.host = host.to_owned();
+    }
+    if let Some(port) = matches.value_of("port") {
+        config.port = parse_port(port);
+    }
+    if let Some(port) = matches.value_of("binary-port") {
+        config.binary_port = parse_port(port);
+    }
+    if let Some(port) = matches.value_of("pg-port") {
+        config.pg_port = parse_port(port);
+    }
+    if let Some(data_dir) = matches.value_of("data-dir") {
+        config.data_dir = data_dir.to_owned();
+    }
+    if matches.is_present("memory") {
+        config.memory = true;
+    }
 
     info!("DB is starting");
 
-    let mut dbs: dbserver::DBServer = Default::default();
+    let mut dbs = dbserver::DBServer::new(config);
     dbs.init();
 
     if let Some(file_name) = matches.value_of("dump") {
@@ -63,3 +162,10 @@ fn main() {
 
     dbs.run();
 }
+
+fn parse_port(raw: &str) -> u16 {
+    raw.parse().unwrap_or_else(|_| {
+        eprintln!("Invalid port: {}", raw);
+        process::exit(1);
+    })
+}
@@ -0,0 +1,296 @@
+use std::collections::HashSet;
+use std::slice;
+use std::sync::Arc;
+
+pub type TxId = u64;
+
+// Transaction id of snapshots that only read.
+const NO_TX: TxId = TxId::MAX;
+/// Transaction id of the rows read from storage, committed before any other.
+pub const LOADED_TX: TxId = 0;
+
+const CHUNK_SIZE: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct RowVersion {
+    pub data: Vec<u8>,
+    pub created: TxId,
+    pub deleted: Option<TxId>,
+}
+
+impl RowVersion {
+    pub fn new(data: Vec<u8>, created: TxId) -> RowVersion {
+        RowVersion {
+            data,
+            created,
+            deleted: None,
+        }
+    }
+}
+
+/// Decides which row versions a transaction can see: its own writes, and the ones of
+/// transactions committed before it started.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub tx: TxId,
+    // Transactions starting from here are not visible.
+    xmax: TxId,
+    // Transactions in progress when the snapshot was taken.
+    active: HashSet<TxId>,
+}
+
+impl Snapshot {
+    pub fn sees(&self, tx: TxId) -> bool {
+        tx == self.tx || (tx < self.xmax && !self.active.contains(&tx))
+    }
+
+    pub fn is_visible(&self, version: &RowVersion) -> bool {
+        self.sees(version.created) && !version.deleted.is_some_and(|tx| self.sees(tx))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TxManager {
+    next: TxId,
+    active: HashSet<TxId>,
+}
+
+impl Default for TxManager {
+    fn default() -> TxManager {
+        TxManager {
+            next: LOADED_TX + 1,
+            active: HashSet::new(),
+        }
+    }
+}
+
+impl TxManager {
+    pub fn begin(&mut self) -> Snapshot {
+        let tx = self.next;
+        self.next += 1;
+
+        let snapshot = Snapshot {
+            tx,
+            xmax: tx,
+            active: self.active.clone(),
+        };
+        self.active.insert(tx);
+
+        snapshot
+    }
+
+    /// Ends a transaction. Aborted ones must have their versions purged before.
+    pub fn end(&mut self, tx: TxId) {
+        self.active.remove(&tx);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            tx: NO_TX,
+            xmax: self.next,
+            active: self.active.clone(),
+        }
+    }
+
+    /// What others will see once the transaction commits: its writes, and the ones of the
+    /// transactions committed so far.
+    pub fn commit_snapshot(&self, tx: TxId) -> Snapshot {
+        Snapshot {
+            tx,
+            xmax: self.next,
+            active: self.active.clone(),
+        }
+    }
+
+    pub fn is_active(&self, tx: TxId) -> bool {
+        self.active.contains(&tx)
+    }
+}
+
+/// Append-only row versions stored in shared chunks. Cloning only copies the chunk pointers,
+/// and a writer copies a chunk only if a clone still holds it, so readers can keep scanning a
+/// clone without locks while writes go on.
+#[derive(Debug, Clone, Default)]
+pub struct Rows {
+    chunks: Vec<Arc<Vec<RowVersion>>>,
+    // Position of the first version of each chunk, as chunks shrink when versions are removed.
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl Rows {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn push(&mut self, version: RowVersion) {
+        if self
+            .chunks
+            .last()
+            .is_none_or(|chunk| chunk.len() >= CHUNK_SIZE)
+        {
+            self.chunks.push(Arc::new(Vec::with_capacity(CHUNK_SIZE)));
+            self.starts.push(self.len);
+        }
+
+        Arc::make_mut(self.chunks.last_mut().unwrap()).push(version);
+        self.len += 1;
+    }
+
+    pub fn get(&self, position: usize) -> &RowVersion {
+        let chunk = self.chunk_of(position);
+        &self.chunks[chunk][position - self.starts[chunk]]
+    }
+
+    pub fn get_mut(&mut self, position: usize) -> &mut RowVersion {
+        let chunk = self.chunk_of(position);
+        let offset = position - self.starts[chunk];
+        &mut Arc::make_mut(&mut self.chunks[chunk])[offset]
+    }
+
+    fn chunk_of(&self, position: usize) -> usize {
+        self.starts.partition_point(|start| *start <= position) - 1
+    }
+
+    pub fn iter(&self) -> RowsIter<'_> {
+        RowsIter {
+            chunks: self.chunks.iter(),
+            current: [].iter(),
+        }
+    }
+
+    /// Keeps the versions passing the predicate, positions of the rest shift down. Only the
+    /// chunks losing versions are copied. Returns the number removed.
+    pub fn retain<F: Fn(&RowVersion) -> bool>(&mut self, predicate: F) -> usize {
+        let mut removed = 0;
+        for chunk in &mut self.chunks {
+            if chunk.iter().all(&predicate) {
+                continue;
+            }
+
+            let kept: Vec<RowVersion> = chunk.iter().filter(|v| predicate(v)).cloned().collect();
+            removed += chunk.len() - kept.len();
+            *chunk = Arc::new(kept);
+        }
+        if removed == 0 {
+            return 0;
+        }
+
+        self.chunks.retain(|chunk| !chunk.is_empty());
+        self.starts.clear();
+        self.len = 0;
+        for chunk in &self.chunks {
+            self.starts.push(self.len);
+            self.len += chunk.len();
+        }
+        removed
+    }
+}
+
+pub struct RowsIter<'a> {
+    chunks: slice::Iter<'a, Arc<Vec<RowVersion>>>,
+    current: slice::Iter<'a, RowVersion>,
+}
+
+impl<'a> Iterator for RowsIter<'a> {
+    type Item = &'a RowVersion;
+
+    fn next(&mut self) -> Option<&'a RowVersion> {
+        loop {
+            if let Some(version) = self.current.next() {
+                return Some(version);
+            }
+            self.current = self.chunks.next()?.iter();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_does_not_see_concurrent_transactions() {
+        let mut tx_manager: TxManager = Default::default();
+
+        let first = tx_manager.begin();
+        let second = tx_manager.begin();
+        tx_manager.end(first.tx);
+        let third = tx_manager.begin();
+
+        assert!(second.sees(second.tx));
+        assert!(!second.sees(first.tx));
+        assert!(!first.sees(second.tx));
+        assert!(third.sees(first.tx));
+        assert!(!third.sees(second.tx));
+        assert!(!tx_manager.snapshot().sees(second.tx));
+    }
+
+    #[test]
+    fn test_snapshot_version_visibility() {
+        let mut tx_manager: TxManager = Default::default();
+
+        let writer = tx_manager.begin();
+        let mut version = RowVersion::new(vec![], writer.tx);
+        tx_manager.end(writer.tx);
+
+        let reader = tx_manager.begin();
+        let deleter = tx_manager.begin();
+        version.deleted = Some(deleter.tx);
+
+        assert!(reader.is_visible(&version));
+        assert!(!deleter.is_visible(&version));
+
+        tx_manager.end(deleter.tx);
+        assert!(reader.is_visible(&version));
+        assert!(!tx_manager.snapshot().is_visible(&version));
+    }
+
+    #[test]
+    fn test_rows_clone_is_not_affected_by_writes() {
+        let mut rows: Rows = Default::default();
+        for i in 0..(CHUNK_SIZE + 10) {
+            rows.push(RowVersion::new(vec![i as u8], 0));
+        }
+
+        let clone = rows.clone();
+        rows.get_mut(3).deleted = Some(1);
+        rows.push(RowVersion::new(vec![], 1));
+
+        assert_eq!(CHUNK_SIZE + 11, rows.len());
+        assert_eq!(CHUNK_SIZE + 10, clone.len());
+        assert_eq!(CHUNK_SIZE + 10, clone.iter().count());
+        assert_eq!(Some(1), rows.get(3).deleted);
+        assert_eq!(None, clone.get(3).deleted);
+
+        rows.retain(|version| version.deleted.is_none());
+        assert_eq!(CHUNK_SIZE + 10, rows.len());
+        assert_eq!(vec![4u8], rows.get(3).data);
+    }
+
+    #[test]
+    fn test_rows_retain_copies_only_chunks_losing_versions() {
+        let mut rows: Rows = Default::default();
+        for i in 0..(3 * CHUNK_SIZE) {
+            rows.push(RowVersion::new(vec![(i / CHUNK_SIZE) as u8], i as TxId));
+        }
+        let clone = rows.clone();
+
+        assert_eq!(0, rows.retain(|_| true));
+        assert_eq!(
+            1,
+            rows.retain(|version| version.created != CHUNK_SIZE as TxId)
+        );
+        assert!(Arc::ptr_eq(&clone.chunks[0], &rows.chunks[0]));
+        assert!(!Arc::ptr_eq(&clone.chunks[1], &rows.chunks[1]));
+        assert!(Arc::ptr_eq(&clone.chunks[2], &rows.chunks[2]));
+
+        // Positions after the removed version shift down, new versions go to a new chunk.
+        assert_eq!(3 * CHUNK_SIZE - 1, rows.len());
+        assert_eq!(CHUNK_SIZE as TxId + 1, rows.get(CHUNK_SIZE).created);
+        assert_eq!(vec![2u8], rows.get(2 * CHUNK_SIZE - 1).data);
+        rows.push(RowVersion::new(vec![3], 0));
+        assert_eq!(vec![3u8], rows.get(3 * CHUNK_SIZE - 1).data);
+        assert_eq!(rows.len(), rows.iter().count());
+    }
+}
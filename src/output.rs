@@ -0,0 +1,317 @@
+use dbclient;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // Aligned, with a row count and the time taken.
+    Table,
+    Csv,
+    // An object per row, a line each.
+    Json,
+}
+
+impl Format {
+    pub fn from(raw: &str) -> Result<Format, ()> {
+        match &raw.to_lowercase()[..] {
+            "table" => Ok(Format::Table),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Target {
+    Stdout(io::Stdout),
+    File(BufWriter<File>),
+}
+
+impl Write for Target {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Target::Stdout(out) => out.write(buf),
+            Target::File(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Target::Stdout(out) => out.flush(),
+            Target::File(out) => out.flush(),
+        }
+    }
+}
+
+/// Writes results in the chosen format, to stdout or to a file. Errors always go to stderr.
+#[derive(Debug)]
+pub struct Renderer {
+    format: Format,
+    target: Target,
+    start: Instant,
+    is_timing: bool,
+    columns: Vec<String>,
+    // Only tables wait for all the rows, to align them.
+    rows: Vec<Vec<Value>>,
+    has_columns: bool,
+    has_failed: bool,
+}
+
+impl Default for Renderer {
+    fn default() -> Renderer {
+        Renderer {
+            format: Format::Table,
+            target: Target::Stdout(io::stdout()),
+            start: Instant::now(),
+            is_timing: true,
+            columns: vec![],
+            rows: vec![],
+            has_columns: false,
+            has_failed: false,
+        }
+    }
+}
+
+impl Renderer {
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    /// Turns showing the time taken on or off, returns whether it's on.
+    pub fn toggle_timing(&mut self) -> bool {
+        self.is_timing = !self.is_timing;
+        self.is_timing
+    }
+
+    /// Sends the results to the file, truncating it, or back to stdout.
+    pub fn set_output(&mut self, path: Option<&str>) -> Result<(), ()> {
+        let _ = self.target.flush();
+        self.target = match path {
+            Some(path) => {
+                let file = match File::create(path) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        self.has_failed = true;
+                        eprintln!("Error: {} cannot be written: {}", path, e);
+                        return Err(());
+                    }
+                };
+                Target::File(BufWriter::new(file))
+            }
+            None => Target::Stdout(io::stdout()),
+        };
+        Ok(())
+    }
+
+    /// Whether any statement failed, or the output couldn't be written.
+    pub fn has_failed(&self) -> bool {
+        self.has_failed
+    }
+
+    /// Starts the result of the next statement.
+    pub fn start(&mut self) {
+        self.start = Instant::now();
+        self.columns.clear();
+        self.rows.clear();
+        self.has_columns = false;
+    }
+
+    fn write_row(&mut self, row: &[Value]) -> io::Result<()> {
+        match self.format {
+            Format::Table => Ok(()),
+            Format::Csv => {
+                let cells: Vec<String> = row.iter().map(|v| csv_cell(&text(v))).collect();
+                writeln!(self.target, "{}", cells.join(","))
+            }
+            Format::Json => {
+                let fields: Vec<String> = self
+                    .columns
+                    .iter()
+                    .zip(row)
+                    .map(|(column, value)| format!("{}:{}", Value::from(&column[..]), value))
+                    .collect();
+                writeln!(self.target, "{{{}}}", fields.join(","))
+            }
+        }
+    }
+
+    fn write_table(&mut self) -> io::Result<()> {
+        let rows: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(text).collect())
+            .collect();
+        let mut widths: Vec<usize> = self.columns.iter().map(|c| c.chars().count()).collect();
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let separator: Vec<String> = widths.iter().map(|w| "-".repeat(w + 2)).collect();
+        let separator = format!("+{}+", separator.join("+"));
+        writeln!(self.target, "{}", separator)?;
+        let header: Vec<String> = self
+            .columns
+            .iter()
+            .zip(&widths)
+            .map(|(column, width)| format!(" {:<1$} ", column, width))
+            .collect();
+        writeln!(self.target, "|{}|", header.join("|"))?;
+        writeln!(self.target, "{}", separator)?;
+        for (row, values) in rows.iter().zip(&self.rows) {
+            // Numbers are aligned to the right.
+            let cells: Vec<String> = row
+                .iter()
+                .zip(values)
+                .zip(&widths)
+                .map(|((cell, value), width)| match value {
+                    Value::Number(_) => format!(" {:>1$} ", cell, width),
+                    _ => format!(" {:<1$} ", cell, width),
+                })
+                .collect();
+            writeln!(self.target, "|{}|", cells.join("|"))?;
+        }
+        writeln!(self.target, "{}", separator)?;
+
+        let count = match rows.len() {
+            1 => "1 row".to_owned(),
+            n => format!("{} rows", n),
+        };
+        writeln!(self.target, "({}{})", count, self.elapsed())
+    }
+
+    fn report(&mut self, res: io::Result<()>) {
+        if let Err(e) = res {
+            self.has_failed = true;
+            eprintln!("Error: Output cannot be written: {}", e);
+        }
+    }
+
+    fn elapsed(&self) -> String {
+        if self.is_timing {
+            format!(", {:.3} ms", self.start.elapsed().as_secs_f64() * 1000.0)
+        } else {
+            String::new()
+        }
+    }
+
+    fn finish(&mut self, affected: u64, message: Option<String>) -> io::Result<()> {
+        if let Some(message) = message {
+            write!(self.target, "{}", message)?;
+        } else if self.format == Format::Table {
+            if self.has_columns {
+                self.write_table()?;
+            } else {
+                writeln!(self.target, "OK, {} affected{}", affected, self.elapsed())?;
+            }
+        }
+        self.target.flush()
+    }
+}
+
+impl dbclient::ResultHandler for Renderer {
+    fn columns(&mut self, columns: Vec<String>) {
+        self.has_columns = true;
+        self.columns = columns;
+        if self.format == Format::Csv {
+            let header: Vec<String> = self.columns.iter().map(|c| csv_cell(c)).collect();
+            let line = header.join(",");
+            let res = writeln!(self.target, "{}", line);
+            self.report(res);
+        }
+    }
+
+    fn row(&mut self, row: Vec<Value>) {
+        if self.format == Format::Table {
+            self.rows.push(row);
+        } else {
+            let res = self.write_row(&row);
+            self.report(res);
+        }
+    }
+
+    fn complete(&mut self, affected: u64, message: Option<String>) {
+        let res = self.finish(affected, message);
+        self.report(res);
+    }
+
+    fn error(&mut self, message: &str) {
+        self.has_failed = true;
+        let _ = self.target.flush();
+        eprintln!("Error: {}", message);
+    }
+}
+
+// Strings without their quotes, nulls empty.
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+fn csv_cell(cell: &str) -> String {
+    if cell.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dbclient::ResultHandler;
+    use std::env;
+    use std::fs;
+
+    fn render(format: Format, path: &str) -> String {
+        let mut renderer = Renderer::default();
+        renderer.set_format(format);
+        renderer.set_output(Some(path)).unwrap();
+        renderer.start();
+        renderer.columns(vec!["id".to_owned(), "name".to_owned()]);
+        renderer.row(vec![Value::from(7), Value::from("Ann")]);
+        renderer.row(vec![Value::from(12), Value::from("Lee, \"Jr\"")]);
+        renderer.complete(0, None);
+        renderer.set_output(None).unwrap();
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn test_formats() {
+        let path = env::temp_dir().join(format!("toydb-output-{}", ::std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let table = render(Format::Table, path);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            vec![
+                "+----+-----------+",
+                "| id | name      |",
+                "+----+-----------+",
+                "|  7 | Ann       |",
+                "| 12 | Lee, \"Jr\" |",
+                "+----+-----------+",
+            ],
+            lines[..6].to_vec()
+        );
+        assert!(lines[6].starts_with("(2 rows, "));
+
+        assert_eq!(
+            "id,name\n7,Ann\n12,\"Lee, \"\"Jr\"\"\"\n",
+            render(Format::Csv, path)
+        );
+        assert_eq!(
+            "{\"id\":7,\"name\":\"Ann\"}\n{\"id\":12,\"name\":\"Lee, \\\"Jr\\\"\"}\n",
+            render(Format::Json, path)
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+}
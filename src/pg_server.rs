@@ -0,0 +1,752 @@
+use api;
+use auth;
+use dbserver;
+use engine_operator;
+use listener;
+use query;
+use query_parser;
+use script;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use util;
+
+const PROTOCOL_VERSION: i32 = 196_608;
+const SSL_REQUEST: i32 = 80_877_103;
+const GSSENC_REQUEST: i32 = 80_877_104;
+const CANCEL_REQUEST: i32 = 80_877_102;
+
+// Bigger messages are taken as garbage instead of buffered.
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+// Type OIDs of the columns. Ints are unsigned 32 bit, so only int8 holds all of them.
+const INT8_OID: i32 = 20;
+const TEXT_OID: i32 = 25;
+const VARCHAR_OID: i32 = 1043;
+
+const SQLSTATE_SYNTAX_ERROR: &str = "42601";
+const SQLSTATE_FEATURE_NOT_SUPPORTED: &str = "0A000";
+const SQLSTATE_PROTOCOL_VIOLATION: &str = "08P01";
+const SQLSTATE_INTERNAL_ERROR: &str = "XX000";
+const SQLSTATE_INVALID_AUTHORIZATION: &str = "28000";
+const SQLSTATE_INVALID_PASSWORD: &str = "28P01";
+const SQLSTATE_INSUFFICIENT_PRIVILEGE: &str = "42501";
+const SQLSTATE_UNDEFINED_TABLE: &str = "42P01";
+const SQLSTATE_INTEGRITY_CONSTRAINT_VIOLATION: &str = "23000";
+const SQLSTATE_SERIALIZATION_FAILURE: &str = "40001";
+
+// Authentication request codes.
+const AUTH_OK: i32 = 0;
+const AUTH_CLEARTEXT_PASSWORD: i32 = 3;
+
+/// Serves the simple query flow of the Postgres protocol (v3), so `psql` and other Postgres
+/// clients can run queries. When auth is required the password (or a token) is asked in
+/// cleartext, so only over loopback. The extended query protocol is refused. Every connection is
+/// a session, its open transaction is rolled back when it closes.
+#[derive(Debug, Clone)]
+pub struct PgServer {
+    engine_operator: Arc<engine_operator::EngineOperator>,
+    query_parser: Arc<query_parser::QueryParser>,
+}
+
+impl PgServer {
+    pub fn new(
+        engine_operator: Arc<engine_operator::EngineOperator>,
+        query_parser: Arc<query_parser::QueryParser>,
+    ) -> PgServer {
+        PgServer {
+            engine_operator,
+            query_parser,
+        }
+    }
+
+    pub fn serve(&self, listener: TcpListener, limits: listener::ConnectionLimits) {
+        let server = self.clone();
+        listener::serve(listener, limits, "Postgres", move |stream| {
+            server.handle_connection(stream)
+        });
+    }
+
+    fn handle_connection(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let is_loopback = stream.peer_addr()?.ip().is_loopback();
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+
+        let user = match startup(&mut reader, &mut writer)? {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        let user = if self.engine_operator.is_auth_required() {
+            if !is_loopback {
+                warn!("Credentials cannot be taken in cleartext over the network");
+                write_error(
+                    &mut writer,
+                    SQLSTATE_INVALID_AUTHORIZATION,
+                    "Password authentication is only possible over loopback",
+                )?;
+                return writer.flush();
+            }
+            if !self.authenticate(&user, &mut reader, &mut writer)? {
+                return writer.flush();
+            }
+            Some(user)
+        } else {
+            None
+        };
+
+        let session = auth::new_session_id("pg");
+        write_message(&mut writer, b'R', &AUTH_OK.to_be_bytes())?;
+        for (name, value) in &[
+            ("server_version", "9.6.0"),
+            ("server_encoding", "UTF8"),
+            ("client_encoding", "UTF8"),
+            ("DateStyle", "ISO"),
+            ("integer_datetimes", "on"),
+            ("standard_conforming_strings", "on"),
+        ] {
+            let mut body = vec![];
+            put_cstring(&mut body, name);
+            put_cstring(&mut body, value);
+            write_message(&mut writer, b'S', &body)?;
+        }
+        self.ready_for_query(&session, user.as_deref(), &mut writer)?;
+
+        let res = self.handle_messages(&session, user.as_deref(), &mut reader, &mut writer);
+        self.engine_operator.end_session(user.as_deref(), &session);
+        res
+    }
+
+    // Asks for the password in cleartext. Returns false, after answering with an error, if it
+    // isn't the user's password or one of their tokens.
+    fn authenticate(
+        &self,
+        user: &str,
+        reader: &mut BufReader<TcpStream>,
+        writer: &mut BufWriter<TcpStream>,
+    ) -> io::Result<bool> {
+        write_message(writer, b'R', &AUTH_CLEARTEXT_PASSWORD.to_be_bytes())?;
+        writer.flush()?;
+
+        match read_message(reader)? {
+            Some((b'p', body)) => {
+                if self.engine_operator.authenticate(user, &cstring(&body)?) {
+                    return Ok(true);
+                }
+                warn!("Authentication failed for {}", user);
+                write_error(
+                    writer,
+                    SQLSTATE_INVALID_PASSWORD,
+                    &format!("password authentication failed for user \"{}\"", user),
+                )?;
+            }
+            _ => write_error(writer, SQLSTATE_INVALID_AUTHORIZATION, "Missing password")?,
+        }
+        Ok(false)
+    }
+
+    fn handle_messages(
+        &self,
+        session: &str,
+        user: Option<&str>,
+        reader: &mut BufReader<TcpStream>,
+        writer: &mut BufWriter<TcpStream>,
+    ) -> io::Result<()> {
+        // Set after refusing an extended query message, until the client syncs.
+        let mut is_skipping = false;
+
+        while let Some((tag, body)) = read_message(reader)? {
+            match tag {
+                b'Q' => {
+                    let raw = cstring(&body)?;
+                    self.simple_query(session, user, &raw, writer)?;
+                    self.ready_for_query(session, user, writer)?;
+                }
+                b'P' | b'B' | b'D' | b'E' | b'C' | b'H' => {
+                    if !is_skipping {
+                        write_error(
+                            writer,
+                            SQLSTATE_FEATURE_NOT_SUPPORTED,
+                            "Only the simple query protocol is supported",
+                        )?;
+                        writer.flush()?;
+                        is_skipping = true;
+                    }
+                }
+                b'S' => {
+                    is_skipping = false;
+                    self.ready_for_query(session, user, writer)?;
+                }
+                b'X' => return Ok(()),
+                _ => {
+                    write_error(
+                        writer,
+                        SQLSTATE_PROTOCOL_VIOLATION,
+                        &format!("Unexpected message {:?}", tag as char),
+                    )?;
+                    return writer.flush();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Statements are separated by semicolons and run until the first failing one.
+    fn simple_query<W: Write>(
+        &self,
+        session: &str,
+        user: Option<&str>,
+        raw: &str,
+        w: &mut W,
+    ) -> io::Result<()> {
+        let statements = script::split_statements(raw);
+        if statements.is_empty() {
+            return write_message(w, b'I', &[]);
+        }
+
+        for statement in statements {
+            match self.execute_statement(session, user, statement) {
+                Ok((result, tag)) => {
+                    if !write_result(w, result, tag)? {
+                        return Ok(());
+                    }
+                }
+                Err(error) => {
+                    let code = match error.kind {
+                        api::ErrorKind::Parse => SQLSTATE_SYNTAX_ERROR,
+                        api::ErrorKind::Unauthenticated => SQLSTATE_INVALID_AUTHORIZATION,
+                        api::ErrorKind::PermissionDenied => SQLSTATE_INSUFFICIENT_PRIVILEGE,
+                        api::ErrorKind::UnknownTable => SQLSTATE_UNDEFINED_TABLE,
+                        api::ErrorKind::ConstraintViolation => {
+                            SQLSTATE_INTEGRITY_CONSTRAINT_VIOLATION
+                        }
+                        api::ErrorKind::Conflict => SQLSTATE_SERIALIZATION_FAILURE,
+                        _ => SQLSTATE_INTERNAL_ERROR,
+                    };
+                    return write_error(w, code, &error.message);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn execute_statement(
+        &self,
+        session: &str,
+        user: Option<&str>,
+        raw: &str,
+    ) -> Result<(engine_operator::QueryStream, &'static str), api::ErrorDetails> {
+        let dialect = self
+            .engine_operator
+            .dialect(user, Some(session))
+            .unwrap_or(query::Dialect::Sql);
+        let query = dbserver::parse_query(raw, dialect, &self.query_parser)?;
+        let tag = command_tag(&query);
+        let result =
+            dbserver::execute_query(query, Some(session), user, self.engine_operator.clone())?;
+        Ok((result, tag))
+    }
+
+    fn ready_for_query<W: Write>(
+        &self,
+        session: &str,
+        user: Option<&str>,
+        w: &mut W,
+    ) -> io::Result<()> {
+        let status = if self.engine_operator.in_transaction(user, session) {
+            b'T'
+        } else {
+            b'I'
+        };
+        write_message(w, b'Z', &[status])?;
+        w.flush()
+    }
+}
+
+// Reads the startup packet, answering SSL and GSS requests with a no. Returns the user param, or
+// None if the connection is to be closed.
+fn startup<R: Read, W: Write>(r: &mut R, w: &mut W) -> io::Result<Option<String>> {
+    loop {
+        let mut len = [0u8; 4];
+        r.read_exact(&mut len)?;
+        let len = i32::from_be_bytes(len) as usize;
+        if !(8..=MAX_MESSAGE_LEN).contains(&len) {
+            return Err(invalid_data("Invalid startup packet length"));
+        }
+
+        let mut body = vec![0u8; len - 4];
+        r.read_exact(&mut body)?;
+        let code = i32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+
+        match code {
+            SSL_REQUEST | GSSENC_REQUEST => {
+                w.write_all(b"N")?;
+                w.flush()?;
+            }
+            // Queries aren't cancellable, nothing to do.
+            CANCEL_REQUEST => return Ok(None),
+            PROTOCOL_VERSION => {
+                // Params are pairs of cstrings. The database param is accepted as it is.
+                let params: Vec<&[u8]> = body[4..].split(|&b| b == 0).collect();
+                let user = params
+                    .chunks(2)
+                    .find(|pair| pair[0] == b"user")
+                    .and_then(|pair| pair.get(1))
+                    .map(|user| String::from_utf8_lossy(user).into_owned())
+                    .unwrap_or_default();
+                return Ok(Some(user));
+            }
+            _ => {
+                write_error(
+                    w,
+                    SQLSTATE_PROTOCOL_VIOLATION,
+                    &format!("Unsupported protocol version {}", code),
+                )?;
+                w.flush()?;
+                return Ok(None);
+            }
+        }
+    }
+}
+
+fn command_tag(query: &query::Query) -> &'static str {
+    match query {
+        query::Query::Create(_) => "CREATE TABLE",
+        query::Query::Select(_) => "SELECT",
+        query::Query::Insert(_) => "INSERT",
+        query::Query::Delete(_) => "DELETE",
+        query::Query::Describe(_) => "SHOW",
+        query::Query::Transaction(query::TransactionQuery::Begin) => "BEGIN",
+        query::Query::Transaction(query::TransactionQuery::Commit) => "COMMIT",
+        query::Query::Transaction(query::TransactionQuery::Rollback) => "ROLLBACK",
+        query::Query::CreateIndex(_) => "CREATE INDEX",
+        query::Query::SetDialect(_) => "SET",
+        query::Query::Cursor(query::CursorQuery::Declare { .. }) => "DECLARE CURSOR",
+        query::Query::Cursor(query::CursorQuery::Fetch { .. }) => "FETCH",
+        query::Query::Cursor(query::CursorQuery::Close(_)) => "CLOSE CURSOR",
+        query::Query::Auth(query::AuthQuery::CreateUser { .. }) => "CREATE ROLE",
+        query::Query::Auth(query::AuthQuery::DropUser(_)) => "DROP ROLE",
+        query::Query::Auth(query::AuthQuery::Grant { .. }) => "GRANT",
+        query::Query::Auth(query::AuthQuery::Revoke { .. }) => "REVOKE",
+        query::Query::Auth(query::AuthQuery::CreateToken) => "SHOW",
+    }
+}
+
+// Data rows are written as they are read. Returns false if a row could not be read, the error
+// being written instead of the command completion.
+fn write_result<W: Write>(
+    w: &mut W,
+    stream: engine_operator::QueryStream,
+    tag: &str,
+) -> io::Result<bool> {
+    let engine_operator::QueryStream { mut result, rows } = stream;
+    // Rows already collected, like those of a fetch, come before the streamed ones.
+    let rows = mem::take(&mut result.rows)
+        .into_iter()
+        .map(Ok)
+        .chain(rows.into_iter().flatten());
+
+    // Text output, like the database description, is a single text column, a row per line.
+    if let Some(message) = result.message {
+        write_row_description(w, &[("description".to_owned(), TEXT_OID, -1, -1)])?;
+        for line in message.lines() {
+            write_data_row(w, &[line])?;
+        }
+        write_command_complete(w, tag)?;
+        return Ok(true);
+    }
+
+    let fields: Vec<(String, i32, i16, i32)> = result
+        .columns
+        .iter()
+        .map(|column| match column.data_type {
+            query::Type::Int => (column.name.clone(), INT8_OID, 8, -1),
+            // The type modifier of varchars is their size plus the 4 byte header.
+            query::Type::Varchar(n) => (column.name.clone(), VARCHAR_OID, -1, i32::from(n) + 4),
+        })
+        .collect();
+    if !fields.is_empty() {
+        write_row_description(w, &fields)?;
+    }
+    let mut count = 0;
+    for row in rows {
+        let row = match row {
+            Ok(row) => row,
+            Err(()) => {
+                write_error(w, SQLSTATE_INTERNAL_ERROR, "Row cannot be read")?;
+                return Ok(false);
+            }
+        };
+        let values: Vec<String> = row.iter().map(text_value).collect();
+        let values: Vec<&str> = values.iter().map(|v| &v[..]).collect();
+        write_data_row(w, &values)?;
+        count += 1;
+    }
+
+    match tag {
+        "SELECT" | "FETCH" => write_command_complete(w, &format!("{} {}", tag, count)),
+        "INSERT" => write_command_complete(w, &format!("INSERT 0 {}", result.affected)),
+        "DELETE" => write_command_complete(w, &format!("DELETE {}", result.affected)),
+        _ => write_command_complete(w, tag),
+    }?;
+    Ok(true)
+}
+
+fn text_value(val: &util::Val) -> String {
+    match val {
+        util::Val::U32(n) => n.to_string(),
+        util::Val::Varchar(s) => s.clone(),
+    }
+}
+
+fn write_row_description<W: Write>(
+    w: &mut W,
+    fields: &[(String, i32, i16, i32)],
+) -> io::Result<()> {
+    let mut body = vec![];
+    body.extend_from_slice(&(fields.len() as i16).to_be_bytes());
+    for (name, type_oid, type_len, type_modifier) in fields {
+        put_cstring(&mut body, name);
+        // Not a column of a real table, so no table oid and attribute number.
+        body.extend_from_slice(&0i32.to_be_bytes());
+        body.extend_from_slice(&0i16.to_be_bytes());
+        body.extend_from_slice(&type_oid.to_be_bytes());
+        body.extend_from_slice(&type_len.to_be_bytes());
+        body.extend_from_slice(&type_modifier.to_be_bytes());
+        // Text format.
+        body.extend_from_slice(&0i16.to_be_bytes());
+    }
+    write_message(w, b'T', &body)
+}
+
+fn write_data_row<W: Write>(w: &mut W, values: &[&str]) -> io::Result<()> {
+    let mut body = vec![];
+    body.extend_from_slice(&(values.len() as i16).to_be_bytes());
+    for value in values {
+        body.extend_from_slice(&(value.len() as i32).to_be_bytes());
+        body.extend_from_slice(value.as_bytes());
+    }
+    write_message(w, b'D', &body)
+}
+
+fn write_command_complete<W: Write>(w: &mut W, tag: &str) -> io::Result<()> {
+    let mut body = vec![];
+    put_cstring(&mut body, tag);
+    write_message(w, b'C', &body)
+}
+
+fn write_error<W: Write>(w: &mut W, code: &str, message: &str) -> io::Result<()> {
+    let mut body = vec![];
+    for (field, value) in &[
+        (b'S', "ERROR"),
+        (b'V', "ERROR"),
+        (b'C', code),
+        (b'M', message),
+    ] {
+        body.push(*field);
+        put_cstring(&mut body, value);
+    }
+    body.push(0);
+    write_message(w, b'E', &body)
+}
+
+fn write_message<W: Write>(w: &mut W, tag: u8, body: &[u8]) -> io::Result<()> {
+    w.write_all(&[tag])?;
+    w.write_all(&((body.len() + 4) as i32).to_be_bytes())?;
+    w.write_all(body)
+}
+
+// Reads the tag and body of the next message, or `None` if the client closed the connection.
+fn read_message<R: Read>(r: &mut R) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut tag = [0u8; 1];
+    match r.read_exact(&mut tag) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = i32::from_be_bytes(len) as usize;
+    if !(4..=MAX_MESSAGE_LEN).contains(&len) {
+        return Err(invalid_data("Invalid message length"));
+    }
+
+    let mut body = vec![0u8; len - 4];
+    r.read_exact(&mut body)?;
+    Ok(Some((tag[0], body)))
+}
+
+// Strings can't hold the NUL terminator, any inside are dropped.
+fn put_cstring(buf: &mut Vec<u8>, s: &str) {
+    buf.extend(s.bytes().filter(|b| *b != 0));
+    buf.push(0);
+}
+
+fn cstring(body: &[u8]) -> io::Result<String> {
+    let end = body
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| invalid_data("Missing string terminator"))?;
+    String::from_utf8(body[..end].to_vec()).map_err(|_| invalid_data("String is not valid UTF-8"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+    use std::thread;
+    use table_sync;
+    use temp_dir::TempDir;
+
+    // A minimal Postgres client, enough for the simple query flow.
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn connect(port: u16) -> Client {
+            let mut client = Client::startup(port, "test");
+            let messages = client.read_until_ready();
+            assert_eq!(b'R', messages[0].0);
+            client
+        }
+
+        // Sends the startup packet only.
+        fn startup(port: u16, user: &str) -> Client {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+            let mut body = PROTOCOL_VERSION.to_be_bytes().to_vec();
+            for s in &["user", user, "database", "toydb", ""] {
+                put_cstring(&mut body, s);
+            }
+            stream
+                .write_all(&((body.len() + 4) as i32).to_be_bytes())
+                .unwrap();
+            stream.write_all(&body).unwrap();
+
+            Client { stream }
+        }
+
+        // Answers the password request. Returns the messages up to the error or the ready.
+        fn authenticate(&mut self, password: &str) -> Vec<(u8, Vec<u8>)> {
+            let (tag, body) = read_message(&mut self.stream).unwrap().unwrap();
+            assert_eq!(
+                (b'R', AUTH_CLEARTEXT_PASSWORD.to_be_bytes().to_vec()),
+                (tag, body)
+            );
+
+            let mut body = vec![];
+            put_cstring(&mut body, password);
+            write_message(&mut self.stream, b'p', &body).unwrap();
+
+            let mut messages = vec![];
+            while let Some(message) = read_message(&mut self.stream).unwrap() {
+                let is_last = message.0 == b'Z' || message.0 == b'E';
+                messages.push(message);
+                if is_last {
+                    break;
+                }
+            }
+            messages
+        }
+
+        fn query(&mut self, raw: &str) -> Vec<(u8, Vec<u8>)> {
+            let mut body = vec![];
+            put_cstring(&mut body, raw);
+            write_message(&mut self.stream, b'Q', &body).unwrap();
+            self.read_until_ready()
+        }
+
+        fn read_until_ready(&mut self) -> Vec<(u8, Vec<u8>)> {
+            let mut messages = vec![];
+            loop {
+                let message = read_message(&mut self.stream).unwrap().unwrap();
+                let is_ready = message.0 == b'Z';
+                messages.push(message);
+                if is_ready {
+                    return messages;
+                }
+            }
+        }
+    }
+
+    fn start(data_dir: &Path) -> u16 {
+        start_with_auth(data_dir, false)
+    }
+
+    fn start_with_auth(data_dir: &Path, is_auth_required: bool) -> u16 {
+        let engine_operator =
+            engine_operator::EngineOperator::new(table_sync::TableSyncer::new(data_dir))
+                .with_auth(is_auth_required);
+        engine_operator.init().unwrap();
+        engine_operator.ensure_admin("admin", "secret").unwrap();
+        let server = PgServer::new(Arc::new(engine_operator), Default::default());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || server.serve(listener, Default::default()));
+        port
+    }
+
+    fn tags(messages: &[(u8, Vec<u8>)]) -> Vec<char> {
+        messages.iter().map(|(tag, _)| *tag as char).collect()
+    }
+
+    fn data_row(body: &[u8]) -> Vec<String> {
+        let count = i16::from_be_bytes([body[0], body[1]]);
+        let mut pos = 2;
+        let mut values = vec![];
+        for _ in 0..count {
+            let len = i32::from_be_bytes([body[pos], body[pos + 1], body[pos + 2], body[pos + 3]])
+                as usize;
+            pos += 4;
+            values.push(String::from_utf8(body[pos..pos + len].to_vec()).unwrap());
+            pos += len;
+        }
+        values
+    }
+
+    // Name and type oid of each field.
+    fn row_description(body: &[u8]) -> Vec<(String, i32)> {
+        let count = i16::from_be_bytes([body[0], body[1]]);
+        let mut pos = 2;
+        let mut fields = vec![];
+        for _ in 0..count {
+            let name = cstring(&body[pos..]).unwrap();
+            pos += name.len() + 1 + 6;
+            let oid = i32::from_be_bytes([body[pos], body[pos + 1], body[pos + 2], body[pos + 3]]);
+            pos += 12;
+            fields.push((name, oid));
+        }
+        fields
+    }
+
+    // The SQLSTATE code of an error response.
+    fn error_code(body: &[u8]) -> String {
+        let mut pos = 0;
+        while body[pos] != 0 {
+            let value = cstring(&body[pos + 1..]).unwrap();
+            if body[pos] == b'C' {
+                return value;
+            }
+            pos += value.len() + 2;
+        }
+        panic!("Error has no code");
+    }
+
+    #[test]
+    fn test_simple_query_flow() {
+        let dir = TempDir::new("pg");
+        let port = start(dir.path());
+        let mut client = Client::connect(port);
+
+        let messages = client.query(
+            "CREATE TABLE pg (id INT, name VARCHAR(8)); INSERT INTO pg (id, name) VALUES (1, 'Ann');",
+        );
+        assert_eq!(vec!['C', 'C', 'Z'], tags(&messages));
+        assert_eq!("CREATE TABLE", cstring(&messages[0].1).unwrap());
+        assert_eq!("INSERT 0 1", cstring(&messages[1].1).unwrap());
+
+        let messages = client.query("SELECT id, name FROM pg WHERE id = 1");
+        assert_eq!(vec!['T', 'D', 'C', 'Z'], tags(&messages));
+        assert_eq!(
+            vec![
+                ("id".to_owned(), INT8_OID),
+                ("name".to_owned(), VARCHAR_OID)
+            ],
+            row_description(&messages[0].1)
+        );
+        assert_eq!(vec!["1", "Ann"], data_row(&messages[1].1));
+        assert_eq!("SELECT 1", cstring(&messages[2].1).unwrap());
+        assert_eq!(vec![b'I'], messages[3].1);
+
+        let messages = client.query("");
+        assert_eq!(vec!['I', 'Z'], tags(&messages));
+
+        let messages = client.query("SET DIALECT tql; ? id > pg");
+        assert_eq!(vec!['C', 'T', 'D', 'C', 'Z'], tags(&messages));
+    }
+
+    #[test]
+    fn test_errors_and_transaction_status() {
+        let dir = TempDir::new("pg-errors");
+        let port = start(dir.path());
+        let mut client = Client::connect(port);
+
+        let messages = client
+            .query("CREATE TABLE pg_errors (id INT); BEGIN; INSERT INTO pg_errors (id) VALUES (1)");
+        assert_eq!(vec!['C', 'C', 'C', 'Z'], tags(&messages));
+        assert_eq!(vec![b'T'], messages[3].1);
+
+        // Statements after a failing one are skipped.
+        let messages = client.query("nonsense; ROLLBACK");
+        assert_eq!(vec!['E', 'Z'], tags(&messages));
+        assert_eq!(SQLSTATE_SYNTAX_ERROR, error_code(&messages[0].1));
+        assert_eq!(vec![b'T'], messages[1].1);
+
+        let messages = client.query("SELECT id FROM pg_missing");
+        assert_eq!(SQLSTATE_UNDEFINED_TABLE, error_code(&messages[0].1));
+
+        let messages = client.query("ROLLBACK");
+        assert_eq!(vec!['C', 'Z'], tags(&messages));
+        assert_eq!(vec![b'I'], messages[1].1);
+    }
+
+    #[test]
+    fn test_ssl_is_refused_and_extended_protocol_errors_until_sync() {
+        let dir = TempDir::new("pg-ssl");
+        let port = start(dir.path());
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(&8i32.to_be_bytes()).unwrap();
+        stream.write_all(&SSL_REQUEST.to_be_bytes()).unwrap();
+        let mut answer = [0u8; 1];
+        stream.read_exact(&mut answer).unwrap();
+        assert_eq!(b'N', answer[0]);
+        drop(stream);
+
+        let mut client = Client::connect(port);
+        write_message(&mut client.stream, b'P', &[0, b'x', 0, 0, 0]).unwrap();
+        write_message(&mut client.stream, b'B', &[]).unwrap();
+        write_message(&mut client.stream, b'S', &[]).unwrap();
+        let messages = client.read_until_ready();
+        assert_eq!(vec!['E', 'Z'], tags(&messages));
+        assert_eq!(SQLSTATE_FEATURE_NOT_SUPPORTED, error_code(&messages[0].1));
+    }
+
+    #[test]
+    fn test_password_is_asked_and_privileges_are_checked() {
+        let dir = TempDir::new("pg-auth");
+        let port = start_with_auth(dir.path(), true);
+
+        let messages = Client::startup(port, "admin").authenticate("wrong");
+        assert_eq!(vec!['E'], tags(&messages));
+        assert_eq!(SQLSTATE_INVALID_PASSWORD, error_code(&messages[0].1));
+
+        let mut admin = Client::startup(port, "admin");
+        assert_eq!('Z', *tags(&admin.authenticate("secret")).last().unwrap());
+        let messages =
+            admin.query("CREATE TABLE pg_auth (id INT); CREATE USER ann WITH PASSWORD 'pw'");
+        assert_eq!(vec!['C', 'C', 'Z'], tags(&messages));
+        assert_eq!("CREATE ROLE", cstring(&messages[1].1).unwrap());
+
+        let mut ann = Client::startup(port, "ann");
+        ann.authenticate("pw");
+        let messages = ann.query("SELECT id FROM pg_auth");
+        assert_eq!(SQLSTATE_INSUFFICIENT_PRIVILEGE, error_code(&messages[0].1));
+
+        admin.query("GRANT SELECT ON pg_auth TO ann");
+        assert_eq!(
+            vec!['T', 'C', 'Z'],
+            tags(&ann.query("SELECT id FROM pg_auth"))
+        );
+    }
+}
@@ -2,11 +2,18 @@
 use std::collections::HashMap;
 use std::fmt;
 
+#[derive(Clone)]
 pub enum Query {
     Create(CreateQuery),
     Select(SelectQuery),
     Insert(InsertQuery),
+    Delete(DeleteQuery),
     Describe(DescribeQuery),
+    Transaction(TransactionQuery),
+    CreateIndex(CreateIndexQuery),
+    SetDialect(Dialect),
+    Cursor(CursorQuery),
+    Auth(AuthQuery),
 }
 
 impl fmt::Debug for Query {
@@ -15,12 +22,18 @@ impl fmt::Debug for Query {
             Query::Create(q) => write!(f, "Create query [{:#?}]", q),
             Query::Select(q) => write!(f, "Select query [{:#?}]", q),
             Query::Insert(q) => write!(f, "Insert query [{:#?}]", q),
+            Query::Delete(q) => write!(f, "Delete query [{:#?}]", q),
             Query::Describe(q) => write!(f, "Describe [{:#?}]", q),
+            Query::Transaction(q) => write!(f, "Transaction [{:#?}]", q),
+            Query::CreateIndex(q) => write!(f, "Create index [{:#?}]", q),
+            Query::SetDialect(d) => write!(f, "Set dialect [{:#?}]", d),
+            Query::Cursor(q) => write!(f, "Cursor [{:#?}]", q),
+            Query::Auth(q) => write!(f, "Auth [{:#?}]", q),
         }
     }
 }
 
-#[derive(PartialEq, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub enum Type {
     Int,
     Varchar(u8),
@@ -28,42 +41,123 @@ pub enum Type {
 
 impl fmt::Debug for Type {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &Type::Int => write!(f, "Int"),
-            &Type::Varchar(n) => write!(f, "Varchar of size {}", n),
+        match *self {
+            Type::Int => write!(f, "Int"),
+            Type::Varchar(n) => write!(f, "Varchar of size {}", n),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FieldDef {
     pub name: String,
     pub config: Type,
+    // Raw literal used when an insert omits the field.
+    #[serde(default)]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub auto_increment: bool,
 }
 
 impl FieldDef {
     pub fn new(name: String, config: Type) -> FieldDef {
-        FieldDef { name, config }
+        FieldDef {
+            name,
+            config,
+            default: None,
+            auto_increment: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForeignKey {
+    pub field: String,
+    pub table: String,
+    pub column: String,
+}
+
+impl ForeignKey {
+    pub fn new(field: String, table: String, column: String) -> ForeignKey {
+        ForeignKey {
+            field,
+            table,
+            column,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Constraints {
+    // Each check is a list of conditions that all have to pass, same as a select.
+    pub checks: Vec<Vec<FieldCondition>>,
+    pub foreign_keys: Vec<ForeignKey>,
+}
+
+/// How the rows of a table are kept on disk, chosen when it's created.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Storage {
+    // One file of whole rows, appended to and rewritten after deletes.
+    #[default]
+    Rows,
+    // A log-structured merge-tree ordered by the primary key, for insert heavy tables.
+    Lsm,
+    // A file per column, for tables scanned a few columns at a time.
+    Columnar,
+}
+
+impl Storage {
+    pub fn from(raw: &str) -> Option<Storage> {
+        match &raw.to_lowercase()[..] {
+            "rows" => Some(Storage::Rows),
+            "lsm" => Some(Storage::Lsm),
+            "columnar" => Some(Storage::Columnar),
+            _ => None,
+        }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CreateQuery {
     pub table: String,
     pub fields: Vec<FieldDef>,
     pub indices: Vec<String>,
+    pub constraints: Constraints,
+    // Unique and indexed. LSM tables are ordered by it.
+    pub primary_key: Option<String>,
+    pub storage: Storage,
 }
 
 impl CreateQuery {
-    pub fn new(table: String, fields: Vec<FieldDef>, indices: Vec<String>) -> CreateQuery {
+    pub fn new(
+        table: String,
+        fields: Vec<FieldDef>,
+        indices: Vec<String>,
+        constraints: Constraints,
+    ) -> CreateQuery {
         CreateQuery {
             table,
             fields,
             indices,
+            constraints,
+            primary_key: None,
+            storage: Default::default(),
         }
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct CreateIndexQuery {
+    pub table: String,
+    pub field: String,
+}
+
+impl CreateIndexQuery {
+    pub fn new(table: String, field: String) -> CreateIndexQuery {
+        CreateIndexQuery { table, field }
+    }
+}
+
 pub enum Relation {
     Eq,
     Lt,
@@ -71,8 +165,8 @@ pub enum Relation {
 }
 
 impl Relation {
-    pub fn from(raw: &String) -> Option<Relation> {
-        match &raw[..] {
+    pub fn from(raw: &str) -> Option<Relation> {
+        match raw {
             "=" => Some(Relation::Eq),
             "<" => Some(Relation::Lt),
             ">" => Some(Relation::Gt),
@@ -83,15 +177,15 @@ impl Relation {
 
 impl fmt::Debug for Relation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &Relation::Eq => write!(f, "="),
-            &Relation::Lt => write!(f, "<"),
-            &Relation::Gt => write!(f, ">"),
+        match *self {
+            Relation::Eq => write!(f, "="),
+            Relation::Lt => write!(f, "<"),
+            Relation::Gt => write!(f, ">"),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FieldCondition {
     pub field_name: String,
     pub relation: String,
@@ -108,7 +202,7 @@ impl FieldCondition {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SelectQuery {
     pub table: String,
     pub columns: Vec<String>,
@@ -129,7 +223,7 @@ impl SelectQuery {
     }
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct InsertQuery {
     pub table_name: String,
     pub raw_inserts: HashMap<String, String>,
@@ -144,5 +238,194 @@ impl InsertQuery {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct DeleteQuery {
+    pub table: String,
+    pub conditions: Vec<FieldCondition>,
+}
+
+impl DeleteQuery {
+    pub fn new(table: String, conditions: Vec<FieldCondition>) -> DeleteQuery {
+        DeleteQuery { table, conditions }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct DescribeQuery;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionQuery {
+    Begin,
+    Commit,
+    Rollback,
+}
+
+/// Named cursor of a session, paging through the rows of a select.
+#[derive(Debug, Clone)]
+pub enum CursorQuery {
+    Declare { name: String, select: SelectQuery },
+    Fetch { name: String, count: usize },
+    Close(String),
+}
+
+/// What a user may do with a table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Privilege {
+    Select,
+    Insert,
+    Update,
+    Delete,
+    // Creating the table and its indices.
+    Ddl,
+}
+
+impl Privilege {
+    pub fn from(raw: &str) -> Option<Privilege> {
+        match &raw.to_lowercase()[..] {
+            "select" => Some(Privilege::Select),
+            "insert" => Some(Privilege::Insert),
+            "update" => Some(Privilege::Update),
+            "delete" => Some(Privilege::Delete),
+            "ddl" => Some(Privilege::Ddl),
+            _ => None,
+        }
+    }
+
+    pub fn all() -> Vec<Privilege> {
+        vec![
+            Privilege::Select,
+            Privilege::Insert,
+            Privilege::Update,
+            Privilege::Delete,
+            Privilege::Ddl,
+        ]
+    }
+}
+
+/// Management of users and their grants. `*` as the table stands for every table.
+#[derive(Clone)]
+pub enum AuthQuery {
+    CreateUser {
+        name: String,
+        password: String,
+    },
+    DropUser(String),
+    Grant {
+        privileges: Vec<Privilege>,
+        table: String,
+        user: String,
+    },
+    Revoke {
+        privileges: Vec<Privilege>,
+        table: String,
+        user: String,
+    },
+    // A token the current user can authenticate with instead of the password.
+    CreateToken,
+}
+
+// Passwords are left out of the logs.
+impl fmt::Debug for AuthQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthQuery::CreateUser { name, .. } => write!(f, "CreateUser [{}]", name),
+            AuthQuery::DropUser(name) => write!(f, "DropUser [{}]", name),
+            AuthQuery::Grant {
+                privileges,
+                table,
+                user,
+            } => write!(f, "Grant [{:?} on {} to {}]", privileges, table, user),
+            AuthQuery::Revoke {
+                privileges,
+                table,
+                user,
+            } => write!(f, "Revoke [{:?} on {} from {}]", privileges, table, user),
+            AuthQuery::CreateToken => write!(f, "CreateToken"),
+        }
+    }
+}
+
+/// Language of the queries: the symbolic TQL or SQL.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    Tql,
+    Sql,
+}
+
+impl Dialect {
+    pub fn from(raw: &str) -> Option<Dialect> {
+        match &raw.to_lowercase()[..] {
+            "tql" => Some(Dialect::Tql),
+            "sql" => Some(Dialect::Sql),
+            _ => None,
+        }
+    }
+}
+
+/// A value filled in when a prepared statement runs: `?1` by position, or `$name`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Placeholder {
+    Position(usize),
+    Name(String),
+}
+
+/// Where a placeholder stands: the value of an inserted field, or of the nth condition.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Slot {
+    Insert(String),
+    Condition(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct PreparedQuery {
+    pub query: Query,
+    pub placeholders: Vec<(Slot, Placeholder)>,
+}
+
+impl Query {
+    /// Table the values of the query belong to.
+    pub fn table(&self) -> Option<&str> {
+        match self {
+            Query::Create(q) => Some(&q.table),
+            Query::Select(q) => Some(&q.table),
+            Query::Insert(q) => Some(&q.table_name),
+            Query::Delete(q) => Some(&q.table),
+            Query::CreateIndex(q) => Some(&q.table),
+            Query::Cursor(CursorQuery::Declare { select, .. }) => Some(&select.table),
+            _ => None,
+        }
+    }
+
+    pub fn conditions(&self) -> &[FieldCondition] {
+        match self {
+            Query::Select(q) => &q.conditions,
+            Query::Delete(q) => &q.conditions,
+            Query::Cursor(CursorQuery::Declare { select, .. }) => &select.conditions,
+            _ => &[],
+        }
+    }
+
+    /// Field whose value is at the slot.
+    pub fn slot_field<'a>(&'a self, slot: &'a Slot) -> Option<&'a str> {
+        match slot {
+            Slot::Insert(field_name) => Some(field_name),
+            Slot::Condition(i) => self.conditions().get(*i).map(|c| &c.field_name[..]),
+        }
+    }
+
+    pub fn set_slot(&mut self, slot: &Slot, value: String) {
+        match (self, slot) {
+            (Query::Insert(q), Slot::Insert(field_name)) => {
+                q.raw_inserts.insert(field_name.clone(), value);
+            }
+            (Query::Select(q), Slot::Condition(i)) => q.conditions[*i].value = value,
+            (Query::Delete(q), Slot::Condition(i)) => q.conditions[*i].value = value,
+            (Query::Cursor(CursorQuery::Declare { select, .. }), Slot::Condition(i)) => {
+                select.conditions[*i].value = value
+            }
+            _ => {}
+        }
+    }
+}
@@ -4,30 +4,54 @@ use std::collections::HashMap;
 pub struct QueryParser;
 
 use query;
+use sql_parser;
 
 impl QueryParser {
-    pub fn looks_like_query(raw: &String) -> bool {
-        let slice: &str = &raw[..];
-        if slice.to_lowercase().starts_with("?") {
-            return true;
+    pub fn parse_as(&self, raw: &str, dialect: query::Dialect) -> Result<query::Query, ()> {
+        match dialect {
+            query::Dialect::Tql => self.parse(raw),
+            query::Dialect::Sql => sql_parser::parse(raw),
         }
-        if slice.to_lowercase().starts_with("+") {
-            return true;
-        }
-        if slice.to_lowercase().starts_with(">") {
-            return true;
+    }
+
+    #[cfg(test)]
+    pub fn prepare(&self, raw: &str) -> Result<query::PreparedQuery, ()> {
+        self.prepare_as(raw, query::Dialect::Tql)
+    }
+
+    /// Parses a query whose values can be placeholders, bound later on each execution.
+    pub fn prepare_as(
+        &self,
+        raw: &str,
+        dialect: query::Dialect,
+    ) -> Result<query::PreparedQuery, ()> {
+        let query = self.parse_as(raw, dialect)?;
+        let mut placeholders: Vec<(query::Slot, query::Placeholder)> = vec![];
+
+        if let query::Query::Insert(ref q) = query {
+            for (field_name, value) in &q.raw_inserts {
+                if let Some(placeholder) = parse_placeholder(value)? {
+                    placeholders.push((query::Slot::Insert(field_name.clone()), placeholder));
+                }
+            }
         }
-        if slice.to_lowercase().starts_with(":") {
-            return true;
+        for (i, condition) in query.conditions().iter().enumerate() {
+            if let Some(placeholder) = parse_placeholder(&condition.value)? {
+                placeholders.push((query::Slot::Condition(i), placeholder));
+            }
         }
 
-        return false;
+        validate_placeholders(&placeholders)?;
+        Ok(query::PreparedQuery {
+            query,
+            placeholders,
+        })
     }
 
-    pub fn parse(&self, raw: &String) -> Result<query::Query, ()> {
+    pub fn parse(&self, raw: &str) -> Result<query::Query, ()> {
         let mut tokens = tokenize(raw);
 
-        if tokens.len() == 0 {
+        if tokens.is_empty() {
             return Err(());
         }
 
@@ -35,17 +59,151 @@ impl QueryParser {
             "+" => parse_create_table(&mut tokens),
             "?" => parse_select(&mut tokens),
             ">" => parse_insert(&mut tokens),
+            "-" => parse_delete(&mut tokens),
             ":db" => Ok(query::Query::Describe(query::DescribeQuery)),
+            ":begin" => Ok(query::Query::Transaction(query::TransactionQuery::Begin)),
+            ":commit" => Ok(query::Query::Transaction(query::TransactionQuery::Commit)),
+            ":rollback" => Ok(query::Query::Transaction(query::TransactionQuery::Rollback)),
+            ":dialect" if tokens.len() == 2 => match query::Dialect::from(tokens[1]) {
+                Some(dialect) => Ok(query::Query::SetDialect(dialect)),
+                None => {
+                    error!("Unknown dialect: {}", tokens[1]);
+                    Err(())
+                }
+            },
+            ":declare" if tokens.len() >= 6 && tokens[2] == "?" => {
+                let name = tokens[1].to_owned();
+                match parse_select(&mut tokens.split_off(2))? {
+                    query::Query::Select(select) => {
+                        Ok(query::Query::Cursor(query::CursorQuery::Declare {
+                            name,
+                            select,
+                        }))
+                    }
+                    _ => Err(()),
+                }
+            }
+            ":fetch" if tokens.len() == 3 => match tokens[2].parse() {
+                Ok(count) => Ok(query::Query::Cursor(query::CursorQuery::Fetch {
+                    name: tokens[1].to_owned(),
+                    count,
+                })),
+                Err(_) => {
+                    error!("Row count must be a number: {}", tokens[2]);
+                    Err(())
+                }
+            },
+            ":close" if tokens.len() == 2 => Ok(query::Query::Cursor(query::CursorQuery::Close(
+                tokens[1].to_owned(),
+            ))),
+            ":create_user" if tokens.len() == 3 => {
+                Ok(query::Query::Auth(query::AuthQuery::CreateUser {
+                    name: tokens[1].to_owned(),
+                    password: tokens[2].to_owned(),
+                }))
+            }
+            ":drop_user" if tokens.len() == 2 => Ok(query::Query::Auth(
+                query::AuthQuery::DropUser(tokens[1].to_owned()),
+            )),
+            ":grant" | ":revoke" if tokens.len() == 4 => {
+                let privileges = parse_privileges(tokens[1])?;
+                let table = tokens[2].to_owned();
+                let user = tokens[3].to_owned();
+                Ok(query::Query::Auth(if tokens[0] == ":grant" {
+                    query::AuthQuery::Grant {
+                        privileges,
+                        table,
+                        user,
+                    }
+                } else {
+                    query::AuthQuery::Revoke {
+                        privileges,
+                        table,
+                        user,
+                    }
+                }))
+            }
+            ":token" => Ok(query::Query::Auth(query::AuthQuery::CreateToken)),
             _ => {
-                error!("Unknown query: {:#?}", raw);
-                return Err(());
+                error!("Unknown query: {}", tokens[0]);
+                Err(())
             }
         }
     }
 }
 
-fn tokenize(raw: &String) -> Vec<&str> {
-    let slice: &str = (&raw[..]).trim();
+// Comma separated privileges, or `all`.
+fn parse_privileges(raw: &str) -> Result<Vec<query::Privilege>, ()> {
+    if raw.eq_ignore_ascii_case("all") {
+        return Ok(query::Privilege::all());
+    }
+
+    raw.split(',')
+        .map(|name| {
+            query::Privilege::from(name).ok_or_else(|| error!("Unknown privilege: {}", name))
+        })
+        .collect()
+}
+
+// `?N` with N starting from 1, or `$name`. Anything else is a literal value.
+fn parse_placeholder(raw: &str) -> Result<Option<query::Placeholder>, ()> {
+    if let Some(position) = raw.strip_prefix('?') {
+        return match position.parse::<usize>() {
+            Ok(position) if position > 0 => Ok(Some(query::Placeholder::Position(position))),
+            _ => {
+                error!("Invalid positional placeholder: {}", raw);
+                Err(())
+            }
+        };
+    }
+
+    if let Some(name) = raw.strip_prefix('$') {
+        let mut chars = name.chars();
+        let is_valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_valid {
+            error!("Invalid named placeholder: {}", raw);
+            return Err(());
+        }
+        return Ok(Some(query::Placeholder::Name(name.to_owned())));
+    }
+
+    Ok(None)
+}
+
+// Positions must run from ?1 without gaps, and cannot be mixed with names.
+fn validate_placeholders(placeholders: &[(query::Slot, query::Placeholder)]) -> Result<(), ()> {
+    let mut positions: Vec<usize> = placeholders
+        .iter()
+        .filter_map(|(_, placeholder)| match placeholder {
+            query::Placeholder::Position(position) => Some(*position),
+            query::Placeholder::Name(_) => None,
+        })
+        .collect();
+
+    if !positions.is_empty() && positions.len() != placeholders.len() {
+        error!("Positional and named placeholders cannot be mixed");
+        return Err(());
+    }
+
+    positions.sort_unstable();
+    positions.dedup();
+    if positions
+        .iter()
+        .enumerate()
+        .any(|(i, position)| i + 1 != *position)
+    {
+        error!("Positional placeholders must be numbered from ?1 without gaps");
+        return Err(());
+    }
+
+    Ok(())
+}
+
+fn tokenize(raw: &str) -> Vec<&str> {
+    let slice: &str = raw.trim();
     slice.split(' ').collect()
 }
 
@@ -59,13 +217,21 @@ fn parse_create_table(tokens: &mut Vec<&str>) -> Result<query::Query, ()> {
 
     let table_name = tokens.remove(0);
     let mut fields: Vec<query::FieldDef> = vec![];
+    let mut constraints: query::Constraints = Default::default();
+    let mut primary_key: Option<String> = None;
 
-    while tokens.len() > 0 {
-        // Indices are next.
-        if tokens[0] == ":" {
+    while !tokens.is_empty() {
+        // Indices or the storage are next.
+        if tokens[0] == ":" || tokens[0] == "using" {
             break;
         }
 
+        // Table level check constraint.
+        if tokens[0] == "check" {
+            constraints.checks.push(parse_check(tokens)?);
+            continue;
+        }
+
         if tokens.len() < 2 {
             return Err(());
         }
@@ -76,11 +242,11 @@ fn parse_create_table(tokens: &mut Vec<&str>) -> Result<query::Query, ()> {
         let data_type: query::Type = match &type_name {
             &"int" => query::Type::Int,
             &"varchar" => {
-                if tokens.len() < 1 {
+                if tokens.is_empty() {
                     return Err(());
                 }
 
-                let size: u8 = match u8::from_str_radix(tokens.remove(0), 10) {
+                let size: u8 = match tokens.remove(0).parse::<u8>() {
                     Ok(n) => n,
                     Err(e) => {
                         error!("Cannot read varchar size: {:?}", e);
@@ -95,49 +261,217 @@ fn parse_create_table(tokens: &mut Vec<&str>) -> Result<query::Query, ()> {
             }
         };
 
-        fields.push(query::FieldDef::new(field_name.to_owned(), data_type));
+        let mut field_def = query::FieldDef::new(field_name.to_owned(), data_type);
+        parse_field_modifiers(tokens, &mut field_def, &mut constraints, &mut primary_key)?;
+        fields.push(field_def);
+    }
+
+    if fields.iter().filter(|field| field.auto_increment).count() > 1 {
+        error!("Only one auto_increment field is allowed per table.");
+        return Err(());
     }
 
     let mut indices: Vec<String> = vec![];
 
-    if tokens.len() > 0 {
+    if !tokens.is_empty() && tokens[0] != "using" {
         if ":" != tokens.remove(0) {
             error!("Index token ':' must follow field list.");
             return Err(());
         }
 
         // @TODO Must be some kind of unrolling.
-        while tokens.len() > 0 {
+        while !tokens.is_empty() && tokens[0] != "using" {
             indices.push(tokens.remove(0).to_owned());
         }
     }
 
-    Ok(query::Query::Create(query::CreateQuery::new(
+    let mut storage: query::Storage = Default::default();
+    if !tokens.is_empty() {
+        tokens.remove(0);
+        if tokens.len() != 1 {
+            error!("Storage must be the last token: using rows|lsm|columnar");
+            return Err(());
+        }
+        storage = match query::Storage::from(tokens.remove(0)) {
+            Some(storage) => storage,
+            None => {
+                error!("Unknown storage, expected rows, lsm or columnar");
+                return Err(());
+            }
+        };
+    }
+
+    let mut create_query = query::CreateQuery::new(
         table_name.to_owned(),
         fields,
         indices,
-    )))
+        constraints,
+    );
+    create_query.primary_key = primary_key;
+    create_query.storage = storage;
+    Ok(query::Query::Create(create_query))
+}
+
+fn parse_field_modifiers(
+    tokens: &mut Vec<&str>,
+    field_def: &mut query::FieldDef,
+    constraints: &mut query::Constraints,
+    primary_key: &mut Option<String>,
+) -> Result<(), ()> {
+    loop {
+        match tokens.first() {
+            Some(&"primary_key") => {
+                tokens.remove(0);
+                if primary_key.is_some() {
+                    error!("Only one primary key is allowed per table.");
+                    return Err(());
+                }
+                *primary_key = Some(field_def.name.clone());
+            }
+            Some(&"default") => {
+                tokens.remove(0);
+                if tokens.is_empty() {
+                    error!("Missing default value for field: {}", field_def.name);
+                    return Err(());
+                }
+
+                let literal = tokens.remove(0);
+                if field_def.config == query::Type::Int && literal.parse::<u32>().is_err() {
+                    error!("Default value is not an int: {}", literal);
+                    return Err(());
+                }
+                field_def.default = Some(literal.to_owned());
+            }
+            Some(&"auto_increment") => {
+                tokens.remove(0);
+                if field_def.config != query::Type::Int {
+                    error!("Only int fields can be auto_increment: {}", field_def.name);
+                    return Err(());
+                }
+                field_def.auto_increment = true;
+            }
+            Some(&"check") => {
+                constraints.checks.push(parse_check(tokens)?);
+            }
+            Some(&"references") => {
+                tokens.remove(0);
+                if tokens.is_empty() {
+                    error!("Missing reference for field: {}", field_def.name);
+                    return Err(());
+                }
+
+                let (table, column) = parse_reference(tokens.remove(0))?;
+                constraints.foreign_keys.push(query::ForeignKey::new(
+                    field_def.name.clone(),
+                    table,
+                    column,
+                ));
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+
+// Parses `check ( FIELD OP VALUE ... )`, parentheses may stick to the first and last tokens.
+fn parse_check(tokens: &mut Vec<&str>) -> Result<Vec<query::FieldCondition>, ()> {
+    if "check" != tokens.remove(0) {
+        return Err(());
+    }
+
+    if tokens.is_empty() || !tokens[0].starts_with('(') {
+        error!("Check expression must be wrapped in parentheses.");
+        return Err(());
+    }
+
+    let mut expression = String::new();
+    loop {
+        if tokens.is_empty() {
+            error!("Check expression is not closed.");
+            return Err(());
+        }
+
+        let token = tokens.remove(0);
+        expression.push(' ');
+        expression.push_str(token);
+        if token.ends_with(')') {
+            break;
+        }
+    }
+
+    let expression = expression.trim();
+    let parts: Vec<&str> = expression[1..expression.len() - 1]
+        .split_whitespace()
+        .collect();
+    if parts.is_empty() || !parts.len().is_multiple_of(3) {
+        error!(
+            "Check expression must be a list of FIELD OP VALUE: {}",
+            expression
+        );
+        return Err(());
+    }
+
+    Ok(parts
+        .chunks(3)
+        .map(|c| query::FieldCondition::new(c[0].to_owned(), c[1].to_owned(), c[2].to_owned()))
+        .collect())
+}
+
+// Parses `TABLE(COLUMN)`.
+fn parse_reference(raw: &str) -> Result<(String, String), ()> {
+    let open = raw.find('(');
+    if open.is_none() || !raw.ends_with(')') {
+        error!("Reference must look like table(column): {}", raw);
+        return Err(());
+    }
+
+    let open = open.unwrap();
+    let table = &raw[..open];
+    let column = &raw[open + 1..raw.len() - 1];
+    if table.is_empty() || column.is_empty() {
+        error!("Reference must look like table(column): {}", raw);
+        return Err(());
+    }
+
+    Ok((table.to_owned(), column.to_owned()))
 }
 
 fn parse_select(tokens: &mut Vec<&str>) -> Result<query::Query, ()> {
-    assert!(tokens.len() >= 4);
-    assert_eq!("?", tokens.remove(0));
+    if tokens.len() < 4 || "?" != tokens.remove(0) {
+        return Err(());
+    }
 
     let mut columns: Vec<String> = vec![];
 
-    while tokens[0] != ">" {
+    while !tokens.is_empty() && tokens[0] != ">" {
         let column_name = tokens.remove(0);
         columns.push(column_name.to_owned());
     }
 
-    assert_eq!(">", tokens.remove(0));
+    // The table follows the '>'.
+    if tokens.len() < 2 {
+        error!("Select must look like ? COLUMNS > TABLE");
+        return Err(());
+    }
+    tokens.remove(0);
     let table = tokens.remove(0).to_owned();
 
+    let conditions = parse_conditions(tokens)?;
+
+    Ok(query::Query::Select(query::SelectQuery::new(
+        table, columns, conditions,
+    )))
+}
+
+// Conditions are optional, but must come in FIELD OP VALUE triplets after ':'.
+fn parse_conditions(tokens: &mut Vec<&str>) -> Result<Vec<query::FieldCondition>, ()> {
     let mut conditions: Vec<query::FieldCondition> = vec![];
-    if tokens.len() > 0 {
-        assert_eq!(":", tokens.remove(0));
+    if !tokens.is_empty() {
+        if tokens.remove(0) != ":" || !tokens.len().is_multiple_of(3) {
+            error!("Conditions must look like : FIELD OP VALUE");
+            return Err(());
+        }
 
-        while tokens.len() > 0 {
+        while !tokens.is_empty() {
             let field_name = tokens.remove(0).to_owned();
             let op_raw = tokens.remove(0).to_owned();
             let value_raw = tokens.remove(0).to_owned();
@@ -145,19 +479,19 @@ fn parse_select(tokens: &mut Vec<&str>) -> Result<query::Query, ()> {
         }
     }
 
-    Ok(query::Query::Select(query::SelectQuery::new(
-        table, columns, conditions,
-    )))
+    Ok(conditions)
 }
 
 fn parse_insert(tokens: &mut Vec<&str>) -> Result<query::Query, ()> {
-    assert!(tokens.len() >= 4);
-    assert_eq!(">", tokens.remove(0));
+    // Fields can all be omitted when they have defaults, but must come in pairs.
+    if tokens.len() < 2 || !tokens.len().is_multiple_of(2) || ">" != tokens.remove(0) {
+        return Err(());
+    }
 
     let table_name = tokens.remove(0).to_owned();
     let mut raw_inserts: HashMap<String, String> = HashMap::new();
 
-    while tokens.len() > 0 {
+    while !tokens.is_empty() {
         raw_inserts.insert(tokens.remove(0).to_owned(), tokens.remove(0).to_owned());
     }
 
@@ -167,6 +501,19 @@ fn parse_insert(tokens: &mut Vec<&str>) -> Result<query::Query, ()> {
     )))
 }
 
+fn parse_delete(tokens: &mut Vec<&str>) -> Result<query::Query, ()> {
+    if tokens.len() < 2 || "-" != tokens.remove(0) {
+        return Err(());
+    }
+
+    let table = tokens.remove(0).to_owned();
+    let conditions = parse_conditions(tokens)?;
+
+    Ok(query::Query::Delete(query::DeleteQuery::new(
+        table, conditions,
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -202,7 +549,9 @@ mod tests {
 
     #[test]
     fn test_parse_create_table_simple() {
-        let res = parse_create_table(&mut vec!["+", "users", "id", "int", "name", "varchar", "30"]);
+        let res = parse_create_table(&mut vec![
+            "+", "users", "id", "int", "name", "varchar", "30",
+        ]);
         assert!(res.is_ok());
 
         if let query::Query::Create(query) = res.unwrap() {
@@ -218,7 +567,9 @@ mod tests {
 
     #[test]
     fn test_parse_create_table_with_indices() {
-        let res = parse_create_table(&mut vec!["+", "users", "id", "int", "age", "int", ":", "id", "age"]);
+        let res = parse_create_table(&mut vec![
+            "+", "users", "id", "int", "age", "int", ":", "id", "age",
+        ]);
         assert!(res.is_ok());
 
         if let query::Query::Create(query) = res.unwrap() {
@@ -229,4 +580,324 @@ mod tests {
             panic!("Query is not create query.");
         }
     }
+
+    #[test]
+    fn test_parse_create_table_with_storage() {
+        let res = parse_create_table(&mut vec![
+            "+", "events", "id", "int", "primary_key", "kind", "int", ":", "kind", "using", "lsm",
+        ]);
+
+        if let Ok(query::Query::Create(query)) = res {
+            assert_eq!(Some("id".to_owned()), query.primary_key);
+            assert_eq!(query::Storage::Lsm, query.storage);
+            assert_eq!(vec!["kind".to_owned()], query.indices);
+        } else {
+            panic!("Query is not create query.");
+        }
+
+        if let Ok(query::Query::Create(query)) =
+            parse_create_table(&mut vec!["+", "t", "id", "int", "using", "columnar"])
+        {
+            assert_eq!(query::Storage::Columnar, query.storage);
+        } else {
+            panic!("Query is not create query.");
+        }
+        assert!(parse_create_table(&mut vec!["+", "t", "id", "int", "using", "paged"]).is_err());
+        assert!(parse_create_table(&mut vec!["+", "t", "id", "int", "using"]).is_err());
+        assert!(parse_create_table(&mut vec![
+            "+", "t", "id", "int", "primary_key", "no", "int", "primary_key",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_insert_without_fields() {
+        let res = parse_insert(&mut vec![">", "users"]);
+
+        if let Ok(query::Query::Insert(query)) = res {
+            assert_eq!("users", query.table_name);
+            assert!(query.raw_inserts.is_empty());
+        } else {
+            panic!("Query is not insert query.");
+        }
+    }
+
+    #[test]
+    fn test_parse_insert_fails_on_missing_value() {
+        assert!(parse_insert(&mut vec![">", "users", "id"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_select_fails_on_missing_table() {
+        assert!(parse_select(&mut vec!["?", "a", "b", "c"]).is_err());
+        assert!(parse_select(&mut vec!["?", "id", "name", ">"]).is_err());
+        assert!(parse_select(&mut vec![">", "id", ">", "users"]).is_err());
+        assert!(QueryParser.parse("? a b c").is_err());
+        assert!(QueryParser.parse(":declare c ? a b c").is_err());
+    }
+
+    #[test]
+    fn test_parse_create_table_with_field_modifiers() {
+        let res = parse_create_table(&mut vec![
+            "+",
+            "users",
+            "id",
+            "int",
+            "auto_increment",
+            "name",
+            "varchar",
+            "30",
+            "default",
+            "anon",
+            "age",
+            "int",
+            "default",
+            "18",
+            ":",
+            "id",
+        ]);
+        assert!(res.is_ok());
+
+        if let query::Query::Create(query) = res.unwrap() {
+            assert!(query.fields[0].auto_increment);
+            assert_eq!(None, query.fields[0].default);
+            assert!(!query.fields[1].auto_increment);
+            assert_eq!(Some("anon".to_owned()), query.fields[1].default);
+            assert_eq!(Some("18".to_owned()), query.fields[2].default);
+            assert_eq!(vec!["id".to_owned()], query.indices);
+        } else {
+            panic!("Query is not create query.");
+        }
+    }
+
+    #[test]
+    fn test_parse_create_table_with_constraints() {
+        let res = parse_create_table(&mut vec![
+            "+",
+            "booking",
+            "id",
+            "int",
+            "user_id",
+            "int",
+            "references",
+            "users(id)",
+            "check",
+            "(user_id",
+            "<",
+            "1000)",
+            "pages",
+            "int",
+            "check",
+            "(",
+            "pages",
+            ">",
+            "0",
+            "pages",
+            "<",
+            "500",
+            ")",
+        ]);
+        assert!(res.is_ok());
+
+        if let query::Query::Create(query) = res.unwrap() {
+            assert_eq!(3, query.fields.len());
+
+            let foreign_keys = &query.constraints.foreign_keys;
+            assert_eq!(1, foreign_keys.len());
+            assert_eq!("user_id", foreign_keys[0].field);
+            assert_eq!("users", foreign_keys[0].table);
+            assert_eq!("id", foreign_keys[0].column);
+
+            let checks = &query.constraints.checks;
+            assert_eq!(2, checks.len());
+            assert_eq!(1, checks[0].len());
+            assert_eq!("user_id", checks[0][0].field_name);
+            assert_eq!("1000", checks[0][0].value);
+            assert_eq!(2, checks[1].len());
+            assert_eq!("<", checks[1][1].relation);
+        } else {
+            panic!("Query is not create query.");
+        }
+    }
+
+    #[test]
+    fn test_parse_create_table_fails_on_invalid_constraints() {
+        assert!(
+            parse_create_table(&mut vec!["+", "t", "id", "int", "check", "id", ">", "1"]).is_err()
+        );
+        assert!(parse_create_table(&mut vec!["+", "t", "id", "int", "check", "(id", ">"]).is_err());
+        assert!(
+            parse_create_table(&mut vec!["+", "t", "id", "int", "check", "(id", ">)"]).is_err()
+        );
+        assert!(
+            parse_create_table(&mut vec!["+", "t", "id", "int", "references", "users"]).is_err()
+        );
+        assert!(
+            parse_create_table(&mut vec!["+", "t", "id", "int", "references", "(id)"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_transaction_commands() {
+        let parser: QueryParser = Default::default();
+
+        for (raw, expected) in [
+            (":begin", query::TransactionQuery::Begin),
+            (":commit", query::TransactionQuery::Commit),
+            (":rollback", query::TransactionQuery::Rollback),
+        ] {
+            match parser.parse(raw) {
+                Ok(query::Query::Transaction(q)) => assert_eq!(expected, q),
+                _ => panic!("Query is not transaction query."),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_cursor_commands() {
+        match QueryParser.parse(":declare export ? id > users : id > 3") {
+            Ok(query::Query::Cursor(query::CursorQuery::Declare { name, select })) => {
+                assert_eq!("export", name);
+                assert_eq!("users", select.table);
+                assert_eq!(1, select.conditions.len());
+            }
+            _ => panic!("Query is not a cursor declaration."),
+        }
+        assert!(matches!(
+            QueryParser.parse(":fetch export 100"),
+            Ok(query::Query::Cursor(query::CursorQuery::Fetch {
+                count: 100,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            QueryParser.parse(":close export"),
+            Ok(query::Query::Cursor(query::CursorQuery::Close(_)))
+        ));
+        assert!(QueryParser.parse(":fetch export many").is_err());
+        assert!(QueryParser.parse(":declare export > users id 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_auth_commands() {
+        match QueryParser.parse(":grant select,insert users ann") {
+            Ok(query::Query::Auth(query::AuthQuery::Grant {
+                privileges,
+                table,
+                user,
+            })) => {
+                assert_eq!(
+                    vec![query::Privilege::Select, query::Privilege::Insert],
+                    privileges
+                );
+                assert_eq!("users", table);
+                assert_eq!("ann", user);
+            }
+            _ => panic!("Query is not a grant."),
+        }
+        assert!(matches!(
+            QueryParser.parse(":revoke all * ann"),
+            Ok(query::Query::Auth(query::AuthQuery::Revoke { ref privileges, .. }))
+                if privileges.len() == 5
+        ));
+        assert!(matches!(
+            QueryParser.parse(":create_user ann secret"),
+            Ok(query::Query::Auth(query::AuthQuery::CreateUser { .. }))
+        ));
+        assert!(QueryParser.parse(":grant drop users ann").is_err());
+        assert!(QueryParser.parse(":create_user ann").is_err());
+    }
+
+    #[test]
+    fn test_parse_delete() {
+        let res = parse_delete(&mut vec!["-", "users", ":", "id", "=", "3"]);
+
+        if let Ok(query::Query::Delete(query)) = res {
+            assert_eq!("users", query.table);
+            assert_eq!(1, query.conditions.len());
+            assert_eq!("id", query.conditions[0].field_name);
+        } else {
+            panic!("Query is not delete query.");
+        }
+
+        assert!(parse_delete(&mut vec!["-", "users"]).is_ok());
+        assert!(parse_delete(&mut vec!["-", "users", ":", "id", "="]).is_err());
+        assert!(parse_delete(&mut vec!["-", "users", "id"]).is_err());
+        assert!(parse_delete(&mut vec!["-", "users", "id", "a", "b", "c"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_create_table_fails_on_invalid_field_modifiers() {
+        assert!(parse_create_table(&mut vec!["+", "users", "id", "int", "default"]).is_err());
+        assert!(
+            parse_create_table(&mut vec!["+", "users", "id", "int", "default", "abc"]).is_err()
+        );
+        assert!(parse_create_table(&mut vec![
+            "+",
+            "users",
+            "name",
+            "varchar",
+            "8",
+            "auto_increment"
+        ])
+        .is_err());
+        assert!(parse_create_table(&mut vec![
+            "+",
+            "users",
+            "id",
+            "int",
+            "auto_increment",
+            "no",
+            "int",
+            "auto_increment"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_prepare_finds_placeholders() {
+        let prepared = QueryParser.prepare("> users id ?1 name ?2 age 30").unwrap();
+        let mut placeholders = prepared.placeholders.clone();
+        placeholders.sort_by_key(|(_, placeholder)| format!("{:?}", placeholder));
+        assert_eq!(
+            vec![
+                (
+                    query::Slot::Insert("id".to_owned()),
+                    query::Placeholder::Position(1)
+                ),
+                (
+                    query::Slot::Insert("name".to_owned()),
+                    query::Placeholder::Position(2)
+                ),
+            ],
+            placeholders
+        );
+
+        let prepared = QueryParser
+            .prepare("? id > users : age > $min name = Ann")
+            .unwrap();
+        assert_eq!(
+            vec![(
+                query::Slot::Condition(0),
+                query::Placeholder::Name("min".to_owned())
+            )],
+            prepared.placeholders
+        );
+
+        assert!(QueryParser
+            .prepare("? id > users")
+            .unwrap()
+            .placeholders
+            .is_empty());
+    }
+
+    #[test]
+    fn test_prepare_fails_on_invalid_placeholders() {
+        assert!(QueryParser.prepare("- users : id = ?0").is_err());
+        assert!(QueryParser.prepare("- users : id = ?x").is_err());
+        assert!(QueryParser.prepare("- users : id = $1a").is_err());
+        assert!(QueryParser.prepare("- users : id = ?2").is_err());
+        assert!(QueryParser.prepare("- users : id = ?1 age = $age").is_err());
+        assert!(QueryParser.prepare("- users : id = ?1 age = ?1").is_ok());
+    }
 }
@@ -1,10 +1,44 @@
-use dbclient;
-use query_parser;
-use std::io::{self, prelude::*, Write};
+use dbclient::{self, ResultHandler};
+use output;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{self, Context, Editor, Helper};
+use script;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+
+const HISTORY_FILE: &str = ".toydb_history";
+const PROMPT: &str = "> ";
+// Shown while a statement goes on over more lines.
+const CONTINUATION_PROMPT: &str = ". ";
+
+// First words of the SQL statements the server knows.
+const SQL_KEYWORDS: &[&str] = &[
+    "create", "insert", "select", "delete", "begin", "start", "commit", "rollback", "set",
+    "declare", "fetch", "close", "drop", "grant", "revoke",
+];
 
 enum ReplCommand {
     Quit,
     Help,
+    Format(output::Format),
+    // Back to stdout without a file.
+    Output(Option<String>),
+    ListTables,
+    DescribeTable(String),
+    // Runs the statements of a local file.
+    Include(String),
+    Timing,
+    // The port defaults to the one of the protocol.
+    Connect(String, Option<u16>),
 }
 
 enum Command {
@@ -19,43 +53,103 @@ enum ReplResponseAction {
 
 #[derive(Debug, Default)]
 pub struct Repl {
-    client: dbclient::DBClient,
+    // Replaced when connecting to another server.
+    client: RefCell<dbclient::DBClient>,
+    renderer: RefCell<output::Renderer>,
 }
 
 impl Repl {
-    pub fn new() -> Repl {
+    pub fn new(client: dbclient::DBClient) -> Repl {
         info!("REPL has been initialized");
-        Default::default()
+        Repl {
+            client: RefCell::new(client),
+            renderer: Default::default(),
+        }
     }
 
     pub fn start(&self) {
         info!("REPL is listening");
 
-        print!("> ");
-        let _ = io::stdout().flush();
-
-        let mut command = String::new();
-        let read_res = io::stdin().read_line(&mut command);
+        let mut editor: Editor<ReplHelper, _> = match Editor::new() {
+            Ok(editor) => editor,
+            Err(e) => {
+                error!("Line editor cannot be started: {}", e);
+                return;
+            }
+        };
+        editor.set_helper(Some(ReplHelper {
+            client: &self.client,
+        }));
+        let history = history_path();
+        if let Some(ref path) = history {
+            // There's none on the first run.
+            let _ = editor.load_history(path);
+        }
 
-        match read_res {
-            Ok(n) => {
-                info!("{} byes are read", n);
-                match self.execute_raw_command(&command) {
-                    ReplResponseAction::Finish => return,
-                    _ => {}
+        let mut input = String::new();
+        loop {
+            let prompt = if input.is_empty() {
+                PROMPT
+            } else {
+                CONTINUATION_PROMPT
+            };
+            let line = match editor.readline(prompt) {
+                Ok(line) => line,
+                // Ctrl-C drops the statement being typed.
+                Err(ReadlineError::Interrupted) => {
+                    input.clear();
+                    continue;
                 }
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    error!("Read error: {}", e);
+                    break;
+                }
+            };
+            if input.is_empty() && line.trim().is_empty() {
+                continue;
             }
-            Err(e) => {
-                error!("Read error: {}", e);
-                return;
+
+            input.push_str(&line);
+            input.push('\n');
+            if !is_complete(&input) {
+                continue;
+            }
+
+            let input = mem::take(&mut input);
+            let entry: Vec<&str> = input.lines().map(str::trim).collect();
+            let _ = editor.add_history_entry(entry.join(" "));
+            if let ReplResponseAction::Finish = self.execute_input(&input) {
+                break;
             }
         }
 
-        self.start();
+        if let Some(ref path) = history {
+            if let Err(e) = editor.save_history(path) {
+                warn!("History cannot be saved to {:?}: {}", path, e);
+            }
+        }
+    }
+
+    // Input ending with a semicolon can hold more statements.
+    fn execute_input(&self, input: &str) -> ReplResponseAction {
+        let input = input.trim();
+        let statements = if input.ends_with(';') {
+            script::split_statements(input)
+        } else {
+            vec![input]
+        };
+
+        for statement in statements {
+            if let ReplResponseAction::Finish = self.execute_raw_command(statement) {
+                return ReplResponseAction::Finish;
+            }
+        }
+        ReplResponseAction::Continue
     }
 
-    fn execute_raw_command(&self, command: &String) -> ReplResponseAction {
-        match parse_command(&command) {
+    fn execute_raw_command(&self, command: &str) -> ReplResponseAction {
+        match parse_command(command) {
             Ok(Command::ReplCommand(repl_command)) => match repl_command {
                 ReplCommand::Quit => {
                     println!("Bye!");
@@ -64,21 +158,277 @@ impl Repl {
                 ReplCommand::Help => {
                     print_help();
                 }
+                ReplCommand::Format(format) => {
+                    self.renderer.borrow_mut().set_format(format);
+                }
+                ReplCommand::Output(path) => {
+                    let _ = self.renderer.borrow_mut().set_output(path.as_deref());
+                }
+                ReplCommand::ListTables => self.list_tables(),
+                ReplCommand::DescribeTable(table) => self.describe_table(&table),
+                ReplCommand::Include(path) => return self.execute_script(&path),
+                ReplCommand::Timing => {
+                    let is_on = self.renderer.borrow_mut().toggle_timing();
+                    println!("Timing is {}.", if is_on { "on" } else { "off" });
+                }
+                ReplCommand::Connect(host, port) => self.connect(&host, port),
             },
             Ok(Command::DBCommand(db_command)) => {
-                self.client.send(&db_command);
+                let mut renderer = self.renderer.borrow_mut();
+                renderer.start();
+                self.client.borrow().send(&db_command, &mut *renderer);
             }
             Err(_) => {
-                info!("Command [{:#?}] not known. Try again.", command);
+                self.renderer.borrow_mut().error(&format!(
+                    "Unknown command: {}\nType help for the list of commands.",
+                    command.trim()
+                ));
             }
         }
 
         ReplResponseAction::Continue
     }
+
+    /// Runs the statements of the input, without prompting. Returns whether they all succeeded.
+    pub fn run(&self, input: &str) -> bool {
+        self.execute_input(input);
+        !self.renderer.borrow().has_failed()
+    }
+
+    /// Runs a script, its statements ending the same way as when typed. Returns whether they
+    /// all succeeded.
+    pub fn run_script(&self, script: &str) -> bool {
+        self.execute_lines(script);
+        !self.renderer.borrow().has_failed()
+    }
+
+    pub fn set_format(&self, format: output::Format) {
+        self.renderer.borrow_mut().set_format(format);
+    }
+
+    fn execute_script(&self, path: &str) -> ReplResponseAction {
+        match fs::read_to_string(path) {
+            Ok(script) => self.execute_lines(&script),
+            Err(e) => {
+                let message = format!("{} cannot be read: {}", path, e);
+                self.renderer.borrow_mut().error(&message);
+                ReplResponseAction::Continue
+            }
+        }
+    }
+
+    fn execute_lines(&self, script: &str) -> ReplResponseAction {
+        let mut input = String::new();
+        for line in script.lines() {
+            if input.is_empty() && line.trim().is_empty() {
+                continue;
+            }
+            input.push_str(line);
+            input.push('\n');
+            if is_complete(&input) {
+                if let ReplResponseAction::Finish = self.execute_input(&mem::take(&mut input)) {
+                    return ReplResponseAction::Finish;
+                }
+            }
+        }
+        if input.is_empty() {
+            ReplResponseAction::Continue
+        } else {
+            self.execute_input(&input)
+        }
+    }
+
+    fn list_tables(&self) {
+        let mut renderer = self.renderer.borrow_mut();
+        renderer.start();
+        let description = match self.client.borrow().describe() {
+            Ok(description) => description,
+            Err(_) => return renderer.error("Tables cannot be listed"),
+        };
+
+        renderer.columns(vec!["table".to_owned()]);
+        for table in description.lines().filter(|line| !line.starts_with('\t')) {
+            renderer.row(vec![Value::from(table.trim())]);
+        }
+        renderer.complete(0, None);
+    }
+
+    fn describe_table(&self, table: &str) {
+        let mut renderer = self.renderer.borrow_mut();
+        renderer.start();
+        let description = match self.client.borrow().describe() {
+            Ok(description) => description,
+            Err(_) => return renderer.error(&format!("{} cannot be described", table)),
+        };
+        let columns = match table_columns(&description, table) {
+            Some(columns) => columns,
+            None => return renderer.error(&format!("No table named {}", table)),
+        };
+
+        renderer.columns(vec![
+            "column".to_owned(),
+            "type".to_owned(),
+            "index".to_owned(),
+        ]);
+        for column in columns {
+            let index = if column.is_indexed { "yes" } else { "" };
+            renderer.row(vec![
+                Value::from(column.name),
+                Value::from(column.type_name),
+                Value::from(index),
+            ]);
+        }
+        renderer.complete(0, None);
+    }
+
+    fn connect(&self, host: &str, port: Option<u16>) {
+        let port = port.unwrap_or(if self.client.borrow().is_binary() {
+            dbclient::BINARY_PORT
+        } else {
+            dbclient::HTTP_PORT
+        });
+        let client = self.client.borrow().connect_to(host, port);
+        match client {
+            Ok(client) => {
+                *self.client.borrow_mut() = client;
+                println!("Connected to {}:{}.", host, port);
+            }
+            Err(_) => self
+                .renderer
+                .borrow_mut()
+                .error(&format!("Cannot connect to server: {}:{}", host, port)),
+        }
+    }
+}
+
+// Completes table and column names, as the server describes them at the time.
+struct ReplHelper<'a> {
+    client: &'a RefCell<dbclient::DBClient>,
+}
+
+impl<'a> Completer for ReplHelper<'a> {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| !is_name_char(c))
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let prefix = &line[start..pos];
+
+        let names = match self.client.borrow().describe() {
+            Ok(description) => catalog_names(&description),
+            Err(_) => return Ok((start, vec![])),
+        };
+        let candidates = names
+            .into_iter()
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl<'a> Hinter for ReplHelper<'a> {
+    type Hint = String;
+}
+
+impl<'a> Highlighter for ReplHelper<'a> {}
+
+impl<'a> Validator for ReplHelper<'a> {}
+
+impl<'a> Helper for ReplHelper<'a> {}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Table and column names in the text of `:db`. Tables start a line, their columns follow
+// indented as `name : type`.
+fn catalog_names(description: &str) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    for line in description.lines() {
+        let name = if line.starts_with('\t') {
+            match line.split(" : ").next() {
+                Some(column) if column.len() < line.len() => column.trim(),
+                _ => continue,
+            }
+        } else {
+            line.trim()
+        };
+
+        if !name.is_empty() && name.chars().all(is_name_char) {
+            names.insert(name.to_owned());
+        }
+    }
+    names
+}
+
+struct TableColumn {
+    name: String,
+    type_name: String,
+    is_indexed: bool,
+}
+
+// The columns of a table in the text of `:db`, where each column line holds the debug
+// output of its `ColumnInfo`.
+fn table_columns(description: &str, table: &str) -> Option<Vec<TableColumn>> {
+    let mut lines = description
+        .lines()
+        .skip_while(|line| line.starts_with('\t') || line.trim() != table);
+    lines.next()?;
+
+    let mut columns = vec![];
+    let mut indexed = vec![];
+    for line in lines.take_while(|line| line.starts_with('\t')) {
+        let line = line.trim();
+        if let Some(column) = line.strip_prefix("Index on:") {
+            indexed.push(column.trim().to_owned());
+        } else if let Some((name, info)) = line.split_once(" : ") {
+            if !info.starts_with("ColumnInfo") {
+                continue;
+            }
+            let type_name = info
+                .split("config: ")
+                .nth(1)
+                .and_then(|config| config.split(", default: ").next())
+                .unwrap_or_default();
+            columns.push(TableColumn {
+                name: name.trim().to_owned(),
+                type_name: type_name.to_owned(),
+                is_indexed: false,
+            });
+        }
+    }
+    for column in &mut columns {
+        column.is_indexed = indexed.contains(&column.name);
+    }
+    Some(columns)
+}
+
+// A statement ends with a semicolon. A line of TQL, or a REPL command, needs none, and a
+// line that's neither is complete too, to be reported.
+fn is_complete(input: &str) -> bool {
+    let input = input.trim();
+    if input.ends_with(';') {
+        return true;
+    }
+
+    let is_tql = input.starts_with(|c| "?+>-:".contains(c));
+    let is_sql = matches!(parse_command(input), Ok(Command::DBCommand(_))) && !is_tql;
+    !input.contains('\n') && !is_sql
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
 }
 
-fn parse_command(command: &String) -> Result<Command, ()> {
-    let slice: &str = &command[..];
+fn parse_command(command: &str) -> Result<Command, ()> {
+    let slice: &str = command;
 
     match &slice.trim().to_lowercase()[..] {
         "q" | "quit" | "exit" => {
@@ -90,19 +440,168 @@ fn parse_command(command: &String) -> Result<Command, ()> {
         _ => {}
     };
 
-    if query_parser::QueryParser::looks_like_query(command) {
-        return Ok(Command::DBCommand(command.clone()));
+    if let Some(meta_command) = slice.trim().strip_prefix('\\') {
+        return parse_meta_command(meta_command).map(Command::ReplCommand);
+    }
+
+    if looks_like_query(command) {
+        return Ok(Command::DBCommand(command.to_owned()));
     }
 
     Err(())
 }
 
+// TQL commands and the SQL statements the server knows, sent as they are.
+fn looks_like_query(raw: &str) -> bool {
+    let slice: &str = raw;
+    if slice.to_lowercase().starts_with("?") {
+        return true;
+    }
+    if slice.to_lowercase().starts_with("+") {
+        return true;
+    }
+    if slice.to_lowercase().starts_with(">") {
+        return true;
+    }
+    if slice.to_lowercase().starts_with("-") {
+        return true;
+    }
+    if slice.to_lowercase().starts_with(":") {
+        return true;
+    }
+
+    let first_word = slice.split_whitespace().next().unwrap_or("").to_lowercase();
+    SQL_KEYWORDS.contains(&&first_word[..])
+}
+
+// Commands starting with a backslash.
+fn parse_meta_command(command: &str) -> Result<ReplCommand, ()> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    match &words[..] {
+        ["format", format] => output::Format::from(format).map(ReplCommand::Format),
+        ["o"] => Ok(ReplCommand::Output(None)),
+        ["o", path] => Ok(ReplCommand::Output(Some((*path).to_owned()))),
+        ["dt"] => Ok(ReplCommand::ListTables),
+        ["d", table] => Ok(ReplCommand::DescribeTable((*table).to_owned())),
+        ["i", path] => Ok(ReplCommand::Include((*path).to_owned())),
+        ["timing"] => Ok(ReplCommand::Timing),
+        ["c", host] | ["connect", host] => Ok(ReplCommand::Connect((*host).to_owned(), None)),
+        ["c", host, port] | ["connect", host, port] => port
+            .parse()
+            .map(|port| ReplCommand::Connect((*host).to_owned(), Some(port)))
+            .map_err(|_| ()),
+        _ => Err(()),
+    }
+}
+
 fn print_help() {
     println!("Command list:");
     println!("\tQUIT");
     println!("\tHELP");
-    println!("\tCreate table: + TABLENAME (FIELDNAME TYPE)+ (: (INDICES)+)");
+    println!("\tCreate table: + TABLENAME (FIELDNAME TYPE (MODIFIER)* | check (CONDITIONS))+ (: (INDICES)+)");
+    println!("\t\tModifiers: default VALUE, auto_increment, check (CONDITIONS), references TABLENAME(FIELDNAME)");
     println!("\tSelect query: ? (FIELD_NAME)+ > TABLENAME (: (FIELD_NAME OP VALUE)+)");
-    println!("\tInsert query: > TABLENAME (FIELD_NAME VALUE)+");
+    println!("\tInsert query: > TABLENAME (FIELD_NAME VALUE)*");
+    println!("\tDelete query: - TABLENAME (: (FIELD_NAME OP VALUE)+)");
     println!("\tDescribe database: :db");
+    println!("\tTransactions: :begin, :commit, :rollback");
+    println!("\tSwitch language: :dialect sql, SET DIALECT tql");
+    println!("\tCursors: :declare NAME SELECT_QUERY, :fetch NAME COUNT, :close NAME");
+    println!("\tSQL: CREATE TABLE, CREATE INDEX, INSERT INTO ... VALUES, SELECT ... FROM ... WHERE, DELETE FROM");
+    println!("\tSQL statements can span lines and end with ';', TQL runs at the end of its line");
+    println!("\tTab completes table and column names");
+    println!("\tOutput format: \\format table|csv|json");
+    println!("\tOutput to a file: \\o FILE, back to the screen: \\o");
+    println!("\tList tables: \\dt, describe a table: \\d TABLENAME");
+    println!("\tRun the statements of a file: \\i FILE");
+    println!("\tShow the time taken, on or off: \\timing");
+    println!("\tConnect to another server: \\c HOST (PORT)");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_statements_end_with_semicolon_or_line_of_tql() {
+        assert!(is_complete("? id > users\n"));
+        assert!(is_complete("quit\n"));
+        assert!(is_complete("SELECT id\nFROM users;\n"));
+        assert!(!is_complete("SELECT id\n"));
+        assert!(!is_complete("SELECT id\nFROM users\n"));
+        // TQL going on over more lines needs the semicolon too.
+        assert!(!is_complete("? id\n> users\n"));
+        assert!(is_complete("\\o out.csv\n"));
+        assert!(is_complete("hello there\n"));
+    }
+
+    #[test]
+    fn test_meta_commands() {
+        assert!(matches!(
+            parse_command("\\format csv"),
+            Ok(Command::ReplCommand(ReplCommand::Format(
+                output::Format::Csv
+            )))
+        ));
+        assert!(matches!(
+            parse_command(" \\o  out.txt "),
+            Ok(Command::ReplCommand(ReplCommand::Output(Some(ref path)))) if path == "out.txt"
+        ));
+        assert!(matches!(
+            parse_command("\\o"),
+            Ok(Command::ReplCommand(ReplCommand::Output(None)))
+        ));
+        assert!(parse_command("\\format xml").is_err());
+        assert!(matches!(
+            parse_command("\\c db.local 9000"),
+            Ok(Command::ReplCommand(ReplCommand::Connect(ref host, Some(9000)))) if host == "db.local"
+        ));
+        assert!(parse_command("\\c db.local port").is_err());
+        assert!(parse_command("\\x").is_err());
+        assert!(parse_command("hello").is_err());
+    }
+
+    #[test]
+    fn test_failures_are_remembered() {
+        let repl = Repl::new(Default::default());
+        repl.set_format(output::Format::Csv);
+        assert!(repl.run("\\format json"));
+        assert!(!repl.run("hello"));
+        assert!(!repl.run("\\format table"));
+    }
+
+    #[test]
+    fn test_table_columns() {
+        let description = "books\n\tid           : ColumnInfo { name: \"id\", offs: 0, size: 4, \
+                           field_def: FieldDef { name: \"id\", config: Int, default: None, \
+                           auto_increment: false } }\nusers\n\
+                           \tid           : ColumnInfo { name: \"id\", offs: 0, size: 4, \
+                           field_def: FieldDef { name: \"id\", config: Int, default: None, \
+                           auto_increment: false } }\n\
+                           \tname         : ColumnInfo { name: \"name\", offs: 4, size: 8, \
+                           field_def: FieldDef { name: \"name\", config: Varchar of size 8, \
+                           default: None, auto_increment: false } }\n\
+                           \tIndex on: id          \n\tCheck: Condition { a : b }\n";
+        let columns: Vec<(String, String, bool)> = table_columns(description, "users")
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.name, c.type_name, c.is_indexed))
+            .collect();
+        assert_eq!(
+            vec![
+                ("id".to_owned(), "Int".to_owned(), true),
+                ("name".to_owned(), "Varchar of size 8".to_owned(), false),
+            ],
+            columns
+        );
+        assert!(table_columns(description, "authors").is_none());
+    }
+
+    #[test]
+    fn test_catalog_names() {
+        let description = "users\n\tid           : Int\n\tname         : Varchar(8)\n\
+                           \tIndex on: id          \n\tCheck: Condition { a : b }\nbooks\n";
+        let names: Vec<String> = catalog_names(description).into_iter().collect();
+        assert_eq!(vec!["books", "id", "name", "users"], names);
+    }
 }
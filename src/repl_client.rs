@@ -1,19 +1,181 @@
 #[macro_use]
 extern crate log;
 extern crate env_logger;
-extern crate futures;
-extern crate hyper;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
+extern crate clap;
+extern crate rustyline;
+extern crate serde_json;
+extern crate toydb;
 
-mod dbclient;
-mod query;
-mod query_parser;
+mod output;
 mod repl;
+mod script;
+
+use clap::{App, Arg};
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::process;
+use toydb::dbclient;
 
 fn main() {
   env_logger::init();
+
+  let matches = App::new("ToyDB client")
+    .version("0.1")
+    .arg(
+      Arg::with_name("host")
+        .long("host")
+        .value_name("HOST")
+        .help("Server host")
+        .default_value("localhost")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("port")
+        .short("p")
+        .long("port")
+        .value_name("PORT")
+        .help("Server port [default: 8421, or 8422 for the binary protocol]")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("protocol")
+        .long("protocol")
+        .value_name("PROTOCOL")
+        .help("Protocol to talk to the server with")
+        .possible_values(&["http", "binary"])
+        .default_value("http")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("tls")
+        .long("tls")
+        .help("Talk HTTPS to the server"),
+    )
+    .arg(
+      Arg::with_name("ca-cert")
+        .long("ca-cert")
+        .value_name("FILE")
+        .help("PEM certificate to trust besides the system roots, implies --tls")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("user")
+        .short("U")
+        .long("user")
+        .value_name("USER")
+        .help("User to authenticate as, with the password in TOYDB_PASSWORD")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("token")
+        .long("token")
+        .value_name("TOKEN")
+        .help("Token to authenticate with, instead of a user and password")
+        .conflicts_with("user")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("execute")
+        .short("e")
+        .long("execute")
+        .value_name("STATEMENT")
+        .help("Run the statement and exit, can be given more times")
+        .multiple(true)
+        .number_of_values(1)
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("file")
+        .short("f")
+        .long("file")
+        .value_name("FILE")
+        .help("Run the statements of the file and exit")
+        .conflicts_with("execute")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("format")
+        .long("format")
+        .value_name("FORMAT")
+        .help("Output format [default: table, or csv when not interactive]")
+        .possible_values(&["table", "csv", "json"])
+        .takes_value(true),
+    )
+    .get_matches();
+
+  let host = matches.value_of("host").unwrap();
+  let is_binary = matches.value_of("protocol") == Some("binary");
+  let port = match matches.value_of("port") {
+    Some(port) => port.parse::<u16>().unwrap_or_else(|_| {
+      eprintln!("Error: Invalid port {}", port);
+      process::exit(1);
+    }),
+    None if is_binary => dbclient::BINARY_PORT,
+    None => dbclient::HTTP_PORT,
+  };
+  let credentials = match matches.value_of("token") {
+    Some(token) => Some(dbclient::Credentials::Token(token.to_owned())),
+    None => matches.value_of("user").map(|user| dbclient::Credentials::Password {
+      user: user.to_owned(),
+      password: env::var("TOYDB_PASSWORD").unwrap_or_default(),
+    }),
+  };
+  let client = if is_binary {
+    dbclient::DBClient::connect_binary(host, port, credentials.as_ref())
+  } else {
+    dbclient::DBClient::new(host, port, credentials.as_ref())
+  };
+  let client = if matches.is_present("tls") || matches.is_present("ca-cert") {
+    client.and_then(|client| client.with_tls(matches.value_of("ca-cert")))
+  } else {
+    client
+  };
+  let client = match client {
+    Ok(client) => client,
+    Err(_) => {
+      eprintln!("Cannot connect to server: {}:{}", host, port);
+      process::exit(1);
+    }
+  };
+
+  // Statements given as arguments, in a file or piped in run without the prompt.
+  let script = if let Some(path) = matches.value_of("file") {
+    Some(fs::read_to_string(path).unwrap_or_else(|e| {
+      eprintln!("Error: {} cannot be read: {}", path, e);
+      process::exit(1);
+    }))
+  } else if !matches.is_present("execute") && !io::stdin().is_terminal() {
+    let mut script = String::new();
+    if let Err(e) = io::stdin().read_to_string(&mut script) {
+      eprintln!("Error: Input cannot be read: {}", e);
+      process::exit(1);
+    }
+    Some(script)
+  } else {
+    None
+  };
+  let is_interactive = script.is_none() && !matches.is_present("execute");
+
+  let repl = repl::Repl::new(client);
+  match matches.value_of("format").map(output::Format::from) {
+    Some(Ok(format)) => repl.set_format(format),
+    _ if !is_interactive => repl.set_format(output::Format::Csv),
+    _ => {}
+  }
+
   info!("DB is starting");
-  repl::Repl::new().start();
+  if let Some(statements) = matches.values_of("execute") {
+    // Each runs, even if one before failed.
+    let results: Vec<bool> = statements.map(|statement| repl.run(statement)).collect();
+    if results.contains(&false) {
+      process::exit(1);
+    }
+  } else if let Some(script) = script {
+    if !repl.run_script(&script) {
+      process::exit(1);
+    }
+  } else {
+    repl.start();
+  }
 }
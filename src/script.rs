@@ -0,0 +1,39 @@
+/// Splits at the semicolons outside of string literals, dropping empty statements.
+pub fn split_statements(raw: &str) -> Vec<&str> {
+    let mut statements = vec![];
+    let mut is_quoted = false;
+    let mut start = 0;
+
+    for (i, c) in raw.char_indices() {
+        match c {
+            // A doubled quote inside a literal toggles twice, so it's handled as well.
+            '\'' => is_quoted = !is_quoted,
+            ';' if !is_quoted => {
+                statements.push(&raw[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    statements.push(&raw[start..]);
+
+    statements
+        .into_iter()
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_statements() {
+        assert_eq!(
+            vec!["SELECT a FROM t WHERE b = 'x;y'", "COMMIT"],
+            split_statements(" SELECT a FROM t WHERE b = 'x;y'; ;COMMIT;")
+        );
+        assert!(split_statements(" ; ").is_empty());
+    }
+}
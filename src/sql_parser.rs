@@ -0,0 +1,828 @@
+use query;
+use std::collections::HashMap;
+
+/// Parses the SQL subset TQL can express into the same queries:
+///
+/// - `CREATE TABLE t (id INT AUTO_INCREMENT, name VARCHAR(8) DEFAULT 'x', INDEX (id))`, columns
+///   can have `PRIMARY KEY`, `CHECK (...)` and `REFERENCES t(c)`, the table `PRIMARY KEY (c)`,
+///   `CHECK (...)` and `FOREIGN KEY (c) REFERENCES t(c)`. `USING LSM` or `USING COLUMNAR`
+///   after the columns chooses the storage.
+/// - `CREATE INDEX [name] ON t (c)`
+/// - `INSERT INTO t (c, ...) VALUES (v, ...)`
+/// - `SELECT c, ... FROM t [WHERE c = v AND ...]` and `DELETE FROM t [WHERE ...]`, with the
+///   `=`, `<` and `>` operators
+/// - `BEGIN`, `COMMIT`, `ROLLBACK` and `SET DIALECT tql`
+///
+/// Keywords are case insensitive, names are kept as written. Values can be placeholders of
+/// prepared statements.
+pub fn parse(raw: &str) -> Result<query::Query, ()> {
+    let tokens = tokenize(raw)?;
+    let mut parser = Parser { tokens, pos: 0 };
+
+    let query = parser.statement()?;
+
+    parser.eat_symbol(';');
+    if let Some(token) = parser.peek() {
+        error!("Unexpected {:?} after the statement", token);
+        return Err(());
+    }
+    Ok(query)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Number(String),
+    Str(String),
+    Placeholder(String),
+    Symbol(char),
+}
+
+fn set_primary_key(primary_key: &mut Option<String>, field_name: &str) -> Result<(), ()> {
+    if primary_key.is_some() {
+        error!("Only one primary key is allowed per table.");
+        return Err(());
+    }
+    *primary_key = Some(field_name.to_owned());
+    Ok(())
+}
+
+fn tokenize(raw: &str) -> Result<Vec<Token>, ()> {
+    let mut tokens = vec![];
+    let mut chars = raw.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            tokens.push(Token::Word(take_word(&mut chars)));
+        } else if c.is_ascii_digit() {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                number.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Number(number));
+        } else if c == '\'' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    // A doubled quote stands for a quote.
+                    Some('\'') if chars.peek() == Some(&'\'') => {
+                        chars.next();
+                        s.push('\'');
+                    }
+                    Some('\'') => break,
+                    Some(c) => s.push(c),
+                    None => {
+                        error!("String is not closed: {}", raw);
+                        return Err(());
+                    }
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else if c == '?' {
+            chars.next();
+            let mut placeholder = "?".to_owned();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                placeholder.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Placeholder(placeholder));
+        } else if c == '$' {
+            chars.next();
+            tokens.push(Token::Placeholder(format!("${}", take_word(&mut chars))));
+        } else if "(),;*=<>".contains(c) {
+            chars.next();
+            tokens.push(Token::Symbol(c));
+        } else {
+            error!("Unexpected character {:?} in: {}", c, raw);
+            return Err(());
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn take_word<I: Iterator<Item = char>>(chars: &mut ::std::iter::Peekable<I>) -> String {
+    let mut word = String::new();
+    while let Some(&c) = chars
+        .peek()
+        .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+    {
+        word.push(c);
+        chars.next();
+    }
+    word
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn statement(&mut self) -> Result<query::Query, ()> {
+        let keyword = self.word()?.to_lowercase();
+
+        match &keyword[..] {
+            "create" if self.eat_keyword("table") => self.create_table(),
+            "create" if self.eat_keyword("index") => self.create_index(),
+            "create" if self.eat_keyword("user") => {
+                let name = self.word()?;
+                self.eat_keyword("with");
+                self.keyword("password")?;
+                let password = match self.next() {
+                    Some(Token::Str(password)) => password,
+                    _ => return self.fail("password"),
+                };
+                Ok(query::Query::Auth(query::AuthQuery::CreateUser {
+                    name,
+                    password,
+                }))
+            }
+            "create" if self.eat_keyword("token") => {
+                Ok(query::Query::Auth(query::AuthQuery::CreateToken))
+            }
+            "drop" => {
+                self.keyword("user")?;
+                let name = self.word()?;
+                Ok(query::Query::Auth(query::AuthQuery::DropUser(name)))
+            }
+            "grant" | "revoke" => self.grant(&keyword),
+            "insert" => self.insert(),
+            "select" => self.select(),
+            "delete" => self.delete(),
+            "begin" => {
+                self.eat_keyword("transaction");
+                Ok(query::Query::Transaction(query::TransactionQuery::Begin))
+            }
+            "start" => {
+                self.keyword("transaction")?;
+                Ok(query::Query::Transaction(query::TransactionQuery::Begin))
+            }
+            "commit" => Ok(query::Query::Transaction(query::TransactionQuery::Commit)),
+            "rollback" => Ok(query::Query::Transaction(query::TransactionQuery::Rollback)),
+            "set" => {
+                self.keyword("dialect")?;
+                self.eat_symbol('=');
+                let raw = match self.next() {
+                    Some(Token::Word(raw)) | Some(Token::Str(raw)) => raw,
+                    _ => return self.fail("dialect"),
+                };
+                match query::Dialect::from(&raw) {
+                    Some(dialect) => Ok(query::Query::SetDialect(dialect)),
+                    None => {
+                        error!("Unknown dialect: {}", raw);
+                        Err(())
+                    }
+                }
+            }
+            "declare" => self.declare(),
+            "fetch" => self.fetch(),
+            "close" => {
+                let name = self.word()?;
+                Ok(query::Query::Cursor(query::CursorQuery::Close(name)))
+            }
+            _ => {
+                error!("Unknown statement: {}", keyword);
+                Err(())
+            }
+        }
+    }
+
+    fn create_table(&mut self) -> Result<query::Query, ()> {
+        let table_name = self.word()?;
+        let mut fields: Vec<query::FieldDef> = vec![];
+        let mut indices: Vec<String> = vec![];
+        let mut constraints: query::Constraints = Default::default();
+        let mut primary_key: Option<String> = None;
+
+        self.symbol('(')?;
+        loop {
+            if self.eat_keyword("index") {
+                indices.extend(self.name_list()?);
+            } else if self.eat_keyword("primary") {
+                self.keyword("key")?;
+                let fields = self.name_list()?;
+                if fields.len() != 1 {
+                    error!("Primary keys cover a single column: {:?}", fields);
+                    return Err(());
+                }
+                set_primary_key(&mut primary_key, &fields[0])?;
+            } else if self.eat_keyword("check") {
+                constraints.checks.push(self.check()?);
+            } else if self.eat_keyword("foreign") {
+                self.keyword("key")?;
+                self.symbol('(')?;
+                let field_name = self.word()?;
+                self.symbol(')')?;
+                self.keyword("references")?;
+                let (table, column) = self.reference()?;
+                constraints
+                    .foreign_keys
+                    .push(query::ForeignKey::new(field_name, table, column));
+            } else {
+                fields.push(self.column(&mut constraints, &mut primary_key)?);
+            }
+
+            if !self.eat_symbol(',') {
+                break;
+            }
+        }
+        self.symbol(')')?;
+
+        let mut storage: query::Storage = Default::default();
+        if self.eat_keyword("using") {
+            let name = self.word()?;
+            storage = match query::Storage::from(&name) {
+                Some(storage) => storage,
+                None => {
+                    error!("Unknown storage, expected ROWS, LSM or COLUMNAR: {}", name);
+                    return Err(());
+                }
+            };
+        }
+
+        if fields.is_empty() {
+            error!("Table must have columns: {}", table_name);
+            return Err(());
+        }
+        if fields.iter().filter(|field| field.auto_increment).count() > 1 {
+            error!("Only one auto_increment field is allowed per table.");
+            return Err(());
+        }
+
+        let mut create_query = query::CreateQuery::new(table_name, fields, indices, constraints);
+        create_query.primary_key = primary_key;
+        create_query.storage = storage;
+        Ok(query::Query::Create(create_query))
+    }
+
+    fn column(
+        &mut self,
+        constraints: &mut query::Constraints,
+        primary_key: &mut Option<String>,
+    ) -> Result<query::FieldDef, ()> {
+        let field_name = self.word()?;
+        let type_name = self.word()?.to_lowercase();
+        let data_type = match &type_name[..] {
+            "int" | "integer" => query::Type::Int,
+            "varchar" => {
+                self.symbol('(')?;
+                let size = match self.next() {
+                    Some(Token::Number(n)) => n.parse::<u8>().map_err(|e| {
+                        error!("Cannot read varchar size: {:?}", e);
+                    })?,
+                    _ => return self.fail("varchar size"),
+                };
+                self.symbol(')')?;
+                query::Type::Varchar(size)
+            }
+            other => {
+                error!("Unknown type: {}", other);
+                return Err(());
+            }
+        };
+
+        let mut field_def = query::FieldDef::new(field_name, data_type);
+        loop {
+            if self.eat_keyword("default") {
+                let literal = self.value()?;
+                if field_def.config == query::Type::Int && literal.parse::<u32>().is_err() {
+                    error!("Default value is not an int: {}", literal);
+                    return Err(());
+                }
+                field_def.default = Some(literal);
+            } else if self.eat_keyword("auto_increment") {
+                if field_def.config != query::Type::Int {
+                    error!("Only int fields can be auto_increment: {}", field_def.name);
+                    return Err(());
+                }
+                field_def.auto_increment = true;
+            } else if self.eat_keyword("primary") {
+                self.keyword("key")?;
+                set_primary_key(primary_key, &field_def.name)?;
+            } else if self.eat_keyword("check") {
+                constraints.checks.push(self.check()?);
+            } else if self.eat_keyword("references") {
+                let (table, column) = self.reference()?;
+                constraints.foreign_keys.push(query::ForeignKey::new(
+                    field_def.name.clone(),
+                    table,
+                    column,
+                ));
+            } else {
+                return Ok(field_def);
+            }
+        }
+    }
+
+    fn create_index(&mut self) -> Result<query::Query, ()> {
+        // Indices are known by their column, the name is optional and unused.
+        if !self.eat_keyword("on") {
+            self.word()?;
+            self.keyword("on")?;
+        }
+        let table_name = self.word()?;
+        let fields = self.name_list()?;
+        if fields.len() != 1 {
+            error!("Indices cover a single column: {:?}", fields);
+            return Err(());
+        }
+
+        Ok(query::Query::CreateIndex(query::CreateIndexQuery::new(
+            table_name,
+            fields.into_iter().next().unwrap(),
+        )))
+    }
+
+    fn insert(&mut self) -> Result<query::Query, ()> {
+        self.keyword("into")?;
+        let table_name = self.word()?;
+        let fields = self.name_list()?;
+        self.keyword("values")?;
+
+        self.symbol('(')?;
+        let mut values = vec![self.value()?];
+        while self.eat_symbol(',') {
+            values.push(self.value()?);
+        }
+        self.symbol(')')?;
+
+        if fields.len() != values.len() {
+            error!(
+                "Insert has {} columns but {} values",
+                fields.len(),
+                values.len()
+            );
+            return Err(());
+        }
+
+        let raw_inserts: HashMap<String, String> = fields.into_iter().zip(values).collect();
+        Ok(query::Query::Insert(query::InsertQuery::new(
+            table_name,
+            raw_inserts,
+        )))
+    }
+
+    fn select(&mut self) -> Result<query::Query, ()> {
+        if self.eat_symbol('*') {
+            error!("Select needs the list of columns, * is not supported");
+            return Err(());
+        }
+
+        let mut columns = vec![self.word()?];
+        while self.eat_symbol(',') {
+            columns.push(self.word()?);
+        }
+        self.keyword("from")?;
+        let table = self.word()?;
+        let conditions = self.conditions()?;
+
+        Ok(query::Query::Select(query::SelectQuery::new(
+            table, columns, conditions,
+        )))
+    }
+
+    fn declare(&mut self) -> Result<query::Query, ()> {
+        let name = self.word()?;
+        self.keyword("cursor")?;
+        self.keyword("for")?;
+        self.keyword("select")?;
+
+        match self.select()? {
+            query::Query::Select(select) => Ok(query::Query::Cursor(query::CursorQuery::Declare {
+                name,
+                select,
+            })),
+            _ => Err(()),
+        }
+    }
+
+    // FETCH [FORWARD] [NEXT | count] [FROM | IN] name, a single row unless counted.
+    fn fetch(&mut self) -> Result<query::Query, ()> {
+        self.eat_keyword("forward");
+        let count = if self.eat_keyword("next") {
+            1
+        } else if let Some(Token::Number(raw)) = self.peek().cloned() {
+            self.pos += 1;
+            raw.parse().or_else(|_| self.fail("row count"))?
+        } else {
+            1
+        };
+        if !self.eat_keyword("from") {
+            self.eat_keyword("in");
+        }
+        let name = self.word()?;
+
+        Ok(query::Query::Cursor(query::CursorQuery::Fetch {
+            name,
+            count,
+        }))
+    }
+
+    // GRANT privileges ON [TABLE] table TO user, or REVOKE ... FROM user. The table can be `*`.
+    fn grant(&mut self, keyword: &str) -> Result<query::Query, ()> {
+        let privileges = if self.eat_keyword("all") {
+            self.eat_keyword("privileges");
+            query::Privilege::all()
+        } else {
+            let mut privileges = vec![self.privilege()?];
+            while self.eat_symbol(',') {
+                privileges.push(self.privilege()?);
+            }
+            privileges
+        };
+        self.keyword("on")?;
+        self.eat_keyword("table");
+        let table = if self.eat_symbol('*') {
+            "*".to_owned()
+        } else {
+            self.word()?
+        };
+
+        if keyword == "grant" {
+            self.keyword("to")?;
+            let user = self.word()?;
+            Ok(query::Query::Auth(query::AuthQuery::Grant {
+                privileges,
+                table,
+                user,
+            }))
+        } else {
+            self.keyword("from")?;
+            let user = self.word()?;
+            Ok(query::Query::Auth(query::AuthQuery::Revoke {
+                privileges,
+                table,
+                user,
+            }))
+        }
+    }
+
+    fn privilege(&mut self) -> Result<query::Privilege, ()> {
+        let raw = self.word()?;
+        match query::Privilege::from(&raw) {
+            Some(privilege) => Ok(privilege),
+            None => self.fail("privilege"),
+        }
+    }
+
+    fn delete(&mut self) -> Result<query::Query, ()> {
+        self.keyword("from")?;
+        let table = self.word()?;
+        let conditions = self.conditions()?;
+
+        Ok(query::Query::Delete(query::DeleteQuery::new(
+            table, conditions,
+        )))
+    }
+
+    fn conditions(&mut self) -> Result<Vec<query::FieldCondition>, ()> {
+        if !self.eat_keyword("where") {
+            return Ok(vec![]);
+        }
+        self.condition_list()
+    }
+
+    // Conditions joined by AND, the only connective the engine knows.
+    fn condition_list(&mut self) -> Result<Vec<query::FieldCondition>, ()> {
+        let mut conditions = vec![self.condition()?];
+        while self.eat_keyword("and") {
+            conditions.push(self.condition()?);
+        }
+        Ok(conditions)
+    }
+
+    fn condition(&mut self) -> Result<query::FieldCondition, ()> {
+        let field_name = self.word()?;
+        let relation = match self.next() {
+            Some(Token::Symbol(c)) if "=<>".contains(c) => c.to_string(),
+            _ => return self.fail("=, < or >"),
+        };
+        let value = self.value()?;
+
+        Ok(query::FieldCondition::new(field_name, relation, value))
+    }
+
+    fn check(&mut self) -> Result<Vec<query::FieldCondition>, ()> {
+        self.symbol('(')?;
+        let conditions = self.condition_list()?;
+        self.symbol(')')?;
+        Ok(conditions)
+    }
+
+    // `TABLE(COLUMN)`
+    fn reference(&mut self) -> Result<(String, String), ()> {
+        let table = self.word()?;
+        self.symbol('(')?;
+        let column = self.word()?;
+        self.symbol(')')?;
+        Ok((table, column))
+    }
+
+    // `(NAME, ...)`
+    fn name_list(&mut self) -> Result<Vec<String>, ()> {
+        self.symbol('(')?;
+        let mut names = vec![self.word()?];
+        while self.eat_symbol(',') {
+            names.push(self.word()?);
+        }
+        self.symbol(')')?;
+        Ok(names)
+    }
+
+    // A literal or a placeholder, as the raw value TQL would have.
+    fn value(&mut self) -> Result<String, ()> {
+        match self.next() {
+            Some(Token::Number(raw)) | Some(Token::Str(raw)) | Some(Token::Placeholder(raw)) => {
+                Ok(raw)
+            }
+            _ => self.fail("value"),
+        }
+    }
+
+    fn word(&mut self) -> Result<String, ()> {
+        match self.next() {
+            Some(Token::Word(word)) => Ok(word),
+            _ => self.fail("name"),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), ()> {
+        if self.eat_keyword(keyword) {
+            return Ok(());
+        }
+        self.pos += 1;
+        self.fail(keyword)
+    }
+
+    fn symbol(&mut self, symbol: char) -> Result<(), ()> {
+        if self.eat_symbol(symbol) {
+            return Ok(());
+        }
+        self.pos += 1;
+        self.fail(&symbol.to_string())
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {}
+            _ => return false,
+        }
+        self.pos += 1;
+        true
+    }
+
+    fn eat_symbol(&mut self, symbol: char) -> bool {
+        if self.peek() != Some(&Token::Symbol(symbol)) {
+            return false;
+        }
+        self.pos += 1;
+        true
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    // Logs what was expected instead of the last token.
+    fn fail<T>(&self, expected: &str) -> Result<T, ()> {
+        match self.tokens.get(self.pos - 1) {
+            Some(token) => error!("Expected {}, found {:?}", expected, token),
+            None => error!("Expected {}, found the end of the statement", expected),
+        }
+        Err(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_table() {
+        let query = parse(
+            "CREATE TABLE booking (
+                id INT AUTO_INCREMENT,
+                user_id integer REFERENCES users(id) CHECK (user_id > 0),
+                book VARCHAR(20) DEFAULT 'It''s new',
+                INDEX (id, user_id),
+                CHECK (id < 1000 AND book = 'x'),
+                FOREIGN KEY (book) REFERENCES books(title)
+            );",
+        )
+        .unwrap();
+
+        if let query::Query::Create(q) = query {
+            assert_eq!("booking", q.table);
+            assert_eq!(3, q.fields.len());
+            assert!(q.fields[0].auto_increment);
+            assert_eq!(query::Type::Int, q.fields[1].config);
+            assert_eq!(query::Type::Varchar(20), q.fields[2].config);
+            assert_eq!(Some("It's new".to_owned()), q.fields[2].default);
+            assert_eq!(vec!["id".to_owned(), "user_id".to_owned()], q.indices);
+            assert_eq!(2, q.constraints.checks.len());
+            assert_eq!(2, q.constraints.checks[1].len());
+            assert_eq!("books", q.constraints.foreign_keys[1].table);
+            assert_eq!("title", q.constraints.foreign_keys[1].column);
+        } else {
+            panic!("Query is not create query.");
+        }
+    }
+
+    #[test]
+    fn test_create_table_with_storage() {
+        for raw in &[
+            "CREATE TABLE events (id INT PRIMARY KEY, kind INT) USING LSM",
+            "create table events (id int, kind int, primary key (id)) using lsm;",
+        ] {
+            if let query::Query::Create(q) = parse(raw).unwrap() {
+                assert_eq!(Some("id".to_owned()), q.primary_key);
+                assert_eq!(query::Storage::Lsm, q.storage);
+            } else {
+                panic!("Query is not create query.");
+            }
+        }
+
+        if let query::Query::Create(q) = parse("CREATE TABLE t (id INT) USING COLUMNAR").unwrap() {
+            assert_eq!(query::Storage::Columnar, q.storage);
+        } else {
+            panic!("Query is not create query.");
+        }
+        assert!(parse("CREATE TABLE t (id INT) USING PAGED").is_err());
+        assert!(parse("CREATE TABLE t (id INT PRIMARY KEY, no INT PRIMARY KEY)").is_err());
+        assert!(parse("CREATE TABLE t (id INT, PRIMARY KEY (id, id))").is_err());
+    }
+
+    #[test]
+    fn test_create_index() {
+        for raw in &[
+            "CREATE INDEX ON users (age)",
+            "create index users_age on users(age)",
+        ] {
+            if let query::Query::CreateIndex(q) = parse(raw).unwrap() {
+                assert_eq!("users", q.table);
+                assert_eq!("age", q.field);
+            } else {
+                panic!("Query is not create index query.");
+            }
+        }
+
+        assert!(parse("CREATE INDEX ON users (age, name)").is_err());
+    }
+
+    #[test]
+    fn test_insert() {
+        let query = parse("INSERT INTO users (id, name) VALUES (1, 'Ann Lee')").unwrap();
+
+        if let query::Query::Insert(q) = query {
+            assert_eq!("users", q.table_name);
+            assert_eq!(Some(&"1".to_owned()), q.raw_inserts.get("id"));
+            assert_eq!(Some(&"Ann Lee".to_owned()), q.raw_inserts.get("name"));
+        } else {
+            panic!("Query is not insert query.");
+        }
+
+        assert!(parse("INSERT INTO users (id, name) VALUES (1)").is_err());
+        assert!(parse("INSERT INTO users VALUES (1)").is_err());
+    }
+
+    #[test]
+    fn test_select_and_delete() {
+        let query = parse("select id, name from users where id > 1 and name = ?1").unwrap();
+
+        if let query::Query::Select(q) = query {
+            assert_eq!("users", q.table);
+            assert_eq!(vec!["id".to_owned(), "name".to_owned()], q.columns);
+            let conditions: Vec<(&str, &str, &str)> = q
+                .conditions
+                .iter()
+                .map(|c| (&c.field_name[..], &c.relation[..], &c.value[..]))
+                .collect();
+            assert_eq!(vec![("id", ">", "1"), ("name", "=", "?1")], conditions);
+        } else {
+            panic!("Query is not select query.");
+        }
+
+        if let query::Query::Delete(q) = parse("DELETE FROM users").unwrap() {
+            assert_eq!("users", q.table);
+            assert!(q.conditions.is_empty());
+        } else {
+            panic!("Query is not delete query.");
+        }
+    }
+
+    #[test]
+    fn test_transactions_and_dialect() {
+        assert!(matches!(
+            parse("BEGIN").unwrap(),
+            query::Query::Transaction(query::TransactionQuery::Begin)
+        ));
+        assert!(matches!(
+            parse("start transaction;").unwrap(),
+            query::Query::Transaction(query::TransactionQuery::Begin)
+        ));
+        assert!(matches!(
+            parse("ROLLBACK").unwrap(),
+            query::Query::Transaction(query::TransactionQuery::Rollback)
+        ));
+        assert!(matches!(
+            parse("SET DIALECT = 'tql'").unwrap(),
+            query::Query::SetDialect(query::Dialect::Tql)
+        ));
+    }
+
+    #[test]
+    fn test_cursors() {
+        match parse("DECLARE export CURSOR FOR SELECT id FROM users WHERE id > 3").unwrap() {
+            query::Query::Cursor(query::CursorQuery::Declare { name, select }) => {
+                assert_eq!("export", name);
+                assert_eq!("users", select.table);
+                assert_eq!(1, select.conditions.len());
+            }
+            _ => panic!("Query is not a cursor declaration."),
+        }
+        for (raw, expected) in &[
+            ("FETCH 100 FROM export", 100),
+            ("FETCH FORWARD 5 IN export", 5),
+            ("FETCH NEXT FROM export", 1),
+            ("fetch from export", 1),
+            ("FETCH export", 1),
+        ] {
+            match parse(raw).unwrap() {
+                query::Query::Cursor(query::CursorQuery::Fetch { name, count }) => {
+                    assert_eq!("export", name);
+                    assert_eq!(*expected, count);
+                }
+                _ => panic!("Query is not a fetch."),
+            }
+        }
+        assert!(parse("FETCH 5").is_err());
+        assert!(matches!(
+            parse("CLOSE export").unwrap(),
+            query::Query::Cursor(query::CursorQuery::Close(ref name)) if name == "export"
+        ));
+    }
+
+    #[test]
+    fn test_users_and_grants() {
+        assert!(matches!(
+            parse("CREATE USER ann WITH PASSWORD 'it''s secret'").unwrap(),
+            query::Query::Auth(query::AuthQuery::CreateUser { ref name, ref password })
+                if name == "ann" && password == "it's secret"
+        ));
+        assert!(matches!(
+            parse("DROP USER ann").unwrap(),
+            query::Query::Auth(query::AuthQuery::DropUser(ref name)) if name == "ann"
+        ));
+        assert!(matches!(
+            parse("CREATE TOKEN").unwrap(),
+            query::Query::Auth(query::AuthQuery::CreateToken)
+        ));
+
+        match parse("GRANT select, ddl ON TABLE users TO ann").unwrap() {
+            query::Query::Auth(query::AuthQuery::Grant {
+                privileges,
+                table,
+                user,
+            }) => {
+                assert_eq!(
+                    vec![query::Privilege::Select, query::Privilege::Ddl],
+                    privileges
+                );
+                assert_eq!("users", table);
+                assert_eq!("ann", user);
+            }
+            _ => panic!("Query is not a grant."),
+        }
+        assert!(matches!(
+            parse("REVOKE ALL PRIVILEGES ON * FROM ann").unwrap(),
+            query::Query::Auth(query::AuthQuery::Revoke { ref table, .. }) if table == "*"
+        ));
+        assert!(parse("GRANT truncate ON users TO ann").is_err());
+        assert!(parse("CREATE USER ann PASSWORD secret").is_err());
+    }
+
+    #[test]
+    fn test_invalid_statements_fail() {
+        assert!(parse("").is_err());
+        assert!(parse("SELECT * FROM users").is_err());
+        assert!(parse("SELECT id FROM users WHERE id != 1").is_err());
+        assert!(parse("SELECT id FROM users WHERE id = 1 OR id = 2").is_err());
+        assert!(parse("SELECT id FROM users; SELECT id FROM users").is_err());
+        assert!(parse("CREATE TABLE users (id FLOAT)").is_err());
+        assert!(parse("CREATE TABLE users (name VARCHAR(300))").is_err());
+        assert!(parse("CREATE TABLE users (name VARCHAR(8) AUTO_INCREMENT)").is_err());
+        assert!(parse("INSERT INTO users (name) VALUES ('Ann)").is_err());
+        assert!(parse("SET DIALECT cobol").is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use query;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use util;
+
+// Upper bound of statements prepared at once, so forgotten handles can't grow the cache forever.
+const MAX_STATEMENTS: usize = 10_000;
+
+/// Parameters of a statement: a list for `?N` placeholders, or an object for `$name` ones.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Params {
+    Positional(Vec<Value>),
+    Named(Map<String, Value>),
+}
+
+impl Default for Params {
+    fn default() -> Params {
+        Params::Positional(vec![])
+    }
+}
+
+impl Params {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Params::Positional(params) => params.is_empty(),
+            Params::Named(params) => params.is_empty(),
+        }
+    }
+
+    fn get(&self, placeholder: &query::Placeholder) -> Option<&Value> {
+        match (self, placeholder) {
+            (Params::Positional(params), query::Placeholder::Position(position)) => {
+                params.get(position - 1)
+            }
+            (Params::Named(params), query::Placeholder::Name(name)) => params.get(name),
+            _ => None,
+        }
+    }
+}
+
+/// Fills the placeholders of the statement with the params, each checked against the type of
+/// the column it is compared to or inserted into.
+pub fn bind<F>(
+    prepared: &query::PreparedQuery,
+    params: &Params,
+    column_type: F,
+) -> Result<query::Query, String>
+where
+    F: Fn(&str, &str) -> Option<query::Type>,
+{
+    let expected: HashSet<&query::Placeholder> = prepared
+        .placeholders
+        .iter()
+        .map(|(_, placeholder)| placeholder)
+        .collect();
+    let given = match params {
+        Params::Positional(params) => params.len(),
+        Params::Named(params) => params.len(),
+    };
+    if given != expected.len() {
+        return Err(format!(
+            "Statement takes {} params, got {}",
+            expected.len(),
+            given
+        ));
+    }
+
+    let mut query = prepared.query.clone();
+    let table_name = prepared.query.table().unwrap_or_default();
+
+    for (slot, placeholder) in &prepared.placeholders {
+        let param = params
+            .get(placeholder)
+            .ok_or_else(|| format!("Missing param {}", placeholder_name(placeholder)))?;
+
+        let field_name = prepared.query.slot_field(slot).unwrap_or_default();
+        let data_type = column_type(table_name, field_name)
+            .ok_or_else(|| format!("Unknown column {}.{}", table_name, field_name))?;
+        let raw = raw_param(param, &data_type).ok_or_else(|| {
+            format!(
+                "Param {} is not a valid {:?}: {}",
+                placeholder_name(placeholder),
+                data_type,
+                param
+            )
+        })?;
+
+        query.set_slot(slot, raw);
+    }
+
+    Ok(query)
+}
+
+// The param as a raw TQL value, if it fits the column.
+fn raw_param(param: &Value, data_type: &query::Type) -> Option<String> {
+    let raw = match param {
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.clone(),
+        _ => return None,
+    };
+
+    // TQL values are space separated, so they can't contain one.
+    if raw.is_empty() || raw.contains(char::is_whitespace) {
+        return None;
+    }
+
+    match util::Val::from(raw.clone(), data_type)? {
+        // Varchars are truncated to the column size, too long values are rejected instead.
+        util::Val::Varchar(ref s) if *s != raw => None,
+        _ => Some(raw),
+    }
+}
+
+fn placeholder_name(placeholder: &query::Placeholder) -> String {
+    match placeholder {
+        query::Placeholder::Position(position) => format!("?{}", position),
+        query::Placeholder::Name(name) => format!("${}", name),
+    }
+}
+
+// User and session a statement was prepared in, and its handle.
+type StatementKey = (Option<String>, String, u64);
+
+/// Parsed statements of every connection of the server. A statement is only found by the user
+/// and session that prepared it.
+#[derive(Debug, Default)]
+pub struct StatementCache {
+    last_handle: AtomicU64,
+    statements: Mutex<HashMap<StatementKey, Arc<query::PreparedQuery>>>,
+}
+
+impl StatementCache {
+    pub fn insert(
+        &self,
+        user: Option<&str>,
+        session: &str,
+        prepared: query::PreparedQuery,
+    ) -> Result<u64, ()> {
+        let mut statements = self.statements.lock().unwrap();
+        if statements.len() >= MAX_STATEMENTS {
+            warn!("Too many prepared statements");
+            return Err(());
+        }
+
+        let handle = self.last_handle.fetch_add(1, Ordering::SeqCst) + 1;
+        statements.insert(key(user, session, handle), Arc::new(prepared));
+        Ok(handle)
+    }
+
+    pub fn get(
+        &self,
+        user: Option<&str>,
+        session: &str,
+        handle: u64,
+    ) -> Option<Arc<query::PreparedQuery>> {
+        let statements = self.statements.lock().unwrap();
+        statements.get(&key(user, session, handle)).cloned()
+    }
+
+    pub fn remove(&self, user: Option<&str>, session: &str, handle: u64) -> bool {
+        let mut statements = self.statements.lock().unwrap();
+        statements.remove(&key(user, session, handle)).is_some()
+    }
+
+    /// Drops the statements of a session that went away.
+    pub fn end_session(&self, user: Option<&str>, session: &str) {
+        self.statements
+            .lock()
+            .unwrap()
+            .retain(|(owner, id, _), _| owner.as_deref() != user || id != session);
+    }
+}
+
+fn key(user: Option<&str>, session: &str, handle: u64) -> StatementKey {
+    (user.map(str::to_owned), session.to_owned(), handle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use query_parser::QueryParser;
+    use serde_json;
+
+    fn column_type(_table_name: &str, column_name: &str) -> Option<query::Type> {
+        match column_name {
+            "id" => Some(query::Type::Int),
+            "name" => Some(query::Type::Varchar(4)),
+            _ => None,
+        }
+    }
+
+    fn params(json: &str) -> Params {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_bind_positional_params() {
+        let prepared = QueryParser.prepare("> users id ?1 name ?2").unwrap();
+        let query = bind(&prepared, &params(r#"[7, "Ann"]"#), column_type).unwrap();
+
+        if let query::Query::Insert(q) = query {
+            assert_eq!(Some(&"7".to_owned()), q.raw_inserts.get("id"));
+            assert_eq!(Some(&"Ann".to_owned()), q.raw_inserts.get("name"));
+        } else {
+            panic!("Query is not insert query.");
+        }
+    }
+
+    #[test]
+    fn test_bind_named_params() {
+        let prepared = QueryParser
+            .prepare("? name > users : id > $id id < $max")
+            .unwrap();
+        let query = bind(&prepared, &params(r#"{"id": 1, "max": "9"}"#), column_type).unwrap();
+
+        let values: Vec<&str> = query.conditions().iter().map(|c| &c.value[..]).collect();
+        assert_eq!(vec!["1", "9"], values);
+    }
+
+    #[test]
+    fn test_bind_rejects_params_not_fitting_the_columns() {
+        let prepared = QueryParser.prepare("> users id ?1 name ?2").unwrap();
+
+        assert!(bind(&prepared, &params(r#"[1]"#), column_type).is_err());
+        assert!(bind(&prepared, &params(r#"[1, "Ann", 3]"#), column_type).is_err());
+        assert!(bind(
+            &prepared,
+            &params(r#"{"id": 1, "name": "Ann"}"#),
+            column_type
+        )
+        .is_err());
+        assert!(bind(&prepared, &params(r#"["x", "Ann"]"#), column_type).is_err());
+        assert!(bind(&prepared, &params(r#"[-1, "Ann"]"#), column_type).is_err());
+        assert!(bind(&prepared, &params(r#"[1, "Annabel"]"#), column_type).is_err());
+        assert!(bind(&prepared, &params(r#"[1, "A b"]"#), column_type).is_err());
+        assert!(bind(&prepared, &params(r#"[1, null]"#), column_type).is_err());
+    }
+
+    #[test]
+    fn test_statement_cache() {
+        let cache: StatementCache = Default::default();
+        let prepare = |user, session| {
+            let prepared = QueryParser.prepare("? id > users").unwrap();
+            cache.insert(user, session, prepared).unwrap()
+        };
+        let first = prepare(Some("ann"), "a");
+        let second = prepare(Some("ann"), "a");
+        let third = prepare(Some("ann"), "b");
+
+        assert_ne!(first, second);
+        assert!(cache.get(Some("ann"), "a", first).is_some());
+        assert!(cache.remove(Some("ann"), "a", first));
+        assert!(!cache.remove(Some("ann"), "a", first));
+        assert!(cache.get(Some("ann"), "a", first).is_none());
+        assert!(cache.get(Some("ann"), "a", second).is_some());
+
+        // Other sessions and users don't find them.
+        assert!(cache.get(Some("ann"), "b", second).is_none());
+        assert!(cache.get(Some("bob"), "a", second).is_none());
+        assert!(cache.get(None, "a", second).is_none());
+        assert!(!cache.remove(Some("bob"), "a", second));
+
+        cache.end_session(Some("ann"), "a");
+        assert!(cache.get(Some("ann"), "a", second).is_none());
+        assert!(cache.get(Some("ann"), "b", third).is_some());
+    }
+}
@@ -0,0 +1,89 @@
+use auth;
+use engine;
+use std::collections::HashMap;
+use std::fmt;
+use std::iter;
+
+/// Rows of a table as they're read, in the order they were written.
+pub type RowScan<'a> = Box<dyn Iterator<Item = Result<engine::Row, ()>> + 'a>;
+
+/// Where the tables and the system catalog are kept between runs. Only committed rows are
+/// handed to the backend, and the engine rebuilds its indices from them.
+pub trait StorageBackend: fmt::Debug + Send + Sync {
+    /// The tables with their schema, indices, constraints and sequence, but no rows.
+    fn read_tables(&self) -> Result<HashMap<String, engine::Table>, ()>;
+
+    /// Writes everything about the table but its rows.
+    fn write_table(&self, name: &str, table: &engine::Table) -> Result<(), ()>;
+
+    /// Adds rows after the ones the table has.
+    fn append_rows(&self, name: &str, rows: &[&engine::Row]) -> Result<(), ()>;
+
+    /// Replaces all the rows of the table, once some were deleted. Not for tables kept in key
+    /// order, their rows are deleted by key instead.
+    fn write_rows(&self, name: &str, rows: &[&engine::Row]) -> Result<(), ()>;
+
+    /// Removes rows by their primary key. Only for tables kept in key order, the others are
+    /// rewritten instead.
+    fn delete_rows(&self, name: &str, rows: &[&engine::Row]) -> Result<(), ()>;
+
+    /// The row with the primary key. Only for tables kept in key order.
+    fn read_row(&self, name: &str, key: &[u8]) -> Result<Option<engine::Row>, ()>;
+
+    fn scan(&self, name: &str) -> Result<RowScan<'_>, ()>;
+
+    /// Rows with only the given columns read, the others left zeroed. None unless the table is
+    /// kept by column, its rows are then read whole.
+    fn scan_columns(&self, name: &str, columns: &[&str]) -> Result<Option<RowScan<'_>>, ()>;
+
+    fn read_catalog(&self) -> Result<auth::Catalog, ()>;
+
+    fn write_catalog(&self, catalog: &auth::Catalog) -> Result<(), ()>;
+}
+
+/// Keeps nothing: the engine holds the tables, and they're gone with it. For tests and
+/// throwaway databases.
+#[derive(Debug, Default)]
+pub struct MemoryBackend;
+
+impl StorageBackend for MemoryBackend {
+    fn read_tables(&self) -> Result<HashMap<String, engine::Table>, ()> {
+        Ok(HashMap::new())
+    }
+
+    fn write_table(&self, _name: &str, _table: &engine::Table) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn append_rows(&self, _name: &str, _rows: &[&engine::Row]) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn write_rows(&self, _name: &str, _rows: &[&engine::Row]) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn delete_rows(&self, _name: &str, _rows: &[&engine::Row]) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn read_row(&self, _name: &str, _key: &[u8]) -> Result<Option<engine::Row>, ()> {
+        Ok(None)
+    }
+
+    fn scan(&self, _name: &str) -> Result<RowScan<'_>, ()> {
+        Ok(Box::new(iter::empty()))
+    }
+
+    fn scan_columns(&self, _name: &str, _columns: &[&str]) -> Result<Option<RowScan<'_>>, ()> {
+        Ok(None)
+    }
+
+    fn read_catalog(&self) -> Result<auth::Catalog, ()> {
+        Ok(Default::default())
+    }
+
+    fn write_catalog(&self, _catalog: &auth::Catalog) -> Result<(), ()> {
+        Ok(())
+    }
+}
@@ -1,18 +1,157 @@
+use auth;
+use columnar;
 use engine;
+use lsm;
+use query;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::iter;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use storage;
 
-#[derive(Debug, Default)]
-pub struct TableSyncer {}
+// Name of the system catalog files, which no table file uses.
+const SYSTEM_CATALOG: &str = "_system";
+
+/// Keeps each table in files of the data directory: its schema, rows, indexed fields,
+/// constraints, sequence, primary key and storage. The rows of LSM and columnar tables are in a
+/// directory of their own.
+#[derive(Debug)]
+pub struct TableSyncer {
+    data_dir: PathBuf,
+    stores: Mutex<HashMap<String, Arc<TableStore>>>,
+}
+
+// Rows kept some other way than one after the other in a file.
+#[derive(Debug)]
+enum TableStore {
+    Keyed(KeyedTable),
+    Columns(columnar::ColumnStore),
+}
+
+// A table kept in an LSM tree, with the column its rows are ordered by.
+#[derive(Debug)]
+struct KeyedTable {
+    tree: lsm::LsmTree,
+    key: engine::ColumnInfo,
+}
+
+impl KeyedTable {
+    fn entry(&self, row: &engine::Row, is_deleted: bool) -> (lsm::Key, Option<engine::Row>) {
+        let value = if is_deleted { None } else { Some(row.clone()) };
+        (self.key.sort_key(row), value)
+    }
+}
+
+impl Default for TableSyncer {
+    fn default() -> TableSyncer {
+        TableSyncer::new("./db/")
+    }
+}
 
 impl TableSyncer {
-    pub fn read_tables(&self) -> Result<HashMap<String, engine::Table>, ()> {
+    pub fn new<P: Into<PathBuf>>(data_dir: P) -> TableSyncer {
+        TableSyncer {
+            data_dir: data_dir.into(),
+            stores: Default::default(),
+        }
+    }
+
+    fn store(&self, name: &str) -> Option<Arc<TableStore>> {
+        self.stores.lock().unwrap().get(name).cloned()
+    }
+
+    // Opens the LSM tree or the column files of the table, unless it has none or they're open
+    // already.
+    fn open_store(&self, name: &str, table: &engine::Table) -> Result<(), ()> {
+        let mut stores = self.stores.lock().unwrap();
+        if stores.contains_key(name) {
+            return Ok(());
+        }
+
+        let store = match table.storage {
+            query::Storage::Rows => return Ok(()),
+            query::Storage::Lsm => {
+                let key = table
+                    .primary_key
+                    .as_ref()
+                    .and_then(|primary_key| table.schema.get(primary_key))
+                    .ok_or_else(|| error!("LSM table has no primary key: {}", name))?;
+                let tree = lsm::LsmTree::open(self.path(name, "lsm"), Default::default())?;
+                TableStore::Keyed(KeyedTable {
+                    tree,
+                    key: key.clone(),
+                })
+            }
+            query::Storage::Columnar => TableStore::Columns(columnar::ColumnStore::open(
+                self.path(name, "columns"),
+                table.schema.clone(),
+            )?),
+        };
+        stores.insert(name.to_owned(), Arc::new(store));
+        Ok(())
+    }
+
+    fn path(&self, name: &str, ext: &str) -> PathBuf {
+        self.data_dir.join(format!("{}.tdb.{}", name, ext))
+    }
+
+    fn read_sequence(&self, name: &str) -> Result<u32, ()> {
+        let mut f = match File::open(self.path(name, "seq")) {
+            Ok(f) => f,
+            // Tables synced before sequences existed start from scratch.
+            Err(_) => return Ok(0),
+        };
+
+        let mut raw: String = String::new();
+        f.read_to_string(&mut raw).map_err(|_| ())?;
+        raw.trim().parse::<u32>().map_err(|_| ())
+    }
+
+    fn write_json<V: Serialize>(&self, name: &str, ext: &str, value: &V) -> Result<(), ()> {
+        let mut f = File::create(self.path(name, ext)).map_err(|_| ())?;
+        let json = serde_json::to_string(value).map_err(|_| ())?;
+        f.write_all(json.as_bytes()).map_err(|_| ())
+    }
+
+    // Rows have the same size, so they're written one after the other without separators.
+    fn row_size(&self, name: &str) -> Result<usize, ()> {
+        let schema: engine::Schema = self.read_json(name, "table")?.ok_or_else(|| {
+            error!("Table cannot be found: {}", name);
+        })?;
+        let table: engine::Table = engine::Table::new_with_schema(schema, vec![]);
+        Ok(table.schema_byte_size())
+    }
+
+    fn read_json<V: DeserializeOwned>(&self, name: &str, ext: &str) -> Result<Option<V>, ()> {
+        let mut f = match File::open(self.path(name, ext)) {
+            Ok(f) => f,
+            Err(_) => return Ok(None),
+        };
+
+        let mut raw: String = String::new();
+        f.read_to_string(&mut raw).map_err(|_| ())?;
+        serde_json::from_str(raw.as_ref()).map(Some).map_err(|_| ())
+    }
+}
+
+impl storage::StorageBackend for TableSyncer {
+    fn read_tables(&self) -> Result<HashMap<String, engine::Table>, ()> {
+        fs::create_dir_all(&self.data_dir).map_err(|e| {
+            error!(
+                "Data directory cannot be created {:?}: {}",
+                self.data_dir, e
+            );
+        })?;
+
         let mut tables: HashMap<String, engine::Table> = HashMap::new();
-        for entry in fs::read_dir("./db/").unwrap() {
+        for entry in fs::read_dir(&self.data_dir).unwrap() {
             let entry = entry.unwrap();
             let file_name: String = entry.file_name().into_string().unwrap();
-            let (base, ext) = dbg!(file_name.split_at(file_name.find(".").unwrap()));
+            let (base, ext) = file_name.split_at(file_name.find(".").unwrap());
             if ext != ".tdb.table" {
                 continue;
             }
@@ -21,36 +160,271 @@ impl TableSyncer {
             let path = path.to_str().unwrap();
 
             let mut raw: String = String::new();
-            let mut f: File = File::open(path.clone()).unwrap();
+            let mut f: File = File::open(path).unwrap();
             f.read_to_string(&mut raw).map_err(|_| ())?;
 
             let table_schema: engine::Schema = serde_json::from_str(raw.as_ref()).unwrap();
-            println!("Schema found for {:?}: {:#?}", path, table_schema);
+            debug!("Schema found for {:?}: {:#?}", path, table_schema);
 
-            let table = engine::Table::new_with_schema(table_schema);
+            let index_fields: Vec<String> = self.read_json(base, "indices")?.unwrap_or_default();
+            let mut table = engine::Table::new_with_schema(table_schema, index_fields);
+            table.sequence = self.read_sequence(base)?;
+            table.constraints = self.read_json(base, "constraints")?.unwrap_or_default();
+            table.primary_key = self.read_json(base, "key")?.unwrap_or_default();
+            table.storage = self.read_json(base, "storage")?.unwrap_or_default();
+            self.open_store(base, &table)?;
             tables.insert(base.into(), table);
         }
 
         Ok(tables)
     }
 
-    pub fn create(&self, name: String, table: &engine::Table) -> Result<(), ()> {
+    fn write_table(&self, name: &str, table: &engine::Table) -> Result<(), ()> {
         // write table def
-        let mut f_table_def = File::create(format!("./db/{}.tdb.table", name)).map_err(|_| ())?;
+        let mut f_table_def = File::create(self.path(name, "table")).map_err(|_| ())?;
         let schema_json = serde_json::to_string(&table.schema).unwrap();
         f_table_def
             .write_all(schema_json.as_bytes())
             .map_err(|_| ())?;
 
-        // write data
-        let mut f_data = File::create(format!("./db/{}.tdb.data", name)).map_err(|_| ())?;
-        for row in &table.data {
-            f_data.write_all(row.as_ref()).map_err(|_| ())?;
-        }
+        // write indices, constraints, primary key and storage
+        self.write_json(name, "indices", &table.index_fields())?;
+        self.write_json(name, "constraints", &table.constraints)?;
+        self.write_json(name, "key", &table.primary_key)?;
+        self.write_json(name, "storage", &table.storage)?;
+        self.open_store(name, table)?;
+
+        // write auto_increment sequence
+        let mut f_seq = File::create(self.path(name, "seq")).map_err(|_| ())?;
+        f_seq
+            .write_all(table.sequence.to_string().as_bytes())
+            .map_err(|_| ())?;
 
         Ok(())
     }
-    // pub fn save(&self, table: &engine::Table) -> bool {
-    //     false
-    // }
+
+    fn append_rows(&self, name: &str, rows: &[&engine::Row]) -> Result<(), ()> {
+        match self.store(name).as_deref() {
+            Some(TableStore::Keyed(keyed)) => {
+                // A key that is there already would have its row overwritten.
+                for row in rows {
+                    if keyed.tree.get(&keyed.key.sort_key(row))?.is_some() {
+                        error!("Rows of {} have the key already", name);
+                        return Err(());
+                    }
+                }
+                return keyed
+                    .tree
+                    .write(rows.iter().map(|row| keyed.entry(row, false)).collect());
+            }
+            Some(TableStore::Columns(columns)) => return columns.append(rows),
+            None => {}
+        }
+
+        let f_data = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(name, "data"))
+            .map_err(|e| error!("Rows of {} cannot be written: {}", name, e))?;
+        write_rows(f_data, rows)
+    }
+
+    fn write_rows(&self, name: &str, rows: &[&engine::Row]) -> Result<(), ()> {
+        match self.store(name).as_deref() {
+            Some(TableStore::Keyed(_)) => {
+                error!("Rows of {} are kept by key, they're deleted by key", name);
+                return Err(());
+            }
+            Some(TableStore::Columns(columns)) => return columns.write(rows),
+            None => {}
+        }
+
+        // Written aside and renamed, so a failed write leaves the old rows.
+        let path = self.path(name, "data");
+        let tmp_path = self.path(name, "data.tmp");
+        let f_data = File::create(&tmp_path)
+            .map_err(|e| error!("Rows of {} cannot be written: {}", name, e))?;
+        write_rows(f_data, rows)?;
+        fs::rename(tmp_path, path).map_err(|e| error!("Rows of {} cannot be written: {}", name, e))
+    }
+
+    fn delete_rows(&self, name: &str, rows: &[&engine::Row]) -> Result<(), ()> {
+        match self.store(name).as_deref() {
+            Some(TableStore::Keyed(keyed)) => keyed
+                .tree
+                .write(rows.iter().map(|row| keyed.entry(row, true)).collect()),
+            _ => {
+                error!("Rows of {} have no key to be deleted by", name);
+                Err(())
+            }
+        }
+    }
+
+    fn read_row(&self, name: &str, key: &[u8]) -> Result<Option<engine::Row>, ()> {
+        match self.store(name).as_deref() {
+            Some(TableStore::Keyed(keyed)) => keyed.tree.get(key),
+            _ => {
+                error!("Rows of {} have no key to be read by", name);
+                Err(())
+            }
+        }
+    }
+
+    fn scan(&self, name: &str) -> Result<storage::RowScan<'_>, ()> {
+        match self.store(name).as_deref() {
+            Some(TableStore::Keyed(keyed)) => {
+                return Ok(Box::new(
+                    keyed.tree.scan().map(|entry| entry.map(|(_, row)| row)),
+                ));
+            }
+            Some(TableStore::Columns(columns)) => {
+                let names: Vec<&str> = columns.names().collect();
+                return Ok(Box::new(columns.scan(&names)?));
+            }
+            None => {}
+        }
+
+        let row_size = self.row_size(name)?;
+        let mut f_data = match File::open(self.path(name, "data")) {
+            Ok(f) => BufReader::new(f),
+            Err(_) => return Ok(Box::new(iter::empty())),
+        };
+
+        let name = name.to_owned();
+        Ok(Box::new(iter::from_fn(move || {
+            let mut row = vec![0; row_size];
+            match f_data.read_exact(&mut row) {
+                Ok(()) => Some(Ok(row)),
+                // A row cut short by a crash while appending is dropped.
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+                Err(e) => {
+                    error!("Rows of {} cannot be read: {}", name, e);
+                    Some(Err(()))
+                }
+            }
+        })))
+    }
+
+    fn scan_columns(
+        &self,
+        name: &str,
+        columns: &[&str],
+    ) -> Result<Option<storage::RowScan<'_>>, ()> {
+        match self.store(name).as_deref() {
+            Some(TableStore::Columns(store)) => Ok(Some(Box::new(store.scan(columns)?))),
+            _ => Ok(None),
+        }
+    }
+
+    fn read_catalog(&self) -> Result<auth::Catalog, ()> {
+        self.read_json(SYSTEM_CATALOG, "users")
+            .map(Option::unwrap_or_default)
+            .map_err(|_| error!("System catalog cannot be read"))
+    }
+
+    fn write_catalog(&self, catalog: &auth::Catalog) -> Result<(), ()> {
+        self.write_json(SYSTEM_CATALOG, "users", catalog)
+            .map_err(|_| error!("System catalog cannot be written"))
+    }
+}
+
+fn write_rows(f_data: File, rows: &[&engine::Row]) -> Result<(), ()> {
+    let mut f_data = BufWriter::new(f_data);
+    for row in rows {
+        f_data.write_all(row).map_err(|_| ())?;
+    }
+    f_data
+        .flush()
+        .map_err(|e| error!("Rows cannot be written: {}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use query;
+    use storage::StorageBackend;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn test_tables_are_synced_to_the_data_dir() {
+        let dir = TempDir::new("sync");
+        let table_syncer = TableSyncer::new(dir.path());
+        assert!(table_syncer.read_tables().unwrap().is_empty());
+
+        let mut table: engine::Table = engine::Table::new(
+            vec![query::FieldDef::new("id".to_owned(), query::Type::Int)],
+            vec!["id".to_owned()],
+        );
+        table.sequence = 7;
+        assert!(table_syncer.write_table("users", &table).is_ok());
+        assert!(dir.path().join("users.tdb.table").exists());
+
+        let tables = table_syncer.read_tables().unwrap();
+        let users = tables.get("users").unwrap();
+        assert_eq!(7, users.sequence);
+        assert_eq!(vec!["id".to_owned()], users.index_fields());
+    }
+
+    #[test]
+    fn test_rows_are_appended_and_rewritten() {
+        let dir = TempDir::new("rows");
+        let table_syncer = TableSyncer::new(dir.path());
+        table_syncer.read_tables().unwrap();
+        let table: engine::Table = engine::Table::new(
+            vec![query::FieldDef::new("id".to_owned(), query::Type::Int)],
+            vec![],
+        );
+        table_syncer.write_table("users", &table).unwrap();
+        let scan = |syncer: &TableSyncer| -> Vec<engine::Row> {
+            syncer.scan("users").unwrap().map(Result::unwrap).collect()
+        };
+        assert!(scan(&table_syncer).is_empty());
+
+        let (first, second, third) = (vec![1, 0, 0, 0], vec![2, 0, 0, 0], vec![3, 0, 0, 0]);
+        table_syncer.append_rows("users", &[&first]).unwrap();
+        table_syncer
+            .append_rows("users", &[&second, &third])
+            .unwrap();
+        assert_eq!(
+            vec![first.clone(), second.clone(), third.clone()],
+            scan(&table_syncer)
+        );
+
+        table_syncer.write_rows("users", &[&first, &third]).unwrap();
+        assert_eq!(vec![first, third], scan(&table_syncer));
+        assert!(table_syncer.read_row("users", &[0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_keyed_rows_are_read_and_deleted_by_key() {
+        let dir = TempDir::new("keyed");
+        let table_syncer = TableSyncer::new(dir.path());
+        table_syncer.read_tables().unwrap();
+        let mut table: engine::Table = engine::Table::new(
+            vec![query::FieldDef::new("id".to_owned(), query::Type::Int)],
+            vec!["id".to_owned()],
+        );
+        table.primary_key = Some("id".to_owned());
+        table.storage = query::Storage::Lsm;
+        table_syncer.write_table("users", &table).unwrap();
+
+        let (first, second) = (vec![1, 0, 0, 0], vec![2, 0, 0, 0]);
+        table_syncer
+            .append_rows("users", &[&first, &second])
+            .unwrap();
+        assert!(table_syncer.append_rows("users", &[&second]).is_err());
+        assert_eq!(
+            Ok(Some(second.clone())),
+            table_syncer.read_row("users", &[0, 0, 0, 2])
+        );
+
+        table_syncer.delete_rows("users", &[&second]).unwrap();
+        assert_eq!(Ok(None), table_syncer.read_row("users", &[0, 0, 0, 2]));
+        assert_eq!(
+            Ok(Some(first)),
+            table_syncer.read_row("users", &[0, 0, 0, 1])
+        );
+        assert!(table_syncer.write_rows("users", &[&second]).is_err());
+        table_syncer.append_rows("users", &[&second]).unwrap();
+    }
 }
@@ -0,0 +1,127 @@
+use native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector};
+use std::fs;
+
+/// Acceptor for the server, from a PEM certificate (or chain) and its PKCS #8 PEM key.
+pub fn acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, ()> {
+    let cert = read(cert_path)?;
+    let key = read(key_path)?;
+    let identity = Identity::from_pkcs8(&cert, &key)
+        .map_err(|e| error!("TLS certificate or key cannot be loaded: {}", e))?;
+
+    TlsAcceptor::new(identity).map_err(|e| error!("TLS cannot be set up: {}", e))
+}
+
+/// Connector for clients. Besides the system roots, it trusts the PEM certificate given, so
+/// servers with a self-signed one can be reached.
+pub fn connector(ca_cert_path: Option<&str>) -> Result<TlsConnector, ()> {
+    let mut builder = TlsConnector::builder();
+    if let Some(path) = ca_cert_path {
+        let cert = Certificate::from_pem(&read(path)?)
+            .map_err(|e| error!("CA certificate cannot be loaded: {}", e))?;
+        builder.add_root_certificate(cert);
+    }
+
+    builder
+        .build()
+        .map_err(|e| error!("TLS cannot be set up: {}", e))
+}
+
+fn read(path: &str) -> Result<Vec<u8>, ()> {
+    fs::read(path).map_err(|e| error!("{} cannot be read: {}", path, e))
+}
+
+/// Writes a certificate for `localhost` and `127.0.0.1` signed by its own key, returning the
+/// paths of the certificate and the key.
+#[cfg(test)]
+pub fn write_self_signed(dir: &::std::path::Path) -> (String, String) {
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder
+        .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    let alt_names = SubjectAlternativeName::new()
+        .dns("localhost")
+        .ip("127.0.0.1")
+        .build(&builder.x509v3_context(None, None))
+        .unwrap();
+    builder.append_extension(alt_names).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+    fs::create_dir_all(dir).unwrap();
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    fs::write(&cert_path, builder.build().to_pem().unwrap()).unwrap();
+    fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+    (
+        cert_path.to_string_lossy().into_owned(),
+        key_path.to_string_lossy().into_owned(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn test_client_trusts_the_given_certificate() {
+        let dir = TempDir::new("tls");
+        let (cert_path, key_path) = write_self_signed(dir.path());
+
+        let tls_acceptor = acceptor(&cert_path, &key_path).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(mut stream) = tls_acceptor.accept(stream.unwrap()) {
+                    let _ = stream.write_all(b"hello");
+                }
+            }
+        });
+
+        let untrusted = connector(None).unwrap().connect(
+            "localhost",
+            TcpStream::connect(("127.0.0.1", port)).unwrap(),
+        );
+        assert!(untrusted.is_err());
+
+        let mut stream = connector(Some(&cert_path))
+            .unwrap()
+            .connect(
+                "localhost",
+                TcpStream::connect(("127.0.0.1", port)).unwrap(),
+            )
+            .unwrap();
+        let mut greeting = [0u8; 5];
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(b"hello", &greeting);
+
+        assert!(acceptor(&cert_path, "missing.pem").is_err());
+    }
+}
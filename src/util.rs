@@ -3,7 +3,7 @@ use std::cmp::Ordering;
 use std::fmt;
 use std::hash::Hash;
 
-#[derive(Serialize, Deserialize, Eq, Hash)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 #[serde(tag = "type", content = "val")]
 pub enum Val {
     U32(u32),
@@ -21,14 +21,14 @@ impl fmt::Debug for Val {
 
 impl Val {
     pub fn from(raw: String, data_type: &query::Type) -> Option<Val> {
-        match data_type {
-            &query::Type::Int => Val::wrap_raw_int(raw),
-            &query::Type::Varchar(n) => Val::wrap_raw_varchar(raw, n),
+        match *data_type {
+            query::Type::Int => Val::wrap_raw_int(raw),
+            query::Type::Varchar(n) => Val::wrap_raw_varchar(raw, n),
         }
     }
 
     fn wrap_raw_int(raw: String) -> Option<Val> {
-        let num_res = u32::from_str_radix(&raw[..], 10);
+        let num_res = raw.parse::<u32>();
 
         if num_res.is_err() {
             return None;
@@ -38,17 +38,27 @@ impl Val {
     }
 
     fn wrap_raw_varchar(raw: String, len: u8) -> Option<Val> {
-        Some(Val::Varchar(raw[0..(len as usize)].to_owned()))
+        Some(Val::Varchar(
+            raw[0..usize::min(len as usize, raw.len())].to_owned(),
+        ))
     }
 }
 
-impl PartialEq for Val {
-    fn eq(&self, other: &Val) -> bool {
-        match (self, other) {
-            (Val::U32(l), Val::U32(r)) => l == r,
-            (Val::Varchar(l), Val::Varchar(r)) => l == r,
-            _ => false,
-        }
+impl From<u32> for Val {
+    fn from(n: u32) -> Val {
+        Val::U32(n)
+    }
+}
+
+impl<'a> From<&'a str> for Val {
+    fn from(s: &'a str) -> Val {
+        Val::Varchar(s.to_owned())
+    }
+}
+
+impl From<String> for Val {
+    fn from(s: String) -> Val {
+        Val::Varchar(s)
     }
 }
 
@@ -0,0 +1,583 @@
+use query;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use util;
+
+/// Version of the binary protocol, checked in the handshake.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Rows sent in one batch of a result.
+pub const BATCH_SIZE: usize = 256;
+
+// Opens the handshake, so connections speaking something else are dropped right away.
+const MAGIC: &[u8; 4] = b"TDB\x01";
+
+// Bigger frames are taken as garbage instead of buffered.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+const TAG_HELLO: u8 = 1;
+const TAG_REQUEST: u8 = 2;
+const TAG_AUTH: u8 = 3;
+const TAG_READY: u8 = 16;
+const TAG_COLUMNS: u8 = 17;
+const TAG_ROWS: u8 = 18;
+const TAG_COMPLETE: u8 = 19;
+const TAG_ERROR: u8 = 20;
+const TAG_AUTH_REQUEST: u8 = 21;
+
+/// A message of the binary protocol. On the wire each one is a big endian `u32` length, then a
+/// tag byte and the payload. Integers are varints, strings are length prefixed UTF-8.
+///
+/// The client opens with `Hello` and the server answers `Ready`. A server requiring
+/// authentication answers `AuthRequest` first, and the client sends `Auth` with a password or
+/// a token. Then the client sends
+/// `Request`s, without waiting for the previous answers if it wants. Every request is answered
+/// in order, by optional `Columns` and `Rows` batches and a closing `Complete` or `Error`,
+/// all carrying the id of the request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Hello {
+        version: u16,
+    },
+    // The body is the same as of an HTTP request: TQL or a JSON request.
+    Request {
+        id: u32,
+        body: String,
+    },
+    Auth {
+        user: String,
+        secret: String,
+    },
+    Ready {
+        version: u16,
+        session: String,
+    },
+    AuthRequest,
+    Columns {
+        id: u32,
+        columns: Vec<(String, query::Type)>,
+    },
+    Rows {
+        id: u32,
+        rows: Vec<Vec<util::Val>>,
+    },
+    Complete {
+        id: u32,
+        affected: u64,
+        generated_ids: Vec<u32>,
+        message: Option<String>,
+        statement: Option<u64>,
+    },
+    Error {
+        id: u32,
+        kind: String,
+        message: String,
+    },
+}
+
+impl Frame {
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut buf = Encoder::default();
+
+        match self {
+            Frame::Hello { version } => {
+                buf.u8(TAG_HELLO);
+                buf.bytes.extend_from_slice(MAGIC);
+                buf.varint(u64::from(*version));
+            }
+            Frame::Request { id, body } => {
+                buf.u8(TAG_REQUEST);
+                buf.varint(u64::from(*id));
+                buf.string(body);
+            }
+            Frame::Auth { user, secret } => {
+                buf.u8(TAG_AUTH);
+                buf.string(user);
+                buf.string(secret);
+            }
+            Frame::AuthRequest => buf.u8(TAG_AUTH_REQUEST),
+            Frame::Ready { version, session } => {
+                buf.u8(TAG_READY);
+                buf.varint(u64::from(*version));
+                buf.string(session);
+            }
+            Frame::Columns { id, columns } => {
+                buf.u8(TAG_COLUMNS);
+                buf.varint(u64::from(*id));
+                buf.varint(columns.len() as u64);
+                for (name, data_type) in columns {
+                    buf.string(name);
+                    buf.data_type(data_type);
+                }
+            }
+            Frame::Rows { id, rows } => {
+                buf.u8(TAG_ROWS);
+                buf.varint(u64::from(*id));
+                buf.varint(rows.len() as u64);
+                for row in rows {
+                    buf.varint(row.len() as u64);
+                    for val in row {
+                        buf.val(val);
+                    }
+                }
+            }
+            Frame::Complete {
+                id,
+                affected,
+                generated_ids,
+                message,
+                statement,
+            } => {
+                buf.u8(TAG_COMPLETE);
+                buf.varint(u64::from(*id));
+                buf.varint(*affected);
+                buf.varint(generated_ids.len() as u64);
+                for generated_id in generated_ids {
+                    buf.varint(u64::from(*generated_id));
+                }
+                match message {
+                    Some(message) => {
+                        buf.u8(1);
+                        buf.string(message);
+                    }
+                    None => buf.u8(0),
+                }
+                match statement {
+                    Some(statement) => {
+                        buf.u8(1);
+                        buf.varint(*statement);
+                    }
+                    None => buf.u8(0),
+                }
+            }
+            Frame::Error { id, kind, message } => {
+                buf.u8(TAG_ERROR);
+                buf.varint(u64::from(*id));
+                buf.string(kind);
+                buf.string(message);
+            }
+        }
+
+        w.write_all(&(buf.bytes.len() as u32).to_be_bytes())?;
+        w.write_all(&buf.bytes)
+    }
+
+    /// Reads the next frame, or `None` if the peer closed the connection in between frames.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Option<Frame>> {
+        let mut len = [0u8; 4];
+        match r.read_exact(&mut len) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 || len > MAX_FRAME_LEN {
+            return Err(invalid_data("Invalid frame length"));
+        }
+
+        let mut bytes = vec![0u8; len];
+        r.read_exact(&mut bytes)?;
+        Frame::decode(&bytes).map(Some)
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Frame> {
+        let mut buf = Decoder { bytes, pos: 0 };
+
+        let frame = match buf.u8()? {
+            TAG_HELLO => {
+                if buf.take(MAGIC.len())? != MAGIC {
+                    return Err(invalid_data("Not a toydb client"));
+                }
+                Frame::Hello {
+                    version: buf.varint_u16()?,
+                }
+            }
+            TAG_REQUEST => Frame::Request {
+                id: buf.varint_u32()?,
+                body: buf.string()?,
+            },
+            TAG_AUTH => Frame::Auth {
+                user: buf.string()?,
+                secret: buf.string()?,
+            },
+            TAG_AUTH_REQUEST => Frame::AuthRequest,
+            TAG_READY => Frame::Ready {
+                version: buf.varint_u16()?,
+                session: buf.string()?,
+            },
+            TAG_COLUMNS => {
+                let id = buf.varint_u32()?;
+                let len = buf.len()?;
+                let mut columns = Vec::with_capacity(len);
+                for _ in 0..len {
+                    columns.push((buf.string()?, buf.data_type()?));
+                }
+                Frame::Columns { id, columns }
+            }
+            TAG_ROWS => {
+                let id = buf.varint_u32()?;
+                let len = buf.len()?;
+                let mut rows = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let row_len = buf.len()?;
+                    let mut row = Vec::with_capacity(row_len);
+                    for _ in 0..row_len {
+                        row.push(buf.val()?);
+                    }
+                    rows.push(row);
+                }
+                Frame::Rows { id, rows }
+            }
+            TAG_COMPLETE => {
+                let id = buf.varint_u32()?;
+                let affected = buf.varint()?;
+                let len = buf.len()?;
+                let mut generated_ids = Vec::with_capacity(len);
+                for _ in 0..len {
+                    generated_ids.push(buf.varint_u32()?);
+                }
+                let message = match buf.u8()? {
+                    0 => None,
+                    _ => Some(buf.string()?),
+                };
+                let statement = match buf.u8()? {
+                    0 => None,
+                    _ => Some(buf.varint()?),
+                };
+                Frame::Complete {
+                    id,
+                    affected,
+                    generated_ids,
+                    message,
+                    statement,
+                }
+            }
+            TAG_ERROR => Frame::Error {
+                id: buf.varint_u32()?,
+                kind: buf.string()?,
+                message: buf.string()?,
+            },
+            _ => return Err(invalid_data("Unknown frame")),
+        };
+
+        if buf.pos != bytes.len() {
+            return Err(invalid_data("Trailing bytes in frame"));
+        }
+        Ok(frame)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    // LEB128, so small numbers take a single byte.
+    fn varint(&mut self, mut n: u64) {
+        while n >= 0x80 {
+            self.bytes.push((n as u8 & 0x7f) | 0x80);
+            n >>= 7;
+        }
+        self.bytes.push(n as u8);
+    }
+
+    fn string(&mut self, s: &str) {
+        self.varint(s.len() as u64);
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn data_type(&mut self, data_type: &query::Type) {
+        match data_type {
+            query::Type::Int => self.u8(0),
+            query::Type::Varchar(n) => {
+                self.u8(1);
+                self.u8(*n);
+            }
+        }
+    }
+
+    fn val(&mut self, val: &util::Val) {
+        match val {
+            util::Val::U32(n) => {
+                self.u8(0);
+                self.varint(u64::from(*n));
+            }
+            util::Val::Varchar(s) => {
+                self.u8(1);
+                self.string(s);
+            }
+        }
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < n {
+            return Err(invalid_data("Truncated frame"));
+        }
+
+        let bytes = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut n: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            n |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(invalid_data("Varint too long"))
+    }
+
+    fn varint_u32(&mut self) -> io::Result<u32> {
+        let n = self.varint()?;
+        if n > u64::from(u32::MAX) {
+            return Err(invalid_data("Number out of range"));
+        }
+        Ok(n as u32)
+    }
+
+    fn varint_u16(&mut self) -> io::Result<u16> {
+        let n = self.varint()?;
+        if n > u64::from(u16::MAX) {
+            return Err(invalid_data("Number out of range"));
+        }
+        Ok(n as u16)
+    }
+
+    // Length of a list. Every item takes at least a byte, which keeps allocations in check.
+    fn len(&mut self) -> io::Result<usize> {
+        let n = self.varint()? as usize;
+        if n > self.bytes.len() - self.pos {
+            return Err(invalid_data("Truncated frame"));
+        }
+        Ok(n)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid_data("String is not valid UTF-8"))
+    }
+
+    fn data_type(&mut self) -> io::Result<query::Type> {
+        match self.u8()? {
+            0 => Ok(query::Type::Int),
+            1 => Ok(query::Type::Varchar(self.u8()?)),
+            _ => Err(invalid_data("Unknown type")),
+        }
+    }
+
+    fn val(&mut self) -> io::Result<util::Val> {
+        match self.u8()? {
+            0 => Ok(util::Val::U32(self.varint_u32()?)),
+            1 => Ok(util::Val::Varchar(self.string()?)),
+            _ => Err(invalid_data("Unknown value")),
+        }
+    }
+}
+
+/// Client side of a binary protocol connection.
+#[derive(Debug)]
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    last_id: u32,
+    session: String,
+}
+
+impl Connection {
+    #[cfg(test)]
+    pub fn connect(host: &str, port: u16) -> io::Result<Connection> {
+        Connection::connect_as(host, port, None)
+    }
+
+    /// Connects with a user and its password or token, sent if the server asks for them. The
+    /// user can be left empty with a token.
+    pub fn connect_as(
+        host: &str,
+        port: u16,
+        credentials: Option<(&str, &str)>,
+    ) -> io::Result<Connection> {
+        let stream = TcpStream::connect((host, port))?;
+        stream.set_nodelay(true)?;
+
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            last_id: 0,
+            session: String::new(),
+        };
+
+        connection.write(&Frame::Hello {
+            version: PROTOCOL_VERSION,
+        })?;
+        connection.flush()?;
+        let mut answer = connection.read()?;
+        if answer == Frame::AuthRequest {
+            let (user, secret) = credentials.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Server requires authentication",
+                )
+            })?;
+            connection.write(&Frame::Auth {
+                user: user.to_owned(),
+                secret: secret.to_owned(),
+            })?;
+            connection.flush()?;
+            answer = connection.read()?;
+        }
+        match answer {
+            Frame::Ready { session, .. } => connection.session = session,
+            Frame::Error { message, .. } => {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, message))
+            }
+            _ => return Err(invalid_data("Unexpected handshake answer")),
+        }
+
+        Ok(connection)
+    }
+
+    /// Session of the connection on the server, holding its transaction.
+    #[cfg(test)]
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    /// Queues a request, sent on the next `flush`. Returns the id its answers carry.
+    pub fn send(&mut self, body: &str) -> io::Result<u32> {
+        self.last_id = self.last_id.wrapping_add(1);
+        let id = self.last_id;
+        self.write(&Frame::Request {
+            id,
+            body: body.to_owned(),
+        })?;
+        Ok(id)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Reads the next frame of the answers, failing if the server closed the connection.
+    pub fn read(&mut self) -> io::Result<Frame> {
+        Frame::read_from(&mut self.reader)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Server closed"))
+    }
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        frame.write_to(&mut self.writer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(frame: Frame) {
+        let mut bytes = vec![];
+        frame.write_to(&mut bytes).unwrap();
+
+        assert_eq!(Some(frame), Frame::read_from(&mut &bytes[..]).unwrap());
+    }
+
+    #[test]
+    fn test_frames_round_trip() {
+        round_trip(Frame::Hello { version: 1 });
+        round_trip(Frame::Request {
+            id: 300,
+            body: "? id > users".to_owned(),
+        });
+        round_trip(Frame::Columns {
+            id: 1,
+            columns: vec![
+                ("id".to_owned(), query::Type::Int),
+                ("name".to_owned(), query::Type::Varchar(8)),
+            ],
+        });
+        round_trip(Frame::Rows {
+            id: 1,
+            rows: vec![
+                vec![
+                    util::Val::U32(u32::MAX),
+                    util::Val::Varchar("Ann".to_owned()),
+                ],
+                vec![util::Val::U32(0), util::Val::Varchar("".to_owned())],
+            ],
+        });
+        round_trip(Frame::Complete {
+            id: 2,
+            affected: 1,
+            generated_ids: vec![5],
+            message: None,
+            statement: Some(7),
+        });
+        round_trip(Frame::AuthRequest);
+        round_trip(Frame::Auth {
+            user: "ann".to_owned(),
+            secret: "pw".to_owned(),
+        });
+        round_trip(Frame::Error {
+            id: 3,
+            kind: "parse".to_owned(),
+            message: "Query cannot be parsed".to_owned(),
+        });
+    }
+
+    #[test]
+    fn test_vals_are_compact() {
+        let mut bytes = vec![];
+        Frame::Rows {
+            id: 1,
+            rows: vec![vec![util::Val::U32(5), util::Val::Varchar("ab".to_owned())]],
+        }
+        .write_to(&mut bytes)
+        .unwrap();
+
+        // Length, tag, id, row count, row length, then 2 bytes per number and 4 for the string.
+        assert_eq!(4 + 1 + 1 + 1 + 1 + 2 + 4, bytes.len());
+    }
+
+    #[test]
+    fn test_invalid_frames_are_rejected() {
+        let mut bytes = vec![];
+        Frame::Request {
+            id: 1,
+            body: "? id > users".to_owned(),
+        }
+        .write_to(&mut bytes)
+        .unwrap();
+
+        assert!(Frame::read_from(&mut &bytes[..bytes.len() - 1]).is_err());
+        assert!(Frame::read_from(&mut &[0u8, 0, 0, 1, 99][..]).is_err());
+        assert!(Frame::read_from(&mut &[0xffu8, 0xff, 0xff, 0xff][..]).is_err());
+        assert!(
+            Frame::read_from(&mut &[0u8, 0, 0, 5, TAG_HELLO, b'H', b'T', b'T', b'P'][..]).is_err()
+        );
+        assert_eq!(None, Frame::read_from(&mut &[][..]).unwrap());
+    }
+}
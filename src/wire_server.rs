@@ -0,0 +1,471 @@
+use api;
+use auth;
+use dbserver;
+use engine_operator;
+use listener;
+use query_parser;
+use statements;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use wire::{self, Frame};
+
+/// Serves binary protocol connections, each on its own thread. Every connection is a session,
+/// its open transaction is rolled back when it closes. When auth is required the client
+/// authenticates once, after the handshake.
+#[derive(Debug, Clone)]
+pub struct WireServer {
+    engine_operator: Arc<engine_operator::EngineOperator>,
+    query_parser: Arc<query_parser::QueryParser>,
+    statements: Arc<statements::StatementCache>,
+}
+
+impl WireServer {
+    pub fn new(
+        engine_operator: Arc<engine_operator::EngineOperator>,
+        query_parser: Arc<query_parser::QueryParser>,
+        statements: Arc<statements::StatementCache>,
+    ) -> WireServer {
+        WireServer {
+            engine_operator,
+            query_parser,
+            statements,
+        }
+    }
+
+    pub fn serve(&self, listener: TcpListener, limits: listener::ConnectionLimits) {
+        let server = self.clone();
+        listener::serve(listener, limits, "Binary", move |stream| {
+            server.handle_connection(stream)
+        });
+    }
+
+    fn handle_connection(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let is_loopback = stream.peer_addr()?.ip().is_loopback();
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+
+        match Frame::read_from(&mut reader)? {
+            Some(Frame::Hello { version }) if version == wire::PROTOCOL_VERSION => {}
+            Some(Frame::Hello { version }) => {
+                Frame::Error {
+                    id: 0,
+                    kind: api::ErrorKind::InvalidRequest.as_str().to_owned(),
+                    message: format!("Unsupported protocol version {}", version),
+                }
+                .write_to(&mut writer)?;
+                return writer.flush();
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Missing handshake",
+                ))
+            }
+        }
+
+        let user = match self.authenticate(is_loopback, &mut reader, &mut writer)? {
+            Ok(user) => user,
+            Err(()) => return writer.flush(),
+        };
+
+        let session = auth::new_session_id("wire");
+        Frame::Ready {
+            version: wire::PROTOCOL_VERSION,
+            session: session.clone(),
+        }
+        .write_to(&mut writer)?;
+        writer.flush()?;
+
+        let res = self.handle_requests(&session, user.as_deref(), &mut reader, &mut writer);
+        self.engine_operator.end_session(user.as_deref(), &session);
+        self.statements.end_session(user.as_deref(), &session);
+        res
+    }
+
+    // Asks for the credentials when auth is required. Refused credentials are answered with an
+    // error frame. There's no TLS on this port, so they are only taken over loopback.
+    fn authenticate(
+        &self,
+        is_loopback: bool,
+        reader: &mut BufReader<TcpStream>,
+        writer: &mut BufWriter<TcpStream>,
+    ) -> io::Result<Result<Option<String>, ()>> {
+        if !self.engine_operator.is_auth_required() {
+            return Ok(Ok(None));
+        }
+        if !is_loopback {
+            warn!("Credentials cannot be taken in cleartext over the network");
+            Frame::Error {
+                id: 0,
+                kind: api::ErrorKind::Unauthenticated.as_str().to_owned(),
+                message: "Authentication is only possible over loopback, use HTTP with TLS"
+                    .to_owned(),
+            }
+            .write_to(writer)?;
+            return Ok(Err(()));
+        }
+
+        Frame::AuthRequest.write_to(writer)?;
+        writer.flush()?;
+        match Frame::read_from(reader)? {
+            // A token is enough to know the user.
+            Some(Frame::Auth { user, secret }) if user.is_empty() => {
+                if let Some(user) = self.engine_operator.token_user(&secret) {
+                    return Ok(Ok(Some(user)));
+                }
+                warn!("Authentication failed for an unknown token");
+            }
+            Some(Frame::Auth { user, secret }) => {
+                if self.engine_operator.authenticate(&user, &secret) {
+                    return Ok(Ok(Some(user)));
+                }
+                warn!("Authentication failed for {}", user);
+            }
+            _ => warn!("Missing credentials"),
+        }
+
+        Frame::Error {
+            id: 0,
+            kind: api::ErrorKind::Unauthenticated.as_str().to_owned(),
+            message: "Authentication failed".to_owned(),
+        }
+        .write_to(writer)?;
+        Ok(Err(()))
+    }
+
+    fn handle_requests(
+        &self,
+        session: &str,
+        user: Option<&str>,
+        reader: &mut BufReader<TcpStream>,
+        writer: &mut BufWriter<TcpStream>,
+    ) -> io::Result<()> {
+        while let Some(frame) = Frame::read_from(reader)? {
+            let (id, body) = match frame {
+                Frame::Request { id, body } => (id, body),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unexpected frame",
+                    ))
+                }
+            };
+
+            let result = dbserver::execute_request(
+                &body,
+                Some(session),
+                user,
+                self.engine_operator.clone(),
+                self.query_parser.clone(),
+                self.statements.clone(),
+            );
+            write_result(writer, id, result)?;
+
+            // Answers of pipelined requests already received go out together.
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Rows are written batch by batch as they are read. A row that cannot be read ends the
+// answer with an error after the batches already sent.
+fn write_result<W: Write>(
+    w: &mut W,
+    id: u32,
+    result: Result<engine_operator::QueryStream, api::ErrorDetails>,
+) -> io::Result<()> {
+    let engine_operator::QueryStream { mut result, rows } = match result {
+        Ok(stream) => stream,
+        Err(error) => return write_error(w, id, error),
+    };
+
+    if !result.columns.is_empty() {
+        Frame::Columns {
+            id,
+            columns: result
+                .columns
+                .into_iter()
+                .map(|column| (column.name, column.data_type))
+                .collect(),
+        }
+        .write_to(w)?;
+    }
+
+    // Rows already collected, like those of a fetch, come before the streamed ones.
+    let mut rows = mem::take(&mut result.rows)
+        .into_iter()
+        .map(Ok)
+        .chain(rows.into_iter().flatten());
+    loop {
+        let batch = match rows
+            .by_ref()
+            .take(wire::BATCH_SIZE)
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(batch) => batch,
+            Err(()) => {
+                return write_error(
+                    w,
+                    id,
+                    api::ErrorDetails::new(api::ErrorKind::Execution, "Row cannot be read"),
+                )
+            }
+        };
+        let is_last = batch.len() < wire::BATCH_SIZE;
+        if !batch.is_empty() {
+            Frame::Rows { id, rows: batch }.write_to(w)?;
+        }
+        if is_last {
+            break;
+        }
+    }
+
+    Frame::Complete {
+        id,
+        affected: result.affected as u64,
+        generated_ids: result.generated_ids,
+        message: result.message,
+        statement: result.statement,
+    }
+    .write_to(w)
+}
+
+fn write_error<W: Write>(w: &mut W, id: u32, error: api::ErrorDetails) -> io::Result<()> {
+    Frame::Error {
+        id,
+        kind: error.kind.as_str().to_owned(),
+        message: error.message,
+    }
+    .write_to(w)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use query;
+    use std::path::Path;
+    use std::thread;
+    use std::time::Duration;
+    use table_sync;
+    use temp_dir::TempDir;
+    use util::Val;
+
+    fn start(data_dir: &Path) -> (u16, Arc<engine_operator::EngineOperator>) {
+        start_with_auth(data_dir, false)
+    }
+
+    fn start_with_auth(
+        data_dir: &Path,
+        is_auth_required: bool,
+    ) -> (u16, Arc<engine_operator::EngineOperator>) {
+        let engine_operator = Arc::new(
+            engine_operator::EngineOperator::new(table_sync::TableSyncer::new(data_dir))
+                .with_auth(is_auth_required),
+        );
+        engine_operator.init().unwrap();
+        let server = WireServer::new(
+            engine_operator.clone(),
+            Default::default(),
+            Default::default(),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || server.serve(listener, Default::default()));
+        (port, engine_operator)
+    }
+
+    #[test]
+    fn test_pipelined_requests_are_answered_in_order() {
+        let dir = TempDir::new("wire");
+        let (port, _) = start(dir.path());
+        let mut connection = wire::Connection::connect("127.0.0.1", port).unwrap();
+
+        let ids: Vec<u32> = [
+            "+ wire id int name varchar 8",
+            "> wire id 1 name Ann",
+            "? id name > wire",
+            "? nope > wire",
+        ]
+        .iter()
+        .map(|body| connection.send(body).unwrap())
+        .collect();
+        connection.flush().unwrap();
+
+        let mut frames = vec![];
+        while frames.len() < 6 {
+            frames.push(connection.read().unwrap());
+        }
+
+        let complete = |id, affected| Frame::Complete {
+            id,
+            affected,
+            generated_ids: vec![],
+            message: None,
+            statement: None,
+        };
+        assert_eq!(
+            vec![
+                complete(ids[0], 0),
+                complete(ids[1], 1),
+                Frame::Columns {
+                    id: ids[2],
+                    columns: vec![
+                        ("id".to_owned(), query::Type::Int),
+                        ("name".to_owned(), query::Type::Varchar(8)),
+                    ],
+                },
+                Frame::Rows {
+                    id: ids[2],
+                    rows: vec![vec![Val::U32(1), Val::Varchar("Ann".to_owned())]],
+                },
+                complete(ids[2], 0),
+                Frame::Error {
+                    id: ids[3],
+                    kind: "execution".to_owned(),
+                    message: "Query failed, see the server log for details".to_owned(),
+                },
+            ],
+            frames
+        );
+    }
+
+    #[test]
+    fn test_results_are_streamed_in_batches() {
+        let dir = TempDir::new("wire-batches");
+        let (port, _) = start(dir.path());
+        let mut connection = wire::Connection::connect("127.0.0.1", port).unwrap();
+
+        connection.send("+ batches id int").unwrap();
+        for i in 0..wire::BATCH_SIZE + 1 {
+            connection.send(&format!("> batches id {}", i)).unwrap();
+        }
+        let id = connection.send("? id > batches").unwrap();
+        connection.flush().unwrap();
+
+        let mut batches = vec![];
+        loop {
+            match connection.read().unwrap() {
+                Frame::Rows { id: rows_id, rows } if rows_id == id => batches.push(rows.len()),
+                Frame::Complete {
+                    id: complete_id, ..
+                } if complete_id == id => break,
+                _ => {}
+            }
+        }
+        assert_eq!(vec![wire::BATCH_SIZE, 1], batches);
+    }
+
+    #[test]
+    fn test_open_transaction_is_rolled_back_on_disconnect() {
+        let dir = TempDir::new("wire-tx");
+        let (port, engine_operator) = start(dir.path());
+
+        let mut first = wire::Connection::connect("127.0.0.1", port).unwrap();
+        let second = wire::Connection::connect("127.0.0.1", port).unwrap();
+        assert_ne!(first.session(), second.session());
+        let session = first.session().to_owned();
+
+        for body in &["+ wire_tx id int", ":begin", "> wire_tx id 1"] {
+            first.send(body).unwrap();
+        }
+        first.flush().unwrap();
+        for _ in 0..3 {
+            assert!(matches!(first.read().unwrap(), Frame::Complete { .. }));
+        }
+        drop(first);
+
+        // The rollback happens once the server notices the closed connection, after which the
+        // session can begin a new transaction.
+        let begin = || {
+            let query = query_parser::QueryParser.parse(":begin").unwrap();
+            engine_operator.execute(None, Some(&session), query)
+        };
+        let mut is_rolled_back = false;
+        for _ in 0..50 {
+            if begin().is_ok() {
+                is_rolled_back = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(is_rolled_back);
+
+        let query = query_parser::QueryParser.parse("? id > wire_tx").unwrap();
+        assert!(engine_operator
+            .execute(None, None, query)
+            .unwrap()
+            .rows
+            .is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_version_is_refused() {
+        let dir = TempDir::new("wire-version");
+        let (port, _) = start(dir.path());
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        Frame::Hello { version: 99 }.write_to(&mut stream).unwrap();
+
+        assert!(matches!(
+            Frame::read_from(&mut stream).unwrap(),
+            Some(Frame::Error { ref message, .. }) if message.contains("version")
+        ));
+    }
+
+    #[test]
+    fn test_statements_need_credentials_and_grants() {
+        let dir = TempDir::new("wire-auth");
+        let (port, engine_operator) = start_with_auth(dir.path(), true);
+        engine_operator.ensure_admin("admin", "secret").unwrap();
+
+        let refused = |credentials| wire::Connection::connect_as("127.0.0.1", port, credentials);
+        assert!(refused(None).is_err());
+        assert!(refused(Some(("admin", "wrong"))).is_err());
+
+        let request = |connection: &mut wire::Connection, body: &str| {
+            let id = connection.send(body).unwrap();
+            connection.flush().unwrap();
+            loop {
+                match connection.read().unwrap() {
+                    Frame::Complete { message, .. } if message.is_some() => return message,
+                    Frame::Complete { .. } => return None,
+                    Frame::Error {
+                        id: frame_id, kind, ..
+                    } if frame_id == id => return Some(kind),
+                    _ => {}
+                }
+            }
+        };
+
+        let mut admin =
+            wire::Connection::connect_as("127.0.0.1", port, Some(("admin", "secret"))).unwrap();
+        assert_eq!(None, request(&mut admin, ":create_user ann pw"));
+        assert_eq!(None, request(&mut admin, "+ wire_auth id int"));
+
+        let mut ann = wire::Connection::connect_as("127.0.0.1", port, Some(("ann", "pw"))).unwrap();
+        assert_eq!(
+            Some("permission_denied".to_owned()),
+            request(&mut ann, "? id > wire_auth")
+        );
+        assert_eq!(None, request(&mut admin, ":grant select wire_auth ann"));
+        assert_eq!(None, request(&mut ann, "? id > wire_auth"));
+        assert_eq!(
+            Some("permission_denied".to_owned()),
+            request(&mut ann, "> wire_auth id 1")
+        );
+
+        // A token stands for the user it was issued to.
+        let token = request(&mut ann, ":token").unwrap();
+        let mut by_token =
+            wire::Connection::connect_as("127.0.0.1", port, Some(("", token.trim()))).unwrap();
+        assert_eq!(None, request(&mut by_token, "? id > wire_auth"));
+    }
+}